@@ -0,0 +1,162 @@
+//! Hashing the members of tar archives, so one manifest can cover both loose files and the
+//! contents of archives without extracting them.
+
+use flate2::read::GzDecoder;
+use indicatif::ProgressBar;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use crate::{
+    events::Event,
+    hashing::{self, HashSettings, Hashed, SecondaryHash},
+    ScanState, ScannedFile,
+};
+
+/// Whether a file looks like an archive we know how to read, going by its name.
+pub fn is_archive(path: &Path) -> bool {
+    let name = path.to_string_lossy().to_lowercase();
+    name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// Split the path of an archive member, as recorded by `scan_archive`, into the path of the
+/// archive and the member's path within it.
+pub fn split_member(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let path = path.to_str()?;
+    path.match_indices('!').find_map(|(i, _)| {
+        let archive = Path::new(&path[..i]);
+        is_archive(archive).then(|| (archive.to_path_buf(), PathBuf::from(&path[i + 1..])))
+    })
+}
+
+/// Call `f` with the path and contents of every regular file in a tar archive, in the order
+/// they are stored.
+pub fn for_each_member(
+    path: &Path,
+    mut f: impl FnMut(PathBuf, &mut dyn Read) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let file = File::open(path)?;
+    let name = path.to_string_lossy().to_lowercase();
+    let reader: Box<dyn Read> = match name.ends_with(".tar") {
+        true => Box::new(file),
+        false => Box::new(GzDecoder::new(file)),
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let member = entry.path()?.into_owned();
+        f(member, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Hash every regular file in a tar archive, returning the member paths and their hashes.
+fn hash_members(path: &Path, settings: &HashSettings) -> std::io::Result<Vec<(PathBuf, Hashed)>> {
+    let mut members = vec![];
+    for_each_member(path, |member, reader| {
+        let algorithm = settings.algorithm_for(&member);
+        if let Some(hashed) = hashing::hash_reader(reader, algorithm, settings)? {
+            members.push((member, hashed));
+        }
+        Ok(())
+    })?;
+    Ok(members)
+}
+
+/// Hash the members of the archive at `path`. Members are recorded with paths of the form
+/// `archive.tar!member/path`.
+pub async fn scan_archive(
+    state: &ScanState,
+    progressbar: &ProgressBar,
+    path: &Path,
+) -> Vec<ScannedFile> {
+    progressbar.set_message(format!("Scanning archive: {}", path.to_string_lossy()));
+
+    let archive_path = path.to_path_buf();
+    let settings = state.hash_settings.clone();
+    let members = tokio::task::spawn_blocking(move || hash_members(&archive_path, &settings))
+        .await
+        .unwrap();
+
+    let members = match members {
+        Ok(members) => members,
+        Err(e) => {
+            state.report_io_error(
+                progressbar,
+                path,
+                format!("Cannot read archive: {} {}", path.to_string_lossy(), e),
+                &e,
+            );
+            return vec![];
+        }
+    };
+
+    members
+        .into_iter()
+        .map(|(member, mut hashed)| {
+            let mut member_path = path.as_os_str().to_owned();
+            member_path.push("!");
+            member_path.push(member.as_os_str());
+            let member_path = PathBuf::from(member_path);
+
+            state.files_hashed.fetch_add(1, Ordering::Relaxed);
+            state
+                .bytes_hashed
+                .fetch_add(hashed.bytes_read, Ordering::Relaxed);
+            let algorithm = state.hash_settings.algorithm_for(&member);
+            let chunk_hashes = hashed.chunk_hashes.take();
+            let ssdeep = hashed.ssdeep.take();
+            let cid = hashed.cid.take();
+            let cdc_chunks = hashed.cdc_chunks.take();
+            let line_endings_normalized = hashed.line_endings_normalized;
+            if state.args.hash_filenames {
+                hashed.update_filename(&member);
+            }
+            let also = hashed.take_also();
+            let hash = hashed.hex_digest();
+            let digests = state
+                .hash_settings
+                .requested_digests(algorithm, &hash, &also);
+            if let Some(events) = &state.events {
+                events.emit(&Event::File {
+                    path: &member_path,
+                    hash: &hash,
+                });
+            }
+
+            ScannedFile {
+                hash: Some(hash),
+                path: member_path,
+                attributes: None,
+                error: None,
+                raced: false,
+                chunk_hashes,
+                streams: BTreeMap::new(),
+                algorithm: (algorithm != state.args.algorithm()).then_some(algorithm),
+                sampled: None,
+                link_target: None,
+                link_target_resolved: false,
+                size_mismatch: None,
+                filename_hashed: state.args.hash_filenames,
+                line_endings_normalized,
+                secondary: SecondaryHash::find(state.args.secondary_algorithm, &also),
+                digests,
+                tree: None,
+                ssdeep,
+                pieces_root: None,
+                cid,
+                cdc_chunks,
+                perceptual_hash: None,
+                hash_duration_ms: None,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,93 @@
+//! Compact binary output with `--format msgpack`. Hashes are stored as raw digest bytes rather
+//! than hex, halving their size, and files are listed once each rather than keyed by hash.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+use crate::{
+    manifest::{Header, Manifest},
+    ScannedFile,
+};
+
+/// Hashes wrapped with the header describing the scan that produced them, unless `--raw` was
+/// given.
+#[derive(Serialize, Deserialize)]
+pub struct BinaryManifest<H, F> {
+    pub header: Option<H>,
+    pub files: Vec<F>,
+}
+
+/// Encode a scan's files as MessagePack.
+pub fn encode(
+    header: Option<&Header>,
+    data: &HashMap<String, Vec<ScannedFile>>,
+) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let manifest = BinaryManifest {
+        header,
+        files: data.values().flatten().collect(),
+    };
+    // Fields are named as many are left out when empty, which positional encoding can't express
+    rmp_serde::to_vec_named(&manifest)
+}
+
+/// Decode a scan written by `encode`, grouping its files by hash again.
+pub fn decode(data: &[u8]) -> Result<Manifest, rmp_serde::decode::Error> {
+    let manifest: BinaryManifest<Header, ScannedFile> = rmp_serde::from_slice(data)?;
+    let mut files: HashMap<String, Vec<ScannedFile>> = HashMap::new();
+    for file in manifest.files {
+        files.entry(file.group_key()).or_default().push(file);
+    }
+    Ok(Manifest {
+        header: manifest.header,
+        files,
+    })
+}
+
+/// Serialize a hex hash as raw bytes for binary formats, and as it is for text formats.
+pub fn serialize_hash<S: Serializer>(
+    hash: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let bytes = hash
+        .as_deref()
+        .filter(|_| !serializer.is_human_readable())
+        .and_then(from_hex);
+    match bytes {
+        Some(bytes) => serializer.serialize_some(&ByteBuf::from(bytes)),
+        None => hash.serialize(serializer),
+    }
+}
+
+/// Read a hash written by `serialize_hash`, in either form.
+pub fn deserialize_hash<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hash {
+        Hex(String),
+        Bytes(ByteBuf),
+    }
+
+    if deserializer.is_human_readable() {
+        return Option::<String>::deserialize(deserializer);
+    }
+    Ok(
+        Option::<Hash>::deserialize(deserializer)?.map(|hash| match hash {
+            Hash::Hex(hex) => hex,
+            Hash::Bytes(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        }),
+    )
+}
+
+/// Decode a lowercase hex string, as every hash is written.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
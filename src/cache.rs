@@ -0,0 +1,124 @@
+//! Hashes kept between scans in `{name}.cache.json`, for `--hash-cache`, so re-scanning a tree
+//! which has mostly not changed only reads the files which have. Unlike `--xattr-cache` this
+//! works on any filesystem and leaves the scanned files untouched.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::{hashing::HashAlgorithm, output, xattr_cache::Cached, Args};
+
+/// A file is assumed to be unchanged if its size and modification time are the same.
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    modified: SystemTime,
+    algorithm: HashAlgorithm,
+    hash: String,
+    /// Both affect the hash, and depend on the file's size and the options given for the scan
+    sampled: Option<u64>,
+    tree: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct HashCache {
+    /// Hashes from the previous scan
+    previous: HashMap<PathBuf, Entry>,
+    /// Hashes of the files seen in this scan, which replace the previous ones when saved so
+    /// files which have since been removed are forgotten
+    current: Mutex<HashMap<PathBuf, Entry>>,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl HashCache {
+    /// Load the hashes saved by the previous scan. A cache which is missing or can't be parsed
+    /// is treated as empty, so every file is hashed again.
+    pub async fn load(args: &Args) -> std::io::Result<Self> {
+        let previous = match tokio::fs::read(args.output_file("cache.json")).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            previous,
+            ..Self::default()
+        })
+    }
+
+    /// The hash saved for the file at `path`, if the file hasn't changed since and it was hashed
+    /// the way this scan would hash it.
+    pub fn get(
+        &self,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        algorithm: HashAlgorithm,
+        sampled: Option<u64>,
+        tree: Option<u64>,
+    ) -> Option<Cached> {
+        let entry = self.previous.get(path).filter(|entry| {
+            entry.size == metadata.len()
+                && metadata
+                    .modified()
+                    .is_ok_and(|modified| modified == entry.modified)
+                && entry.algorithm == algorithm
+                && entry.sampled == sampled
+                && entry.tree == tree
+        });
+        let counter = match entry {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let entry = entry?;
+        // Still hashed this way, so kept for the next scan too
+        self.current
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), entry.clone());
+        Some(Cached {
+            hash: entry.hash.clone(),
+            sampled: entry.sampled,
+            tree: entry.tree,
+        })
+    }
+
+    /// Record the hash of the file at `path`, as of the given metadata.
+    pub fn insert(
+        &self,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        algorithm: HashAlgorithm,
+        cached: Cached,
+    ) {
+        let modified = match metadata.modified() {
+            Ok(modified) => modified,
+            Err(_) => return,
+        };
+        let entry = Entry {
+            size: metadata.len(),
+            modified,
+            algorithm,
+            hash: cached.hash,
+            sampled: cached.sampled,
+            tree: cached.tree,
+        };
+        self.current
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), entry);
+    }
+
+    /// Write the hashes of every file seen in this scan to `{name}.cache.json`, for the next.
+    pub async fn save(&self, args: &Args) -> std::io::Result<()> {
+        let data = serde_json::to_vec(&*self.current.lock().unwrap())?;
+        output::write_atomic(args, "cache.json", data).await
+    }
+}
@@ -0,0 +1,182 @@
+//! Content defined chunking with `--cdc-chunks`. Files are split where a rolling hash of their
+//! contents says to, using FastCDC, rather than at fixed offsets, so data shared between files
+//! which aren't identical, such as a log file and the same log appended to, splits into the same
+//! chunks with the same hashes.
+
+use fastcdc::v2020::{FastCDC, AVERAGE_MAX, AVERAGE_MIN};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hashing::{HashAlgorithm, Hasher},
+    spill,
+};
+
+/// Parse the average chunk size for `--cdc-chunks`, within the range FastCDC supports.
+pub fn parse_average_size(size: &str) -> Result<u32, String> {
+    let bytes = spill::parse_byte_size(size)?;
+    if bytes < AVERAGE_MIN as u64 || bytes > AVERAGE_MAX as u64 {
+        return Err(format!(
+            "the average chunk size must be between {} and {} bytes",
+            AVERAGE_MIN, AVERAGE_MAX
+        ));
+    }
+    Ok(bytes as u32)
+}
+
+/// One chunk of a file, as recorded in its entry.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CdcChunk {
+    pub offset: u64,
+    pub length: u64,
+    pub hash: String,
+}
+
+/// Splits a file into chunks as its contents are read.
+pub struct Chunker {
+    hasher: Hasher,
+    /// Chunks are between a quarter and four times this size, as FastCDC recommends
+    average: u32,
+    /// Read but not yet split off into a chunk, which is never more than the largest chunk
+    /// plus one read
+    pending: Vec<u8>,
+    offset: u64,
+    chunks: Vec<CdcChunk>,
+}
+
+impl Chunker {
+    pub fn new(algorithm: HashAlgorithm, average: u32) -> Self {
+        Self {
+            hasher: algorithm.hasher(),
+            average,
+            pending: vec![],
+            offset: 0,
+            chunks: vec![],
+        }
+    }
+
+    fn cdc<'a>(&self, source: &'a [u8]) -> FastCDC<'a> {
+        FastCDC::new(source, self.average / 4, self.average, self.average * 4)
+    }
+
+    fn push(&mut self, length: usize) {
+        self.hasher.update(&self.pending[..length]);
+        self.chunks.push(CdcChunk {
+            offset: self.offset,
+            length: length as u64,
+            hash: self.hasher.finalize_reset(),
+        });
+        self.offset += length as u64;
+        self.pending.drain(..length);
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        // FastCDC never looks further than the largest chunk ahead for a cut point, so a cut
+        // found once that much is pending is where it would be in the whole file
+        let max = self.average as usize * 4;
+        while self.pending.len() >= max {
+            let (_, end) = self.cdc(&self.pending).cut(0, self.pending.len());
+            self.push(end);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<CdcChunk> {
+        let lengths: Vec<usize> = self.cdc(&self.pending).map(|chunk| chunk.length).collect();
+        for length in lengths {
+            self.push(length);
+        }
+        self.chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    const AVERAGE: u32 = 4096;
+
+    /// `len` bytes from a xorshift generator, varied enough for FastCDC to find cut points.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    /// The chunks of `data`, fed to a `Chunker` in pieces of `piece` bytes.
+    fn chunks(data: &[u8], piece: usize) -> Vec<CdcChunk> {
+        let mut chunker = Chunker::new(HashAlgorithm::Sha256, AVERAGE);
+        for piece in data.chunks(piece) {
+            chunker.update(piece);
+        }
+        chunker.finish()
+    }
+
+    #[test]
+    fn chunks_cover_the_file_in_order() {
+        let data = noise(1_000_000);
+        let chunks = chunks(&data, 65536);
+        let mut offset = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, offset);
+            let contents = &data[offset as usize..][..chunk.length as usize];
+            assert_eq!(chunk.hash, format!("{:x}", Sha256::digest(contents)));
+            offset += chunk.length;
+        }
+        assert_eq!(offset, data.len() as u64);
+    }
+
+    #[test]
+    fn chunks_are_within_the_size_limits() {
+        let chunks = chunks(&noise(1_000_000), 65536);
+        assert!(chunks.len() > 100, "only {} chunks", chunks.len());
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert!(
+                (AVERAGE as u64 / 4..=AVERAGE as u64 * 4).contains(&chunk.length),
+                "{:?}",
+                chunk
+            );
+        }
+        // Only the end of the file can be cut short
+        assert!(last.length <= AVERAGE as u64 * 4, "{:?}", last);
+    }
+
+    #[test]
+    fn chunks_dont_depend_on_how_the_file_is_read() {
+        let data = noise(300_000);
+        let whole: Vec<(u64, u64)> = FastCDC::new(&data, AVERAGE / 4, AVERAGE, AVERAGE * 4)
+            .map(|chunk| (chunk.offset as u64, chunk.length as u64))
+            .collect();
+        for piece in [1000, 4096, 65536, data.len()] {
+            let streamed: Vec<(u64, u64)> = chunks(&data, piece)
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.length))
+                .collect();
+            assert_eq!(streamed, whole, "{}", piece);
+        }
+    }
+
+    #[test]
+    fn an_inserted_byte_only_changes_the_chunk_holding_it() {
+        let data = noise(500_000);
+        let mut inserted = data.clone();
+        inserted.insert(100, 0xaa);
+
+        let hashes = |data: &[u8]| -> Vec<String> {
+            chunks(data, 65536)
+                .into_iter()
+                .map(|chunk| chunk.hash)
+                .collect()
+        };
+        let (before, after) = (hashes(&data), hashes(&inserted));
+        assert_ne!(before[0], after[0]);
+        assert_eq!(before[1..], after[1..]);
+    }
+}
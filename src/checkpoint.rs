@@ -0,0 +1,82 @@
+//! Periodic checkpoints of a scan's progress, so an interrupted scan can be resumed with
+//! `--resume` rather than started again from scratch.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{output, Args, ScanState, ScannedFile};
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Directories whose files have all been hashed and whose children have been queued
+    pub completed: HashSet<PathBuf>,
+    /// Everything hashed so far
+    pub result: HashMap<String, Vec<ScannedFile>>,
+}
+
+impl Checkpoint {
+    /// Load the checkpoint left by a previous run, or an empty one if there is none.
+    pub async fn load(args: &Args) -> std::io::Result<Self> {
+        match tokio::fs::read(args.output_file("checkpoint")).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the checkpoint once it is no longer needed.
+    pub async fn remove(args: &Args) -> std::io::Result<()> {
+        match tokio::fs::remove_file(args.output_file("checkpoint")).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Snapshot the scan's progress to `{name}.checkpoint`.
+async fn write(state: &ScanState) -> std::io::Result<()> {
+    let data = {
+        // Directories are only marked complete while holding the result lock, so holding it
+        // here guarantees the two halves of the checkpoint agree with each other.
+        let result = state.result.read().await;
+        let completed = state.completed.lock().unwrap();
+        serde_json::to_vec(&CheckpointRef {
+            completed: &completed,
+            result: &result,
+        })?
+    };
+
+    output::write_atomic(&state.args, "checkpoint", data).await
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    completed: &'a HashSet<PathBuf>,
+    result: &'a HashMap<String, Vec<ScannedFile>>,
+}
+
+/// Write a checkpoint periodically until the scan completes.
+pub async fn checkpoint_ticker(state: Arc<ScanState>) {
+    let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+    interval.tick().await; // The first tick completes immediately
+    loop {
+        interval.tick().await;
+        if state.main_pb.read().await.is_finished() {
+            break;
+        }
+        if let Err(e) = write(&state).await {
+            state
+                .main_pb
+                .read()
+                .await
+                .println(format!("Failed to write checkpoint: {}", e));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Comparing two directory trees directly, file-for-file by content hash, without a manifest.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use crate::hashing::{self, HashAlgorithm, HashSettings};
+
+/// Hash every file under `root`, keyed by its path relative to `root`. Symlinked directories
+/// aren't descended into, so a link cycle can't make this run forever. Directories which can't
+/// be read are recorded as errors against their path, as unreadable files are, so only failing
+/// to read `root` itself is an error.
+fn hash_tree(root: &Path) -> std::io::Result<BTreeMap<PathBuf, std::io::Result<String>>> {
+    let mut hashes = BTreeMap::new();
+    let relative = |path: &Path| path.strip_prefix(root).unwrap().to_path_buf();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(e),
+            Err(e) => {
+                hashes.insert(relative(&dir), Err(e));
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    hashes.insert(relative(&dir), Err(e));
+                    break;
+                }
+            };
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    stack.push(path);
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    hashes.insert(relative(&path), Err(e));
+                    continue;
+                }
+            }
+            if !path.is_file() {
+                continue;
+            }
+            let hash = std::fs::File::open(&path)
+                .and_then(|file| {
+                    hashing::hash_reader(file, HashAlgorithm::default(), &HashSettings::default())
+                })
+                .map(|hashed| {
+                    hashed
+                        .expect("every file is hashed without --text-only")
+                        .hex_digest()
+                });
+            hashes.insert(relative(&path), hash);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Scan both trees, printing files only present on one side and files whose contents differ.
+/// Returns whether the trees matched, or an error if either root can't be read.
+pub async fn compare_directories(left: PathBuf, right: PathBuf) -> std::io::Result<bool> {
+    let (left_hashes, right_hashes) = tokio::try_join!(
+        tokio::task::spawn_blocking({
+            let left = left.clone();
+            move || hash_tree(&left)
+        }),
+        tokio::task::spawn_blocking({
+            let right = right.clone();
+            move || hash_tree(&right)
+        }),
+    )
+    .unwrap();
+    let (left_hashes, right_hashes) = (left_hashes?, right_hashes?);
+
+    let paths: BTreeSet<&PathBuf> = left_hashes.keys().chain(right_hashes.keys()).collect();
+    let mut differences = 0;
+    for path in &paths {
+        let shown = path.to_string_lossy();
+        match (left_hashes.get(*path), right_hashes.get(*path)) {
+            (Some(Ok(l)), Some(Ok(r))) if l == r => continue,
+            (Some(Ok(_)), Some(Ok(_))) => println!("differs: {}", shown),
+            (Some(Err(e)), _) => println!("error: {} {}", left.join(path).to_string_lossy(), e),
+            (_, Some(Err(e))) => println!("error: {} {}", right.join(path).to_string_lossy(), e),
+            (Some(_), None) => println!("only in {}: {}", left.to_string_lossy(), shown),
+            (None, Some(_)) => println!("only in {}: {}", right.to_string_lossy(), shown),
+            (None, None) => unreachable!("every path came from one side"),
+        }
+        differences += 1;
+    }
+    println!(
+        "{} of {} files match",
+        paths.len() - differences,
+        paths.len()
+    );
+    Ok(differences == 0)
+}
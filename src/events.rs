@@ -0,0 +1,62 @@
+//! Machine-readable progress for embedding dexy in another program. Events are written as
+//! newline-delimited JSON to a side channel, independent of the progress bars and the output
+//! file.
+
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::{Args, ScanSummary};
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A file was successfully hashed
+    File { path: &'a Path, hash: &'a str },
+    /// A file or directory could not be processed
+    Error { path: &'a Path, message: &'a str },
+    /// A directory finished processing, `total` grows as new directories are discovered
+    Progress { done: u64, total: u64 },
+    /// The scan is complete, this is always the last event emitted
+    Done { summary: &'a ScanSummary },
+}
+
+pub struct EventSink {
+    out: Mutex<LineWriter<File>>,
+}
+
+impl EventSink {
+    /// Open the events channel requested by the user, if any.
+    pub fn from_args(args: &Args) -> std::io::Result<Option<Self>> {
+        if let Some(path) = &args.events_file {
+            return Ok(Some(Self::new(File::create(path)?)));
+        }
+
+        #[cfg(unix)]
+        if let Some(fd) = args.events_fd {
+            use std::os::unix::io::FromRawFd;
+            // SAFETY: the fd is handed to us by the parent process, which is responsible for
+            // keeping it open for the duration of the scan. We take ownership and close it on exit.
+            return Ok(Some(Self::new(unsafe { File::from_raw_fd(fd) })));
+        }
+
+        Ok(None)
+    }
+
+    fn new(file: File) -> Self {
+        Self {
+            out: Mutex::new(LineWriter::new(file)),
+        }
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let mut line = serde_json::to_vec(event).expect("events are always serializable");
+        line.push(b'\n');
+        // The consumer going away shouldn't take the scan down with it, so write errors are ignored.
+        let _ = self.out.lock().unwrap().write_all(&line);
+    }
+}
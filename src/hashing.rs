@@ -0,0 +1,991 @@
+//! Feeding file contents through a hasher.
+
+use clap::ArgEnum;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use memmap2::{Mmap, MmapOptions};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    num::NonZeroUsize,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    cdc::{CdcChunk, Chunker},
+    ipfs::CidHasher,
+    throttle::RateLimiter,
+    torrent::{PieceHasher, PieceHashes},
+    Args,
+};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+/// How much of a mapped file is hashed at a time, with `--io-backend mmap`
+const MAPPED_PIECE_SIZE: usize = 1024 * 1024;
+
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    /// BLAKE3, cryptographic and much faster than SHA-2 on modern CPUs
+    Blake3,
+    /// 64-bit XXH3, much faster but only suitable for spotting accidental changes
+    Xxh3,
+    /// For matching against existing checksums, collisions can be deliberately constructed
+    Md5,
+    /// For matching against existing checksums, collisions can be deliberately constructed
+    Sha1,
+}
+
+impl HashAlgorithm {
+    /// The name of the algorithm, as accepted on the command line and written to the output.
+    pub fn name(&self) -> &'static str {
+        self.to_possible_value()
+            .expect("no algorithms are hidden")
+            .get_name()
+    }
+
+    /// Whether the algorithm is designed to resist deliberately constructed collisions, rather
+    /// than just accidental ones.
+    pub fn is_cryptographic(&self) -> bool {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha512 | HashAlgorithm::Blake3 => true,
+            // Once were, but collisions can now be found with little effort
+            HashAlgorithm::Md5 | HashAlgorithm::Sha1 => false,
+            HashAlgorithm::Xxh3 => false,
+        }
+    }
+
+    /// A fresh hasher for this algorithm.
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+        }
+    }
+}
+
+/// Parse an `--algorithm-for` mapping of the form `ext=algorithm`.
+pub fn parse_algorithm_for(mapping: &str) -> Result<(String, HashAlgorithm), String> {
+    let (extension, algorithm) = mapping
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` isn't of the form ext=algorithm", mapping))?;
+    let algorithm = HashAlgorithm::from_str(algorithm, true)?;
+    let extension = extension.trim_start_matches('.').to_lowercase();
+    Ok((extension, algorithm))
+}
+
+/// A hasher for any of the supported algorithms.
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+    Md5(Md5),
+    Sha1(Sha1),
+    /// HMAC-SHA256 under the key from `--hmac-key-file`
+    HmacSha256(Box<Hmac<Sha256>>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Xxh3(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::HmacSha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Feed in the name of the file at `path`, for `--hash-filenames`. Its length follows, so a
+    /// name can't be mistaken for the end of the contents.
+    pub fn update_filename(&mut self, path: &Path) {
+        let name = crate::path_bytes(path.file_name().unwrap_or_default().as_ref());
+        self.update(&name);
+        self.update(&(name.len() as u64).to_le_bytes());
+    }
+
+    /// The digest of everything fed in so far, as a lowercase hex string, starting afresh.
+    pub(crate) fn finalize_reset(&mut self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize_reset()),
+            Hasher::Sha512(hasher) => format!("{:x}", hasher.finalize_reset()),
+            Hasher::Blake3(hasher) => {
+                let digest = hasher.finalize();
+                hasher.reset();
+                digest.to_hex().to_string()
+            }
+            Hasher::Md5(hasher) => format!("{:x}", hasher.finalize_reset()),
+            Hasher::Sha1(hasher) => format!("{:x}", hasher.finalize_reset()),
+            Hasher::HmacSha256(hasher) => format!("{:x}", hasher.finalize_reset().into_bytes()),
+            Hasher::Xxh3(hasher) => {
+                let digest = hasher.digest();
+                hasher.reset();
+                format!("{:016x}", digest)
+            }
+        }
+    }
+
+    /// The digest of everything fed in, as a lowercase hex string.
+    pub fn finalize(mut self) -> String {
+        self.finalize_reset()
+    }
+}
+
+/// Print every supported algorithm, for `--list-algorithms`.
+pub fn list_algorithms() {
+    for algorithm in HashAlgorithm::value_variants() {
+        let kind = match algorithm.is_cryptographic() {
+            true => "cryptographic",
+            false => "non-cryptographic",
+        };
+        println!("{:<10} {}", algorithm.name(), kind);
+    }
+}
+
+/// Read the key for `--hmac-key-file`, which is used as is, bytes and all.
+pub fn read_key(path: &Path) -> std::io::Result<Arc<[u8]>> {
+    let key = std::fs::read(path)?;
+    if key.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "the key file is empty",
+        ));
+    }
+    Ok(key.into())
+}
+
+/// How file contents are hashed, shared between all workers.
+#[derive(Default)]
+pub struct HashSettings {
+    /// Algorithm used for files without an extension in `algorithm_for`
+    pub algorithm: HashAlgorithm,
+    /// Algorithm to use instead for files with each (lowercase) extension
+    pub algorithm_for: HashMap<String, HashAlgorithm>,
+    /// Limit on the rate at which file contents are read
+    pub limiter: Option<RateLimiter>,
+    /// Files with a NUL byte within this many bytes of the start are treated as binary
+    pub text_sample: usize,
+    /// Skip binary files entirely
+    pub text_only: bool,
+    /// Hash CRLF line endings in text files as if they were LF
+    pub normalize_line_endings: bool,
+    /// Also hash each block of this many bytes separately
+    pub chunk_size: Option<usize>,
+    /// Also hash file contents with these algorithms in the same read, for
+    /// `--secondary-algorithm` and every `--algorithm` after the first
+    pub also: Vec<HashAlgorithm>,
+    /// Every algorithm given to `--algorithm`, if it was given more than once
+    pub requested: Vec<HashAlgorithm>,
+    /// Key from `--hmac-key-file`, making SHA-256 file digests HMAC-SHA256 instead
+    pub key: Option<Arc<[u8]>>,
+    /// Also compute an ssdeep similarity hash of the contents as stored
+    #[cfg_attr(not(feature = "fuzzy"), allow(dead_code))]
+    pub ssdeep: bool,
+    /// Also measure the entropy of the contents as stored
+    pub entropy: bool,
+    /// Also build the BitTorrent v2 hashes of the contents as stored, with pieces of this many
+    /// bytes
+    pub piece_length: Option<u64>,
+    /// Also work out the IPFS CID of the contents as stored
+    pub ipfs_cid: bool,
+    /// Also split the contents as stored into content defined chunks averaging this many bytes
+    pub cdc_average: Option<u32>,
+    /// Threads free to hash blocks of files larger than `--parallel-above`
+    pub tree_threads: ThreadBudget,
+}
+
+/// Threads shared between every file being hashed as blocks in parallel, so several large files
+/// hashed at once by different workers don't start more threads than there are cores.
+pub struct ThreadBudget {
+    free: AtomicUsize,
+}
+
+impl Default for ThreadBudget {
+    /// One thread for each core.
+    fn default() -> Self {
+        Self {
+            free: AtomicUsize::new(
+                std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            ),
+        }
+    }
+}
+
+impl ThreadBudget {
+    /// Take up to `wanted` of the free threads, until the claim is dropped.
+    fn claim(&self, wanted: usize) -> ClaimedThreads<'_> {
+        let free = self
+            .free
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |free| {
+                Some(free - free.min(wanted))
+            })
+            .expect("the update always succeeds");
+        ClaimedThreads {
+            budget: self,
+            threads: free.min(wanted),
+        }
+    }
+}
+
+/// Threads taken from a `ThreadBudget`, returned to it when dropped.
+struct ClaimedThreads<'a> {
+    budget: &'a ThreadBudget,
+    threads: usize,
+}
+
+impl Drop for ClaimedThreads<'_> {
+    fn drop(&mut self) {
+        self.budget.free.fetch_add(self.threads, Ordering::AcqRel);
+    }
+}
+
+impl HashSettings {
+    /// Settings for a scan with `args`, keyed with `key` read from `--hmac-key-file`.
+    pub fn from_args(args: &Args, key: Option<Arc<[u8]>>) -> Self {
+        Self {
+            algorithm: args.algorithm(),
+            algorithm_for: args.algorithm_for.iter().cloned().collect(),
+            limiter: args.max_read_rate.map(|rate| RateLimiter::new(rate.get())),
+            text_sample: args.text_sample_bytes,
+            text_only: args.text_only,
+            normalize_line_endings: args.normalize_line_endings,
+            chunk_size: args.chunk_hashes.map(|size| size.get()),
+            also: {
+                let mut also: Vec<HashAlgorithm> = args.algorithms[1..]
+                    .iter()
+                    .copied()
+                    .chain(args.secondary_algorithm)
+                    .collect();
+                also.sort();
+                also.dedup();
+                also
+            },
+            requested: match args.algorithms.len() {
+                1 => vec![],
+                _ => args.algorithms.clone(),
+            },
+            key,
+            ssdeep: args.ssdeep,
+            entropy: args.entropy,
+            piece_length: args.torrent.then_some(args.torrent_piece_length),
+            ipfs_cid: args.ipfs_cid,
+            cdc_average: args.cdc_chunks,
+            tree_threads: ThreadBudget::default(),
+        }
+    }
+
+    /// A fresh hasher for a file's own digest with `algorithm`, keyed if a key was given.
+    /// Chunk, secondary and other digests are never keyed.
+    pub fn hasher(&self, algorithm: HashAlgorithm) -> Hasher {
+        match (&self.key, algorithm) {
+            (Some(key), HashAlgorithm::Sha256) => Hasher::HmacSha256(Box::new(
+                Hmac::new_from_slice(key).expect("HMAC accepts keys of any length"),
+            )),
+            _ => algorithm.hasher(),
+        }
+    }
+
+    /// The algorithm to hash the file at `path` with, going by its extension.
+    pub fn algorithm_for(&self, path: &Path) -> HashAlgorithm {
+        path.extension()
+            .and_then(|extension| {
+                let extension = extension.to_string_lossy().to_lowercase();
+                self.algorithm_for.get(&extension).copied()
+            })
+            .unwrap_or(self.algorithm)
+    }
+
+    /// The other algorithms to hash a file with, besides its own `algorithm`.
+    fn also_for(&self, algorithm: HashAlgorithm) -> Vec<HashAlgorithm> {
+        self.also
+            .iter()
+            .copied()
+            .filter(|also| *also != algorithm)
+            .collect()
+    }
+
+    /// The digests to record for a file when `--algorithm` was given more than once: its `hash`
+    /// from `algorithm`, and those from every other algorithm requested.
+    pub fn requested_digests(
+        &self,
+        algorithm: HashAlgorithm,
+        hash: &str,
+        also: &BTreeMap<HashAlgorithm, String>,
+    ) -> BTreeMap<HashAlgorithm, String> {
+        if self.requested.is_empty() {
+            return BTreeMap::new();
+        }
+        let mut digests: BTreeMap<HashAlgorithm, String> = also
+            .iter()
+            .filter(|(requested, _)| self.requested.contains(requested))
+            .map(|(requested, digest)| (*requested, digest.clone()))
+            .collect();
+        digests.insert(algorithm, hash.to_string());
+        digests
+    }
+}
+
+/// A second hash of a file's contents, with `--secondary-algorithm`.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SecondaryHash {
+    pub algorithm: HashAlgorithm,
+    pub hash: String,
+}
+
+impl SecondaryHash {
+    /// The hash from `algorithm` among the other digests of a file, if one was requested.
+    pub fn find(
+        algorithm: Option<HashAlgorithm>,
+        also: &BTreeMap<HashAlgorithm, String>,
+    ) -> Option<Self> {
+        let algorithm = algorithm?;
+        Some(Self {
+            algorithm,
+            hash: also.get(&algorithm)?.clone(),
+        })
+    }
+}
+
+/// The result of reading a file through the hasher.
+pub struct Hashed {
+    pub hasher: Hasher,
+    /// The same contents fed through every other algorithm requested
+    pub also: Vec<(HashAlgorithm, Hasher)>,
+    pub bytes_read: u64,
+    /// Hashes of each block of the file, if requested
+    pub chunk_hashes: Option<Vec<String>>,
+    /// Only this many bytes from each end of the file were hashed, see `hash_sampled`
+    pub sampled: Option<u64>,
+    /// The file was hashed as blocks of this many bytes in parallel, see `hash_tree`
+    pub tree: Option<u64>,
+    /// Similarity hash of the contents, if requested
+    pub ssdeep: Option<String>,
+    /// Entropy of the contents, if requested
+    pub entropy: Option<Entropy>,
+    /// BitTorrent v2 hashes of the contents, if requested
+    pub pieces: Option<PieceHashes>,
+    /// IPFS CID of the contents, if requested
+    pub cid: Option<String>,
+    /// Content defined chunks of the contents, if requested
+    pub cdc_chunks: Option<Vec<CdcChunk>>,
+    /// CRLF line endings were hashed as LF, as the file was taken to be text
+    pub line_endings_normalized: bool,
+}
+
+impl Hashed {
+    /// Feed the name of the file at `path` into every hash, for `--hash-filenames`.
+    pub fn update_filename(&mut self, path: &Path) {
+        self.hasher.update_filename(path);
+        for (_, hasher) in &mut self.also {
+            hasher.update_filename(path);
+        }
+    }
+
+    /// The digests from every other algorithm requested, by algorithm.
+    pub fn take_also(&mut self) -> BTreeMap<HashAlgorithm, String> {
+        std::mem::take(&mut self.also)
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+            .collect()
+    }
+
+    /// The final digest, as a lowercase hex string.
+    pub fn hex_digest(self) -> String {
+        self.hasher.finalize()
+    }
+}
+
+/// Everything worked out from a file's contents as they're passed through once.
+struct Pass<'a> {
+    algorithm: HashAlgorithm,
+    settings: &'a HashSettings,
+    total: u64,
+    /// Until enough has been read to tell whether the file is text, what has been read is held
+    /// back as it may need to be hashed differently
+    sniffed: Vec<u8>,
+    also: Vec<HashAlgorithm>,
+    sink: Option<Sink>,
+    /// Blocks are always of the file as stored, even if line endings are normalized
+    blocks: Option<Blocks>,
+    #[cfg(feature = "fuzzy")]
+    ssdeep: Option<crate::ssdeep::Ssdeep>,
+    byte_counts: Option<ByteCounts>,
+    pieces: Option<PieceHasher>,
+    cid: Option<CidHasher>,
+    chunker: Option<Chunker>,
+}
+
+impl<'a> Pass<'a> {
+    fn new(algorithm: HashAlgorithm, settings: &'a HashSettings) -> Self {
+        let must_sniff = settings.text_only || settings.normalize_line_endings;
+        let also = settings.also_for(algorithm);
+        Self {
+            algorithm,
+            settings,
+            total: 0,
+            sniffed: vec![],
+            sink: (!must_sniff).then(|| Sink::new(settings.hasher(algorithm), &also, false)),
+            also,
+            blocks: settings.chunk_size.map(|size| Blocks::new(algorithm, size)),
+            #[cfg(feature = "fuzzy")]
+            ssdeep: settings.ssdeep.then(crate::ssdeep::Ssdeep::new),
+            byte_counts: settings.entropy.then(ByteCounts::new),
+            pieces: settings.piece_length.map(PieceHasher::new),
+            cid: settings.ipfs_cid.then(CidHasher::new),
+            chunker: settings
+                .cdc_average
+                .map(|average| Chunker::new(algorithm, average)),
+        }
+    }
+
+    /// Pass on the next of the contents, returning `false` if the file should be skipped as it
+    /// looks like a binary file and only text files were requested.
+    fn update(&mut self, data: &[u8]) -> bool {
+        if let Some(limiter) = &self.settings.limiter {
+            limiter.consume(data.len());
+        }
+        self.total += data.len() as u64;
+        if let Some(blocks) = &mut self.blocks {
+            blocks.update(data);
+        }
+        #[cfg(feature = "fuzzy")]
+        if let Some(ssdeep) = &mut self.ssdeep {
+            ssdeep.update(data);
+        }
+        if let Some(byte_counts) = &mut self.byte_counts {
+            byte_counts.update(data);
+        }
+        if let Some(pieces) = &mut self.pieces {
+            pieces.update(data);
+        }
+        if let Some(cid) = &mut self.cid {
+            cid.update(data);
+        }
+        if let Some(chunker) = &mut self.chunker {
+            chunker.update(data);
+        }
+
+        match &mut self.sink {
+            Some(sink) => sink.update(data),
+            None => {
+                self.sniffed.extend_from_slice(data);
+                if self.sniffed.len() >= self.settings.text_sample {
+                    match Sink::after_sniffing(&self.sniffed, self.algorithm, self.settings) {
+                        Some(sink) => self.sink = Some(sink),
+                        None => return false,
+                    }
+                    self.sniffed = vec![];
+                }
+            }
+        }
+        true
+    }
+
+    fn finish(self) -> Option<Hashed> {
+        let sink = match self.sink {
+            Some(sink) => sink,
+            None => Sink::after_sniffing(&self.sniffed, self.algorithm, self.settings)?,
+        };
+        let line_endings_normalized = sink.normalize_line_endings;
+        let (hasher, also_hashers) = sink.finish();
+        #[cfg(feature = "fuzzy")]
+        let ssdeep = self.ssdeep.map(crate::ssdeep::Ssdeep::finish);
+        #[cfg(not(feature = "fuzzy"))]
+        let ssdeep = None;
+        Some(Hashed {
+            hasher,
+            also: self.also.into_iter().zip(also_hashers).collect(),
+            bytes_read: self.total,
+            chunk_hashes: self.blocks.map(Blocks::finish),
+            sampled: None,
+            tree: None,
+            ssdeep,
+            entropy: self.byte_counts.map(|counts| counts.entropy()),
+            pieces: self.pieces.map(PieceHasher::finish),
+            cid: self.cid.map(CidHasher::finish),
+            cdc_chunks: self.chunker.map(Chunker::finish),
+            line_endings_normalized,
+        })
+    }
+}
+
+/// Hash everything that can be read from `reader` with `algorithm`, returning `None` if the
+/// contents were skipped because they look like a binary file and only text files were
+/// requested.
+pub fn hash_reader<R: Read>(
+    mut reader: R,
+    algorithm: HashAlgorithm,
+    settings: &HashSettings,
+) -> std::io::Result<Option<Hashed>> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut pass = Pass::new(algorithm, settings);
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if !pass.update(&buffer[..read]) {
+            return Ok(None);
+        }
+    }
+    Ok(pass.finish())
+}
+
+/// Hash a file mapped into memory with `map_file` as `hash_reader` would, straight from the
+/// map rather than copying it through a buffer.
+pub fn hash_mapped(
+    map: &Mmap,
+    algorithm: HashAlgorithm,
+    settings: &HashSettings,
+) -> Option<Hashed> {
+    let mut pass = Pass::new(algorithm, settings);
+    // Passed on a piece at a time so --max-read-rate still applies as it's read
+    for piece in map.chunks(MAPPED_PIECE_SIZE) {
+        if !pass.update(piece) {
+            return None;
+        }
+    }
+    pass.finish()
+}
+
+/// Fingerprint a file from its first and last `sample` bytes and its size, which is much faster
+/// than reading all of a large file. Files too small for the two samples not to overlap are
+/// hashed in full instead. As only part of the file is read, `bytes_read` is its size.
+pub fn hash_sampled<R: Read + Seek>(
+    mut reader: R,
+    algorithm: HashAlgorithm,
+    settings: &HashSettings,
+    sample: u64,
+) -> std::io::Result<Option<Hashed>> {
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+    if size < sample * 2 {
+        return hash_reader(reader, algorithm, settings);
+    }
+
+    let mut hasher = settings.hasher(algorithm);
+    let mut also: Vec<(HashAlgorithm, Hasher)> = settings
+        .also_for(algorithm)
+        .into_iter()
+        .map(|algorithm| (algorithm, algorithm.hasher()))
+        .collect();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    for start in [0, size - sample] {
+        reader.seek(SeekFrom::Start(start))?;
+        let mut remaining = sample;
+        while remaining > 0 {
+            let want = remaining.min(BUFFER_SIZE as u64) as usize;
+            reader.read_exact(&mut buffer[..want])?;
+            if let Some(limiter) = &settings.limiter {
+                limiter.consume(want);
+            }
+            hasher.update(&buffer[..want]);
+            for (_, hasher) in &mut also {
+                hasher.update(&buffer[..want]);
+            }
+            remaining -= want as u64;
+        }
+    }
+    hasher.update(&size.to_le_bytes());
+    for (_, hasher) in &mut also {
+        hasher.update(&size.to_le_bytes());
+    }
+
+    Ok(Some(Hashed {
+        hasher,
+        also,
+        bytes_read: size,
+        chunk_hashes: None,
+        sampled: Some(sample),
+        tree: None,
+        ssdeep: None,
+        entropy: None,
+        pieces: None,
+        cid: None,
+        cdc_chunks: None,
+        line_endings_normalized: false,
+    }))
+}
+
+/// How file contents are read to be hashed, for `--io-backend`.
+#[derive(ArgEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Read each file through a buffer
+    #[default]
+    Read,
+    /// Map each file into memory, saving a system call for every buffer's worth read
+    Mmap,
+}
+
+/// Map all of `file` into memory for `--io-backend mmap`. Returns `None` for anything but a
+/// regular file large enough to need more than one read, if it can't be mapped, or if it's
+/// already shorter than what was mapped, in which case it should be read as usual.
+pub fn map_file(file: &File) -> Option<Mmap> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() <= BUFFER_SIZE as u64 {
+        return None;
+    }
+    // Safety: the map is only ever read from, and only as far as the file's size when it was
+    // opened, so a file which grows is hashed as it was and then retried by the caller. Reading
+    // past the end of a file truncated by another process faults on unix, so the size is checked
+    // again once mapped, and a file found to be shorter is read through a buffer instead. That
+    // leaves only files truncated while they're being hashed.
+    let map = unsafe { MmapOptions::new().len(metadata.len() as usize).map(file) }.ok()?;
+    if file.metadata().ok()?.len() < map.len() as u64 {
+        return None;
+    }
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Some(map)
+}
+
+/// Hash a large file as blocks of `block_size` bytes, read and hashed in parallel on as many of
+/// the threads in `settings.tree_threads` as are free, or just one if none are.
+/// The result is a hash of each block's digest in turn followed by the size, so it differs from
+/// a hash of the whole file, but files with the same contents still share it.
+pub fn hash_tree(
+    file: File,
+    algorithm: HashAlgorithm,
+    settings: &HashSettings,
+    block_size: u64,
+) -> std::io::Result<Hashed> {
+    let size = file.metadata()?.len();
+    let blocks = size.div_ceil(block_size) as usize;
+    let also = settings.also_for(algorithm);
+    let algorithms: Vec<HashAlgorithm> = std::iter::once(algorithm).chain(also).collect();
+    let claimed = settings.tree_threads.claim(blocks);
+    let threads = claimed.threads.max(1);
+    let next_block = AtomicUsize::new(0);
+
+    // The digests of each block, from every algorithm in turn
+    let mut digests: Vec<(usize, Vec<String>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> std::io::Result<Vec<(usize, Vec<String>)>> {
+                    let mut hashed = vec![];
+                    let mut buffer = vec![0; BUFFER_SIZE];
+                    loop {
+                        let block = next_block.fetch_add(1, Ordering::Relaxed);
+                        if block >= blocks {
+                            return Ok(hashed);
+                        }
+                        let mut hashers: Vec<Hasher> =
+                            algorithms.iter().map(HashAlgorithm::hasher).collect();
+                        let mut offset = block as u64 * block_size;
+                        let end = (offset + block_size).min(size);
+                        while offset < end {
+                            let want = (end - offset).min(BUFFER_SIZE as u64) as usize;
+                            read_exact_at(&file, &mut buffer[..want], offset)?;
+                            if let Some(limiter) = &settings.limiter {
+                                limiter.consume(want);
+                            }
+                            for hasher in &mut hashers {
+                                hasher.update(&buffer[..want]);
+                            }
+                            offset += want as u64;
+                        }
+                        hashed.push((block, hashers.into_iter().map(Hasher::finalize).collect()));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<std::io::Result<Vec<_>>>()
+            .map(|hashed| hashed.into_iter().flatten().collect())
+    })?;
+    digests.sort_by_key(|(block, _)| *block);
+
+    // Only the combined digest is keyed, as the block digests aren't recorded
+    let mut hashers: Vec<Hasher> = std::iter::once(settings.hasher(algorithm))
+        .chain(algorithms[1..].iter().map(HashAlgorithm::hasher))
+        .collect();
+    for (_, block) in &digests {
+        for (hasher, digest) in hashers.iter_mut().zip(block) {
+            hasher.update(digest.as_bytes());
+        }
+    }
+    for hasher in &mut hashers {
+        hasher.update(&size.to_le_bytes());
+    }
+
+    let mut hashers = algorithms.into_iter().zip(hashers);
+    let (_, hasher) = hashers
+        .next()
+        .expect("the file's own algorithm comes first");
+    Ok(Hashed {
+        hasher,
+        also: hashers.collect(),
+        bytes_read: size,
+        chunk_hashes: None,
+        sampled: None,
+        tree: Some(block_size),
+        ssdeep: None,
+        entropy: None,
+        pieces: None,
+        cid: None,
+        cdc_chunks: None,
+        line_endings_normalized: false,
+    })
+}
+
+/// Fill `buffer` from `offset` in the file without moving its cursor, so several threads can
+/// read from it at once.
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.read_exact_at(buffer, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.seek_read(&mut buffer[filled..], offset + filled as u64) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes fixed size blocks of a file, for working out which parts of it have changed.
+struct Blocks {
+    size: usize,
+    hasher: Hasher,
+    filled: usize,
+    hashes: Vec<String>,
+}
+
+impl Blocks {
+    fn new(algorithm: HashAlgorithm, size: usize) -> Self {
+        Self {
+            size,
+            hasher: algorithm.hasher(),
+            filled: 0,
+            hashes: vec![],
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (self.size - self.filled).min(data.len());
+            self.hasher.update(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled == self.size {
+                self.hashes.push(self.hasher.finalize_reset());
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// The hash of every block, the last of which may be short.
+    fn finish(mut self) -> Vec<String> {
+        if self.filled > 0 {
+            self.hashes.push(self.hasher.finalize());
+        }
+        self.hashes
+    }
+}
+
+/// Shannon entropy of a file's contents in bits per byte, from 0 for a single repeated byte up to
+/// 8 for random data such as encrypted or compressed files.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Entropy(pub f64);
+
+// Entropies are only ever compared as recorded, so comparing their bits is enough
+impl PartialEq for Entropy {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for Entropy {}
+
+impl std::hash::Hash for Entropy {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+/// How often each byte value appears in a file, for working out its entropy.
+struct ByteCounts(Box<[u64; 256]>);
+
+impl ByteCounts {
+    fn new() -> Self {
+        Self(Box::new([0; 256]))
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0[*byte as usize] += 1;
+        }
+    }
+
+    fn entropy(&self) -> Entropy {
+        let total: u64 = self.0.iter().sum();
+        let bits = self
+            .0
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / total as f64;
+                p * (1.0 / p).log2()
+            })
+            .fold(0.0, |bits, term| bits + term);
+        Entropy(bits)
+    }
+}
+
+/// Feeds file contents into the hasher, converting CRLF line endings to LF if requested.
+struct Sink {
+    hasher: Hasher,
+    also: Vec<Hasher>,
+    normalize_line_endings: bool,
+    /// The last chunk ended with a CR, which is dropped if the next chunk starts with LF
+    pending_cr: bool,
+}
+
+impl Sink {
+    fn new(hasher: Hasher, also: &[HashAlgorithm], normalize_line_endings: bool) -> Self {
+        Self {
+            hasher,
+            also: also.iter().map(HashAlgorithm::hasher).collect(),
+            normalize_line_endings,
+            pending_cr: false,
+        }
+    }
+
+    /// Decide how to hash a file from the first bytes read from it, and feed those bytes in.
+    /// Returns `None` if the file should be skipped.
+    fn after_sniffing(
+        sniffed: &[u8],
+        algorithm: HashAlgorithm,
+        settings: &HashSettings,
+    ) -> Option<Self> {
+        let sample = &sniffed[..settings.text_sample.min(sniffed.len())];
+        let is_text = !sample.contains(&0);
+        if !is_text && settings.text_only {
+            return None;
+        }
+
+        let mut sink = Self::new(
+            settings.hasher(algorithm),
+            &settings.also_for(algorithm),
+            is_text && settings.normalize_line_endings,
+        );
+        sink.update(sniffed);
+        Some(sink)
+    }
+
+    /// Feed data through every hasher, as it is.
+    fn feed(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        for hasher in &mut self.also {
+            hasher.update(data);
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if !self.normalize_line_endings {
+            self.feed(data);
+            return;
+        }
+
+        if std::mem::take(&mut self.pending_cr) && data.first() != Some(&b'\n') {
+            self.feed(b"\r");
+        }
+        let mut start = 0;
+        for (i, _) in data.iter().enumerate().filter(|(_, b)| **b == b'\r') {
+            match data.get(i + 1) {
+                Some(b'\n') => {}
+                Some(_) => continue,
+                None => self.pending_cr = true,
+            }
+            self.feed(&data[start..i]);
+            start = i + 1;
+        }
+        self.feed(&data[start..]);
+    }
+
+    /// The hasher for the file's own algorithm and those for every other, with everything fed
+    /// in.
+    fn finish(mut self) -> (Hasher, Vec<Hasher>) {
+        if self.pending_cr {
+            self.feed(b"\r");
+        }
+        (self.hasher, self.also)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entropy(data: &[u8]) -> f64 {
+        let mut counts = ByteCounts::new();
+        counts.update(data);
+        counts.entropy().0
+    }
+
+    #[test]
+    fn a_single_repeated_byte_has_no_entropy() {
+        assert_eq!(entropy(&[b'a'; 1000]), 0.0);
+        assert_eq!(entropy(b"z"), 0.0);
+    }
+
+    #[test]
+    fn every_byte_equally_often_is_eight_bits() {
+        let ramp: Vec<u8> = (0..=255).collect();
+        assert_eq!(entropy(&ramp), 8.0);
+        assert_eq!(entropy(&ramp.repeat(3)), 8.0);
+    }
+
+    #[test]
+    fn two_bytes_equally_often_is_one_bit() {
+        assert_eq!(entropy(&b"ab".repeat(500)), 1.0);
+    }
+}
@@ -0,0 +1,232 @@
+//! IPFS CIDv1 identifiers for `--ipfs-cid`, matching `ipfs add --cid-version 1` with its default
+//! settings: 256KiB chunks stored as raw leaves, linked by a balanced tree of UnixFS nodes with
+//! at most 174 links each. A file which fits in a single chunk is just that raw leaf.
+
+use sha2::{Digest, Sha256};
+
+/// Size of the chunks a file is split into.
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Maximum number of links from one node to its children.
+const MAX_LINKS: usize = 174;
+
+/// Multicodec of a raw block of file contents.
+const RAW: u64 = 0x55;
+/// Multicodec of a DAG-PB node linking to other blocks.
+const DAG_PB: u64 = 0x70;
+/// Multihash code of SHA-256.
+const SHA2_256: u8 = 0x12;
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A protobuf length-delimited field.
+fn put_bytes(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    out.push(field << 3 | 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// A protobuf varint field.
+fn put_uint(out: &mut Vec<u8>, field: u8, value: u64) {
+    out.push(field << 3);
+    put_varint(out, value);
+}
+
+/// The binary form of a CIDv1 for a block with `codec` and contents hashing to `digest`.
+fn cid_bytes(codec: u64, digest: &[u8]) -> Vec<u8> {
+    let mut cid = vec![1];
+    put_varint(&mut cid, codec);
+    cid.extend_from_slice(&[SHA2_256, digest.len() as u8]);
+    cid.extend_from_slice(digest);
+    cid
+}
+
+/// A CID as text, in lowercase base32 with the `b` multibase prefix.
+fn to_base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut text = String::from("b");
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = buffer << 8 | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    text
+}
+
+/// A block in the tree, as its parent links to it.
+struct Block {
+    cid: Vec<u8>,
+    /// Bytes of file contents below this block
+    file_size: u64,
+    /// Bytes of this block and every block below it, as recorded in links to it
+    tree_size: u64,
+}
+
+/// A UnixFS file node linking to `children`.
+fn link(children: &[Block]) -> Block {
+    let file_size = children.iter().map(|child| child.file_size).sum();
+    let mut unixfs = vec![];
+    put_uint(&mut unixfs, 1, 2); // A file
+    put_uint(&mut unixfs, 3, file_size);
+    for child in children {
+        put_uint(&mut unixfs, 4, child.file_size);
+    }
+
+    // Links are written before the data, as DAG-PB requires
+    let mut node = vec![];
+    for child in children {
+        let mut link = vec![];
+        put_bytes(&mut link, 1, &child.cid);
+        put_bytes(&mut link, 2, b"");
+        put_uint(&mut link, 3, child.tree_size);
+        put_bytes(&mut node, 2, &link);
+    }
+    put_bytes(&mut node, 1, &unixfs);
+
+    Block {
+        cid: cid_bytes(DAG_PB, &Sha256::digest(&node)),
+        file_size,
+        tree_size: node.len() as u64 + children.iter().map(|child| child.tree_size).sum::<u64>(),
+    }
+}
+
+/// Works out a file's CID as its contents are read.
+pub struct CidHasher {
+    chunk: Sha256,
+    chunk_filled: usize,
+    leaves: Vec<Block>,
+}
+
+impl CidHasher {
+    pub fn new() -> Self {
+        Self {
+            chunk: Sha256::new(),
+            chunk_filled: 0,
+            leaves: vec![],
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (CHUNK_SIZE - self.chunk_filled).min(data.len());
+            self.chunk.update(&data[..take]);
+            self.chunk_filled += take;
+            data = &data[take..];
+            if self.chunk_filled == CHUNK_SIZE {
+                self.finish_chunk();
+            }
+        }
+    }
+
+    fn finish_chunk(&mut self) {
+        let size = std::mem::take(&mut self.chunk_filled) as u64;
+        self.leaves.push(Block {
+            cid: cid_bytes(RAW, &self.chunk.finalize_reset()),
+            file_size: size,
+            tree_size: size,
+        });
+    }
+
+    /// The file's CID, as text.
+    pub fn finish(mut self) -> String {
+        // An empty file is a single empty chunk
+        if self.chunk_filled > 0 || self.leaves.is_empty() {
+            self.finish_chunk();
+        }
+        let mut layer = self.leaves;
+        while layer.len() > 1 {
+            layer = layer.chunks(MAX_LINKS).map(link).collect();
+        }
+        to_base32(&layer[0].cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The CID of `data`, fed in pieces which don't line up with the chunks.
+    fn cid(data: &[u8]) -> String {
+        let mut hasher = CidHasher::new();
+        for piece in data.chunks(100_003) {
+            hasher.update(piece);
+        }
+        hasher.finish()
+    }
+
+    /// `len` bytes which differ from one chunk to the next.
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| ((i * 7 + i / CHUNK_SIZE) % 256) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn files_of_a_single_chunk_are_raw_leaves() {
+        assert_eq!(
+            cid(b"hello world"),
+            "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e"
+        );
+        assert_eq!(
+            cid(b""),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+        assert_eq!(
+            cid(&contents(CHUNK_SIZE)),
+            "bafkreidgbbu3ejuxfotwd7y77cd4opc72jol6ntfn6afxeqtkhheou6oea"
+        );
+    }
+
+    #[test]
+    fn larger_files_are_linked_by_unixfs_nodes() {
+        // Computed following the balanced layout of go-unixfs, which grows the tree from the
+        // root down rather than a layer at a time
+        let cases = [
+            (
+                CHUNK_SIZE + 1,
+                "bafybeibojcbfbk3wzntr2cnhe5l62uvvdajioc6fn6x724molbcavmh7oi",
+            ),
+            (
+                3 * CHUNK_SIZE + 100,
+                "bafybeib6w52ktcxqrvjkp52cdq7lbcgjnfp3umfnqib77esjmr7h5lue3q",
+            ),
+            (
+                MAX_LINKS * CHUNK_SIZE,
+                "bafybeihwadmg3xzx4s4ksldwqts2o4x2sblfjua5whh4gsio43wjefk24u",
+            ),
+        ];
+        for (len, expected) in cases {
+            assert_eq!(cid(&contents(len)), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn files_of_more_chunks_than_a_node_links_get_another_layer() {
+        let cases = [
+            (
+                MAX_LINKS * CHUNK_SIZE + 1,
+                "bafybeiaid43jgbhnu36wm22op2wiuibwzqdibhe2o6phqgsazdzcabncly",
+            ),
+            (
+                200 * CHUNK_SIZE + 5,
+                "bafybeiaeqjn7txgvavkbswzobbchk7kih7j52vsyixobc7gs7wo3kkomtm",
+            ),
+        ];
+        for (len, expected) in cases {
+            assert_eq!(cid(&contents(len)), expected, "{} bytes", len);
+        }
+    }
+}
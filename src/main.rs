@@ -1,28 +1,78 @@
+mod archive;
+mod binary;
+mod cache;
+mod cdc;
+mod checkpoint;
+mod compare;
+mod events;
+mod hashing;
+mod ipfs;
+mod manifest;
+mod normalize;
+mod output;
+#[cfg(feature = "images")]
+mod perceptual;
+mod progress;
+mod reports;
+mod spill;
+#[cfg(feature = "fuzzy")]
+mod ssdeep;
+mod staged;
+mod stats;
+mod stream;
+mod streams;
+mod throttle;
+mod torrent;
+mod validate;
+mod verify;
+mod xattr_cache;
+
+use cache::HashCache;
+use checkpoint::Checkpoint;
+use events::{Event, EventSink};
+use futures::StreamExt;
+use hashing::{HashAlgorithm, HashSettings, IoBackend, SecondaryHash};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use serde::Serialize;
-use sha2::{Digest, Sha256};
+use manifest::Manifest;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, VecDeque},
-    path::PathBuf,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
+use stream::{Compression, FlushPolicy, NdjsonWriter};
 use tokio::sync::RwLock;
+use xattr_cache::{Cached, XattrCache};
 
-use clap::{Parser, ValueHint};
+use clap::{ArgEnum, CommandFactory, ErrorKind, Parser, Subcommand, ValueHint};
+use normalize::PathNormalization;
+use output::{GroupBy, OutputFormat};
+use progress::{ProgressPreset, ProgressUnit};
+use regex::Regex;
 
 /// An application to recursively scan a directory generating sha256 hashes for all contained
 /// files, and outputing the result to JSON.
 #[derive(Parser, Debug, PartialEq)]
-#[clap(author, version, about, long_about = None, trailing_var_arg = true,)]
+#[clap(author, version, about, long_about = None, trailing_var_arg = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// List of directories to scan
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// List of directories to scan, files given here are hashed directly
     #[clap(required = true, min_values = 1, value_hint = ValueHint::AnyPath)]
     start_directory: Vec<PathBuf>,
 
+    /// Expand glob patterns such as `data/202?/` in the start directories, for when they aren't
+    /// expanded by a shell. Each pattern must match at least one path.
+    #[clap(long)]
+    expand_globs: bool,
+
     /// Output Directory
     #[clap(short, long, default_value = "./", value_hint = ValueHint::DirPath)]
     out: PathBuf,
@@ -31,18 +81,41 @@ struct Args {
     #[clap(short, long, default_value = "dexy")]
     name: String,
 
+    /// Hash dexy's own output files, `{name}.*` in the output directory, if they're found inside
+    /// a start directory. By default they're skipped, as they're being written during the scan.
+    #[clap(long)]
+    no_exclude_self: bool,
+
     /// NOT IMPLEMENTED: Any directory or file that matches this filter will be excluded,
     /// supports regex to match against.
     #[clap(short, long, value_hint = ValueHint::DirPath)]
     exclude: Vec<PathBuf>,
 
+    /// Skip any directory with exactly this name, wherever it appears in the tree
+    /// (e.g. node_modules, .git, target). May be given multiple times.
+    #[clap(long, multiple_occurrences = true)]
+    exclude_dir_name: Vec<String>,
+
+    /// Only hash files with this extension, ignoring case (e.g. jpg). Pass `--no-extension` as
+    /// the extension to match files without one. May be given multiple times.
+    #[clap(long, value_name = "EXT", multiple_occurrences = true, number_of_values = 1, allow_hyphen_values = true, parse(from_str = normalize_extension))]
+    include_extension: Vec<String>,
+
+    /// Skip files with this extension, ignoring case, even if it is also included. Pass
+    /// `--no-extension` as the extension to match files without one. May be given multiple
+    /// times.
+    #[clap(long, value_name = "EXT", multiple_occurrences = true, number_of_values = 1, allow_hyphen_values = true, parse(from_str = normalize_extension))]
+    exclude_extension: Vec<String>,
+
     /// Number of threads to process
     /// default = number of cores
     #[clap(short, long, default_value_t = num_cpus::get())]
     thread_count: usize,
 
     /// Whether empty files (e.g. files with 0 bytes) should be ignored. This is primarily
-    /// useful for avoiding many ""duplicate"" empty files.
+    /// useful for avoiding many ""duplicate"" empty files, as otherwise every empty file shares
+    /// the hash of zero bytes and they are all grouped together. Empty files are still counted
+    /// in the summary either way.
     #[clap(short, long)]
     ignore_empty: bool,
 
@@ -50,225 +123,2236 @@ struct Args {
     #[clap(long)]
     include_hidden: bool,
 
+    /// Decide what's hidden by the platform's own rules, only looking below the start
+    /// directories: names starting with a `.`, and on Windows anything with the hidden or system
+    /// attribute. Files inside a hidden directory are hidden too. By default any path containing
+    /// `/.` is hidden, even if the start directory is.
+    #[clap(long, conflicts_with = "include-hidden")]
+    strict_hidden: bool,
+
     /// Output size and other file information with the scan, note this makes an extra
     /// request to the underlying system, so may add some time to the inital scan.
     #[clap(short, long)]
     load_file_attributes: bool,
 
+    /// Measure the Shannon entropy of each file's contents in the same read, recorded in its
+    /// attributes as bits per byte. Values close to 8 suggest encrypted, compressed or packed
+    /// files. Implies --load-file-attributes. Members of archives read with --scan-archives
+    /// have no attributes, so none is recorded for them.
+    #[clap(long, conflicts_with_all = &["tail-hash", "parallel-above"])]
+    entropy: bool,
+
     /// NOT IMPLEMENTED: Update an existing scan with files that aren't already present. Will attempt to check
     /// size and age of existing scanned files and rehash - but note that this isn't perfect and it's
     /// possible that a file might be missed if it has the same size. If this is a critical
     /// application, it is recommended that you rescan from scratch.
     #[clap(short, long)]
     update_existing: bool,
+
+    /// With --update-existing, also write `{name}.changed.json` with only the files which are new
+    /// or whose hash differs from the scan previously written to the same output, and
+    /// `{name}.removed.json` listing the paths it had which are now gone.
+    #[clap(long, requires = "update-existing")]
+    prune_unchanged: bool,
+
+    /// Write newline-delimited JSON events (files, errors, progress and a final summary) to
+    /// this file, for consumption by another program.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    events_file: Option<PathBuf>,
+
+    /// Write newline-delimited JSON events to an already open file descriptor, see --events-file.
+    #[cfg(unix)]
+    #[clap(long, conflicts_with = "events-file")]
+    events_fd: Option<i32>,
+
+    /// Treat paths within a hash group as the same file if they match after normalization,
+    /// useful on case-insensitive filesystems or where names differ only in unicode form.
+    #[clap(long, arg_enum, multiple_occurrences = true)]
+    normalize_paths: Vec<PathNormalization>,
+
+    /// Silently drop errors for any path matching this regex, rather than printing them and
+    /// recording them in the errors output. Useful for known noise such as /proc or /sys.
+    #[clap(long, validator = |s: &str| Regex::new(s).map(|_| ()))]
+    ignore_errors_matching: Option<String>,
+
+    /// Don't update the access time of files as they are hashed (Linux only). Falls back to a
+    /// normal open for files we don't own, as the kernel only permits this for the owner.
+    #[clap(long)]
+    no_atime: bool,
+
+    /// Preset for the main progress bar, `throughput` drops the ETA. Both show the rate at which
+    /// files and bytes are being hashed. `json-lines` replaces the bars with a line of JSON
+    /// written to stderr every second.
+    #[clap(long, arg_enum, default_value = "default")]
+    progress_style: ProgressPreset,
+
+    /// Custom indicatif template for the per-thread spinners.
+    #[clap(long, default_value = progress::DEFAULT_SPINNER_TEMPLATE, validator = progress::validate_template)]
+    spinner_template: String,
+
+    /// Custom indicatif template for the main progress bar, overrides the one chosen by
+    /// --progress-style.
+    #[clap(long, validator = progress::validate_template)]
+    bar_template: Option<String>,
+
+    /// How heavily the estimated time remaining is smoothed when counting directories, from 0
+    /// to 1. Smaller values give a steadier estimate which is slower to react to changes in
+    /// pace. Custom templates can show this estimate with `{prefix}`.
+    #[clap(long, value_name = "FACTOR", default_value = "0.1", parse(try_from_str = progress::parse_smoothing))]
+    eta_smoothing: f64,
+
+    /// Include files that couldn't be opened or read in the output, with a null hash and the
+    /// reason they couldn't be read, so the output is a complete inventory.
+    #[clap(long)]
+    record_unreadable: bool,
+
+    /// Hash symlinks by the target path they contain, rather than following them, so that
+    /// moved or relinked files can be detected.
+    #[clap(long)]
+    symlink_hash: bool,
+
+    /// With --symlink-hash, resolve relative link targets against the directory containing the
+    /// link, so links are identified by where they point rather than how they spell it. The
+    /// result is absolute if the link's path is, but isn't canonicalized.
+    #[clap(long, requires = "symlink-hash")]
+    resolve_relative_symlinks: bool,
+
+    /// Split the output into `{name}.{prefix}.json` files, grouping hashes by their first N
+    /// characters.
+    #[clap(long, value_name = "N")]
+    shard_by_prefix: Option<NonZeroUsize>,
+
+    /// Limit how fast file contents are read, in bytes per second across all threads, to avoid
+    /// saturating the disks of a busy machine.
+    #[clap(long, value_name = "BYTES_PER_SEC")]
+    max_read_rate: Option<NonZeroU64>,
+
+    /// Whether to descend into directories on a different filesystem to the start directory
+    /// they were found under, like `find -xdev` when false (unix only).
+    #[clap(long, default_value = "true", value_name = "BOOL", parse(try_from_str))]
+    follow_mounts: bool,
+
+    /// Whether to resolve start directories to absolute paths without symlinks. When false,
+    /// output paths are built on the start directories exactly as given, which may be relative
+    /// or go through a symlink. Start directories are always followed, even if they are symlinks.
+    #[clap(long, default_value = "true", value_name = "BOOL", parse(try_from_str))]
+    canonical_paths: bool,
+
+    /// Record paths relative to this directory, which every start directory must be inside,
+    /// e.g. a deploy root above several start directories.
+    #[clap(long, value_name = "BASE", value_hint = ValueHint::DirPath)]
+    emit_relative_to: Option<PathBuf>,
+
+    /// Keep each file's hash, size and modification time in `{name}.cache.json` in the output
+    /// directory, and in later scans with the same output reuse the hash of any file whose size
+    /// and modification time are unchanged rather than reading it again.
+    #[clap(long, conflicts_with_all = &["hmac-key-file", "hash-filenames", "normalize-line-endings", "text-only"])]
+    hash_cache: bool,
+
+    /// Store each file's hash, size and modification time in a `user.dexy.<algorithm>` extended
+    /// attribute on the file, and in later scans reuse the hash of any file whose size and
+    /// modification time are unchanged rather than reading it again. Files whose attributes
+    /// can't be read or written are hashed as usual.
+    #[clap(long, conflicts_with_all = &["hmac-key-file", "hash-filenames", "normalize-line-endings", "text-only"])]
+    xattr_cache: bool,
+
+    /// Permissions of the output files, in octal (e.g. 640). By default they are created
+    /// according to the umask (unix only).
+    #[cfg(unix)]
+    #[clap(long, value_name = "MODE", parse(try_from_str = parse_mode))]
+    output_mode: Option<u32>,
+
+    /// Abandon the scan once this many errors have occurred, exiting with status 3 without
+    /// writing any hashes. By default the scan continues regardless of errors.
+    #[clap(long, value_name = "N")]
+    max_errors: Option<NonZeroUsize>,
+
+    /// Treat files and directories we aren't permitted to read as routine, as when scanning a
+    /// broad tree as an unprivileged user. They're counted in the summary, but not reported or
+    /// counted towards --max-errors.
+    #[clap(long)]
+    ignore_permission_errors: bool,
+
+    /// Record every directory scanned in the output, along with its attributes, under the
+    /// reserved `directories` key.
+    #[clap(long)]
+    include_dir_entries: bool,
+
+    /// With --include-dir-entries, leave out directories without any files in the output
+    /// beneath them.
+    #[clap(long, requires = "include-dir-entries")]
+    exclude_empty_dirs: bool,
+
+    /// Also write a `.dexy.json` into each directory as it's scanned, holding the hashes of just
+    /// the files directly inside it. Existing `.dexy.json` files are left out of the scan.
+    #[clap(long)]
+    mirror_manifests: bool,
+
+    /// With --mirror-manifests, write each directory's `.dexy.json` into a copy of the tree under
+    /// this directory rather than into the scanned tree itself, e.g. when it is read-only.
+    #[clap(long, value_name = "DIR", requires = "mirror-manifests")]
+    mirror_root: Option<PathBuf>,
+
+    /// With --include-dir-entries, output only the directories without any files in the output
+    /// beneath them, for finding empty directories.
+    #[clap(
+        long,
+        requires = "include-dir-entries",
+        conflicts_with_all = &["exclude-empty-dirs", "only-duplicates"]
+    )]
+    only_empty_dirs: bool,
+
+    /// Format of the output. `ndjson` writes each file to `{name}.ndjson` as soon as it is
+    /// hashed, one JSON object per line, rather than holding every hash in memory until the
+    /// scan completes. It can't be combined with options that need the whole scan at once.
+    /// `msgpack` writes a compact binary `{name}.msgpack`, storing hashes as raw bytes.
+    #[clap(long, arg_enum, default_value = "json")]
+    format: OutputFormat,
+
+    /// With `--format ndjson`, start a new output file after every N entries, named
+    /// `{name}.0001.ndjson`, `{name}.0002.ndjson` and so on.
+    #[clap(long, value_name = "N")]
+    rotate_every: Option<NonZeroUsize>,
+
+    /// With `--format ndjson`, how often output is flushed to disk: `buffered` (the default)
+    /// writes in large blocks, `line` flushes every entry so the output can be followed with
+    /// `tail -f`, and `interval:N` flushes at least every N seconds.
+    #[clap(long, value_name = "POLICY", parse(try_from_str))]
+    flush_policy: Option<FlushPolicy>,
+
+    /// With `--format ndjson`, compress the output as it's written, to `{name}.ndjson.gz`. Unless
+    /// --flush-policy is given, it's flushed every 10 seconds so it can be read while the scan
+    /// goes on.
+    #[clap(long, arg_enum, value_name = "ALGORITHM")]
+    compress: Option<Compression>,
+
+    /// Only hash files modified since the given scan was taken, according to its timestamp. For
+    /// scans written with `--raw` the latest modification time it recorded is used instead,
+    /// which requires it to have been taken with --load-file-attributes.
+    #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    since_scan: Option<PathBuf>,
+
+    /// How files are keyed in the output, `path` gives a flat map from each file's path to its
+    /// entry.
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "hash",
+        conflicts_with = "shard-by-prefix"
+    )]
+    group_by: GroupBy,
+
+    /// Append a line with the time, files and bytes hashed so far, the current rate and the ETA
+    /// to this file every few seconds, for following a scan without a terminal.
+    #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    progress_log: Option<PathBuf>,
+
+    /// Leave out any file whose hash already appears in this manifest, for finding what a new
+    /// dataset adds to an archive. The files left out are listed in `{name}.excluded.json`.
+    #[clap(long, value_name = "MANIFEST", value_hint = ValueHint::FilePath)]
+    dedup_against: Option<PathBuf>,
+
+    /// Follow symlinks which lead outside all of the start directories. By default they are
+    /// skipped with a warning, so a scan can't be steered elsewhere on the filesystem.
+    #[clap(long)]
+    allow_symlink_escape: bool,
+
+    /// Follow at most this many symlinks to directories on the way down from a start directory.
+    /// Links which lead back into a directory they're inside are always skipped as cycles.
+    #[clap(long, value_name = "HOPS")]
+    max_symlink_depth: Option<usize>,
+
+    /// Maximum number of times per second the progress bars are redrawn. Defaults to 15 on a
+    /// terminal, and 1 otherwise.
+    #[clap(long, value_name = "HZ")]
+    progress_refresh_rate: Option<NonZeroU64>,
+
+    /// Also record the hash of each block of this many bytes in every file, for working out
+    /// which parts of a large file have changed between scans.
+    #[clap(long, value_name = "BLOCK_SIZE")]
+    chunk_hashes: Option<NonZeroUsize>,
+
+    /// Fingerprint files from their first and last N bytes and their size rather than reading
+    /// them in full, which is much faster for large media files. Files smaller than 2N bytes are
+    /// still hashed in full.
+    #[clap(long, visible_alias = "quick", value_name = "N", conflicts_with_all = &["chunk-hashes", "text-only", "normalize-line-endings", "ssdeep"])]
+    tail_hash: Option<NonZeroU64>,
+
+    /// Hash files larger than this, e.g. `1G`, as blocks read and hashed in parallel on every
+    /// core, so a single huge file doesn't leave the other workers idle. Several such files
+    /// hashed at once share the cores between them. Their hash is then of the hashes of their
+    /// blocks, which differs from a hash of the whole file, and these entries are marked with
+    /// `"tree": BLOCK_SIZE`.
+    #[clap(long, value_name = "SIZE", parse(try_from_str = spill::parse_byte_size), conflicts_with_all = &["chunk-hashes", "text-only", "normalize-line-endings", "tail-hash", "ssdeep"])]
+    parallel_above: Option<u64>,
+
+    /// Size of the blocks hashed in parallel with --parallel-above. Files are only grouped
+    /// together if they were hashed with the same block size.
+    #[clap(long, value_name = "SIZE", default_value = "64M", parse(try_from_str = spill::parse_byte_size))]
+    parallel_block_size: u64,
+
+    /// How file contents are read to be hashed. `mmap` maps each file into memory instead of
+    /// reading it through a buffer, which saves a system call per 64K and helps on fast storage
+    /// with many medium sized files. Files of 64K or less, anything but regular files, files
+    /// found to be shorter once mapped, and any which changed while being hashed, are read as
+    /// usual, as are files hashed with --tail-hash or --parallel-above. A file truncated by
+    /// another process part way through being hashed still crashes the scan on unix, so only use
+    /// `mmap` on files which aren't being written to.
+    #[clap(long, arg_enum, default_value = "read")]
+    io_backend: IoBackend,
+
+    /// Fold each file's name, but not the rest of its path, into its hash, so files with the same
+    /// contents are only grouped together if they also share a name. A renamed file then shows up
+    /// as changed. These entries are marked with `"filename_hashed": true`.
+    #[clap(long)]
+    hash_filenames: bool,
+
+    /// Also record an ssdeep similarity hash of each file's contents as `"ssdeep"`, which
+    /// modified copies largely share, so near duplicates can be clustered later with ssdeep.
+    /// Requires dexy to be built with the `fuzzy` feature.
+    #[clap(long)]
+    ssdeep: bool,
+
+    /// Also hash file contents with this algorithm in the same read, recorded as each file's
+    /// `secondary` hash. `dexy verify` checks both, flagging files where only one still matches
+    /// as a sign of corruption or a collision.
+    #[clap(long, arg_enum, value_name = "ALGORITHM")]
+    secondary_algorithm: Option<HashAlgorithm>,
+
+    /// Skip files last modified longer ago than this before the scan started, e.g. `2y`, `30d`
+    /// or `12h`. Combines with --since-scan, files must satisfy both.
+    #[clap(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+    exclude_older_than: Option<Duration>,
+
+    /// Skip files last modified more recently than this before the scan started, e.g. `1h` to
+    /// leave out files which may still be being written.
+    #[clap(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+    exclude_newer_than: Option<Duration>,
+
+    /// Also hash each file's alternate data streams (NTFS) or resource fork (macOS), which the
+    /// contents hash doesn't cover. Other platforms have no such streams, so this does nothing.
+    #[clap(long)]
+    include_streams: bool,
+
+    /// Skip files and directories whose path is longer than this many bytes, noting each one
+    /// skipped. Useful where deeply nested trees exceed what other tools can handle.
+    #[clap(long, value_name = "BYTES")]
+    max_path_length: Option<NonZeroUsize>,
+
+    /// Run this command for every file found, with the file's path added as its last argument,
+    /// and only hash the file if the command exits successfully. The command is split on
+    /// whitespace, e.g. `--filter-command "grep -q TODO"`.
+    #[clap(long, value_name = "COMMAND", forbid_empty_values = true)]
+    filter_command: Option<String>,
+
+    /// Read each directory's entries in full, then look up up to this many of their metadata at
+    /// once, rather than one after another. Speeds up scans of storage with high latency, such
+    /// as network filesystems.
+    #[clap(long, value_name = "ENTRIES")]
+    prefetch: Option<NonZeroUsize>,
+
+    /// Order in which directories are processed. `dfs` finishes each subtree before moving on
+    /// to its siblings, which keeps the progress bar's total steadier.
+    #[clap(long, arg_enum, default_value = "bfs")]
+    traversal: Traversal,
+
+    /// Take directories from each start directory in turn, so all of them progress together
+    /// rather than one being finished before the next is started. Partial results from an
+    /// interrupted scan are then spread across every start directory.
+    #[clap(long)]
+    parallel_roots: bool,
+
+    /// Write indented, human-readable JSON rather than a single dense line. Applies to all
+    /// outputs of the scan.
+    #[clap(long)]
+    json_pretty: bool,
+
+    /// Write canonical JSON, with object keys sorted, files within each group sorted by path and
+    /// no whitespace, so the same files always produce byte-identical output which can itself be
+    /// hashed. Implies --raw, as the envelope records when and where the scan was run.
+    #[clap(long, conflicts_with = "json-pretty")]
+    canonical_json: bool,
+
+    /// Periodically checkpoint progress to `{name}.checkpoint`, and continue from the last
+    /// checkpoint if one exists rather than starting the scan again.
+    #[clap(long)]
+    resume: bool,
+
+    /// Only hash text files, skipping any file with a NUL byte near its start.
+    #[clap(long)]
+    text_only: bool,
+
+    /// How many bytes from the start of each file are checked for a NUL byte to tell whether it
+    /// is text, for --text-only and --normalize-line-endings.
+    #[clap(long, default_value_t = 8000)]
+    text_sample_bytes: usize,
+
+    /// Hash CRLF line endings in text files as if they were LF, so the same text saved on
+    /// Windows and elsewhere has the same hash. Binary files are hashed unchanged.
+    #[clap(long)]
+    normalize_line_endings: bool,
+
+    /// What the main progress bar measures, `bytes` counts the size of the tree before starting
+    /// so the ETA reflects the actual amount of data to read.
+    #[clap(long, arg_enum, default_value = "directories")]
+    progress_by: ProgressUnit,
+
+    /// Also hash each file within .tar, .tar.gz and .tgz archives, recorded with paths like
+    /// `archive.tar!member/path`.
+    #[clap(long)]
+    scan_archives: bool,
+
+    /// Only output hashes shared by more than one file, for finding duplicates. Empty files all
+    /// share a hash, so they're left out unless --dedup-zero-length-separately is given.
+    #[clap(long)]
+    only_duplicates: bool,
+
+    /// With --only-duplicates, find them in stages rather than hashing every file: files are
+    /// grouped by size, then by a hash of their first and last 64 KiB, and only those still
+    /// sharing a group are hashed in full.
+    /// Files which can't be duplicates are never hashed, so this can't be combined with hashes
+    /// of the whole tree.
+    #[clap(long, requires = "only-duplicates", conflicts_with_all = &["scan-archives", "mirror-manifests", "resume", "fingerprint", "directory-hashes"])]
+    staged_duplicates: bool,
+
+    /// Group empty files under `empty` rather than under the hash of no data, so they're listed
+    /// apart from real duplicates. With --only-duplicates they're kept in this group.
+    #[clap(long)]
+    dedup_zero_length_separately: bool,
+
+    /// With --only-duplicates, only output hashes shared by at least this many files, to find
+    /// the most duplicated.
+    #[clap(long, value_name = "N", requires = "only-duplicates", parse(try_from_str = parse_duplicate_count))]
+    min_duplicate_count: Option<usize>,
+
+    /// Write a separate `{name}.{directory}.json` for each start directory, rather than
+    /// combining them all into one output.
+    #[clap(long)]
+    split_by_root: bool,
+
+    /// Write the bare map of hashes, without the envelope describing how the scan was produced.
+    #[clap(long)]
+    raw: bool,
+
+    /// Limit the memory used to hold hashes to roughly this many bytes, e.g. `512M`. Whenever
+    /// the limit is reached the hashes collected so far are written to `{name}.0001.json`,
+    /// `{name}.0002.json` and so on, and then forgotten. Files sharing a hash may be split
+    /// across parts.
+    #[clap(long, value_name = "BYTES", parse(try_from_str = spill::parse_byte_size))]
+    max_memory: Option<u64>,
+
+    /// Write the hashes collected so far to the next numbered part every N seconds, as with
+    /// --max-memory, so they're kept even if the scan is interrupted.
+    #[clap(long, value_name = "SECONDS")]
+    spill_interval: Option<NonZeroU64>,
+
+    /// Read the output back once it's written and check it matches the scan, exiting with
+    /// status 4 if it doesn't, to catch corruption before relying on it.
+    #[clap(long)]
+    verify_after_write: bool,
+
+    /// Exit with status 5 if no files were hashed, e.g. because the filters given exclude
+    /// everything, so a misconfigured scan is caught rather than leaving an empty output.
+    #[clap(long)]
+    fail_if_empty: bool,
+
+    /// Write `{name}.hardlink-report.json`, listing duplicate files on the same device that
+    /// aren't hardlinked together and the space that linking them would reclaim. Implies
+    /// --load-file-attributes.
+    #[cfg(unix)]
+    #[clap(long)]
+    hardlink_report: bool,
+
+    /// Write `{name}.largest.json`, listing the N largest files found, largest first. Implies
+    /// --load-file-attributes.
+    #[clap(long, value_name = "N")]
+    report_largest: Option<NonZeroUsize>,
+
+    /// Time how long each file takes to open and hash, recorded as `hash_duration_ms`, to find
+    /// files which are unexpectedly slow to read, e.g. on a failing disk.
+    #[clap(long)]
+    profile_files: bool,
+
+    /// With --profile-files, write `{name}.slowest.json`, listing the N files which took longest
+    /// to hash, slowest first.
+    #[clap(long, value_name = "N", requires = "profile-files")]
+    report_slowest: Option<NonZeroUsize>,
+
+    /// Write `{name}.dir-hashes.json`, with a hash of every directory containing hashed files
+    /// derived from the names and hashes of everything inside it. Two subtrees with the same
+    /// contents share a hash, so they can be compared without diffing every file.
+    #[clap(long)]
+    directory_hashes: bool,
+
+    /// Print a single digest of every file's path relative to its start directory and hash once
+    /// the scan completes, and record it in the header as `fingerprint`. Two trees with the same
+    /// contents at the same relative paths share a fingerprint, wherever they're stored.
+    #[clap(long)]
+    fingerprint: bool,
+
+    /// Also write `{name}.torrent`, a BitTorrent v2 torrent of every file hashed, built from
+    /// piece hashes taken in the same read. Each file's merkle root is recorded as
+    /// `pieces_root`.
+    #[clap(long, conflicts_with_all = &["tail-hash", "parallel-above", "staged-duplicates", "resume"])]
+    torrent: bool,
+
+    /// Size of the pieces of --torrent, a power of two of at least 16K
+    #[clap(long, value_name = "SIZE", default_value = "1M", parse(try_from_str = torrent::parse_piece_length))]
+    torrent_piece_length: u64,
+
+    /// Also record each file's IPFS CIDv1 as `cid`, as `ipfs add --cid-version 1` would give it
+    /// with its default chunking, so files can be matched with content pinned in IPFS.
+    #[clap(long, conflicts_with_all = &["tail-hash", "parallel-above"])]
+    ipfs_cid: bool,
+
+    /// Also split each file into content defined chunks averaging this many bytes, using
+    /// FastCDC, and record their offsets, lengths and hashes as `cdc_chunks`. Unlike
+    /// --chunk-hashes, an insertion only changes the chunks around it, so files sharing data at
+    /// different offsets share chunks.
+    #[clap(long, value_name = "AVG_SIZE", parse(try_from_str = cdc::parse_average_size), conflicts_with_all = &["tail-hash", "parallel-above"])]
+    cdc_chunks: Option<u32>,
+
+    /// Also record a perceptual hash of each JPEG, PNG or GIF image, going by its extension, as
+    /// `perceptual_hash`. It stays close when an image is re-encoded or resized, unlike its
+    /// exact hash. Images are decoded in a second read. Requires dexy to be built with the
+    /// `images` feature.
+    #[clap(long)]
+    perceptual_hash: bool,
+
+    /// With --perceptual-hash, write `{name}.similar-images.json`, grouping images whose
+    /// perceptual hashes differ in at most this many of their 64 bits, directly or through
+    /// other images in the group.
+    #[clap(long, value_name = "DISTANCE", requires = "perceptual-hash")]
+    report_similar_images: Option<u32>,
+
+    /// Algorithm used to hash file contents. If given more than once, files are hashed with each
+    /// in the same read and their digests recorded by algorithm, while the first is still used
+    /// to group files.
+    #[clap(
+        long = "algorithm",
+        arg_enum,
+        value_name = "ALGORITHM",
+        default_value = "sha256",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    algorithms: Vec<HashAlgorithm>,
+
+    /// Hash files with this extension using a different algorithm, e.g. `mp4=xxh3` to hash
+    /// large videos faster. May be given multiple times, files with any other extension are
+    /// hashed with --algorithm.
+    #[clap(long, value_name = "EXT=ALGORITHM", multiple_occurrences = true, parse(try_from_str = hashing::parse_algorithm_for))]
+    algorithm_for: Vec<(String, HashAlgorithm)>,
+
+    /// Record each file's hash as an HMAC-SHA256 under the key in this file, so the hashes can
+    /// only be reproduced, or matched against files elsewhere, by someone holding the key.
+    /// Requires --algorithm sha256. The header notes that a key was used, but never records it.
+    /// Only the file's own hash is keyed, so this can't be combined with options recording other
+    /// digests of its contents.
+    #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath, conflicts_with_all = &["algorithm-for", "secondary-algorithm", "chunk-hashes", "cdc-chunks", "ipfs-cid", "torrent", "ssdeep", "perceptual-hash"])]
+    hmac_key_file: Option<PathBuf>,
+
+    /// Print the supported hash algorithms, and whether each is cryptographic, then exit
+    #[clap(long, exclusive = true)]
+    list_algorithms: bool,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize)]
+impl Args {
+    /// Check that the options given make sense for the output format, which clap can't express
+    /// as it depends on the format's value rather than its presence.
+    fn check_format(&self) -> Result<(), String> {
+        if self.hmac_key_file.is_some() && self.algorithm() != HashAlgorithm::Sha256 {
+            return Err("--hmac-key-file requires --algorithm sha256 first".into());
+        }
+        if self.hmac_key_file.is_some() && self.algorithms.len() > 1 {
+            return Err("--hmac-key-file can't be used with more than one --algorithm".into());
+        }
+
+        let single_file = match (self.verify_after_write, self.prune_unchanged) {
+            (true, _) => Some("--verify-after-write"),
+            (_, true) => Some("--prune-unchanged"),
+            (false, false) => None,
+        };
+        if let Some(single_file) = single_file {
+            let several_files = [
+                ("--format ndjson", self.format == OutputFormat::Ndjson),
+                ("--split-by-root", self.split_by_root),
+                ("--shard-by-prefix", self.shard_by_prefix.is_some()),
+                ("--group-by path", self.group_by == GroupBy::Path),
+            ];
+            if let Some((option, _)) = several_files.iter().find(|(_, given)| *given) {
+                return Err(format!("{} can't be used with {}", single_file, option));
+            }
+        }
+
+        if self.format != OutputFormat::Ndjson {
+            match (self.rotate_every, self.flush_policy, self.compress) {
+                (Some(_), _, _) => return Err("--rotate-every requires --format ndjson".into()),
+                (_, Some(_), _) => return Err("--flush-policy requires --format ndjson".into()),
+                (_, _, Some(_)) => return Err("--compress requires --format ndjson".into()),
+                (None, None, None) => {}
+            }
+        }
+
+        let layout = [
+            ("--canonical-json", self.canonical_json),
+            ("--split-by-root", self.split_by_root),
+            ("--shard-by-prefix", self.shard_by_prefix.is_some()),
+            ("--group-by path", self.group_by == GroupBy::Path),
+        ];
+        let whole_scan = [
+            ("--only-duplicates", self.only_duplicates),
+            ("--staged-duplicates", self.staged_duplicates),
+            ("--exclude-empty-dirs", self.exclude_empty_dirs),
+            ("--only-empty-dirs", self.only_empty_dirs),
+            ("--canonical-json", self.canonical_json),
+            (
+                "--dedup-zero-length-separately",
+                self.dedup_zero_length_separately,
+            ),
+            ("--split-by-root", self.split_by_root),
+            ("--shard-by-prefix", self.shard_by_prefix.is_some()),
+            ("--resume", self.resume),
+            ("--group-by path", self.group_by == GroupBy::Path),
+            #[cfg(unix)]
+            ("--hardlink-report", self.hardlink_report),
+            ("--report-largest", self.report_largest.is_some()),
+            ("--directory-hashes", self.directory_hashes),
+            ("--fingerprint", self.fingerprint),
+            (
+                "--report-similar-images",
+                self.report_similar_images.is_some(),
+            ),
+            ("--emit-relative-to", self.emit_relative_to.is_some()),
+        ];
+
+        let spilling = match (self.max_memory, self.spill_interval) {
+            (Some(_), _) => Some("--max-memory"),
+            (_, Some(_)) => Some("--spill-interval"),
+            (None, None) => None,
+        };
+        if let Some(spilling) = spilling {
+            if self.format != OutputFormat::Json {
+                return Err(format!("{} requires --format json", spilling));
+            }
+            let mut incompatible = whole_scan.iter().copied().chain([
+                ("--verify-after-write", self.verify_after_write),
+                ("--prune-unchanged", self.prune_unchanged),
+            ]);
+            if let Some((option, _)) = incompatible.find(|(_, given)| *given) {
+                return Err(format!("{} can't be used with {}", option, spilling));
+            }
+        }
+
+        let incompatible: &[(&str, bool)] = match self.format {
+            OutputFormat::Json => return Ok(()),
+            OutputFormat::Ndjson => &whole_scan,
+            OutputFormat::Msgpack => &layout,
+        };
+        match incompatible.iter().find(|(_, given)| *given) {
+            Some((option, _)) => Err(format!(
+                "{} can't be used with --format {}",
+                option,
+                self.format.to_possible_value().unwrap().get_name()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The algorithm files are hashed and grouped by, the first given to --algorithm.
+    fn algorithm(&self) -> HashAlgorithm {
+        self.algorithms[0]
+    }
+
+    /// Whether file attributes are needed, either for the output or for a report.
+    fn wants_attributes(&self) -> bool {
+        #[cfg(unix)]
+        if self.hardlink_report {
+            return true;
+        }
+        self.load_file_attributes || self.entropy || self.report_largest.is_some()
+    }
+
+    /// Path of an output file for this scan, e.g. `{out}/{name}.{extension}`.
+    fn output_file(&self, extension: &str) -> PathBuf {
+        self.out.join(format!("{}.{}", self.name, extension))
+    }
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Check that the files in a previous scan still have the hashes it recorded, exiting with
+    /// status 1 if any have changed or are missing
+    Verify {
+        /// Output of the scan to check against
+        #[clap(value_hint = ValueHint::FilePath)]
+        manifest: PathBuf,
+        /// The key the scan was run with, for scans using --hmac-key-file
+        #[clap(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+        hmac_key_file: Option<PathBuf>,
+    },
+    /// Check that a previous scan is well-formed without reading the files it lists: hashes of
+    /// the right form for their algorithm, no path listed twice, and entries consistent with the
+    /// header. Exits with status 1 if any problems are found
+    Validate {
+        /// Output of the scan to check
+        #[clap(value_hint = ValueHint::FilePath)]
+        manifest: PathBuf,
+    },
+    /// Print totals, duplicates, and the distribution of extensions and sizes in a previous
+    /// scan
+    Stats {
+        /// Output of the scan to describe
+        #[clap(value_hint = ValueHint::FilePath)]
+        manifest: PathBuf,
+        /// Print the statistics as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check that two directory trees contain the same files with the same contents, without
+    /// writing a manifest, exiting with status 1 if they differ
+    Compare {
+        /// The first tree
+        #[clap(value_hint = ValueHint::DirPath)]
+        left: PathBuf,
+        /// The second tree, compared by path relative to each tree's root
+        #[clap(value_hint = ValueHint::DirPath)]
+        right: PathBuf,
+    },
+}
+
+/// Parse a file mode given in octal, as chmod accepts it.
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("`{}` is not an octal file mode", mode)),
+    }
+}
+
+/// The form of an extension given to `--include-extension` or `--exclude-extension` that is
+/// compared against files, where the empty string stands for no extension at all.
+fn normalize_extension(extension: &str) -> String {
+    match extension {
+        "--no-extension" => String::new(),
+        _ => extension.trim_start_matches('.').to_lowercase(),
+    }
+}
+
+/// Expand glob patterns given as start directories, for `--expand-globs`, keeping the order the
+/// patterns were given in.
+fn expand_globs(patterns: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut expanded = vec![];
+    for pattern in patterns {
+        let pattern = pattern.to_string_lossy();
+        let matches: Vec<PathBuf> = glob::glob(&pattern)
+            .map_err(|e| format!("`{}` isn't a valid pattern: {}", pattern, e))?
+            .filter_map(Result::ok)
+            .collect();
+        if matches.is_empty() {
+            return Err(format!("`{}` doesn't match anything", pattern));
+        }
+        expanded.extend(matches);
+    }
+    Ok(expanded)
+}
+
+/// Parse a number of files sharing a hash, which must be at least two to be duplicates.
+fn parse_duplicate_count(count: &str) -> Result<usize, String> {
+    match count.parse() {
+        Ok(count) if count >= 2 => Ok(count),
+        _ => Err(format!("`{}` isn't a whole number of at least 2", count)),
+    }
+}
+
+/// Exit status when the scan is abandoned because of `--max-errors`.
+const ABORTED_EXIT_CODE: i32 = 3;
+/// Exit status when the output doesn't match the scan when read back with `--verify-after-write`.
+const UNVERIFIED_EXIT_CODE: i32 = 4;
+/// Exit status when a streaming scan is interrupted with Ctrl-C, as a shell reports for SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+/// Exit status when no files were hashed with `--fail-if-empty`.
+const EMPTY_EXIT_CODE: i32 = 5;
+/// Exit status when `dexy compare` can't read one of the trees at all.
+const UNREADABLE_EXIT_CODE: i32 = 6;
+
+/// Key under which files that couldn't be read are grouped in the output, as they have no hash.
+const UNREADABLE_KEY: &str = "unreadable";
+/// Key under which directories are grouped in the output with `--include-dir-entries`.
+const DIRECTORIES_KEY: &str = "directories";
+/// Key under which empty files are grouped in the output with `--dedup-zero-length-separately`.
+const EMPTY_KEY: &str = "empty";
+
+/// Whether a key in the output groups something other than files sharing a hash.
+fn is_reserved_key(key: &str) -> bool {
+    key == UNREADABLE_KEY || key == DIRECTORIES_KEY || key == EMPTY_KEY
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 struct ScannedFile {
-    /// The generated hash for this file
-    hash: String,
+    /// The generated hash for this file, or `None` if it couldn't be read
+    #[serde(
+        serialize_with = "binary::serialize_hash",
+        deserialize_with = "binary::deserialize_hash"
+    )]
+    hash: Option<String>,
     /// The path to this file
     path: PathBuf,
     /// Optional File Attributes
     attributes: Option<FileAttributes>,
+    /// Why this file couldn't be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The file changed while it was being hashed, even after a retry, so the hash may not match
+    /// its contents at any point in time
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    raced: bool,
+    /// Hashes of each block of the file, with `--chunk-hashes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk_hashes: Option<Vec<String>>,
+    /// Hashes of the file's alternate data streams or resource fork, by name, with
+    /// `--include-streams`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    streams: BTreeMap<String, String>,
+    /// The algorithm this file was hashed with, if it isn't the one recorded for the whole scan
+    /// because of `--algorithm-for`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    algorithm: Option<HashAlgorithm>,
+    /// The hash only covers this many bytes from each end of the file and its size, with
+    /// `--tail-hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sampled: Option<u64>,
+    /// The path a symlink points to, with `--symlink-hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_target: Option<PathBuf>,
+    /// The link's target was resolved against the directory containing the link if it was
+    /// relative, with `--resolve-relative-symlinks`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    link_target_resolved: bool,
+    /// The file grew or shrank while it was being hashed, even after a retry, so the hash covers
+    /// a different number of bytes than its size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size_mismatch: Option<SizeMismatch>,
+    /// The file's name was folded into its hash along with its contents, with `--hash-filenames`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    filename_hashed: bool,
+    /// CRLF line endings were hashed as LF, as the file was taken to be text, with
+    /// `--normalize-line-endings`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    line_endings_normalized: bool,
+    /// The contents hashed with a second algorithm as a cross-check, with
+    /// `--secondary-algorithm`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secondary: Option<SecondaryHash>,
+    /// The digest from every algorithm requested, when `--algorithm` was given more than once
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    digests: BTreeMap<HashAlgorithm, String>,
+    /// The hash is of the hashes of blocks of this many bytes, hashed in parallel with
+    /// `--parallel-above`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tree: Option<u64>,
+    /// Similarity hash of the contents, with `--ssdeep`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssdeep: Option<String>,
+    /// Root of the BitTorrent v2 merkle tree of the contents, with `--torrent`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pieces_root: Option<String>,
+    /// IPFS CIDv1 of the contents, with `--ipfs-cid`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
+    /// Content defined chunks of the contents, with `--cdc-chunks`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cdc_chunks: Option<Vec<cdc::CdcChunk>>,
+    /// Hash of how an image looks rather than its exact contents, with `--perceptual-hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    perceptual_hash: Option<String>,
+    /// Milliseconds spent opening and hashing the file, with `--profile-files`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash_duration_ms: Option<u64>,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize)]
+/// How a file's size differed from the number of bytes hashed.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize, Deserialize)]
+struct SizeMismatch {
+    /// Size of the file when it was opened
+    expected: u64,
+    /// Number of bytes actually read, which the hash covers
+    read: u64,
+}
+
+impl ScannedFile {
+    /// An entry for a file which was found but couldn't be hashed.
+    fn unreadable(
+        path: PathBuf,
+        attributes: Option<FileAttributes>,
+        error: std::io::Error,
+    ) -> Self {
+        Self {
+            hash: None,
+            path,
+            attributes,
+            error: Some(error.to_string()),
+            raced: false,
+            chunk_hashes: None,
+            streams: BTreeMap::new(),
+            algorithm: None,
+            sampled: None,
+            link_target: None,
+            link_target_resolved: false,
+            size_mismatch: None,
+            filename_hashed: false,
+            line_endings_normalized: false,
+            secondary: None,
+            digests: BTreeMap::new(),
+            tree: None,
+            ssdeep: None,
+            pieces_root: None,
+            cid: None,
+            cdc_chunks: None,
+            perceptual_hash: None,
+            hash_duration_ms: None,
+        }
+    }
+
+    /// An entry for a directory, which has no hash of its own.
+    fn directory(path: PathBuf, attributes: FileAttributes) -> Self {
+        Self {
+            hash: None,
+            path,
+            attributes: Some(attributes),
+            error: None,
+            raced: false,
+            chunk_hashes: None,
+            streams: BTreeMap::new(),
+            algorithm: None,
+            sampled: None,
+            link_target: None,
+            link_target_resolved: false,
+            size_mismatch: None,
+            filename_hashed: false,
+            line_endings_normalized: false,
+            secondary: None,
+            digests: BTreeMap::new(),
+            tree: None,
+            ssdeep: None,
+            pieces_root: None,
+            cid: None,
+            cdc_chunks: None,
+            perceptual_hash: None,
+            hash_duration_ms: None,
+        }
+    }
+
+    /// The key this file is grouped under in the output.
+    fn group_key(&self) -> String {
+        let is_directory = self
+            .attributes
+            .as_ref()
+            .is_some_and(|a| a.file_type == FileType::Directory);
+        match &self.hash {
+            Some(hash) => hash.clone(),
+            None if is_directory && self.error.is_none() => DIRECTORIES_KEY.into(),
+            None => UNREADABLE_KEY.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 struct FileAttributes {
     size: usize,
     created_date: i128,
     accessed_date: i128,
     edit_date: i128,
     file_type: FileType,
+    /// Inode number, stable across renames within a filesystem
+    #[cfg(unix)]
+    inode: u64,
+    /// Device the file is stored on, which together with the inode identifies the file
+    #[cfg(unix)]
+    device: u64,
+    /// Space actually allocated to the file, which is less than `size` for sparse files and
+    /// usually more for small ones as it's rounded up to whole blocks
+    #[cfg(unix)]
+    #[serde(default)]
+    size_on_disk: u64,
+    /// Shannon entropy of the contents, with `--entropy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entropy: Option<hashing::Entropy>,
+}
+
+impl FileAttributes {
+    fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        FileAttributes {
+            size: metadata.len() as usize,
+            created_date: match metadata.created() {
+                Ok(f) => f
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs() as i128,
+                Err(_) => -1,
+            },
+            accessed_date: match metadata.accessed() {
+                Ok(f) => f
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs() as i128,
+                Err(_) => -1,
+            },
+            edit_date: match metadata.modified() {
+                Ok(f) => f
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_secs() as i128,
+                Err(_) => -1,
+            },
+            file_type: {
+                if metadata.is_symlink() {
+                    FileType::SymLink
+                } else if metadata.is_dir() {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                }
+            },
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(metadata),
+            #[cfg(unix)]
+            device: std::os::unix::fs::MetadataExt::dev(metadata),
+            // Blocks are always counted in 512 byte units, whatever the filesystem's block size
+            #[cfg(unix)]
+            size_on_disk: std::os::unix::fs::MetadataExt::blocks(metadata) * 512,
+            entropy: None,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 enum FileType {
     SymLink,
     Directory,
     File,
 }
 
-async fn worker(
-    thread: usize,
-    dir_queue: Arc<RwLock<VecDeque<PathBuf>>>,
-    num_waiting: Arc<AtomicUsize>,
-    global_result: Arc<RwLock<HashMap<String, Vec<ScannedFile>>>>,
-    progressbar: ProgressBar,
-    main_pb: Arc<RwLock<ProgressBar>>,
-    args: Arc<Args>,
-) {
-    progressbar
-        .set_style(ProgressStyle::default_spinner().template("{spinner} {prefix}: {wide_msg}"));
+/// A file or directory that could not be processed.
+#[derive(Debug, Clone, Serialize)]
+struct ScanError {
+    path: PathBuf,
+    message: String,
+}
+
+/// Totals describing a completed scan.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanSummary {
+    /// Number of files that were hashed
+    files: usize,
+    /// Number of distinct hashes among those files, unknown when streaming the output
+    unique_hashes: Option<usize>,
+    /// Number of directories that were processed
+    directories: u64,
+    /// Number of files and directories that could not be processed
+    errors: usize,
+    /// Total size of all hashed files
+    bytes: u64,
+    /// Number of empty files found, including those skipped by --ignore-empty
+    empty_files: usize,
+    /// Number of files and directories skipped by --ignore-permission-errors
+    permission_denied: usize,
+    /// Number of symlinks to directories skipped as they lead back into a directory they're in
+    symlink_cycles: usize,
+    /// Number of symlinks to directories skipped by --max-symlink-depth
+    symlink_depth_limited: usize,
+    /// Number of files whose hash was reused from --hash-cache
+    cache_hits: u64,
+    /// Number of files looked up in --hash-cache and hashed as they weren't found
+    cache_misses: u64,
+    /// Number of files whose hash was reused from --xattr-cache
+    xattr_cache_hits: u64,
+    /// Number of files looked up in --xattr-cache and hashed as they had no usable hash stored
+    xattr_cache_misses: u64,
+}
+
+/// A start directory given by the user.
+struct Root {
+    path: PathBuf,
+    /// The path with all symlinks resolved, for checking where links lead
+    real_path: Option<PathBuf>,
+    /// The device this root is on, which bounds the scan when not following mounts
+    device: Option<u64>,
+}
+
+impl Root {
+    fn new(path: PathBuf) -> Self {
+        #[cfg(unix)]
+        let device = {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(&path).map(|m| m.dev()).ok()
+        };
+        #[cfg(not(unix))]
+        let device = None;
+
+        Self {
+            real_path: path.canonicalize().ok(),
+            path,
+            device,
+        }
+    }
+
+    /// Whether `path` is on a different filesystem to this root.
+    async fn is_other_device(&self, path: &Path) -> bool {
+        #[cfg(unix)]
+        if let Some(device) = self.device {
+            use std::os::unix::fs::MetadataExt;
+            return tokio::fs::metadata(path)
+                .await
+                .is_ok_and(|m| m.dev() != device);
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+
+        false
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Traversal {
+    /// Breadth first, every directory at one depth is processed before any deeper
+    Bfs,
+    /// Depth first, a directory's subdirectories are processed before its siblings
+    Dfs,
+}
+
+/// A directory waiting to be processed.
+struct QueuedDir {
+    path: PathBuf,
+    /// Index into the scan's roots of the start directory this was found under
+    root: usize,
+    /// Where each symlink followed on the way down from the start directory leads
+    links: Vec<PathBuf>,
+}
+
+/// Directories waiting to be processed. With `--parallel-roots` each start directory has a queue
+/// of its own and they're taken from in turn, otherwise a single queue is shared by all.
+struct DirQueue {
+    queues: Vec<VecDeque<QueuedDir>>,
+    /// The queue the next directory is taken from first
+    next: usize,
+}
+
+impl DirQueue {
+    fn new(parallel_roots: bool, roots: usize) -> Self {
+        let count = match parallel_roots {
+            true => roots.max(1),
+            false => 1,
+        };
+        Self {
+            queues: (0..count).map(|_| VecDeque::new()).collect(),
+            next: 0,
+        }
+    }
+
+    fn queue(&mut self, dir: &QueuedDir) -> &mut VecDeque<QueuedDir> {
+        let count = self.queues.len();
+        &mut self.queues[dir.root % count]
+    }
+
+    fn push_front(&mut self, dir: QueuedDir) {
+        self.queue(&dir).push_front(dir);
+    }
+
+    fn pop_front(&mut self) -> Option<QueuedDir> {
+        let count = self.queues.len();
+        for i in (0..count).map(|offset| (self.next + offset) % count) {
+            if let Some(dir) = self.queues[i].pop_front() {
+                self.next = (i + 1) % count;
+                return Some(dir);
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+impl Extend<QueuedDir> for DirQueue {
+    fn extend<T: IntoIterator<Item = QueuedDir>>(&mut self, dirs: T) {
+        for dir in dirs {
+            self.queue(&dir).push_back(dir);
+        }
+    }
+}
+
+/// State shared between all workers for the duration of a scan.
+struct ScanState {
+    args: Args,
+    roots: Vec<Root>,
+    header: manifest::Header,
+    dir_queue: RwLock<DirQueue>,
+    num_waiting: AtomicUsize,
+    result: RwLock<HashMap<String, Vec<ScannedFile>>>,
+    /// Directories which have been fully processed, tracked when resuming is enabled
+    completed: std::sync::Mutex<HashSet<PathBuf>>,
+    main_pb: RwLock<ProgressBar>,
+    events: Option<EventSink>,
+    ignore_errors: Option<Regex>,
+    /// Hashes from `--dedup-against`, files with these hashes are left out of the output
+    reference_hashes: Option<HashSet<String>>,
+    /// Files left out of the output as they were found in the reference manifest
+    excluded: std::sync::Mutex<Vec<ScannedFile>>,
+    /// Files found with `--staged-duplicates`, which are only hashed once the walk completes
+    staged: Option<std::sync::Mutex<Vec<PathBuf>>>,
+    /// Files last modified before this time, in seconds since the unix epoch, are skipped
+    modified_after: Option<u64>,
+    /// Files last modified after this time, in seconds since the unix epoch, are skipped
+    modified_before: Option<u64>,
+    /// Paths are made relative to this directory in the output, with `--emit-relative-to`
+    relative_to: Option<PathBuf>,
+    /// The output directory with all symlinks resolved, for skipping dexy's own output files,
+    /// unless `--no-exclude-self` was given
+    own_output: Option<PathBuf>,
+    /// Digest of every file's relative path and hash, set once the scan completes with
+    /// `--fingerprint`
+    fingerprint: std::sync::OnceLock<String>,
+    hash_settings: Arc<HashSettings>,
+    errors: std::sync::Mutex<Vec<ScanError>>,
+    hash_cache: Option<HashCache>,
+    xattr_cache: Option<XattrCache>,
+    /// Where hashes are written out as the scan goes, with `--max-memory` or `--spill-interval`
+    spiller: Option<spill::Spiller>,
+    /// Where files are sent as they're hashed with `--format ndjson`, rather than `result`
+    stream: Option<NdjsonWriter>,
+    /// Files to include in the torrent, with `--torrent`
+    torrent: Option<torrent::Torrent>,
+    /// Set once `--max-errors` is reached, telling the workers to stop
+    aborted: AtomicBool,
+    /// Set while the scan is paused by `SIGUSR1`, until `SIGUSR2` resumes it
+    paused: AtomicBool,
+    files_hashed: AtomicU64,
+    bytes_hashed: AtomicU64,
+    dirs_processed: AtomicU64,
+    empty_files: AtomicUsize,
+    permission_denied: AtomicUsize,
+    symlink_cycles: AtomicUsize,
+    symlink_depth_limited: AtomicUsize,
+}
+
+impl ScanState {
+    /// The header to write with the hashes, with the fingerprint once it's known.
+    fn header(&self) -> manifest::Header {
+        manifest::Header {
+            fingerprint: self.fingerprint.get().cloned(),
+            ..self.header.clone()
+        }
+    }
+
+    /// Print an error against the given progress bar, and record it for the errors output and
+    /// events. Errors on paths matching `--ignore-errors-matching` are dropped entirely.
+    fn report_error(&self, progressbar: &ProgressBar, path: &Path, message: String) {
+        if let Some(ignore) = &self.ignore_errors {
+            if ignore.is_match(&path.to_string_lossy()) {
+                return;
+            }
+        }
+
+        progressbar.println(&message);
+        if let Some(events) = &self.events {
+            events.emit(&Event::Error {
+                path,
+                message: &message,
+            });
+        }
+        let mut errors = self.errors.lock().unwrap();
+        errors.push(ScanError {
+            path: path.to_path_buf(),
+            message,
+        });
+        if self
+            .args
+            .max_errors
+            .is_some_and(|max| errors.len() >= max.get())
+        {
+            self.aborted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Report an error from the filesystem, unless it's a permission error and those are being
+    /// ignored, in which case it's only counted.
+    fn report_io_error(
+        &self,
+        progressbar: &ProgressBar,
+        path: &Path,
+        message: String,
+        error: &std::io::Error,
+    ) {
+        if self.args.ignore_permission_errors
+            && error.kind() == std::io::ErrorKind::PermissionDenied
+        {
+            self.permission_denied.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.report_error(progressbar, path, message);
+    }
+
+    /// Whether a symlink resolves to somewhere outside every start directory. Broken links don't
+    /// resolve anywhere, and are left to be reported when they're read.
+    async fn escapes_roots(&self, link: &Path) -> bool {
+        match tokio::fs::canonicalize(link).await {
+            Ok(target) => !self.roots.iter().any(|root| {
+                root.real_path
+                    .as_ref()
+                    .is_some_and(|real| target.starts_with(real))
+            }),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether a file is one of dexy's own output files, named `{name}.*` in the output directory.
+    async fn is_own_output(&self, path: &Path) -> bool {
+        let own_output = match &self.own_output {
+            Some(own_output) => own_output,
+            None => return false,
+        };
+        let named = path.file_name().is_some_and(|file_name| {
+            file_name
+                .to_string_lossy()
+                .starts_with(&format!("{}.", self.args.name))
+        });
+        // Only files with a matching name are resolved, as most won't have one
+        match (named, path.parent()) {
+            (true, Some(parent)) => tokio::fs::canonicalize(parent)
+                .await
+                .is_ok_and(|parent| parent == *own_output),
+            _ => false,
+        }
+    }
+
+    /// Whether a file's hash already appears in the manifest given to `--dedup-against`.
+    fn is_in_reference(&self, file: &ScannedFile) -> bool {
+        match (&self.reference_hashes, &file.hash) {
+            (Some(reference), Some(hash)) => reference.contains(hash),
+            _ => false,
+        }
+    }
+
+    async fn summary(&self) -> ScanSummary {
+        let result = self.result.read().await;
+        let (files, unique_hashes) = match &self.stream {
+            Some(stream) => (stream.hashed_files(), None),
+            None => (
+                result
+                    .values()
+                    .flatten()
+                    .filter(|f| f.hash.is_some())
+                    .count(),
+                Some(result.keys().filter(|k| !is_reserved_key(k)).count()),
+            ),
+        };
+        ScanSummary {
+            files,
+            unique_hashes,
+            directories: self.dirs_processed.load(Ordering::Relaxed),
+            errors: self.errors.lock().unwrap().len(),
+            bytes: self.bytes_hashed.load(Ordering::Relaxed),
+            empty_files: self.empty_files.load(Ordering::Relaxed),
+            permission_denied: self.permission_denied.load(Ordering::Relaxed),
+            symlink_cycles: self.symlink_cycles.load(Ordering::Relaxed),
+            symlink_depth_limited: self.symlink_depth_limited.load(Ordering::Relaxed),
+            cache_hits: self
+                .hash_cache
+                .as_ref()
+                .map_or(0, |cache| cache.hits.load(Ordering::Relaxed)),
+            cache_misses: self
+                .hash_cache
+                .as_ref()
+                .map_or(0, |cache| cache.misses.load(Ordering::Relaxed)),
+            xattr_cache_hits: self
+                .xattr_cache
+                .as_ref()
+                .map_or(0, |cache| cache.hits.load(Ordering::Relaxed)),
+            xattr_cache_misses: self
+                .xattr_cache
+                .as_ref()
+                .map_or(0, |cache| cache.misses.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Remove start directories which are inside another start directory, or repeat one, as they
+/// would otherwise be scanned twice.
+fn drop_nested_roots(roots: Vec<Root>) -> Vec<Root> {
+    let covered: Vec<bool> = roots
+        .iter()
+        .enumerate()
+        .map(|(i, root)| {
+            let covered_by = roots.iter().enumerate().find(|(j, other)| {
+                let nested = root.path.starts_with(&other.path) && root.path != other.path;
+                // Of several identical roots, only the first is kept
+                nested || (root.path == other.path && *j < i)
+            });
+            if let Some((_, other)) = covered_by {
+                println!(
+                    "skipping {}, as it is already covered by {}",
+                    root.path.to_string_lossy(),
+                    other.path.to_string_lossy()
+                );
+            }
+            covered_by.is_some()
+        })
+        .collect();
+
+    roots
+        .into_iter()
+        .zip(covered)
+        .filter_map(|(root, covered)| (!covered).then_some(root))
+        .collect()
+}
+
+/// Open a file for hashing, avoiding updating its access time if requested and supported.
+async fn open_file(path: &Path, no_atime: bool) -> std::io::Result<tokio::fs::File> {
+    #[cfg(target_os = "linux")]
+    if no_atime {
+        let noatime = tokio::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+            .await;
+        match noatime {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {} // Not the owner
+            res => return res,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = no_atime;
+
+    tokio::fs::File::open(long_path(path)).await
+}
+
+/// Windows refuses paths longer than `MAX_PATH` unless they carry the extended-length prefix,
+/// which in turn requires an absolute path. Elsewhere the path is returned unchanged.
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    {
+        const MAX_PATH: usize = 260;
+        let verbatim = path.to_str().is_some_and(|p| p.starts_with(r"\\?\"));
+        if path.as_os_str().len() < MAX_PATH || verbatim {
+            return Cow::Borrowed(path);
+        }
+        let absolute = match std::path::absolute(path) {
+            Ok(absolute) => absolute,
+            Err(_) => return Cow::Borrowed(path),
+        };
+        let mut prefixed = std::ffi::OsString::new();
+        match absolute.to_str().and_then(|p| p.strip_prefix(r"\\")) {
+            Some(unc) => {
+                prefixed.push(r"\\?\UNC\");
+                prefixed.push(unc);
+            }
+            None => {
+                prefixed.push(r"\\?\");
+                prefixed.push(absolute);
+            }
+        }
+        Cow::Owned(PathBuf::from(prefixed))
+    }
+    #[cfg(not(windows))]
+    Cow::Borrowed(path)
+}
+
+/// The raw bytes of a path, for hashing.
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        match path.to_string_lossy() {
+            Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+            Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+        }
+    }
+}
+
+/// Hash a single file, returning `None` if it was skipped or could not be read.
+async fn scan_file(
+    state: &ScanState,
+    progressbar: &ProgressBar,
+    internal_path: PathBuf,
+) -> Option<ScannedFile> {
+    let args = &state.args;
+    progressbar.set_message(format!(
+        "Scanning file: {}",
+        &internal_path.to_string_lossy()
+    ));
+
+    //check if is symlink, and if symlink is broken
+    let metadata = match tokio::fs::symlink_metadata(long_path(&internal_path)).await {
+        Ok(m) => m,
+        Err(e) => {
+            state.report_io_error(
+                progressbar,
+                &internal_path,
+                format!(
+                    "Skipped broken symlink: {}",
+                    internal_path.to_string_lossy()
+                ),
+                &e,
+            );
+            return None;
+        }
+    };
+
+    // Files whose modification time can't be read are never filtered out
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|m| m.as_secs());
+    if let Some(modified) = modified {
+        if state.modified_after.is_some_and(|after| modified < after)
+            || state
+                .modified_before
+                .is_some_and(|before| modified > before)
+        {
+            return None;
+        }
+    }
+
+    if metadata.is_file() && metadata.len() == 0 {
+        state.empty_files.fetch_add(1, Ordering::Relaxed);
+        if args.ignore_empty {
+            return None; //Skip empty files
+        }
+    }
+
+    let mut attributes = args
+        .wants_attributes()
+        .then(|| FileAttributes::from_metadata(&metadata));
+    let mut raced = false;
+    let mut chunk_hashes = None;
+    let mut sampled = None;
+    let mut link_target = None;
+    let mut line_endings_normalized = false;
+    let mut size_mismatch = None;
+    let mut secondary = None;
+    let mut digests = BTreeMap::new();
+    let mut tree = None;
+    let mut ssdeep = None;
+    let mut pieces = None;
+    let mut cid = None;
+    let mut cdc_chunks = None;
+    let metadata_is_file = metadata.is_file();
+    let algorithm = state.hash_settings.algorithm_for(&internal_path);
+    // The caches only hold whole file hashes, without those from any other algorithm
+    let cacheable = args.chunk_hashes.is_none()
+        && !args.ssdeep
+        && !args.entropy
+        && !args.torrent
+        && !args.ipfs_cid
+        && args.cdc_chunks.is_none()
+        && state.hash_settings.also.is_empty();
+    // Whether a cached hash was sampled or hashed as a tree depends only on the size
+    let cached_sampled = args
+        .tail_hash
+        .map(|sample| sample.get())
+        .filter(|sample| metadata.len() >= sample * 2);
+    let cached_tree = args
+        .parallel_above
+        .filter(|above| metadata.len() > *above)
+        .map(|_| args.parallel_block_size);
+
+    let started = Instant::now();
+    let (hash, bytes_read) = if args.symlink_hash && metadata.is_symlink() {
+        // Identify the link by the path it contains, rather than by what it points to
+        match tokio::fs::read_link(&internal_path).await {
+            Ok(target) => {
+                let target = match (args.resolve_relative_symlinks, internal_path.parent()) {
+                    (true, Some(parent)) if target.is_relative() => parent.join(target),
+                    _ => target,
+                };
+                let mut hasher = state.hash_settings.hasher(algorithm);
+                hasher.update(&path_bytes(&target));
+                if args.hash_filenames {
+                    hasher.update_filename(&internal_path);
+                }
+                link_target = Some(target);
+                (hasher.finalize(), 0)
+            }
+            Err(e) => {
+                state.report_io_error(
+                    progressbar,
+                    &internal_path,
+                    format!(
+                        "Cannot read link: {} {}",
+                        internal_path.to_string_lossy(),
+                        e
+                    ),
+                    &e,
+                );
+                return args
+                    .record_unreadable
+                    .then(|| ScannedFile::unreadable(internal_path, attributes, e));
+            }
+        }
+    } else if let Some(cached) = state
+        .hash_cache
+        .as_ref()
+        .filter(|_| cacheable)
+        .and_then(|cache| {
+            cache.get(
+                &internal_path,
+                &metadata,
+                algorithm,
+                cached_sampled,
+                cached_tree,
+            )
+        })
+    {
+        if args.progress_by == ProgressUnit::Bytes {
+            state.main_pb.read().await.inc(metadata.len());
+        }
+        sampled = cached.sampled;
+        tree = cached.tree;
+        (cached.hash, 0)
+    } else if let Some(cached) = state
+        .xattr_cache
+        .as_ref()
+        .filter(|_| cacheable && metadata_is_file)
+        .and_then(|cache| {
+            cache.get(
+                &long_path(&internal_path),
+                &metadata,
+                algorithm,
+                cached_sampled,
+                cached_tree,
+            )
+        })
+    {
+        if args.progress_by == ProgressUnit::Bytes {
+            state.main_pb.read().await.inc(metadata.len());
+        }
+        sampled = cached.sampled;
+        tree = cached.tree;
+        (cached.hash, 0)
+    } else {
+        // A followed link is compared against the file it points to, as that's what is read
+        let mut before = match metadata.is_symlink() {
+            true => tokio::fs::metadata(long_path(&internal_path))
+                .await
+                .unwrap_or(metadata),
+            false => metadata,
+        };
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            // A file which changed while it was mapped is read as usual the second time
+            let backend = match attempts {
+                1 => args.io_backend,
+                _ => IoBackend::Read,
+            };
+            let mut hashed = match hash_file(state, &internal_path, backend).await {
+                Ok(Some(hashed)) => hashed,
+                Ok(None) => return None, // Skipped as binary
+                Err(e) => {
+                    state.report_io_error(
+                        progressbar,
+                        &internal_path,
+                        format!(
+                            "Cannot generate hash: {} {}",
+                            internal_path.to_string_lossy(),
+                            e
+                        ),
+                        &e,
+                    );
+                    return args
+                        .record_unreadable
+                        .then(|| ScannedFile::unreadable(internal_path, attributes, e));
+                }
+            };
+
+            // The file may have been written to while it was being read, in which case the hash
+            // doesn't correspond to the contents at any single point in time
+            let after = tokio::fs::metadata(long_path(&internal_path)).await.ok();
+            let consistent = after.as_ref().is_some_and(|after| {
+                after.len() == before.len()
+                    && after.len() == hashed.bytes_read
+                    && after.modified().ok() == before.modified().ok()
+            });
+            if consistent || attempts == 2 {
+                raced = !consistent;
+                size_mismatch = (before.len() != hashed.bytes_read).then_some(SizeMismatch {
+                    expected: before.len(),
+                    read: hashed.bytes_read,
+                });
+                if args.wants_attributes() {
+                    // Describe the file as it was read, rather than as it was first seen
+                    let mut read = FileAttributes::from_metadata(after.as_ref().unwrap_or(&before));
+                    read.size = hashed.bytes_read as usize;
+                    read.entropy = hashed.entropy;
+                    attributes = Some(read);
+                }
+                let bytes_read = hashed.bytes_read;
+                chunk_hashes = hashed.chunk_hashes.take();
+                ssdeep = hashed.ssdeep.take();
+                pieces = hashed.pieces.take();
+                cid = hashed.cid.take();
+                cdc_chunks = hashed.cdc_chunks.take();
+                line_endings_normalized = hashed.line_endings_normalized;
+                sampled = hashed.sampled;
+                tree = hashed.tree;
+                if args.hash_filenames {
+                    hashed.update_filename(&internal_path);
+                }
+                let also = hashed.take_also();
+                let hash = hashed.hex_digest();
+                secondary = SecondaryHash::find(args.secondary_algorithm, &also);
+                digests = state
+                    .hash_settings
+                    .requested_digests(algorithm, &hash, &also);
+                if let (Some(cache), false) = (&state.hash_cache, raced) {
+                    cache.insert(
+                        &internal_path,
+                        after.as_ref().unwrap(),
+                        algorithm,
+                        Cached {
+                            hash: hash.clone(),
+                            sampled,
+                            tree,
+                        },
+                    );
+                }
+                if let (Some(cache), false, true) = (&state.xattr_cache, raced, metadata_is_file) {
+                    cache.insert(
+                        &long_path(&internal_path),
+                        after.as_ref().unwrap(),
+                        algorithm,
+                        Cached {
+                            hash: hash.clone(),
+                            sampled,
+                            tree,
+                        },
+                    );
+                }
+                break (hash, bytes_read);
+            }
+            if let Some(after) = after {
+                before = after;
+            }
+        }
+    };
+
+    let streams = match args.include_streams && metadata_is_file {
+        true => {
+            let stream_path = internal_path.clone();
+            let settings = state.hash_settings.clone();
+            let streams =
+                tokio::task::spawn_blocking(move || streams::hash_streams(&stream_path, &settings))
+                    .await
+                    .unwrap();
+            streams.unwrap_or_else(|e| {
+                state.report_io_error(
+                    progressbar,
+                    &internal_path,
+                    format!(
+                        "Cannot hash streams: {} {}",
+                        internal_path.to_string_lossy(),
+                        e
+                    ),
+                    &e,
+                );
+                BTreeMap::new()
+            })
+        }
+        false => BTreeMap::new(),
+    };
+    #[cfg(feature = "images")]
+    let perceptual_hash = match args.perceptual_hash && perceptual::is_image(&internal_path) {
+        true => {
+            let image_path = long_path(&internal_path).into_owned();
+            // Images which can't be decoded are still listed by their exact hash
+            tokio::task::spawn_blocking(move || perceptual::hash_image(&image_path).ok())
+                .await
+                .unwrap()
+        }
+        false => None,
+    };
+    #[cfg(not(feature = "images"))]
+    let perceptual_hash = None;
+    let hash_duration_ms = args
+        .profile_files
+        .then(|| started.elapsed().as_millis() as u64);
+
+    let pieces_root = state
+        .torrent
+        .as_ref()
+        .zip(pieces)
+        .and_then(|(torrent, pieces)| torrent.add(&internal_path, bytes_read, pieces));
+
+    state.files_hashed.fetch_add(1, Ordering::Relaxed);
+    state.bytes_hashed.fetch_add(bytes_read, Ordering::Relaxed);
+    if args.progress_by == ProgressUnit::Bytes {
+        state.main_pb.read().await.inc(bytes_read);
+    }
+    if let Some(events) = &state.events {
+        events.emit(&Event::File {
+            path: &internal_path,
+            hash: &hash,
+        });
+    }
+
+    Some(ScannedFile {
+        hash: Some(hash),
+        path: internal_path,
+        attributes,
+        error: None,
+        raced,
+        chunk_hashes,
+        streams,
+        algorithm: (algorithm != args.algorithm()).then_some(algorithm),
+        sampled,
+        link_target_resolved: link_target.is_some() && args.resolve_relative_symlinks,
+        link_target,
+        size_mismatch,
+        filename_hashed: args.hash_filenames,
+        line_endings_normalized,
+        secondary,
+        digests,
+        tree,
+        ssdeep,
+        pieces_root,
+        cid,
+        cdc_chunks,
+        perceptual_hash,
+        hash_duration_ms,
+    })
+}
+
+/// Open a file and feed its contents through the hasher.
+async fn hash_file(
+    state: &ScanState,
+    path: &Path,
+    backend: IoBackend,
+) -> std::io::Result<Option<hashing::Hashed>> {
+    let file = open_file(path, state.args.no_atime).await?.into_std().await;
+    let settings = state.hash_settings.clone();
+    let algorithm = settings.algorithm_for(path);
+    let tail_hash = state.args.tail_hash;
+    let parallel_above = state.args.parallel_above;
+    let block_size = state.args.parallel_block_size;
+    tokio::task::spawn_blocking(move || {
+        if let Some(sample) = tail_hash {
+            return hashing::hash_sampled(file, algorithm, &settings, sample.get());
+        }
+        match parallel_above {
+            Some(above) if file.metadata()?.len() > above => {
+                hashing::hash_tree(file, algorithm, &settings, block_size).map(Some)
+            }
+            _ => match backend {
+                IoBackend::Mmap => match hashing::map_file(&file) {
+                    Some(map) => Ok(hashing::hash_mapped(&map, algorithm, &settings)),
+                    None => hashing::hash_reader(file, algorithm, &settings),
+                },
+                IoBackend::Read => hashing::hash_reader(file, algorithm, &settings),
+            },
+        }
+    })
+    .await
+    .unwrap()
+}
+
+/// An entry found while reading a directory, that should be processed further.
+enum Entry {
+    Dir(QueuedDir),
+    File(PathBuf),
+    /// A symlink to a directory which wasn't followed, as it's part of a cycle or would go past
+    /// `--max-symlink-depth`
+    SkippedLink {
+        cycle: bool,
+    },
+}
+
+/// A file found while reading a directory, unless it's filtered out by its extension or by
+/// `--filter-command`.
+async fn file_entry(state: &ScanState, progressbar: &ProgressBar, path: PathBuf) -> Option<Entry> {
+    let args = &state.args;
+    if args.mirror_manifests && path.file_name() == Some(output::MIRROR_MANIFEST.as_ref()) {
+        return None;
+    }
+    if state.is_own_output(&path).await {
+        return None;
+    }
+    if !args.include_extension.is_empty() || !args.exclude_extension.is_empty() {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if args.exclude_extension.contains(&extension)
+            || (!args.include_extension.is_empty() && !args.include_extension.contains(&extension))
+        {
+            return None;
+        }
+    }
+
+    let command = match &state.args.filter_command {
+        Some(command) => command,
+        None => return Some(Entry::File(path)),
+    };
+    let mut words = command.split_whitespace();
+    let status = tokio::process::Command::new(words.next()?)
+        .args(words)
+        .arg(&path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => Some(Entry::File(path)),
+        Ok(_) => None,
+        Err(e) => {
+            state.report_error(
+                progressbar,
+                &path,
+                format!(
+                    "Cannot run filter command: {} {}",
+                    path.to_string_lossy(),
+                    e
+                ),
+            );
+            None
+        }
+    }
+}
+
+/// Whether an entry found while scanning should be skipped as hidden, see `--strict-hidden`.
+async fn is_hidden(state: &ScanState, root: usize, s: &tokio::fs::DirEntry) -> bool {
+    let path = s.path();
+    if !state.args.strict_hidden {
+        return path.to_string_lossy().contains("/.");
+    }
+
+    let below_root = path.strip_prefix(&state.roots[root].path).unwrap_or(&path);
+    let dotted = below_root
+        .components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+    if dotted {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+        if let Ok(metadata) = s.metadata().await {
+            return metadata.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM)
+                != 0;
+        }
+    }
+    false
+}
+
+/// Decide whether an entry should be descended into, hashed, or skipped.
+async fn classify_entry(
+    state: &ScanState,
+    progressbar: &ProgressBar,
+    dir: &QueuedDir,
+    s: &tokio::fs::DirEntry,
+) -> Option<Entry> {
+    let args = &state.args;
+    let root = dir.root;
+
+    if let Some(max) = args.max_path_length {
+        if s.path().as_os_str().len() > max.get() {
+            progressbar.println(format!(
+                "Skipped path longer than {} bytes: {}",
+                max,
+                s.path().to_string_lossy()
+            ));
+            return None;
+        }
+    }
+
+    if !args.include_hidden && is_hidden(state, root, s).await {
+        progressbar.println(format!(
+            "Skipped hidden path: {}",
+            s.path().to_string_lossy()
+        ));
+        return None;
+    }
+
+    // Links are recorded rather than followed when hashing their targets
+    let is_link = s.file_type().await.is_ok_and(|t| t.is_symlink());
+    if args.symlink_hash && is_link {
+        return file_entry(state, progressbar, s.path()).await;
+    }
+    if is_link && !args.allow_symlink_escape && state.escapes_roots(&s.path()).await {
+        progressbar.println(format!(
+            "Skipped symlink pointing outside the start directories: {}",
+            s.path().to_string_lossy()
+        ));
+        return None;
+    }
+    if !s.path().is_dir() {
+        return file_entry(state, progressbar, s.path()).await;
+    }
+
+    let name = s.file_name();
+    if args
+        .exclude_dir_name
+        .iter()
+        .any(|excluded| name.to_str() == Some(excluded))
+    {
+        progressbar.println(format!(
+            "Skipped excluded directory: {}",
+            s.path().to_string_lossy()
+        ));
+        return None;
+    }
+    if !args.follow_mounts && state.roots[root].is_other_device(&s.path()).await {
+        progressbar.println(format!(
+            "Skipped directory on another filesystem: {}",
+            s.path().to_string_lossy()
+        ));
+        return None;
+    }
+
+    let mut links = dir.links.clone();
+    if is_link {
+        let target = tokio::fs::canonicalize(s.path()).await.ok();
+        let containing = tokio::fs::canonicalize(&dir.path).await.ok();
+        let cycle = target.as_ref().is_some_and(|target| {
+            links.contains(target)
+                || containing.is_some_and(|containing| containing.starts_with(target))
+        });
+        if cycle {
+            progressbar.println(format!(
+                "Skipped symlink cycle: {}",
+                s.path().to_string_lossy()
+            ));
+            return Some(Entry::SkippedLink { cycle: true });
+        }
+        if args.max_symlink_depth.is_some_and(|max| links.len() >= max) {
+            progressbar.println(format!(
+                "Skipped symlink beyond --max-symlink-depth: {}",
+                s.path().to_string_lossy()
+            ));
+            return Some(Entry::SkippedLink { cycle: false });
+        }
+        links.push(target.unwrap_or_else(|| s.path()));
+    }
+    Some(Entry::Dir(QueuedDir {
+        path: s.path(),
+        root,
+        links,
+    }))
+}
+
+async fn worker(thread: usize, progressbar: ProgressBar, state: Arc<ScanState>) {
+    let args = &state.args;
+    progressbar.set_style(ProgressStyle::default_spinner().template(&args.spinner_template));
     progressbar.set_prefix(format!("{}", thread + 1));
     progressbar.set_message("started");
 
     let mut waiting = false;
 
-    while num_waiting.load(Ordering::Relaxed) != args.thread_count {
-        let item = dir_queue.write().await.pop_front();
-        if let Some(path) = item {
+    while state.num_waiting.load(Ordering::Relaxed) != args.thread_count
+        && !state.aborted.load(Ordering::Relaxed)
+    {
+        if state.paused.load(Ordering::Relaxed) {
+            progressbar.set_message("Paused");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+
+        let item = state.dir_queue.write().await.pop_front();
+        if let Some(dir) = item {
+            let (path, root) = (&dir.path, dir.root);
             progressbar.set_message(format!("Processing dir: {:?}", &path));
             if waiting {
-                num_waiting.fetch_sub(1, Ordering::Relaxed);
+                state.num_waiting.fetch_sub(1, Ordering::Relaxed);
                 waiting = false;
             }
 
-            let mut folders: Vec<PathBuf> = vec![];
+            let mut folders: Vec<QueuedDir> = vec![];
             let mut result: HashMap<String, Vec<ScannedFile>> = HashMap::default();
-            let mut fs = match tokio::fs::read_dir(&path).await {
-                Ok(dir) => dir,
-                Err(e) => {
-                    progressbar.println(format!(
-                        "Error: {} {}",
-                        e,
-                        path.to_string_lossy()
-                    ));
-                    continue;
+            let mut files: Vec<PathBuf> = vec![];
+            // Directories completed before resuming only need their children queued again
+            let resumed = args.resume && state.completed.lock().unwrap().contains(path);
+            if !path.is_dir() {
+                // Files given directly as a start directory are hashed as-is
+                if !resumed {
+                    files.push(path.clone());
                 }
-            };
-
-            while let Ok(Some(s)) = fs.next_entry().await {
-                //XXX: there may be a better way to find hidden files?
-                //XXX: Windows support?
-                if !args.include_hidden && s.path().to_str().unwrap().contains("/.") {
-                    progressbar.println(format!(
-                        "Skipped hidden path: {}",
-                        s.path().to_string_lossy()
-                    ));
-                    continue;
+            } else {
+                if args.include_dir_entries && !resumed {
+                    match tokio::fs::symlink_metadata(&path).await {
+                        Ok(metadata) => result.entry(DIRECTORIES_KEY.into()).or_default().push(
+                            ScannedFile::directory(
+                                path.clone(),
+                                FileAttributes::from_metadata(&metadata),
+                            ),
+                        ),
+                        Err(e) => state.report_io_error(
+                            &progressbar,
+                            path,
+                            format!("Error: {} {}", e, path.to_string_lossy()),
+                            &e,
+                        ),
+                    }
                 }
 
-                if s.path().is_dir() {
-                    folders.push(s.path());
-                } else {
-                    progressbar
-                        .set_message(format!("Scanning file: {}", &s.path().to_string_lossy()));
-                    //open file
-                    let internal_path = s.path();
-
-                    //check if is symlink, and if symlink is broken
-                    let metadata = match tokio::fs::symlink_metadata(&internal_path).await {
-                        Ok(m) => m,
-                        Err(_) => {
-                            progressbar.println(format!(
-                                "Skipped broken symlink: {}",
-                                internal_path.to_string_lossy()
-                            ));
-                            continue;
-                        }
-                    };
-
-                    if args.ignore_empty && metadata.len() == 0 {
-                        continue; //Skip empty files
+                let mut fs = match tokio::fs::read_dir(long_path(path)).await {
+                    Ok(dir) => dir,
+                    Err(e) => {
+                        state.report_io_error(
+                            &progressbar,
+                            path,
+                            format!("Error: {} {}", e, path.to_string_lossy()),
+                            &e,
+                        );
+                        continue;
                     }
+                };
 
-                    let file = match tokio::fs::File::open(&internal_path).await {
-                        Ok(f) => f,
-                        Err(e) => {
-                            progressbar.println(format!(
-                                "Error: {} {}",
-                                e,
-                                internal_path.to_string_lossy()
-                            ));
-                            continue;
+                let entries: Vec<Option<Entry>> = match args.prefetch {
+                    Some(prefetch) => {
+                        let mut listed = vec![];
+                        while let Ok(Some(s)) = fs.next_entry().await {
+                            listed.push(s);
+                        }
+                        let (state, progressbar, dir) = (&state, &progressbar, &dir);
+                        futures::stream::iter(listed)
+                            .map(|s| async move {
+                                classify_entry(state, progressbar, dir, &s).await
+                            })
+                            .buffer_unordered(prefetch.get())
+                            .collect()
+                            .await
+                    }
+                    None => {
+                        let mut classified = vec![];
+                        while let Ok(Some(s)) = fs.next_entry().await {
+                            classified.push(classify_entry(&state, &progressbar, &dir, &s).await);
+                        }
+                        classified
+                    }
+                };
+                for entry in entries {
+                    match entry {
+                        Some(Entry::Dir(dir)) => folders.push(dir),
+                        Some(Entry::File(path)) if !resumed => files.push(path),
+                        Some(Entry::SkippedLink { cycle: true }) => {
+                            state.symlink_cycles.fetch_add(1, Ordering::Relaxed);
                         }
-                    };
-
-                    let mut hasher_file = file.into_std().await;
-                    let hasher: Result<Sha256, std::io::Error> =
-                        tokio::task::spawn_blocking(move || {
-                            let mut hasher = Sha256::new();
-                            std::io::copy(&mut hasher_file, &mut hasher)?;
-                            Ok(hasher)
-                        })
-                        .await
-                        .unwrap();
-
-                    let hasher = match hasher {
-                        Ok(f) => f,
-                        Err(e) => {
-                            progressbar.println(format!(
-                                "Cannot generate hash: {} {}",
-                                internal_path.to_string_lossy(),
-                                e
-                            ));
-                            continue;
+                        Some(Entry::SkippedLink { cycle: false }) => {
+                            state.symlink_depth_limited.fetch_add(1, Ordering::Relaxed);
                         }
-                    };
-
-                    let hash = format!("{:x}", hasher.finalize());
-
-                    let attributes = match args.load_file_attributes {
-                        true => Some(FileAttributes {
-                            size: metadata.len() as usize,
-                            created_date: match metadata.created() {
-                                Ok(f) => f
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .expect("time went backwards")
-                                    .as_secs() as i128,
-                                Err(_) => -1,
-                            },
-                            accessed_date: match metadata.accessed() {
-                                Ok(f) => f
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .expect("time went backwards")
-                                    .as_secs() as i128,
-                                Err(_) => -1,
-                            },
-                            edit_date: match metadata.modified() {
-                                Ok(f) => f
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .expect("time went backwards")
-                                    .as_secs() as i128,
-                                Err(_) => -1,
-                            },
-                            file_type: {
-                                if metadata.is_symlink() {
-                                    FileType::SymLink
-                                } else if metadata.is_dir() {
-                                    FileType::Directory
-                                } else {
-                                    FileType::File
-                                }
-                            },
-                        }),
-                        false => None,
-                    };
-
-                    let scanned_file = ScannedFile {
-                        hash,
-                        path: s.path(),
-                        attributes,
-                    };
-
-                    let contains_res = result.contains_key(&scanned_file.hash);
-                    if contains_res {
-                        result
-                            .get_mut(&scanned_file.hash)
-                            .unwrap()
-                            .push(scanned_file);
-                    } else {
-                        result.insert(scanned_file.hash.clone(), vec![scanned_file]);
+                        _ => {}
+                    }
+                }
+            }
+
+            for file in files {
+                if state.aborted.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Some(staged) = &state.staged {
+                    staged.lock().unwrap().push(file);
+                    continue;
+                }
+                let mut scanned = vec![];
+                if args.scan_archives && archive::is_archive(&file) {
+                    scanned.extend(archive::scan_archive(&state, &progressbar, &file).await);
+                }
+                scanned.extend(scan_file(&state, &progressbar, file).await);
+
+                for scanned_file in scanned {
+                    if state.is_in_reference(&scanned_file) {
+                        state.excluded.lock().unwrap().push(scanned_file);
+                        continue;
                     }
+                    normalize::push_unique(
+                        result.entry(scanned_file.group_key()).or_default(),
+                        scanned_file,
+                        &args.normalize_paths,
+                    );
                 }
             }
 
             if !folders.is_empty() {
-                dir_queue.write().await.extend(folders.into_iter());
+                let mut queue = state.dir_queue.write().await;
+                match args.traversal {
+                    Traversal::Bfs => queue.extend(folders),
+                    // Pushed in reverse so they're still processed in the order they were found
+                    Traversal::Dfs => folders
+                        .into_iter()
+                        .rev()
+                        .for_each(|folder| queue.push_front(folder)),
+                }
+            }
+
+            if args.mirror_manifests && !resumed && path.is_dir() {
+                if let Err(e) = output::write_mirror_manifest(&state, path, root, &result).await {
+                    state.report_io_error(
+                        &progressbar,
+                        path,
+                        format!("Cannot write manifest: {} {}", path.to_string_lossy(), e),
+                        &e,
+                    );
+                }
             }
 
-            if !result.is_empty() {
-                global_result.write().await.extend(result.into_iter());
+            if let Some(stream) = &state.stream {
+                for file in result.into_values().flatten() {
+                    stream.write(file);
+                }
+            } else if !result.is_empty() || args.resume {
+                let mut global_result = state.result.write().await;
+                let mut spill = false;
+                for (hash, files) in result {
+                    let group = global_result.entry(hash).or_default();
+                    for file in files {
+                        if let Some(spiller) = &state.spiller {
+                            spill |= spiller.hold(&file);
+                        }
+                        normalize::push_unique(group, file, &args.normalize_paths);
+                    }
+                }
+                if let (Some(spiller), true) = (&state.spiller, spill) {
+                    // Other workers wait on the lock while this is written, which holds back the
+                    // scan rather than letting memory use grow further
+                    if let Err(e) = spiller.spill(&state, &mut global_result, false).await {
+                        state
+                            .main_pb
+                            .read()
+                            .await
+                            .println(format!("Failed to write hashes: {}", e));
+                    }
+                }
+                // Marked while holding the result lock so checkpoints are always consistent
+                if args.resume {
+                    state.completed.lock().unwrap().insert(dir.path);
+                }
             }
 
-            let pb = main_pb.write().await;
-            pb.inc(1);
-            pb.set_length(dir_queue.read().await.len() as u64 + pb.position());
+            state.dirs_processed.fetch_add(1, Ordering::Relaxed);
+            let pb = state.main_pb.write().await;
+            // When progress is measured in bytes the bar advances as files are hashed instead
+            if args.progress_by == ProgressUnit::Directories {
+                pb.inc(1);
+                pb.set_length(state.dir_queue.read().await.len() as u64 + pb.position());
+            }
+            if let Some(events) = &state.events {
+                events.emit(&Event::Progress {
+                    done: pb.position(),
+                    total: pb.length(),
+                });
+            }
         } else {
             progressbar.set_message("Waiting for new tasks");
             if !waiting {
-                num_waiting.fetch_add(1, Ordering::Relaxed);
+                state.num_waiting.fetch_add(1, Ordering::Relaxed);
                 waiting = true;
             }
             tokio::time::sleep(Duration::from_millis(100)).await; //Wait for new tasks to appear
@@ -277,20 +2361,119 @@ async fn worker(
 
     progressbar.finish_with_message("closing...");
     if thread == 0 {
-        main_pb.write().await.finish();
+        state.main_pb.write().await.finish();
     }
 }
 
+/// Close the streamed output if the scan is interrupted with Ctrl-C, rather than being killed
+/// part way through a write, so everything hashed so far is kept and compressed output is
+/// complete.
+async fn finish_stream_on_interrupt(state: Arc<ScanState>) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    if let Some(stream) = &state.stream {
+        if let Err(e) = stream.finish().await {
+            eprintln!("Failed to write output: {}", e);
+        }
+    }
+    std::process::exit(INTERRUPTED_EXIT_CODE);
+}
+
+/// Pause the scan on `SIGUSR1` and resume it on `SIGUSR2`. Workers finish the directory they're
+/// on before pausing, and nothing is lost while paused.
+#[cfg(unix)]
+async fn pause_on_signals(state: Arc<ScanState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut pause = signal(SignalKind::user_defined1()).expect("able to handle SIGUSR1");
+    let mut resume = signal(SignalKind::user_defined2()).expect("able to handle SIGUSR2");
+    loop {
+        let paused = tokio::select! {
+            _ = pause.recv() => true,
+            _ = resume.recv() => false,
+        };
+        state.paused.store(paused, Ordering::Relaxed);
+    }
+}
+
+/// Read the key given to `--hmac-key-file`, exiting with a usage error if it can't be used.
+fn load_key(path: &Path) -> Arc<[u8]> {
+    hashing::read_key(path).unwrap_or_else(|e| {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!(
+                    "unable to read --hmac-key-file {}: {}",
+                    path.to_string_lossy(),
+                    e
+                ),
+            )
+            .exit()
+    })
+}
+
 #[tokio::main]
 async fn main() {
-    let args = Arc::new(Args::parse());
+    let mut args = Args::parse();
+    if let Err(message) = args.check_format() {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+    if cfg!(not(feature = "fuzzy")) && args.ssdeep {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--ssdeep requires dexy to be built with the `fuzzy` feature",
+            )
+            .exit();
+    }
+    if cfg!(not(feature = "images")) && args.perceptual_hash {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--perceptual-hash requires dexy to be built with the `images` feature",
+            )
+            .exit();
+    }
+    if args.list_algorithms {
+        hashing::list_algorithms();
+        return;
+    }
+    let key = args.hmac_key_file.as_deref().map(load_key);
+    match args.command {
+        Some(Command::Verify {
+            manifest,
+            hmac_key_file,
+        }) => {
+            let key = hmac_key_file.as_deref().map(load_key);
+            let all_match = verify::verify_manifest(manifest, key).await;
+            std::process::exit(if all_match { 0 } else { 1 });
+        }
+        Some(Command::Validate { manifest }) => {
+            let valid = validate::validate_manifest(&manifest).await;
+            std::process::exit(if valid { 0 } else { 1 });
+        }
+        Some(Command::Stats { manifest, json }) => {
+            stats::print_stats(&manifest, json)
+                .await
+                .expect("able to read manifest");
+            return;
+        }
+        Some(Command::Compare { left, right }) => {
+            match compare::compare_directories(left, right).await {
+                Ok(all_match) => std::process::exit(if all_match { 0 } else { 1 }),
+                Err(e) => {
+                    eprintln!("Cannot read directory to compare: {}", e);
+                    std::process::exit(UNREADABLE_EXIT_CODE);
+                }
+            }
+        }
+        None => {}
+    }
 
     //TODO: - allow "grep" patterns
 
-    let result = Arc::new(RwLock::new(HashMap::default()));
-    let queue = Arc::new(RwLock::new(VecDeque::new()));
-    let counter = Arc::new(AtomicUsize::new(0));
-
     // // If updating, we should load the existing data
     // if args.update_existing {
     //     let data = tokio::fs::read_to_string(format!(
@@ -298,59 +2481,466 @@ async fn main() {
     //         args.out.join(args.name.clone()).to_string_lossy()
     //     )).await.expect("able to read existing file");
 
-
     // }
 
+    if args.expand_globs {
+        args.start_directory = expand_globs(&args.start_directory).unwrap_or_else(|message| {
+            Args::command()
+                .error(ErrorKind::ValueValidation, message)
+                .exit()
+        });
+    }
+
     println!(
         "starting at: {}",
         &args.start_directory[0].to_string_lossy()
     );
 
-    queue.write().await.extend(    args.start_directory.iter().map(|x| {
-        x.canonicalize().expect("able to canonicalize provided path")
+    let roots: Vec<Root> = args
+        .start_directory
+        .iter()
+        .map(|x| {
+            Root::new(match args.canonical_paths {
+                true => x
+                    .canonicalize()
+                    .expect("able to canonicalize provided path"),
+                false => x.clone(),
+            })
+        })
+        .collect();
+    let roots = drop_nested_roots(roots);
+    let relative_to = args.emit_relative_to.as_ref().map(|base| {
+        let base = match args.canonical_paths {
+            true => base
+                .canonicalize()
+                .expect("able to canonicalize the path given to --emit-relative-to"),
+            false => base.clone(),
+        };
+        if let Some(root) = roots.iter().find(|root| !root.path.starts_with(&base)) {
+            Args::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "start directory {} isn't inside --emit-relative-to {}",
+                        root.path.to_string_lossy(),
+                        base.to_string_lossy()
+                    ),
+                )
+                .exit();
+        }
+        base
+    });
+    let own_output = match args.no_exclude_self {
+        true => None,
+        false => args.out.canonicalize().ok(),
+    };
+    let mut queue = DirQueue::new(args.parallel_roots, roots.len());
+    queue.extend(roots.iter().enumerate().map(|(root, r)| QueuedDir {
+        path: r.path.clone(),
+        root,
+        links: vec![],
     }));
 
-    let progressbar = MultiProgress::new();
-    let main_pb = Arc::new(RwLock::new(progressbar.add(ProgressBar::new(1))));
-    main_pb.write().await.set_style(
+    let events = EventSink::from_args(&args).expect("able to open events output");
+
+    let checkpoint = match args.resume {
+        true => Checkpoint::load(&args)
+            .await
+            .expect("able to read existing checkpoint"),
+        false => Checkpoint::default(),
+    };
+
+    let progressbar = MultiProgress::with_draw_target(progress::draw_target(
+        args.progress_style,
+        args.progress_refresh_rate,
+    ));
+    let main_pb = progressbar.add(ProgressBar::new(1));
+    main_pb.set_style(
         ProgressStyle::default_bar()
             .template(
-                "[{elapsed}]/[{eta}] {wide_bar:.cyan/blue} {pos:>7}/{len:7} {msg}",
+                args.bar_template
+                    .as_deref()
+                    .unwrap_or_else(|| args.progress_style.bar_template(args.progress_by)),
             )
             .progress_chars("##-"),
     );
 
+    let ignore_errors = args
+        .ignore_errors_matching
+        .as_deref()
+        .map(|pattern| Regex::new(pattern).expect("pattern validated by clap"));
+
+    let scan_start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs();
+    let modified_since = match &args.since_scan {
+        Some(path) => {
+            let prior = Manifest::load(path)
+                .await
+                .expect("able to read the scan given to --since-scan");
+            Some(
+                prior
+                    .timestamp()
+                    .expect("scan given to --since-scan has a timestamp or file attributes"),
+            )
+        }
+        None => None,
+    };
+    let older_than = args
+        .exclude_older_than
+        .map(|age| scan_start.saturating_sub(age.as_secs()));
+    // Both limits apply when given, so the later of the two wins
+    let modified_after = modified_since.max(older_than);
+    let modified_before = args
+        .exclude_newer_than
+        .map(|age| scan_start.saturating_sub(age.as_secs()));
+
+    let reference_hashes = match &args.dedup_against {
+        Some(path) => {
+            let reference = Manifest::load(path)
+                .await
+                .expect("able to read the manifest given to --dedup-against");
+            Some(
+                reference
+                    .files
+                    .into_keys()
+                    .filter(|key| !is_reserved_key(key))
+                    .collect(),
+            )
+        }
+        None => None,
+    };
+
+    let hash_settings = Arc::new(HashSettings::from_args(&args, key));
+    let hash_cache = match args.hash_cache {
+        true => Some(
+            HashCache::load(&args)
+                .await
+                .expect("able to read the hash cache"),
+        ),
+        false => None,
+    };
+    let xattr_cache = args.xattr_cache.then(XattrCache::default);
+    let spiller = spill::Spiller::from_args(&args);
+    let staged = args
+        .staged_duplicates
+        .then(|| std::sync::Mutex::new(vec![]));
+    let torrent = args
+        .torrent
+        .then(|| torrent::Torrent::new(args.torrent_piece_length));
+    let stream = match args.format {
+        OutputFormat::Json | OutputFormat::Msgpack => None,
+        OutputFormat::Ndjson => Some(NdjsonWriter::spawn(&args).expect("able to create output")),
+    };
+
+    let state = Arc::new(ScanState {
+        header: manifest::Header {
+            keyed: args.hmac_key_file.is_some(),
+            ..manifest::Header::new(&roots, args.algorithm())
+        },
+        args,
+        roots,
+        dir_queue: RwLock::new(queue),
+        num_waiting: AtomicUsize::new(0),
+        result: RwLock::new(checkpoint.result),
+        completed: std::sync::Mutex::new(checkpoint.completed),
+        main_pb: RwLock::new(main_pb),
+        events,
+        ignore_errors,
+        reference_hashes,
+        excluded: std::sync::Mutex::new(vec![]),
+        staged,
+        modified_after,
+        modified_before,
+        relative_to,
+        own_output,
+        fingerprint: std::sync::OnceLock::new(),
+        hash_settings,
+        errors: std::sync::Mutex::new(vec![]),
+        hash_cache,
+        xattr_cache,
+        spiller,
+        stream,
+        torrent,
+        aborted: AtomicBool::new(false),
+        paused: AtomicBool::new(false),
+        files_hashed: AtomicU64::new(0),
+        bytes_hashed: AtomicU64::new(0),
+        dirs_processed: AtomicU64::new(0),
+        empty_files: AtomicUsize::new(0),
+        permission_denied: AtomicUsize::new(0),
+        symlink_cycles: AtomicUsize::new(0),
+        symlink_depth_limited: AtomicUsize::new(0),
+    });
+
+    if state.args.progress_by == ProgressUnit::Bytes {
+        println!("counting files...");
+        let total = progress::count_bytes(&state).await;
+        state.main_pb.read().await.set_length(total);
+    }
+
     let mut handles = vec![];
-    for i in 0..args.thread_count {
+    for i in 0..state.args.thread_count {
         let thread_pb = progressbar.insert(0, ProgressBar::new(0));
-        let handle = tokio::spawn(worker(
-            i,
-            queue.clone(),
-            counter.clone(),
-            result.clone(),
-            thread_pb,
-            main_pb.clone(),
-            args.clone(),
-        ));
+        let handle = tokio::spawn(worker(i, thread_pb, state.clone()));
         handles.push(handle);
     }
 
+    if state.args.resume {
+        tokio::spawn(checkpoint::checkpoint_ticker(state.clone()));
+    }
+    tokio::spawn(spill::spill_ticker(state.clone()));
+    #[cfg(unix)]
+    tokio::spawn(pause_on_signals(state.clone()));
+    if state.stream.is_some() {
+        tokio::spawn(finish_stream_on_interrupt(state.clone()));
+    }
+
+    tokio::spawn(progress::rate_ticker(state.clone()));
+    let json_lines = (state.args.progress_style == ProgressPreset::JsonLines)
+        .then(|| tokio::spawn(progress::json_lines(state.clone())));
+    let progress_log = state.args.progress_log.as_ref().map(|path| {
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("able to open progress log");
+        tokio::spawn(progress::progress_log(state.clone(), log))
+    });
+
     tokio::time::sleep(Duration::from_millis(100)).await;
 
     progressbar.join().unwrap();
 
     futures::future::join_all(handles).await;
+    if let Some(json_lines) = json_lines {
+        json_lines.await.unwrap();
+    }
+    if let Some(progress_log) = progress_log {
+        if let Err(e) = progress_log.await.unwrap() {
+            println!("failed to write progress log: {}", e);
+        }
+    }
 
     // Finished processing
-    // Write hashes
-    let data = result.read().await;
-    tokio::fs::write(
-        format!(
-            "{}.json",
-            args.out.join(args.name.clone()).to_string_lossy()
-        ),
-        serde_json::to_string(&*data).unwrap(),
-    )
-    .await
-    .unwrap();
+    let args = &state.args;
+    let aborted = state.aborted.load(Ordering::Relaxed);
+    if let Some(stream) = &state.stream {
+        // Whatever was hashed before an abort is kept, as it has already been streamed out
+        stream.finish().await.unwrap();
+    }
+    if aborted {
+        println!(
+            "aborted after {} errors",
+            state.errors.lock().unwrap().len()
+        );
+    } else {
+        staged::hash_candidates(&state).await;
+    }
+    let verified = aborted || state.stream.is_some() || write_results(&state).await;
+
+    let torrent_written = match (&state.torrent, aborted) {
+        (Some(torrent), false) => match torrent.write(&state).await {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Cannot write the torrent: {}", e);
+                false
+            }
+        },
+        _ => true,
+    };
+
+    let excluded = state.excluded.lock().unwrap().clone();
+    if !excluded.is_empty() {
+        output::write_json(args, "excluded.json", &excluded)
+            .await
+            .unwrap();
+    }
+
+    let errors = state.errors.lock().unwrap().clone();
+    if !errors.is_empty() {
+        output::write_json(args, "errors.json", &errors)
+            .await
+            .unwrap();
+    }
+
+    if let (Some(cache), false) = (&state.hash_cache, aborted) {
+        cache.save(args).await.unwrap();
+    }
+
+    if let Some(events) = &state.events {
+        events.emit(&Event::Done {
+            summary: &state.summary().await,
+        });
+    }
+
+    if aborted {
+        std::process::exit(ABORTED_EXIT_CODE);
+    }
+    if !verified {
+        std::process::exit(UNVERIFIED_EXIT_CODE);
+    }
+    if !torrent_written {
+        std::process::exit(1);
+    }
+    if args.fail_if_empty && state.files_hashed.load(Ordering::Relaxed) == 0 {
+        eprintln!("No files were hashed, check the start directories and filters given");
+        std::process::exit(EMPTY_EXIT_CODE);
+    }
+}
+
+/// Write the hashes, and any reports derived from them, for a completed scan. Returns whether
+/// the output matched the scan when read back, if `--verify-after-write` was given.
+async fn write_results(state: &ScanState) -> bool {
+    let args = &state.args;
+    let mut data = state.result.write().await;
+    if args.fingerprint {
+        let roots: Vec<&Path> = state.roots.iter().map(|root| root.path.as_path()).collect();
+        let fingerprint = reports::fingerprint(&data, &roots, args.algorithm());
+        println!("fingerprint: {}", fingerprint);
+        state.fingerprint.set(fingerprint).unwrap();
+    }
+    if let Some(base) = &state.relative_to {
+        for file in data.values_mut().flatten() {
+            file.path = file
+                .path
+                .strip_prefix(base)
+                .expect("every start directory is inside the base")
+                .to_path_buf();
+        }
+    }
+    // Taken before any files are filtered out of the output, as they're still in the directories
+    let directory_hashes = args.directory_hashes.then(|| {
+        let roots: Vec<&Path> = state
+            .roots
+            .iter()
+            .map(|root| match &state.relative_to {
+                Some(base) => root
+                    .path
+                    .strip_prefix(base)
+                    .expect("every start directory is inside the base"),
+                None => &root.path,
+            })
+            .collect();
+        reports::directory_hashes(&data, &roots, args.algorithm())
+    });
+    // Every empty file hashes the same, depending only on the algorithm
+    let empty_hashes: Vec<String> = HashAlgorithm::value_variants()
+        .iter()
+        .map(|algorithm| state.hash_settings.hasher(*algorithm).finalize())
+        .collect();
+    if args.dedup_zero_length_separately {
+        for hash in &empty_hashes {
+            if let Some(files) = data.remove(hash) {
+                data.entry(EMPTY_KEY.into()).or_default().extend(files);
+            }
+        }
+    }
+    if args.exclude_empty_dirs || args.only_empty_dirs {
+        let empty = reports::empty_directories(&data);
+        if let Some(directories) = data.get_mut(DIRECTORIES_KEY) {
+            directories.retain(|dir| empty.contains(&dir.path) == args.only_empty_dirs);
+        }
+        if args.only_empty_dirs {
+            data.retain(|key, _| key == DIRECTORIES_KEY);
+        }
+    }
+    if args.only_duplicates {
+        let min_count = args.min_duplicate_count.unwrap_or(2);
+        data.retain(|key, files| match key.as_str() {
+            EMPTY_KEY => true,
+            key if is_reserved_key(key) || empty_hashes.iter().any(|hash| hash == key) => false,
+            _ => files.len() >= min_count,
+        });
+    }
+    if args.canonical_json {
+        for files in data.values_mut() {
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+    }
+    // Read before it's overwritten by this scan
+    let prior = match args.prune_unchanged {
+        true => match Manifest::load(&output::hashes_file(args)).await {
+            Ok(prior) => Some(prior.files),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(HashMap::new()),
+            Err(e) => {
+                eprintln!("Cannot read the previous scan to prune: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    match &state.spiller {
+        Some(spiller) => spiller.spill(state, &mut data, true).await.unwrap(),
+        None => output::write_hashes(state, &data).await.unwrap(),
+    }
+    if let Some(prior) = prior {
+        let (changed, removed) = reports::changes_since(&prior, &data);
+        output::write_split(state, "changed.", &changed)
+            .await
+            .unwrap();
+        output::write_json(args, "removed.json", &removed)
+            .await
+            .unwrap();
+    }
+    let verified = match args.verify_after_write {
+        true => match output::check_written(state, &data).await {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Output failed verification: {}", e);
+                false
+            }
+        },
+        false => true,
+    };
+
+    #[cfg(unix)]
+    if args.hardlink_report {
+        output::write_json(
+            args,
+            "hardlink-report.json",
+            &reports::hardlink_report(&data),
+        )
+        .await
+        .unwrap();
+    }
+    if let Some(count) = args.report_largest {
+        output::write_json(
+            args,
+            "largest.json",
+            &reports::largest_files(&data, count.get()),
+        )
+        .await
+        .unwrap();
+    }
+    if let Some(count) = args.report_slowest {
+        output::write_json(
+            args,
+            "slowest.json",
+            &reports::slowest_files(&data, count.get()),
+        )
+        .await
+        .unwrap();
+    }
+    if let Some(distance) = args.report_similar_images {
+        output::write_json(
+            args,
+            "similar-images.json",
+            &reports::similar_images(&data, distance),
+        )
+        .await
+        .unwrap();
+    }
+    if let Some(hashes) = directory_hashes {
+        output::write_json(args, "dir-hashes.json", &hashes)
+            .await
+            .unwrap();
+    }
+    drop(data);
+
+    if args.resume {
+        Checkpoint::remove(args).await.unwrap();
+    }
+    verified
 }
@@ -0,0 +1,177 @@
+//! The on-disk format of a scan's output. By default the hashes are wrapped in an envelope
+//! describing how the scan was produced, `--raw` writes the bare map of hashes instead.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{binary, hashing::HashAlgorithm, Root, ScannedFile};
+
+/// Version of the output format written by this version of dexy, bumped whenever a change would
+/// stop older versions reading it correctly. Scans written with `--raw`, or from before the
+/// envelope, are version 0.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Metadata describing how a scan was produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    /// Version of the output format, envelopes from before this was recorded are version 1
+    #[serde(default = "first_envelope_version")]
+    pub schema_version: u32,
+    /// Version of dexy that produced the scan
+    pub dexy_version: String,
+    /// When the scan started, in seconds since the unix epoch
+    pub timestamp: u64,
+    /// The algorithm used to hash file contents
+    pub algorithm: HashAlgorithm,
+    /// File hashes are HMACs under a key given with `--hmac-key-file`, which isn't recorded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keyed: bool,
+    /// Digest of every file's path relative to its start directory and hash, with
+    /// `--fingerprint`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Host the scan was run on
+    pub hostname: String,
+    /// The start directories, after canonicalization
+    pub start_directories: Vec<PathBuf>,
+    /// The command line arguments the scan was run with
+    pub arguments: Vec<String>,
+}
+
+impl Header {
+    pub fn new(roots: &[Root], algorithm: HashAlgorithm) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            dexy_version: env!("CARGO_PKG_VERSION").into(),
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_secs(),
+            algorithm,
+            keyed: false,
+            fingerprint: None,
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            start_directories: roots.iter().map(|root| root.path.clone()).collect(),
+            arguments: std::env::args_os()
+                .skip(1)
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+        }
+    }
+
+    /// Refuse scans written in a newer format than this version of dexy understands.
+    fn check_supported(&self, path: &Path) -> std::io::Result<()> {
+        if self.schema_version <= SCHEMA_VERSION {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{} was written by a newer version of dexy ({}), using output format version {} \
+                 where at most {} is supported",
+                path.to_string_lossy(),
+                self.dexy_version,
+                self.schema_version,
+                SCHEMA_VERSION
+            ),
+        ))
+    }
+}
+
+fn first_envelope_version() -> u32 {
+    1
+}
+
+/// Hashes wrapped with the header describing the scan that produced them.
+#[derive(Serialize)]
+pub struct Envelope<'a, T: Serialize + ?Sized> {
+    #[serde(flatten)]
+    pub header: &'a Header,
+    pub files: &'a T,
+}
+
+/// A previous scan read back from disk.
+#[derive(Debug)]
+pub struct Manifest {
+    /// Missing for scans written with `--raw`, or by a version of dexy from before the envelope
+    pub header: Option<Header>,
+    pub files: HashMap<String, Vec<ScannedFile>>,
+}
+
+impl Manifest {
+    pub async fn load(path: &Path) -> std::io::Result<Self> {
+        let data = tokio::fs::read(path).await?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "msgpack")
+        {
+            let manifest = binary::decode(&data).map_err(std::io::Error::other)?;
+            if let Some(header) = &manifest.header {
+                header.check_supported(path)?;
+            }
+            return Ok(manifest);
+        }
+
+        // Parsed in two steps rather than with an untagged enum, as serde can't buffer the i128
+        // dates in file attributes while it works out which variant it has
+        let mut value: serde_json::Value = serde_json::from_slice(&data)?;
+        if value.get("dexy_version").is_none() {
+            return Ok(Self {
+                header: None,
+                files: files_from_value(value)?,
+            });
+        }
+
+        let files = value["files"].take();
+        let header: Header = serde_json::from_value(value)?;
+        header.check_supported(path)?;
+        Ok(Self {
+            header: Some(header),
+            files: files_from_value(files)?,
+        })
+    }
+
+    /// Version of the output format the scan was written in.
+    pub fn schema_version(&self) -> u32 {
+        self.header
+            .as_ref()
+            .map_or(0, |header| header.schema_version)
+    }
+
+    /// When the scan started, or failing that the latest modification time it recorded.
+    pub fn timestamp(&self) -> Option<u64> {
+        if let Some(header) = &self.header {
+            return Some(header.timestamp);
+        }
+        self.files
+            .values()
+            .flatten()
+            .filter_map(|file| file.attributes.as_ref())
+            .map(|attributes| attributes.edit_date)
+            .max()
+            .and_then(|date| u64::try_from(date).ok())
+    }
+}
+
+/// Read the map of files, regrouping them by hash if they were written with `--group-by path`.
+fn files_from_value(
+    value: serde_json::Value,
+) -> serde_json::Result<HashMap<String, Vec<ScannedFile>>> {
+    let by_path = value
+        .as_object()
+        .is_some_and(|files| files.values().any(|entry| entry.is_object()));
+    if !by_path {
+        return serde_json::from_value(value);
+    }
+
+    let by_path: HashMap<PathBuf, ScannedFile> = serde_json::from_value(value)?;
+    let mut files: HashMap<String, Vec<ScannedFile>> = HashMap::new();
+    for file in by_path.into_values() {
+        files.entry(file.group_key()).or_default().push(file);
+    }
+    Ok(files)
+}
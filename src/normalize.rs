@@ -0,0 +1,47 @@
+//! Path normalization, used to recognise when two entries in a hash group are actually the same
+//! physical file spelled differently (e.g. on case-insensitive filesystems, or NFC vs NFD names).
+
+use clap::ArgEnum;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::ScannedFile;
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathNormalization {
+    /// Compare paths ignoring case
+    Case,
+    /// Compare paths after unicode NFC normalization
+    Nfc,
+}
+
+/// Produce the form of `path` that is used for comparisons under the given normalizations.
+pub fn normalize_path(path: &Path, normalizations: &[PathNormalization]) -> String {
+    let mut path = path.to_string_lossy().into_owned();
+    if normalizations.contains(&PathNormalization::Nfc) {
+        path = path.nfc().collect();
+    }
+    if normalizations.contains(&PathNormalization::Case) {
+        path = path.to_lowercase();
+    }
+    path
+}
+
+/// Add a file to a hash group, unless the group already contains the same path under the given
+/// normalizations.
+pub fn push_unique(
+    group: &mut Vec<ScannedFile>,
+    file: ScannedFile,
+    normalizations: &[PathNormalization],
+) {
+    if !normalizations.is_empty() {
+        let normalized = normalize_path(&file.path, normalizations);
+        if group
+            .iter()
+            .any(|f| normalize_path(&f.path, normalizations) == normalized)
+        {
+            return;
+        }
+    }
+    group.push(file);
+}
@@ -0,0 +1,293 @@
+//! Writing the results of a scan to disk.
+
+use clap::ArgEnum;
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    binary, is_reserved_key,
+    manifest::{Envelope, Manifest},
+    Args, Root, ScanState, ScannedFile, DIRECTORIES_KEY,
+};
+
+/// Name of the manifest written into each directory with `--mirror-manifests`.
+pub const MIRROR_MANIFEST: &str = ".dexy.json";
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A single JSON document grouping files by hash, written once the scan completes
+    Json,
+    /// Newline-delimited JSON, one file per line, written as the scan progresses
+    Ndjson,
+    /// MessagePack, listing each file once with its hash as raw bytes, for the smallest output
+    Msgpack,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    /// Files sharing a hash are listed together under that hash
+    Hash,
+    /// Each file is listed under its own path, sorted by path
+    Path,
+}
+
+/// Write the hashes found by a scan to `{name}.json`, or split across several files if
+/// requested.
+pub async fn write_hashes(
+    state: &ScanState,
+    data: &HashMap<String, Vec<ScannedFile>>,
+) -> std::io::Result<()> {
+    if state.args.format == OutputFormat::Msgpack {
+        let header = state.header();
+        let header = (!state.args.raw).then_some(&header);
+        let encoded = binary::encode(header, data).map_err(std::io::Error::other)?;
+        return write_atomic(&state.args, "msgpack", encoded).await;
+    }
+    if !state.args.split_by_root {
+        return write_split(state, "", data).await;
+    }
+
+    for (label, part) in split_by_root(&state.roots, data) {
+        write_split(state, &format!("{}.", label), &part).await?;
+    }
+    Ok(())
+}
+
+/// Write hashes to `{name}.{stem}json`, or to shards if requested.
+pub async fn write_split(
+    state: &ScanState,
+    stem: &str,
+    data: &HashMap<String, Vec<ScannedFile>>,
+) -> std::io::Result<()> {
+    match state.args.shard_by_prefix {
+        Some(prefix_len) => {
+            for (prefix, shard) in shard_by_prefix(data, prefix_len.get()) {
+                write_manifest(state, &format!("{}{}.json", stem, prefix), &shard).await?;
+            }
+            Ok(())
+        }
+        None => match state.args.group_by {
+            GroupBy::Hash => write_manifest(state, &format!("{}json", stem), data).await,
+            GroupBy::Path => write_manifest(state, &format!("{}json", stem), &by_path(data)).await,
+        },
+    }
+}
+
+/// Write hashes to `{name}.{extension}`, wrapped in an envelope unless `--raw` or
+/// `--canonical-json` was given.
+async fn write_manifest<T: Serialize + ?Sized>(
+    state: &ScanState,
+    extension: &str,
+    files: &T,
+) -> std::io::Result<()> {
+    let args = &state.args;
+    match args.raw || args.canonical_json {
+        true => write_json(args, extension, files).await,
+        false => {
+            let header = state.header();
+            let envelope = Envelope {
+                header: &header,
+                files,
+            };
+            write_json(args, extension, &envelope).await
+        }
+    }
+}
+
+/// Serialize `value` to `{name}.{extension}`, pretty printed or in canonical form if requested.
+pub async fn write_json<T: Serialize + ?Sized>(
+    args: &Args,
+    extension: &str,
+    value: &T,
+) -> std::io::Result<()> {
+    let json = match (args.json_pretty, args.canonical_json) {
+        (true, _) => serde_json::to_string_pretty(value),
+        // Going through a `Value` sorts the keys of every object, as its maps are ordered
+        (_, true) => serde_json::to_value(value).and_then(|value| serde_json::to_string(&value)),
+        _ => serde_json::to_string(value),
+    };
+    write_atomic(args, extension, json.unwrap().into_bytes()).await
+}
+
+/// Write `data` to `{name}.{extension}` via a temporary file which is renamed into place, so the
+/// output is either complete or absent and never truncated by an interruption mid-write.
+pub async fn write_atomic(args: &Args, extension: &str, data: Vec<u8>) -> std::io::Result<()> {
+    let path = args.output_file(extension);
+    let tmp = args.output_file(&format!("{}.tmp", extension));
+    let written = async {
+        tokio::fs::write(&tmp, data).await?;
+        #[cfg(unix)]
+        if let Some(mode) = args.output_mode {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode)).await?;
+        }
+        tokio::fs::rename(&tmp, &path).await
+    }
+    .await;
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    written
+}
+
+/// The file `write_hashes` writes every hash to, unless they're split across several.
+pub fn hashes_file(args: &Args) -> PathBuf {
+    args.output_file(match args.format {
+        OutputFormat::Msgpack => "msgpack",
+        _ => "json",
+    })
+}
+
+/// Read back the hashes written by `write_hashes` and check they match `data`, which is only
+/// possible when they were all written to a single file.
+pub async fn check_written(
+    state: &ScanState,
+    data: &HashMap<String, Vec<ScannedFile>>,
+) -> std::io::Result<()> {
+    let expected = match state.args.format {
+        // Files are regrouped by their hash when read, as only a flat list is written
+        OutputFormat::Msgpack => {
+            let mut regrouped: HashMap<String, Vec<ScannedFile>> = HashMap::new();
+            for file in data.values().flatten() {
+                regrouped
+                    .entry(file.group_key())
+                    .or_default()
+                    .push(file.clone());
+            }
+            Cow::Owned(regrouped)
+        }
+        _ => Cow::Borrowed(data),
+    };
+    let path = hashes_file(&state.args);
+    let written = Manifest::load(&path).await?;
+    match written.files == *expected {
+        true => Ok(()),
+        false => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} doesn't match the scan", path.to_string_lossy()),
+        )),
+    }
+}
+
+/// Write the hashes of the files directly inside `dir` to its `.dexy.json`, or to the same place
+/// under `--mirror-root` if given. There, each start directory is mirrored under its own name.
+pub async fn write_mirror_manifest(
+    state: &ScanState,
+    dir: &Path,
+    root: usize,
+    result: &HashMap<String, Vec<ScannedFile>>,
+) -> std::io::Result<()> {
+    let target = match &state.args.mirror_root {
+        Some(mirror_root) => {
+            let root = &state.roots[root].path;
+            let name = root.file_name().unwrap_or_else(|| "root".as_ref());
+            let target = mirror_root
+                .join(name)
+                .join(dir.strip_prefix(root).unwrap_or(dir));
+            tokio::fs::create_dir_all(&target).await?;
+            target
+        }
+        None => dir.to_path_buf(),
+    };
+
+    let files: HashMap<&String, &Vec<ScannedFile>> = result
+        .iter()
+        .filter(|(key, _)| key.as_str() != DIRECTORIES_KEY)
+        .collect();
+    let json = match state.args.raw {
+        true => serde_json::to_vec(&files),
+        false => serde_json::to_vec(&Envelope {
+            header: &state.header(),
+            files: &files,
+        }),
+    };
+    tokio::fs::write(target.join(MIRROR_MANIFEST), json.unwrap()).await
+}
+
+/// Key every file by its path instead of its hash.
+fn by_path(data: &HashMap<String, Vec<ScannedFile>>) -> BTreeMap<&PathBuf, &ScannedFile> {
+    data.values()
+        .flatten()
+        .map(|file| (&file.path, file))
+        .collect()
+}
+
+/// Split hash groups by the first `prefix_len` characters of their hash. Only shards with at
+/// least one group are returned.
+fn shard_by_prefix(
+    data: &HashMap<String, Vec<ScannedFile>>,
+    prefix_len: usize,
+) -> BTreeMap<&str, HashMap<&str, &Vec<ScannedFile>>> {
+    let mut shards: BTreeMap<&str, HashMap<&str, &Vec<ScannedFile>>> = BTreeMap::new();
+    for (key, files) in data {
+        // Reserved keys aren't hashes, so they get a shard of their own
+        let prefix = match key.as_str() {
+            key if is_reserved_key(key) => key,
+            _ => &key[..prefix_len.min(key.len())],
+        };
+        shards.entry(prefix).or_default().insert(key, files);
+    }
+    shards
+}
+
+/// A name for each start directory, telling them apart: its own name, or for roots sharing a
+/// name, the name followed by an index which no other root is already called.
+pub fn root_labels(roots: &[Root]) -> Vec<String> {
+    let names: Vec<String> = roots
+        .iter()
+        .map(|root| match root.path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => "root".into(),
+        })
+        .collect();
+    let unique = |name: &String| names.iter().filter(|n| *n == name).count() == 1;
+    let mut taken: HashSet<String> = names.iter().filter(|name| unique(name)).cloned().collect();
+    names
+        .iter()
+        .map(|name| match unique(name) {
+            true => name.clone(),
+            false => {
+                let label = (1..)
+                    .map(|i| format!("{}-{}", name, i))
+                    .find(|label| !taken.contains(label))
+                    .unwrap();
+                taken.insert(label.clone());
+                label
+            }
+        })
+        .collect()
+}
+
+/// Split hash groups by the start directory each file was found under, labelled as by
+/// `root_labels`.
+fn split_by_root(
+    roots: &[Root],
+    data: &HashMap<String, Vec<ScannedFile>>,
+) -> Vec<(String, HashMap<String, Vec<ScannedFile>>)> {
+    let mut parts: Vec<(String, HashMap<String, Vec<ScannedFile>>)> = root_labels(roots)
+        .into_iter()
+        .map(|label| (label, HashMap::new()))
+        .collect();
+    for (key, files) in data {
+        for file in files {
+            // Files belong to the most specific root they're under, in case roots are nested
+            let root = roots
+                .iter()
+                .enumerate()
+                .filter(|(_, root)| file.path.starts_with(&root.path))
+                .max_by_key(|(_, root)| root.path.components().count());
+            if let Some((i, _)) = root {
+                parts[i]
+                    .1
+                    .entry(key.clone())
+                    .or_default()
+                    .push(file.clone());
+            }
+        }
+    }
+    parts
+}
@@ -0,0 +1,93 @@
+//! Perceptual hashes of images with `--perceptual-hash`. Unlike an exact hash, these stay close
+//! when an image is re-encoded, resized or lightly edited, so near duplicates in a photo library
+//! can be found by comparing them bit by bit.
+
+use image::{imageops::FilterType, ImageFormat};
+use std::path::Path;
+
+/// Whether the file at `path` is an image that can be decoded, going by its extension.
+pub fn is_image(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// A difference hash of the image at `path`, as 16 hex digits. The image is shrunk to 9x8 grey
+/// pixels, and each bit records whether a pixel is brighter than the one to its right.
+pub fn hash_image(path: &Path) -> image::ImageResult<String> {
+    let small = image::open(path)?
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+    let mut bits = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            bits = bits << 1 | brighter as u64;
+        }
+    }
+    Ok(format!("{:016x}", bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops, GrayImage, Luma};
+
+    /// A smooth picture of `width` by `height` pixels, shaded by `shade` of the position across
+    /// and down it from 0 to 1.
+    fn picture(width: u32, height: u32, shade: impl Fn(f64, f64) -> f64) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            let (x, y) = (x as f64 / width as f64, y as f64 / height as f64);
+            Luma([(shade(x, y).clamp(0.0, 1.0) * 255.0) as u8])
+        })
+    }
+
+    fn hills(x: f64, y: f64) -> f64 {
+        0.5 + 0.4 * (x * 9.0).sin() * (y * 5.0 + 1.0).cos()
+    }
+
+    fn stripes(x: f64, y: f64) -> f64 {
+        0.5 + 0.4 * ((x + 2.0 * y) * 20.0).cos()
+    }
+
+    /// The hash of `image` once saved as `name` in `dir`, as bits.
+    fn hash(dir: &Path, name: &str, image: &GrayImage) -> u64 {
+        let path = dir.join(name);
+        image.save(&path).unwrap();
+        let hash = hash_image(&path).unwrap();
+        u64::from_str_radix(&hash, 16).unwrap()
+    }
+
+    #[test]
+    fn scaled_and_reencoded_copies_hash_alike() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = picture(160, 120, hills);
+        let hash_of_original = hash(dir.path(), "original.png", &original);
+
+        let larger = imageops::resize(&original, 400, 300, imageops::FilterType::Lanczos3);
+        let smaller = imageops::resize(&original, 64, 48, imageops::FilterType::Triangle);
+        for (name, copy) in [
+            ("larger.png", &larger),
+            ("smaller.png", &smaller),
+            ("reencoded.jpg", &original),
+        ] {
+            let distance = (hash(dir.path(), name, copy) ^ hash_of_original).count_ones();
+            assert!(distance <= 4, "{} differs by {} bits", name, distance);
+        }
+    }
+
+    #[test]
+    fn unrelated_images_hash_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let hills = hash(dir.path(), "hills.png", &picture(160, 120, hills));
+        let stripes = hash(dir.path(), "stripes.png", &picture(160, 120, stripes));
+        let distance = (hills ^ stripes).count_ones();
+        assert!(distance >= 20, "only {} bits apart", distance);
+    }
+
+    #[test]
+    fn files_are_images_by_their_extension() {
+        assert!(is_image(Path::new("photo.JPG")));
+        assert!(is_image(Path::new("dir/pic.png")));
+        assert!(!is_image(Path::new("notes.txt")));
+        assert!(!is_image(Path::new("no-extension")));
+    }
+}
@@ -0,0 +1,418 @@
+//! Customisation of the progress bars drawn during a scan.
+
+use clap::ArgEnum;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget};
+use regex::Regex;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{IsTerminal, Write},
+    num::NonZeroU64,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{classify_entry, long_path, Entry, QueuedDir, ScanState};
+
+pub const DEFAULT_SPINNER_TEMPLATE: &str = "{spinner} {prefix}: {wide_msg}";
+/// The main bar's prefix holds the smoothed ETA when counting directories, see `EtaEstimate`.
+pub const DEFAULT_BAR_TEMPLATE: &str =
+    "[{elapsed}]/[{prefix}] {wide_bar:.cyan/blue} {pos:>7}/{len:7} {msg}";
+const THROUGHPUT_BAR_TEMPLATE: &str = "[{elapsed}] {wide_bar:.cyan/blue} {pos:>7}/{len:7} {msg}";
+const DEFAULT_BYTES_BAR_TEMPLATE: &str =
+    "[{elapsed}]/[{eta}] {wide_bar:.cyan/blue} {bytes:>9}/{total_bytes:9} {msg}";
+const THROUGHPUT_BYTES_BAR_TEMPLATE: &str =
+    "[{elapsed}] {wide_bar:.cyan/blue} {bytes:>9}/{total_bytes:9} {msg}";
+
+/// Redraws per second when drawing to a terminal.
+const DEFAULT_REFRESH_RATE: u64 = 15;
+/// Redraws per second when stderr isn't a terminal.
+const UNATTENDED_REFRESH_RATE: u64 = 1;
+/// How often the rate readout is refreshed.
+const RATE_TICK: Duration = Duration::from_millis(500);
+/// Number of ticks the rate readout is averaged over.
+const RATE_WINDOW: usize = 10;
+/// How often a line is added to `--progress-log`.
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a line is written with `--progress-style json-lines`.
+const JSON_LINES_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Every key understood by indicatif's template engine.
+const TEMPLATE_KEYS: &[&str] = &[
+    "wide_bar",
+    "bar",
+    "spinner",
+    "wide_msg",
+    "msg",
+    "prefix",
+    "pos",
+    "len",
+    "percent",
+    "bytes",
+    "total_bytes",
+    "decimal_bytes",
+    "decimal_total_bytes",
+    "binary_bytes",
+    "binary_total_bytes",
+    "elapsed_precise",
+    "elapsed",
+    "per_sec",
+    "bytes_per_sec",
+    "binary_bytes_per_sec",
+    "eta_precise",
+    "eta",
+    "duration_precise",
+    "duration",
+];
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressPreset {
+    /// Elapsed time and ETA based on the number of directories processed
+    Default,
+    /// Elapsed time and progress only, without an ETA
+    Throughput,
+    /// No bars, instead a JSON object describing the scan's progress is written to stderr
+    /// every second, for logging
+    JsonLines,
+}
+
+impl ProgressPreset {
+    pub fn bar_template(&self, unit: ProgressUnit) -> &'static str {
+        match (self, unit) {
+            // The bar is still kept up to date when it isn't drawn, so it needs a template
+            (ProgressPreset::Default | ProgressPreset::JsonLines, ProgressUnit::Directories) => {
+                DEFAULT_BAR_TEMPLATE
+            }
+            (ProgressPreset::Default | ProgressPreset::JsonLines, ProgressUnit::Bytes) => {
+                DEFAULT_BYTES_BAR_TEMPLATE
+            }
+            (ProgressPreset::Throughput, ProgressUnit::Directories) => THROUGHPUT_BAR_TEMPLATE,
+            (ProgressPreset::Throughput, ProgressUnit::Bytes) => THROUGHPUT_BYTES_BAR_TEMPLATE,
+        }
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressUnit {
+    /// Directories processed, the total grows as new directories are discovered
+    Directories,
+    /// Bytes hashed, the total is counted before the scan starts
+    Bytes,
+}
+
+/// Where the progress bars are drawn, redrawing at most `refresh_rate` times a second. By
+/// default this is indicatif's usual rate on a terminal, and much lower otherwise as nobody is
+/// watching it closely. With `json-lines` the bars aren't drawn at all.
+pub fn draw_target(preset: ProgressPreset, refresh_rate: Option<NonZeroU64>) -> ProgressDrawTarget {
+    if preset == ProgressPreset::JsonLines {
+        return ProgressDrawTarget::hidden();
+    }
+    let refresh_rate = match refresh_rate {
+        Some(rate) => rate.get(),
+        None if std::io::stderr().is_terminal() => DEFAULT_REFRESH_RATE,
+        None => UNATTENDED_REFRESH_RATE,
+    };
+    ProgressDrawTarget::stderr_with_hz(refresh_rate)
+}
+
+/// Check a progress template up front, indicatif silently renders unknown keys as nothing and
+/// panics on some malformed widths, neither of which is a helpful way to report a typo.
+pub fn validate_template(template: &str) -> Result<(), String> {
+    let placeholder = Regex::new(r"\{\{|\}\}|\{([^{}]*)\}").unwrap();
+    let key =
+        Regex::new(r"^([a-z_]+)(?::[<^>]?([0-9]+)?!?(?:\.[0-9a-z_.]+)?(?:/[a-z_.]+)?)?$").unwrap();
+
+    let mut remainder = String::with_capacity(template.len());
+    let mut last = 0;
+    for caps in placeholder.captures_iter(template) {
+        let whole = caps.get(0).unwrap();
+        remainder.push_str(&template[last..whole.start()]);
+        last = whole.end();
+
+        let inner = match caps.get(1) {
+            Some(inner) => inner.as_str(),
+            None => continue, // Escaped brace
+        };
+        let parsed = key
+            .captures(inner)
+            .ok_or_else(|| format!("malformed placeholder `{{{}}}`", inner))?;
+        let name = &parsed[1];
+        if !TEMPLATE_KEYS.contains(&name) {
+            return Err(format!(
+                "unknown key `{}`, expected one of: {}",
+                name,
+                TEMPLATE_KEYS.join(", ")
+            ));
+        }
+        if let Some(width) = parsed.get(2) {
+            width
+                .as_str()
+                .parse::<u16>()
+                .map_err(|_| format!("width `{}` of `{}` is too large", width.as_str(), name))?;
+        }
+    }
+    remainder.push_str(&template[last..]);
+
+    if remainder.contains('{') || remainder.contains('}') {
+        return Err("unbalanced braces, use `{{` or `}}` for a literal brace".into());
+    }
+    Ok(())
+}
+
+/// Parse the factor given to `--eta-smoothing`.
+pub fn parse_smoothing(factor: &str) -> Result<f64, String> {
+    match factor.parse::<f64>() {
+        Ok(factor) if factor > 0.0 && factor <= 1.0 => Ok(factor),
+        _ => Err(format!("`{}` isn't a number between 0 and 1", factor)),
+    }
+}
+
+/// Estimates the time remaining while directories are still being discovered, which the bar's
+/// own ETA can't account for as it assumes the current length is final. Directories are
+/// completed at one rate and discovered at another, so the queue empties at the difference
+/// between the two. Both rates are smoothed exponentially, as they vary a lot from one
+/// directory to the next.
+struct EtaEstimate {
+    /// Weight given to the latest measurement, smaller values smooth more
+    factor: f64,
+    last: Option<(Instant, u64, u64)>,
+    completed_per_sec: f64,
+    discovered_per_sec: f64,
+}
+
+impl EtaEstimate {
+    fn new(factor: f64) -> Self {
+        Self {
+            factor,
+            last: None,
+            completed_per_sec: 0.0,
+            discovered_per_sec: 0.0,
+        }
+    }
+
+    /// Take a measurement of the directories completed and discovered so far, returning the
+    /// estimated time remaining, or `None` while the queue isn't shrinking.
+    fn update(&mut self, completed: u64, discovered: u64) -> Option<Duration> {
+        self.update_at(Instant::now(), completed, discovered)
+    }
+
+    /// As `update`, for a measurement taken at `now`.
+    fn update_at(&mut self, now: Instant, completed: u64, discovered: u64) -> Option<Duration> {
+        if let Some((then, last_completed, last_discovered)) = self.last {
+            let elapsed = now.duration_since(then).as_secs_f64();
+            if elapsed > 0.0 {
+                let completed_rate = completed.saturating_sub(last_completed) as f64 / elapsed;
+                let discovered_rate = discovered.saturating_sub(last_discovered) as f64 / elapsed;
+                self.completed_per_sec += self.factor * (completed_rate - self.completed_per_sec);
+                self.discovered_per_sec +=
+                    self.factor * (discovered_rate - self.discovered_per_sec);
+            }
+        }
+        self.last = Some((now, completed, discovered));
+
+        let shrinking = self.completed_per_sec - self.discovered_per_sec;
+        let queued = discovered.saturating_sub(completed) as f64;
+        (shrinking > 0.0).then(|| Duration::from_secs_f64(queued / shrinking))
+    }
+}
+
+/// Keep the main bar's message updated with the rate at which files and bytes are being hashed,
+/// averaged over the last few seconds, until the scan completes. When counting directories, the
+/// bar's prefix is kept updated with the estimated time remaining.
+pub async fn rate_ticker(state: Arc<ScanState>) {
+    let mut interval = tokio::time::interval(RATE_TICK);
+    let mut window: VecDeque<(Instant, u64, u64)> = VecDeque::with_capacity(RATE_WINDOW + 1);
+    let mut eta = EtaEstimate::new(state.args.eta_smoothing);
+
+    loop {
+        interval.tick().await;
+        let pb = state.main_pb.read().await;
+        if pb.is_finished() {
+            break;
+        }
+
+        let now = (
+            Instant::now(),
+            state.files_hashed.load(Ordering::Relaxed),
+            state.bytes_hashed.load(Ordering::Relaxed),
+        );
+        window.push_back(now);
+        if window.len() > RATE_WINDOW {
+            window.pop_front();
+        }
+
+        let (start, start_files, start_bytes) = window[0];
+        let elapsed = now.0.duration_since(start).as_secs_f64();
+        if state.paused.load(Ordering::Relaxed) {
+            pb.set_message("paused, send SIGUSR2 to resume");
+        } else if elapsed > 0.0 {
+            let files = (now.1 - start_files) as f64 / elapsed;
+            let bytes = ((now.2 - start_bytes) as f64 / elapsed) as u64;
+            pb.set_message(format!("{:.0} files/s, {}/s", files, HumanBytes(bytes)));
+        }
+
+        if state.args.progress_by == ProgressUnit::Directories {
+            match eta.update(pb.position(), pb.length()) {
+                Some(remaining) => pb.set_prefix(format!("{:#}", HumanDuration(remaining))),
+                None => pb.set_prefix("?"),
+            }
+        }
+    }
+}
+
+/// Append a snapshot of the scan's progress to `path` periodically, and once more when the
+/// scan completes.
+pub async fn progress_log(state: Arc<ScanState>, mut log: File) -> std::io::Result<()> {
+    let mut last_bytes = 0;
+    let mut last_line = Instant::now();
+
+    loop {
+        tokio::time::sleep(RATE_TICK).await;
+        let pb = state.main_pb.read().await;
+        let finished = pb.is_finished();
+        if !finished && last_line.elapsed() < PROGRESS_LOG_INTERVAL {
+            continue;
+        }
+
+        let bytes = state.bytes_hashed.load(Ordering::Relaxed);
+        let rate = (bytes - last_bytes) as f64 / last_line.elapsed().as_secs_f64();
+        let eta = match finished {
+            true => "done".to_string(),
+            false => HumanDuration(pb.eta()).to_string(),
+        };
+        writeln!(
+            log,
+            "{} files={} bytes={} rate={}/s eta={}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            state.files_hashed.load(Ordering::Relaxed),
+            bytes,
+            HumanBytes(rate as u64),
+            eta
+        )?;
+        last_bytes = bytes;
+        last_line = Instant::now();
+
+        if finished {
+            return Ok(());
+        }
+    }
+}
+
+/// Write a JSON object describing the scan's progress to stderr every second in place of the
+/// progress bars, and once more when the scan completes.
+pub async fn json_lines(state: Arc<ScanState>) {
+    let mut interval = tokio::time::interval(JSON_LINES_INTERVAL);
+    let mut last = (Instant::now(), 0);
+
+    loop {
+        interval.tick().await;
+        let pb = state.main_pb.read().await;
+        let finished = pb.is_finished();
+        let bytes = state.bytes_hashed.load(Ordering::Relaxed);
+        let elapsed = last.0.elapsed().as_secs_f64();
+        let rate_mb_s = match elapsed > 0.0 {
+            true => (bytes - last.1) as f64 / elapsed / 1_000_000.0,
+            false => 0.0,
+        };
+        let line = serde_json::json!({
+            "done": pb.position(),
+            "total": pb.length(),
+            "files": state.files_hashed.load(Ordering::Relaxed),
+            "bytes": bytes,
+            "rate_mb_s": rate_mb_s,
+            "finished": finished,
+        });
+        eprintln!("{}", line);
+        last = (Instant::now(), bytes);
+
+        if finished {
+            return;
+        }
+    }
+}
+
+/// Walk the scan's roots applying the same filters as the workers, totalling the size of every
+/// file that will be hashed.
+pub async fn count_bytes(state: &ScanState) -> u64 {
+    let quiet = ProgressBar::hidden();
+    let mut total = 0;
+    let mut stack: Vec<QueuedDir> = state
+        .roots
+        .iter()
+        .enumerate()
+        .map(|(root, r)| QueuedDir {
+            path: r.path.clone(),
+            root,
+            links: vec![],
+        })
+        .collect();
+
+    while let Some(queued) = stack.pop() {
+        if !queued.path.is_dir() {
+            total += file_size(&queued.path).await;
+            continue;
+        }
+        let mut dir = match tokio::fs::read_dir(long_path(&queued.path)).await {
+            Ok(dir) => dir,
+            Err(_) => continue, // Reported when the scan reaches it
+        };
+        while let Ok(Some(entry)) = dir.next_entry().await {
+            match classify_entry(state, &quiet, &queued, &entry).await {
+                Some(Entry::Dir(dir)) => stack.push(dir),
+                Some(Entry::File(path)) => total += file_size(&path).await,
+                Some(Entry::SkippedLink { .. }) | None => {}
+            }
+        }
+    }
+    total
+}
+
+async fn file_size(path: &std::path::Path) -> u64 {
+    tokio::fs::metadata(path).await.map_or(0, |m| m.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The largest change from one estimate to the next, as a ratio, over a minute of
+    /// directories completed at a steady 20 a second while they're discovered in bursts of 16
+    /// every other second. The first half is left out while the rates settle.
+    fn largest_swing(factor: f64) -> f64 {
+        let mut eta = EtaEstimate::new(factor);
+        let start = Instant::now();
+        let (mut completed, mut discovered) = (0, 2000);
+        let estimates: Vec<Option<Duration>> = (0..60)
+            .map(|second| {
+                completed += 20;
+                discovered += if second % 2 == 0 { 16 } else { 0 };
+                eta.update_at(start + Duration::from_secs(second), completed, discovered)
+            })
+            .collect();
+        let settled: Vec<f64> = estimates[30..]
+            .iter()
+            .map(|eta| eta.expect("the queue is shrinking").as_secs_f64())
+            .collect();
+        settled
+            .windows(2)
+            .map(|pair| pair[0].max(pair[1]) / pair[0].min(pair[1]))
+            .fold(1.0, f64::max)
+    }
+
+    #[test]
+    fn smoothed_eta_is_steady_while_directories_are_discovered_in_bursts() {
+        let smoothed = largest_swing(0.1);
+        assert!(smoothed < 1.2, "{}", smoothed);
+        let raw = largest_swing(1.0);
+        assert!(raw > 2.0, "{}", raw);
+    }
+
+    #[test]
+    fn smoothing_factors_are_between_zero_and_one() {
+        assert_eq!(parse_smoothing("0.5"), Ok(0.5));
+        assert_eq!(parse_smoothing("1"), Ok(1.0));
+        for factor in ["0", "-0.1", "1.5", "NaN", "often"] {
+            assert!(parse_smoothing(factor).is_err(), "{}", factor);
+        }
+    }
+}
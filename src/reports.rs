@@ -0,0 +1,450 @@
+//! Auxiliary reports derived from the hashes collected by a scan.
+
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use crate::{hashing::HashAlgorithm, is_reserved_key, path_bytes, ScannedFile, DIRECTORIES_KEY};
+
+/// Duplicate files on one device which could be hardlinked together to save space.
+#[cfg(unix)]
+#[derive(Debug, Serialize)]
+pub struct LinkGroup<'a> {
+    hash: &'a str,
+    device: u64,
+    size: usize,
+    /// Bytes saved if every file in the group were linked to the same inode
+    reclaimable_bytes: u64,
+    files: Vec<&'a PathBuf>,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Serialize)]
+pub struct HardlinkReport<'a> {
+    reclaimable_bytes: u64,
+    groups: Vec<LinkGroup<'a>>,
+}
+
+/// Find duplicate files that aren't already hardlinked to each other. Files can only be linked
+/// within a device, so each hash group is split by device first.
+#[cfg(unix)]
+pub fn hardlink_report(data: &HashMap<String, Vec<ScannedFile>>) -> HardlinkReport<'_> {
+    let mut groups = vec![];
+    for (hash, files) in data {
+        if is_reserved_key(hash) || files.len() < 2 {
+            continue;
+        }
+
+        let mut by_device: BTreeMap<u64, Vec<&ScannedFile>> = BTreeMap::new();
+        for file in files {
+            if let Some(attributes) = &file.attributes {
+                by_device.entry(attributes.device).or_default().push(file);
+            }
+        }
+
+        for (device, files) in by_device {
+            let inodes: HashSet<u64> = files
+                .iter()
+                .filter_map(|f| f.attributes.as_ref().map(|a| a.inode))
+                .collect();
+            if inodes.len() < 2 {
+                continue;
+            }
+            let size = files[0].attributes.as_ref().unwrap().size;
+            groups.push(LinkGroup {
+                hash,
+                device,
+                size,
+                reclaimable_bytes: (inodes.len() as u64 - 1) * size as u64,
+                files: files.iter().map(|f| &f.path).collect(),
+            });
+        }
+    }
+
+    groups.sort_by_key(|g| std::cmp::Reverse(g.reclaimable_bytes));
+    HardlinkReport {
+        reclaimable_bytes: groups.iter().map(|g| g.reclaimable_bytes).sum(),
+        groups,
+    }
+}
+
+/// Find the directories recorded by `--include-dir-entries` without any file recorded beneath
+/// them, so directories holding only other empty directories are empty too.
+pub fn empty_directories(data: &HashMap<String, Vec<ScannedFile>>) -> HashSet<PathBuf> {
+    let mut occupied: HashSet<&Path> = HashSet::new();
+    let files = data
+        .iter()
+        .filter(|(key, _)| key.as_str() != DIRECTORIES_KEY)
+        .flat_map(|(_, files)| files);
+    for file in files {
+        for ancestor in file.path.ancestors().skip(1) {
+            // Everything above an occupied directory was marked along with it
+            if !occupied.insert(ancestor) {
+                break;
+            }
+        }
+    }
+
+    data.get(DIRECTORIES_KEY)
+        .into_iter()
+        .flatten()
+        .map(|dir| &dir.path)
+        .filter(|path| !occupied.contains(path.as_path()))
+        .cloned()
+        .collect()
+}
+
+/// A file listed in the report of the largest files.
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LargeFile<'a> {
+    size: usize,
+    path: &'a PathBuf,
+    hash: &'a str,
+}
+
+/// Find the `count` largest files, largest first. Only the largest seen so far are held while
+/// looking, so this doesn't need a copy of every file.
+pub fn largest_files(data: &HashMap<String, Vec<ScannedFile>>, count: usize) -> Vec<LargeFile<'_>> {
+    let mut largest = BinaryHeap::with_capacity(count + 1);
+    for (hash, files) in data {
+        if is_reserved_key(hash) {
+            continue;
+        }
+        for file in files {
+            let size = match &file.attributes {
+                Some(attributes) => attributes.size,
+                None => continue,
+            };
+            largest.push(Reverse(LargeFile {
+                size,
+                path: &file.path,
+                hash,
+            }));
+            if largest.len() > count {
+                largest.pop();
+            }
+        }
+    }
+    // Sorting the reversed entries ascending leaves the largest first
+    largest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(file)| file)
+        .collect()
+}
+
+/// Files which are new or whose hash changed since the `prior` scan, grouped as they are in the
+/// output, and the paths `prior` had which are no longer present, in order.
+pub fn changes_since(
+    prior: &HashMap<String, Vec<ScannedFile>>,
+    data: &HashMap<String, Vec<ScannedFile>>,
+) -> (HashMap<String, Vec<ScannedFile>>, Vec<PathBuf>) {
+    let prior_hashes: HashMap<&Path, &Option<String>> = prior
+        .values()
+        .flatten()
+        .map(|file| (file.path.as_path(), &file.hash))
+        .collect();
+    let mut changed: HashMap<String, Vec<ScannedFile>> = HashMap::new();
+    for (key, files) in data {
+        for file in files {
+            if prior_hashes.get(file.path.as_path()) != Some(&&file.hash) {
+                changed.entry(key.clone()).or_default().push(file.clone());
+            }
+        }
+    }
+
+    let present: HashSet<&Path> = data
+        .values()
+        .flatten()
+        .map(|file| file.path.as_path())
+        .collect();
+    let mut removed: Vec<PathBuf> = prior_hashes
+        .into_keys()
+        .filter(|path| !present.contains(path))
+        .map(Path::to_path_buf)
+        .collect();
+    removed.sort();
+    (changed, removed)
+}
+
+/// A file listed in the report of the files which took longest to hash.
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SlowFile<'a> {
+    hash_duration_ms: u64,
+    path: &'a PathBuf,
+    hash: &'a str,
+}
+
+/// Find the `count` files which took longest to hash with `--profile-files`, slowest first.
+pub fn slowest_files(data: &HashMap<String, Vec<ScannedFile>>, count: usize) -> Vec<SlowFile<'_>> {
+    let mut slowest = BinaryHeap::with_capacity(count + 1);
+    for (hash, files) in data {
+        if is_reserved_key(hash) {
+            continue;
+        }
+        for file in files {
+            let hash_duration_ms = match file.hash_duration_ms {
+                Some(duration) => duration,
+                None => continue,
+            };
+            slowest.push(Reverse(SlowFile {
+                hash_duration_ms,
+                path: &file.path,
+                hash,
+            }));
+            if slowest.len() > count {
+                slowest.pop();
+            }
+        }
+    }
+    slowest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(file)| file)
+        .collect()
+}
+
+/// Hash every directory containing hashed files within `roots`, from the name and hash of each
+/// file and subdirectory directly inside it in order of name, with `--directory-hashes`.
+/// Directories with the same contents get the same hash wherever they are.
+pub fn directory_hashes(
+    data: &HashMap<String, Vec<ScannedFile>>,
+    roots: &[&Path],
+    algorithm: HashAlgorithm,
+) -> BTreeMap<PathBuf, String> {
+    // Every directory's entries by name, with the hash of those which are files
+    let mut listings: HashMap<&Path, BTreeMap<&OsStr, Option<&str>>> = HashMap::new();
+    for file in data.values().flatten() {
+        let (hash, root) = match (
+            &file.hash,
+            roots.iter().find(|root| file.path.starts_with(root)),
+        ) {
+            (Some(hash), Some(root)) => (hash, root),
+            _ => continue,
+        };
+        let mut path = file.path.as_path();
+        let mut entry = Some(hash.as_str());
+        while path != *root {
+            let (parent, name) = match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => (parent, name),
+                _ => break,
+            };
+            listings.entry(parent).or_default().insert(name, entry);
+            entry = None;
+            path = parent;
+        }
+    }
+
+    // Deepest first, so every subdirectory is hashed before the directory containing it
+    let mut directories: Vec<&Path> = listings.keys().copied().collect();
+    directories.sort_by_key(|dir| Reverse(dir.components().count()));
+    let mut hashes: BTreeMap<PathBuf, String> = BTreeMap::new();
+    for dir in directories {
+        let mut hasher = algorithm.hasher();
+        for (name, entry) in &listings[dir] {
+            let (kind, hash) = match entry {
+                Some(hash) => (b'f', *hash),
+                None => (b'd', hashes[&dir.join(name)].as_str()),
+            };
+            let name = path_bytes(name.as_ref());
+            hasher.update(&[kind]);
+            hasher.update(&(name.len() as u64).to_le_bytes());
+            hasher.update(&name);
+            hasher.update(hash.as_bytes());
+        }
+        hashes.insert(dir.to_path_buf(), hasher.finalize());
+    }
+    hashes
+}
+
+/// A single digest of every hashed file within `roots`, from its path relative to the start
+/// directory it was found in and its hash, in order of path. Paths are joined with `/` whatever
+/// the platform, so the same tree gives the same fingerprint everywhere.
+pub fn fingerprint(
+    data: &HashMap<String, Vec<ScannedFile>>,
+    roots: &[&Path],
+    algorithm: HashAlgorithm,
+) -> String {
+    let mut entries: Vec<(Vec<u8>, &str)> = data
+        .values()
+        .flatten()
+        .filter_map(|file| {
+            let hash = file.hash.as_deref()?;
+            let relative = roots
+                .iter()
+                .find_map(|root| file.path.strip_prefix(root).ok())?;
+            let components: Vec<_> = relative
+                .iter()
+                .map(|component| path_bytes(component.as_ref()))
+                .collect();
+            Some((components.join(&b'/'), hash))
+        })
+        .collect();
+    entries.sort();
+
+    let mut hasher = algorithm.hasher();
+    for (path, hash) in entries {
+        hasher.update(&(path.len() as u64).to_le_bytes());
+        hasher.update(&path);
+        hasher.update(&(hash.len() as u64).to_le_bytes());
+        hasher.update(hash.as_bytes());
+    }
+    hasher.finalize()
+}
+
+/// An image listed in the report of groups of similar images.
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SimilarImage<'a> {
+    path: &'a PathBuf,
+    perceptual_hash: &'a str,
+}
+
+/// Group images whose perceptual hashes differ in at most `max_distance` bits, directly or
+/// through other images in the group, with `--report-similar-images`. Images without a similar
+/// one are left out, and each group is sorted by path.
+pub fn similar_images(
+    data: &HashMap<String, Vec<ScannedFile>>,
+    max_distance: u32,
+) -> Vec<Vec<SimilarImage<'_>>> {
+    let images: Vec<(SimilarImage, u64)> = data
+        .values()
+        .flatten()
+        .filter_map(|file| {
+            let hash = file.perceptual_hash.as_deref()?;
+            let bits = u64::from_str_radix(hash, 16).ok()?;
+            let image = SimilarImage {
+                path: &file.path,
+                perceptual_hash: hash,
+            };
+            Some((image, bits))
+        })
+        .collect();
+
+    // Two hashes within `max_distance` bits of each other must match exactly in at least one of
+    // `max_distance + 1` segments, so only images sharing a segment need comparing
+    let segments = (max_distance as usize + 1).min(64);
+    let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+    for (i, (_, bits)) in images.iter().enumerate() {
+        for segment in 0..segments {
+            let (start, end) = (segment * 64 / segments, (segment + 1) * 64 / segments);
+            let mask = u64::MAX >> (64 - (end - start));
+            buckets
+                .entry((segment, bits >> start & mask))
+                .or_default()
+                .push(i);
+        }
+    }
+
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    fn find(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+    for bucket in buckets.values() {
+        for (n, &a) in bucket.iter().enumerate() {
+            for &b in &bucket[n + 1..] {
+                if (images[a].1 ^ images[b].1).count_ones() <= max_distance {
+                    let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+                    parents[a] = b;
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<SimilarImage>> = HashMap::new();
+    for (i, (image, _)) in images.into_iter().enumerate() {
+        groups.entry(find(&mut parents, i)).or_default().push(image);
+    }
+    let mut groups: Vec<Vec<SimilarImage>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect();
+    for group in &mut groups {
+        group.sort();
+    }
+    groups.sort();
+    groups
+}
+
+#[cfg(all(test, feature = "images"))]
+mod tests {
+    use super::*;
+
+    /// Scanned images named after their index, with the perceptual hashes `hashes`.
+    fn images(hashes: &[u64]) -> HashMap<String, Vec<ScannedFile>> {
+        let files = hashes
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| ScannedFile {
+                perceptual_hash: Some(format!("{:016x}", hash)),
+                ..ScannedFile::unreadable(
+                    PathBuf::from(i.to_string()),
+                    None,
+                    std::io::ErrorKind::Other.into(),
+                )
+            })
+            .collect();
+        [("images".to_string(), files)].into_iter().collect()
+    }
+
+    /// The groups of `similar_images`, as the indices of the images in each.
+    fn groups(hashes: &[u64], max_distance: u32) -> Vec<Vec<String>> {
+        let data = images(hashes);
+        similar_images(&data, max_distance)
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|image| image.path.to_string_lossy().into_owned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn images_within_the_distance_are_grouped() {
+        let base = 0x0123_4567_89ab_cdef;
+        // Differences spread over the whole hash, so no segment is shared by accident
+        let close = base ^ (1 << 3 | 1 << 40);
+        let far = base ^ 0x8080_8080_8080_8080;
+        assert_eq!(groups(&[base, close, far], 2), vec![vec!["0", "1"]]);
+        assert_eq!(groups(&[base, close, far], 1), Vec::<Vec<String>>::new());
+        assert_eq!(groups(&[base, close, far], 8), vec![vec!["0", "1", "2"]]);
+    }
+
+    #[test]
+    fn every_bucket_finds_its_neighbours() {
+        // The only unchanged segment of 4 is a different one each time
+        let base = u64::MAX / 3;
+        for kept in 0..4 {
+            let changed: u64 = (0..4)
+                .filter(|segment| *segment != kept)
+                .map(|segment| 1 << (segment * 16 + 5))
+                .sum();
+            assert_eq!(
+                groups(&[base, base ^ changed], 3),
+                vec![vec!["0", "1"]],
+                "{}",
+                kept
+            );
+        }
+    }
+
+    #[test]
+    fn groups_join_through_images_in_between() {
+        // Each is 2 bits from the next, but the ends are 6 apart
+        let chain = [0, 0b11, 0b1111, 0b11_1111];
+        let unrelated = u64::MAX;
+        assert_eq!(
+            groups(&[chain[0], chain[3], unrelated, chain[1], chain[2]], 2),
+            vec![vec!["0", "1", "3", "4"]]
+        );
+    }
+}
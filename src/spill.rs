@@ -0,0 +1,117 @@
+//! Bounding the memory used by large scans with `--max-memory` and `--spill-interval`, by writing
+//! the hashes collected so far out as a numbered part and forgetting them, rather than holding
+//! every hash until the scan completes.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{output, Args, ScanState, ScannedFile};
+
+/// Where the hashes collected so far are written out.
+pub struct Spiller {
+    /// Write out the held hashes once they're estimated to take more than this many bytes
+    max_bytes: Option<u64>,
+    /// Estimated size of the hashes currently held
+    held_bytes: AtomicU64,
+    /// Number of parts written so far
+    parts: AtomicUsize,
+}
+
+impl Spiller {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.max_memory.is_none() && args.spill_interval.is_none() {
+            return None;
+        }
+        Some(Self {
+            max_bytes: args.max_memory,
+            held_bytes: AtomicU64::new(0),
+            parts: AtomicUsize::new(0),
+        })
+    }
+
+    /// Account for a file added to the held hashes, returning whether they should now be
+    /// written out.
+    pub fn hold(&self, file: &ScannedFile) -> bool {
+        let size = estimate(file);
+        let held = self.held_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.max_bytes.is_some_and(|max| held > max)
+    }
+
+    /// Write `result` to `{name}.{part}.json` and clear it. Nothing is written if it's empty,
+    /// unless `last` is set and no part has been written yet, so a scan always has some output.
+    pub async fn spill(
+        &self,
+        state: &ScanState,
+        result: &mut HashMap<String, Vec<ScannedFile>>,
+        last: bool,
+    ) -> std::io::Result<()> {
+        if result.is_empty() && !(last && self.parts.load(Ordering::Relaxed) == 0) {
+            return Ok(());
+        }
+        let part = self.parts.fetch_add(1, Ordering::Relaxed) + 1;
+        output::write_split(state, &format!("{:04}.", part), result).await?;
+        result.clear();
+        result.shrink_to_fit();
+        self.held_bytes.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Rough number of bytes a file takes up while held in memory.
+fn estimate(file: &ScannedFile) -> u64 {
+    let hashes = file.hash.iter().chain(file.chunk_hashes.iter().flatten());
+    (std::mem::size_of::<ScannedFile>()
+        + file.path.as_os_str().len()
+        + hashes.map(String::len).sum::<usize>()) as u64
+}
+
+/// Parse a size in bytes, optionally with a `K`, `M` or `G` suffix for powers of 1024.
+pub fn parse_byte_size(size: &str) -> Result<u64, String> {
+    let lower = size.to_lowercase();
+    let trimmed = lower.trim_end_matches("ib").trim_end_matches('b');
+    let (digits, multiplier) = match trimmed.char_indices().last() {
+        Some((i, 'k')) => (&trimmed[..i], 1 << 10),
+        Some((i, 'm')) => (&trimmed[..i], 1 << 20),
+        Some((i, 'g')) => (&trimmed[..i], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    let bytes = digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("`{}` isn't a size like 512M", size))?;
+    match bytes.checked_mul(multiplier) {
+        Some(bytes) if bytes > 0 => Ok(bytes),
+        Some(_) => Err("the size must be more than 0".into()),
+        None => Err(format!("`{}` is too large", size)),
+    }
+}
+
+/// Write out the hashes collected so far every `--spill-interval` until the scan completes.
+pub async fn spill_ticker(state: Arc<ScanState>) {
+    let (spiller, period) = match (&state.spiller, state.args.spill_interval) {
+        (Some(spiller), Some(period)) => (spiller, period),
+        _ => return,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(period.get()));
+    interval.tick().await; // The first tick completes immediately
+    loop {
+        interval.tick().await;
+        if state.main_pb.read().await.is_finished() {
+            break;
+        }
+        let mut result = state.result.write().await;
+        if let Err(e) = spiller.spill(&state, &mut result, false).await {
+            state
+                .main_pb
+                .read()
+                .await
+                .println(format!("Failed to write hashes: {}", e));
+        }
+    }
+}
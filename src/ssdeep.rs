@@ -0,0 +1,288 @@
+//! Similarity hashes in the format of ssdeep, for `--ssdeep`. Unlike a digest, two files which
+//! differ in a few places share most of their ssdeep hash, so modified copies can be clustered
+//! after the scan by comparing hashes with ssdeep itself.
+//!
+//! This is a streaming implementation of ssdeep's context triggered piecewise hashing: a rolling
+//! hash over the last few bytes picks out block boundaries, and each block contributes one
+//! character of the result. As the block size is chosen from the file's size, the blocks for
+//! every candidate size are tracked at once so the file is only read once.
+
+/// Length of the longer part of the hash, in characters.
+const SPAMSUM_LENGTH: usize = 64;
+/// Smallest block size, every other is this doubled some number of times.
+const MIN_BLOCKSIZE: u64 = 3;
+/// Number of candidate block sizes.
+const NUM_BLOCKHASHES: usize = 31;
+/// Number of bytes the rolling hash covers.
+const ROLLING_WINDOW: usize = 7;
+
+const HASH_PRIME: u32 = 0x0100_0193;
+const HASH_INIT: u32 = 0x2802_1967;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn block_size(index: usize) -> u64 {
+    MIN_BLOCKSIZE << index
+}
+
+fn sum_hash(byte: u8, hash: u32) -> u32 {
+    hash.wrapping_mul(HASH_PRIME) ^ byte as u32
+}
+
+/// Hash of the last `ROLLING_WINDOW` bytes, which decides where blocks end.
+#[derive(Default)]
+struct Roll {
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl Roll {
+    fn update(&mut self, byte: u8) {
+        let slot = self.n % ROLLING_WINDOW;
+        self.h2 = self.h2.wrapping_sub(self.h1);
+        self.h2 = self.h2.wrapping_add(ROLLING_WINDOW as u32 * byte as u32);
+        self.h1 = self.h1.wrapping_add(byte as u32);
+        self.h1 = self.h1.wrapping_sub(self.window[slot] as u32);
+        self.window[slot] = byte;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ byte as u32;
+    }
+
+    fn sum(&self) -> u32 {
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+/// The hash being built for one block size.
+#[derive(Clone)]
+struct BlockHash {
+    /// Hash of the current block
+    h: u32,
+    /// Hash of the current block for the shorter, half length part
+    half_h: u32,
+    digest: [u8; SPAMSUM_LENGTH],
+    /// The last character of the half length part, once it has filled up
+    half_digest: u8,
+    len: usize,
+}
+
+impl BlockHash {
+    fn new() -> Self {
+        Self {
+            h: HASH_INIT,
+            half_h: HASH_INIT,
+            digest: [0; SPAMSUM_LENGTH],
+            half_digest: 0,
+            len: 0,
+        }
+    }
+}
+
+/// An ssdeep hash in progress.
+pub struct Ssdeep {
+    roll: Roll,
+    /// Hashes for each block size tried so far, a larger one is only started once the largest
+    /// has ended its first block
+    blocks: Vec<BlockHash>,
+    /// Hash since the largest block size first ended a block, which stands in for its current
+    /// block at the end as its own is reset whenever a block ends
+    last_h: Option<u32>,
+    total: u64,
+}
+
+impl Ssdeep {
+    pub fn new() -> Self {
+        Self {
+            roll: Roll::default(),
+            blocks: vec![BlockHash::new()],
+            last_h: None,
+            total: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.total += data.len() as u64;
+        for &byte in data {
+            self.step(byte);
+        }
+    }
+
+    fn step(&mut self, byte: u8) {
+        self.roll.update(byte);
+        let sum = self.roll.sum();
+        for block in &mut self.blocks {
+            block.h = sum_hash(byte, block.h);
+            block.half_h = sum_hash(byte, block.half_h);
+        }
+        if let Some(last_h) = &mut self.last_h {
+            *last_h = sum_hash(byte, *last_h);
+        }
+
+        let mut i = 0;
+        while i < self.blocks.len() {
+            let size = block_size(i);
+            // Each larger block size ends blocks at a subset of the places the smaller ones do
+            if sum as u64 % size != size - 1 {
+                break;
+            }
+            if self.blocks[i].len == 0 && i + 1 == self.blocks.len() {
+                if i + 1 < NUM_BLOCKHASHES {
+                    let next = self.blocks[i].clone();
+                    self.blocks.push(BlockHash {
+                        digest: [0; SPAMSUM_LENGTH],
+                        half_digest: 0,
+                        len: 0,
+                        ..next
+                    });
+                } else if self.last_h.is_none() {
+                    self.last_h = Some(self.blocks[i].h);
+                }
+            }
+            let block = &mut self.blocks[i];
+            block.digest[block.len] = BASE64[block.h as usize % 64];
+            block.half_digest = BASE64[block.half_h as usize % 64];
+            // Once full, the last character keeps being replaced by that of the latest block
+            if block.len < SPAMSUM_LENGTH - 1 {
+                block.h = HASH_INIT;
+                block.len += 1;
+                block.digest[block.len] = 0;
+                if block.len < SPAMSUM_LENGTH / 2 {
+                    block.half_h = HASH_INIT;
+                    block.half_digest = 0;
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// The hash, as `blocksize:hash:hash` with the second part at double the block size.
+    pub fn finish(self) -> String {
+        let roll_sum = self.roll.sum();
+        let mut i = 0;
+        while block_size(i) * (SPAMSUM_LENGTH as u64) < self.total && i + 1 < NUM_BLOCKHASHES {
+            i += 1;
+        }
+        i = i.min(self.blocks.len() - 1);
+        // Too few blocks ended at that size to tell files apart, so use a smaller one
+        while i > 0 && self.blocks[i].len < SPAMSUM_LENGTH / 2 {
+            i -= 1;
+        }
+
+        let block = &self.blocks[i];
+        let mut hash = format!("{}:", block_size(i)).into_bytes();
+        hash.extend_from_slice(&block.digest[..block.len]);
+        if roll_sum != 0 {
+            hash.push(BASE64[block.h as usize % 64]);
+        } else if block.digest[block.len] != 0 {
+            hash.push(block.digest[block.len]);
+        }
+        hash.push(b':');
+
+        match self.blocks.get(i + 1) {
+            Some(double) => {
+                let len = double.len.min(SPAMSUM_LENGTH / 2 - 1);
+                hash.extend_from_slice(&double.digest[..len]);
+                if roll_sum != 0 {
+                    hash.push(BASE64[double.half_h as usize % 64]);
+                } else if double.half_digest != 0 {
+                    hash.push(double.half_digest);
+                }
+            }
+            // Only the smallest block size, before it has ended a block, or the largest has
+            // no double
+            None if roll_sum != 0 => {
+                let h = self.last_h.unwrap_or(block.h);
+                hash.push(BASE64[h as usize % 64]);
+            }
+            None => {}
+        }
+        String::from_utf8(hash).expect("made of base64 characters")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ssdeep hash of `data`, fed in pieces of `piece` bytes.
+    fn hash(data: &[u8], piece: usize) -> String {
+        let mut ssdeep = Ssdeep::new();
+        for chunk in data.chunks(piece) {
+            ssdeep.update(chunk);
+        }
+        ssdeep.finish()
+    }
+
+    /// `len` bytes from a xorshift generator, so the inputs are the same wherever they're made.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn published_digests_are_reproduced() {
+        // From the examples of the python bindings to libfuzzy
+        let examples: [(&[u8], &str); 3] = [
+            (
+                b"Also called fuzzy hashes, Ctph can match inputs that have homologies.",
+                "3:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C",
+            ),
+            (
+                b"Also called fuzzy hashes, CTPH can match inputs that have homologies.",
+                "3:AXGBicFlIHBGcL6wCrFQEv:AXGH6xLsr2C",
+            ),
+            (b"", "3::"),
+        ];
+        for (data, expected) in examples {
+            assert_eq!(hash(data, 7), expected);
+        }
+    }
+
+    #[test]
+    fn digests_match_the_reference_algorithm() {
+        // Computed with the original spamsum algorithm over the whole input, which picks the
+        // block size first and hashes again at half the size when too few blocks end
+        let text: String = (0..2000)
+            .map(|i| {
+                format!(
+                    "line {} of a text file which changes a little each line\n",
+                    i
+                )
+            })
+            .collect();
+        let cases: [(Vec<u8>, &str); 7] = [
+            (b"hello world".to_vec(), "3:iKFSMPn:rJPn"),
+            (noise(1000, 1), "24:Za4rfcDSKSkT5W0aLKsPZe5IJl4QOZx1ODJjnuv:ZfcD7SklWMsBzJGQOf1WJjnuv"),
+            (noise(4095, 1), "96:ZclWJlJWfyuVJdz48INkQFkKbYoYAlgLy6OROFG1:Ze2UyOX92LctmmORv"),
+            (
+                noise(12288, 1),
+                "192:Ze2UyOX92LctmmOREFhxq120BNwuQwVu++o2QIwOMTKY3rW2svA1+uLt:Ze2UAihx22QNwuQwX2BMTKijsvA1R",
+            ),
+            (
+                noise(100_000, 1),
+                "1536:yDZur2jwVMCKyYnVJAUZh/fPxtQJh4Gs4CTxxWy02p7f3hwkPqzrrN+mjai3Dzoc:gucQsyYVWqBe4Gs4YS2VR3PQfR3DkSH",
+            ),
+            (
+                noise(1 << 20, 1),
+                "24576:KVjoMIV530+fITgvtnxQ+FHaEpwB4nZTX0BTlJV15H1wVUfSACA:K9S530uDtnxLHaufoPLSA",
+            ),
+            (
+                text.into_bytes(),
+                "768:NG6aNMnmZID9KLQJWX8lSSl4z+JrEfK9joVCP0hu7Awrqdc32pYTBOvU1iDIZmWD:84hwYLu1QPSJEzIUD",
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(hash(&data, 4096), expected, "{} bytes", data.len());
+            assert_eq!(hash(&data, 1000), expected, "{} bytes", data.len());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Finding duplicates in stages with `--staged-duplicates`. Files are grouped by size, then by a
+//! hash of their ends, and only those still sharing a group are hashed in full, which reads far
+//! less of a library where most files are unique.
+
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    path::{Path, PathBuf},
+};
+
+use crate::{hashing, long_path, normalize, open_file, scan_file, ScanState};
+
+/// Bytes read from each end of a file to tell apart files of the same size.
+const SAMPLE_BYTES: u64 = 64 * 1024;
+
+/// Hash the files found by the walk which might be duplicates, adding them to the results.
+pub async fn hash_candidates(state: &ScanState) {
+    let found = match &state.staged {
+        Some(found) => std::mem::take(&mut *found.lock().unwrap()),
+        None => return,
+    };
+    let threads = state.args.thread_count;
+
+    // Files which can't be read at all are left out, as they can't be duplicates
+    let sized = futures::stream::iter(found)
+        .map(|path| async move {
+            let metadata = tokio::fs::metadata(long_path(&path)).await.ok()?;
+            Some((metadata.len(), path))
+        })
+        .buffer_unordered(threads)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+
+    let sampled = futures::stream::iter(shared(sized))
+        .map(|(size, path)| async move {
+            let sample = sample_hash(state, &path).await?;
+            Some(((size, sample), path))
+        })
+        .buffer_unordered(threads)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+    let candidates = shared(sampled);
+
+    let progressbar = ProgressBar::new(candidates.len() as u64);
+    progressbar.set_message("hashing candidates");
+    let scanned: Vec<_> = futures::stream::iter(candidates)
+        .map(|(_, path)| {
+            let progressbar = &progressbar;
+            async move {
+                let scanned = scan_file(state, progressbar, path).await;
+                progressbar.inc(1);
+                scanned
+            }
+        })
+        .buffer_unordered(threads)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+    progressbar.finish_and_clear();
+
+    let mut result = state.result.write().await;
+    for file in scanned {
+        if state.is_in_reference(&file) {
+            state.excluded.lock().unwrap().push(file);
+            continue;
+        }
+        normalize::push_unique(
+            result.entry(file.group_key()).or_default(),
+            file,
+            &state.args.normalize_paths,
+        );
+    }
+}
+
+/// The files sharing a key with at least one other, along with their keys.
+fn shared<K: Eq + Hash + Clone>(files: Vec<(K, PathBuf)>) -> Vec<(K, PathBuf)> {
+    let mut groups: HashMap<K, Vec<PathBuf>> = HashMap::new();
+    for (key, path) in files {
+        groups.entry(key).or_default().push(path);
+    }
+    groups
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .flat_map(|(key, paths)| paths.into_iter().map(move |path| (key.clone(), path)))
+        .collect()
+}
+
+/// A hash of the first and last `SAMPLE_BYTES` of a file and its size, or `None` if it can't be
+/// read or is skipped as binary.
+async fn sample_hash(state: &ScanState, path: &Path) -> Option<String> {
+    let file = open_file(path, state.args.no_atime)
+        .await
+        .ok()?
+        .into_std()
+        .await;
+    let settings = state.hash_settings.clone();
+    let algorithm = settings.algorithm_for(path);
+    tokio::task::spawn_blocking(move || {
+        hashing::hash_sampled(file, algorithm, &settings, SAMPLE_BYTES)
+    })
+    .await
+    .unwrap()
+    .ok()
+    .flatten()
+    .map(hashing::Hashed::hex_digest)
+}
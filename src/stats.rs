@@ -0,0 +1,145 @@
+//! Aggregate statistics about a previous scan, without scanning again.
+
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{is_reserved_key, manifest::Manifest, ScannedFile};
+
+/// Upper bounds of the size buckets, in bytes. Files at least as large as the last bound fall
+/// into a final open-ended bucket.
+const SIZE_BUCKETS: &[(u64, &str)] = &[
+    (1, "empty"),
+    (1 << 10, "< 1 KiB"),
+    (1 << 20, "< 1 MiB"),
+    (1 << 30, "< 1 GiB"),
+];
+const LARGEST_BUCKET: &str = ">= 1 GiB";
+
+/// Totals describing a previous scan.
+#[derive(Debug, Serialize)]
+pub struct ManifestStats {
+    /// Version of the output format the scan was written in
+    schema_version: u32,
+    /// Number of files with a hash
+    files: usize,
+    /// Number of distinct hashes
+    unique_hashes: usize,
+    /// Number of hashes shared by more than one file
+    duplicate_groups: usize,
+    /// The hash shared by the most files, and how many share it
+    largest_duplicate_group: Option<(String, usize)>,
+    /// Number of files with each extension, files without one are counted under ""
+    extensions: BTreeMap<String, usize>,
+    /// Size based totals, only known if the scan recorded every file's attributes
+    sizes: Option<SizeStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct SizeStats {
+    /// Total size of every file
+    total_bytes: u64,
+    /// Total size counting each hash once, i.e. what would remain if duplicates were removed
+    unique_bytes: u64,
+    /// Number of files in each size bucket, smallest first
+    buckets: Vec<(&'static str, usize)>,
+}
+
+impl ManifestStats {
+    pub fn from_manifest(manifest: &Manifest) -> Self {
+        let groups: Vec<(&String, &Vec<ScannedFile>)> = manifest
+            .files
+            .iter()
+            .filter(|(key, files)| !is_reserved_key(key) && !files.is_empty())
+            .collect();
+        let files = groups.iter().flat_map(|(_, files)| files.iter());
+
+        let mut extensions = BTreeMap::new();
+        for file in files.clone() {
+            let extension = file
+                .path
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            *extensions.entry(extension).or_default() += 1;
+        }
+
+        let largest_duplicate_group = groups
+            .iter()
+            .filter(|(_, files)| files.len() > 1)
+            .max_by_key(|(hash, files)| (files.len(), std::cmp::Reverse(*hash)))
+            .map(|(hash, files)| (hash.to_string(), files.len()));
+
+        let has_sizes = files.clone().all(|file| file.attributes.is_some());
+        let sizes = has_sizes.then(|| {
+            let size = |file: &ScannedFile| file.attributes.as_ref().unwrap().size as u64;
+            let mut buckets: Vec<(&'static str, usize)> = SIZE_BUCKETS
+                .iter()
+                .map(|(_, label)| (*label, 0))
+                .chain([(LARGEST_BUCKET, 0)])
+                .collect();
+            for file in files.clone() {
+                let bucket = SIZE_BUCKETS
+                    .iter()
+                    .position(|(bound, _)| size(file) < *bound)
+                    .unwrap_or(SIZE_BUCKETS.len());
+                buckets[bucket].1 += 1;
+            }
+            SizeStats {
+                total_bytes: files.clone().map(size).sum(),
+                unique_bytes: groups.iter().map(|(_, files)| size(&files[0])).sum(),
+                buckets,
+            }
+        });
+
+        Self {
+            schema_version: manifest.schema_version(),
+            files: files.count(),
+            unique_hashes: groups.len(),
+            duplicate_groups: groups.iter().filter(|(_, files)| files.len() > 1).count(),
+            largest_duplicate_group,
+            extensions,
+            sizes,
+        }
+    }
+
+    fn print(&self) {
+        println!("schema version: {}", self.schema_version);
+        println!("files: {}", self.files);
+        println!("unique hashes: {}", self.unique_hashes);
+        println!("duplicate groups: {}", self.duplicate_groups);
+        if let Some((hash, count)) = &self.largest_duplicate_group {
+            println!("largest duplicate group: {} files share {}", count, hash);
+        }
+        match &self.sizes {
+            Some(sizes) => {
+                println!("total size: {}", HumanBytes(sizes.total_bytes));
+                println!("unique size: {}", HumanBytes(sizes.unique_bytes));
+                println!("sizes:");
+                for (bucket, count) in &sizes.buckets {
+                    println!("  {:<10} {}", bucket, count);
+                }
+            }
+            None => println!("sizes: unknown, scan with --load-file-attributes to include them"),
+        }
+        println!("extensions:");
+        for (extension, count) in &self.extensions {
+            let extension = match extension.is_empty() {
+                true => "(none)",
+                false => extension,
+            };
+            println!("  {:<10} {}", extension, count);
+        }
+    }
+}
+
+/// Print statistics about the manifest at `path`, as JSON if requested.
+pub async fn print_stats(path: &Path, json: bool) -> std::io::Result<()> {
+    let manifest = Manifest::load(path).await?;
+    let stats = ManifestStats::from_manifest(&manifest);
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+        false => stats.print(),
+    }
+    Ok(())
+}
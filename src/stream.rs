@@ -0,0 +1,246 @@
+//! Streaming output, where each file is written out as soon as it has been hashed rather than
+//! collected in memory until the end of the scan.
+
+use clap::ArgEnum;
+use flate2::{write::GzEncoder, Compression as Level};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+use crate::{Args, ScannedFile};
+
+/// When buffered output is flushed to disk, in addition to when the buffer fills.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only when the buffer fills, for the best throughput
+    Buffered,
+    /// After every entry
+    Line,
+    /// Whenever this long has passed since the last flush, even if nothing else is written
+    Interval(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "buffered" => Ok(FlushPolicy::Buffered),
+            "line" => Ok(FlushPolicy::Line),
+            _ => match policy.strip_prefix("interval:").map(str::parse::<u64>) {
+                Some(Ok(seconds)) if seconds > 0 => {
+                    Ok(FlushPolicy::Interval(Duration::from_secs(seconds)))
+                }
+                _ => Err(format!(
+                    "`{}` isn't one of buffered, line or interval:SECONDS",
+                    policy
+                )),
+            },
+        }
+    }
+}
+
+/// How streamed output is compressed as it's written.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Gzip, adding `.gz` to each file name
+    Gzip,
+}
+
+/// How often compressed output is flushed without a `--flush-policy`. Each flush ends a block
+/// which can be decompressed, so the output can be read up to there while the scan goes on.
+const COMPRESSED_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Writes one JSON object per line to `{name}.ndjson`, or to `{name}.0001.ndjson`,
+/// `{name}.0002.ndjson` and so on when rotating.
+pub struct NdjsonWriter {
+    sender: Mutex<Option<Sender<ScannedFile>>>,
+    /// Number of files with a hash sent to the writer
+    hashed: AtomicUsize,
+    task: Mutex<Option<JoinHandle<std::io::Result<()>>>>,
+}
+
+impl NdjsonWriter {
+    /// Start the writer task, the first output file is created immediately.
+    pub fn spawn(args: &Args) -> std::io::Result<Self> {
+        let (sender, receiver) = channel::<ScannedFile>();
+        let mut out = Rotation::new(args)?;
+        let policy = match (args.flush_policy, args.compress) {
+            (Some(policy), _) => policy,
+            (None, Some(_)) => FlushPolicy::Interval(COMPRESSED_FLUSH_INTERVAL),
+            (None, None) => FlushPolicy::Buffered,
+        };
+
+        let task = tokio::task::spawn_blocking(move || {
+            let interval = match policy {
+                FlushPolicy::Interval(interval) => interval,
+                _ => {
+                    for file in receiver {
+                        out.write(&file)?;
+                        if policy == FlushPolicy::Line {
+                            out.flush()?;
+                        }
+                    }
+                    return out.finish();
+                }
+            };
+
+            // Waiting is bounded so entries don't sit in the buffer while the scan is quiet
+            loop {
+                match receiver.recv_timeout(interval) {
+                    Ok(file) => out.write(&file)?,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return out.finish(),
+                }
+                if out.last_flush.elapsed() >= interval {
+                    out.flush()?;
+                }
+            }
+        });
+
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            hashed: AtomicUsize::new(0),
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    pub fn write(&self, file: ScannedFile) {
+        if file.hash.is_some() {
+            self.hashed.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(sender) = &*self.sender.lock().unwrap() {
+            // A failed writer is reported by `finish`, the scan carries on regardless
+            let _ = sender.send(file);
+        }
+    }
+
+    pub fn hashed_files(&self) -> usize {
+        self.hashed.load(Ordering::Relaxed)
+    }
+
+    /// Write out everything sent so far and close the output.
+    pub async fn finish(&self) -> std::io::Result<()> {
+        self.sender.lock().unwrap().take();
+        let task = self.task.lock().unwrap().take();
+        match task {
+            Some(task) => task.await.unwrap(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The file currently being written, and when to move on to the next.
+struct Rotation {
+    out: PathBuf,
+    name: String,
+    every: Option<usize>,
+    compress: Option<Compression>,
+    index: usize,
+    written: usize,
+    file: Output,
+    last_flush: Instant,
+}
+
+/// A single output file, compressed or as it is.
+enum Output {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Output {
+    /// Flush the file, first ending the compressed stream so it's complete.
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(mut file) => file.flush(),
+            Output::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(file) => file.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(file) => file.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl Rotation {
+    fn new(args: &Args) -> std::io::Result<Self> {
+        let every = args.rotate_every.map(|n| n.get());
+        let file = Self::create(&args.out, &args.name, every.map(|_| 1), args.compress)?;
+        Ok(Self {
+            out: args.out.clone(),
+            name: args.name.clone(),
+            every,
+            compress: args.compress,
+            index: 1,
+            written: 0,
+            file,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Create `{name}.ndjson`, or the `index`th file when rotating, with `.gz` added when
+    /// compressing.
+    fn create(
+        out: &Path,
+        name: &str,
+        index: Option<usize>,
+        compress: Option<Compression>,
+    ) -> std::io::Result<Output> {
+        let file_name = match index {
+            Some(index) => format!("{}.{:04}.ndjson", name, index),
+            None => format!("{}.ndjson", name),
+        };
+        Ok(match compress {
+            Some(Compression::Gzip) => {
+                let file = File::create(out.join(format!("{}.gz", file_name)))?;
+                Output::Gzip(GzEncoder::new(BufWriter::new(file), Level::default()))
+            }
+            None => Output::Plain(BufWriter::new(File::create(out.join(file_name))?)),
+        })
+    }
+
+    fn write(&mut self, file: &ScannedFile) -> std::io::Result<()> {
+        if self.every == Some(self.written) {
+            self.index += 1;
+            self.written = 0;
+            let next = Self::create(&self.out, &self.name, Some(self.index), self.compress)?;
+            std::mem::replace(&mut self.file, next).finish()?;
+        }
+
+        serde_json::to_writer(&mut self.file, file)?;
+        self.file.write_all(b"\n")?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.last_flush = Instant::now();
+        self.file.flush()
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        self.file.finish()
+    }
+}
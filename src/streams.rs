@@ -0,0 +1,101 @@
+//! Additional data attached to a file alongside its contents, which is otherwise invisible to
+//! the hash: alternate data streams on NTFS and resource forks on macOS. Elsewhere files have no
+//! such streams, and none are found.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::hashing::{self, HashSettings};
+
+/// Hash every additional stream of a file, keyed by the stream's name.
+pub fn hash_streams(
+    path: &Path,
+    settings: &HashSettings,
+) -> std::io::Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    let algorithm = settings.algorithm_for(path);
+    for (name, stream_path) in list_streams(path)? {
+        let stream = std::fs::File::open(&stream_path)?;
+        if let Some(hashed) = hashing::hash_reader(stream, algorithm, settings)? {
+            hashes.insert(name, hashed.hex_digest());
+        }
+    }
+    Ok(hashes)
+}
+
+/// The name of each stream, and a path it can be opened by.
+#[cfg(windows)]
+fn list_streams(path: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    use std::{
+        ffi::OsString,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+    };
+    use windows_sys::Win32::{
+        Foundation::INVALID_HANDLE_VALUE,
+        Storage::FileSystem::{
+            FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+            WIN32_FIND_STREAM_DATA,
+        },
+    };
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    // SAFETY: an all-zero WIN32_FIND_STREAM_DATA is valid, it's plain data
+    let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+    // SAFETY: `wide` is NUL terminated, and `data` is the struct the info level calls for
+    let handle = unsafe {
+        FindFirstStreamW(
+            wide.as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut _ as *mut _,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        // Either there are no streams, or the filesystem doesn't support them (e.g. FAT)
+        return Ok(vec![]);
+    }
+
+    let mut streams = vec![];
+    loop {
+        let len = data
+            .cStreamName
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(data.cStreamName.len());
+        let name = OsString::from_wide(&data.cStreamName[..len]);
+        // The unnamed stream is the file's contents, which are hashed already
+        if name != "::$DATA" {
+            let mut stream_path = path.as_os_str().to_owned();
+            stream_path.push(&name);
+            streams.push((
+                name.to_string_lossy().into_owned(),
+                PathBuf::from(stream_path),
+            ));
+        }
+        // SAFETY: `handle` is a valid find handle, and `data` is as above
+        if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut _) } == 0 {
+            break;
+        }
+    }
+    // SAFETY: `handle` is a valid find handle, and isn't used again
+    unsafe { FindClose(handle) };
+    Ok(streams)
+}
+
+/// The name of each stream, and a path it can be opened by.
+#[cfg(target_os = "macos")]
+fn list_streams(path: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let fork = path.join("..namedfork/rsrc");
+    match std::fs::metadata(&fork) {
+        Ok(metadata) if metadata.len() > 0 => Ok(vec![("rsrc".into(), fork)]),
+        _ => Ok(vec![]),
+    }
+}
+
+/// The name of each stream, and a path it can be opened by.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn list_streams(_path: &Path) -> std::io::Result<Vec<(String, PathBuf)>> {
+    Ok(vec![])
+}
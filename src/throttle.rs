@@ -0,0 +1,61 @@
+//! Limiting the rate at which file contents are read, so a scan doesn't saturate the disks of a
+//! busy machine.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A token bucket shared between all workers, so the limit applies to the scan as a whole.
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    /// The most that can be read in a single burst after being idle
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be read right now, negative when readers are in debt
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        // Allow roughly 100ms of reads in a burst, enough to smooth over scheduling jitter
+        let burst = (bytes_per_sec / 10.0).max(1.0);
+        Self {
+            bytes_per_sec,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Account for `bytes` having been read, blocking the current thread for as long as needed
+    /// to keep the overall rate under the limit. Must only be called from a blocking context.
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst) - bytes as f64;
+            bucket.last_refill = now;
+
+            // Readers go into debt rather than waiting for tokens, so reads larger than the
+            // burst still make progress and later readers queue up behind the debt.
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
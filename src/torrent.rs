@@ -0,0 +1,314 @@
+//! Creating a BitTorrent v2 torrent of the scanned files with `--torrent`, so a scan doubles as
+//! torrent creation. Each file's merkle tree of SHA-256 block hashes is built in the same read as
+//! its own hash.
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{output, spill, ScanState};
+
+/// Size of the blocks at the leaves of each file's merkle tree, fixed by BitTorrent v2.
+const BLOCK_SIZE: usize = 16 * 1024;
+
+type Hash = [u8; 32];
+
+/// Parse `--torrent-piece-length`, which must be a power of two of at least 16K.
+pub fn parse_piece_length(size: &str) -> Result<u64, String> {
+    let bytes = spill::parse_byte_size(size)?;
+    if bytes < BLOCK_SIZE as u64 || !bytes.is_power_of_two() {
+        return Err("the piece length must be a power of two of at least 16K".into());
+    }
+    Ok(bytes)
+}
+
+/// The root of a merkle tree over `leaves`, padded with `pad` to `width` leaves.
+fn merkle_root(mut leaves: Vec<Hash>, width: usize, pad: Hash) -> Hash {
+    leaves.resize(width, pad);
+    while leaves.len() > 1 {
+        leaves = leaves
+            .chunks(2)
+            .map(|pair| {
+                Sha256::new()
+                    .chain_update(pair[0])
+                    .chain_update(pair[1])
+                    .finalize()
+                    .into()
+            })
+            .collect();
+    }
+    leaves[0]
+}
+
+/// A file's hashes as recorded in the torrent.
+pub struct PieceHashes {
+    /// Root of the merkle tree over the file's blocks, there is none for empty files
+    root: Option<Hash>,
+    /// The layer of the tree with one hash per piece, for files larger than a piece
+    layer: Vec<Hash>,
+}
+
+/// Builds a file's merkle tree as its contents are read.
+pub struct PieceHasher {
+    piece_length: u64,
+    block: Sha256,
+    block_filled: usize,
+    /// Hashes of the blocks in the current piece
+    leaves: Vec<Hash>,
+    /// Hashes of each complete piece
+    pieces: Vec<Hash>,
+    total: u64,
+}
+
+impl PieceHasher {
+    pub fn new(piece_length: u64) -> Self {
+        Self {
+            piece_length,
+            block: Sha256::new(),
+            block_filled: 0,
+            leaves: vec![],
+            pieces: vec![],
+            total: 0,
+        }
+    }
+
+    fn blocks_per_piece(&self) -> usize {
+        (self.piece_length / BLOCK_SIZE as u64) as usize
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.block_filled).min(data.len());
+            self.block.update(&data[..take]);
+            self.block_filled += take;
+            self.total += take as u64;
+            data = &data[take..];
+            if self.block_filled == BLOCK_SIZE {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        self.leaves.push(self.block.finalize_reset().into());
+        self.block_filled = 0;
+        if self.leaves.len() == self.blocks_per_piece() {
+            let leaves = std::mem::take(&mut self.leaves);
+            let width = self.blocks_per_piece();
+            self.pieces.push(merkle_root(leaves, width, [0; 32]));
+        }
+    }
+
+    pub fn finish(mut self) -> PieceHashes {
+        if self.block_filled > 0 {
+            self.finish_block();
+        }
+        if self.total == 0 {
+            return PieceHashes {
+                root: None,
+                layer: vec![],
+            };
+        }
+
+        // A file of at most one piece is a tree of just its blocks, without a piece layer
+        if self.total <= self.piece_length {
+            let root = match self.pieces.first() {
+                Some(piece) => *piece,
+                None => {
+                    let width = self.leaves.len().next_power_of_two();
+                    merkle_root(self.leaves, width, [0; 32])
+                }
+            };
+            return PieceHashes {
+                root: Some(root),
+                layer: vec![],
+            };
+        }
+
+        let width = self.blocks_per_piece();
+        if !self.leaves.is_empty() {
+            let leaves = std::mem::take(&mut self.leaves);
+            self.pieces.push(merkle_root(leaves, width, [0; 32]));
+        }
+        // Pieces past the end of the file are made of blocks hashing to zero
+        let pad = merkle_root(vec![], width, [0; 32]);
+        let root = merkle_root(
+            self.pieces.clone(),
+            self.pieces.len().next_power_of_two(),
+            pad,
+        );
+        PieceHashes {
+            root: Some(root),
+            layer: self.pieces,
+        }
+    }
+}
+
+/// The torrent's metainfo file, as laid out by BEP 52.
+#[derive(Serialize)]
+struct MetaInfo {
+    #[serde(rename = "created by")]
+    created_by: String,
+    #[serde(rename = "creation date")]
+    creation_date: u64,
+    info: Info,
+    #[serde(rename = "piece layers")]
+    piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+}
+
+#[derive(Serialize)]
+struct Info {
+    #[serde(rename = "file tree")]
+    file_tree: BTreeMap<String, Node>,
+    #[serde(rename = "meta version")]
+    meta_version: u8,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Node {
+    /// Files are listed under an empty key
+    File {
+        #[serde(rename = "")]
+        file: FileEntry,
+    },
+    Directory(BTreeMap<String, Node>),
+}
+
+#[derive(Serialize)]
+struct FileEntry {
+    length: u64,
+    #[serde(rename = "pieces root", skip_serializing_if = "Option::is_none")]
+    pieces_root: Option<ByteBuf>,
+}
+
+/// The files to include in the torrent, collected as they're hashed.
+pub struct Torrent {
+    piece_length: u64,
+    files: Mutex<Vec<(PathBuf, u64, PieceHashes)>>,
+}
+
+impl Torrent {
+    pub fn new(piece_length: u64) -> Self {
+        Self {
+            piece_length,
+            files: Mutex::new(vec![]),
+        }
+    }
+
+    /// Include the file at `path`, returning its merkle root as a lowercase hex string.
+    pub fn add(&self, path: &Path, length: u64, hashes: PieceHashes) -> Option<String> {
+        let root = hashes
+            .root
+            .map(|root| root.iter().map(|b| format!("{:02x}", b)).collect());
+        self.files
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), length, hashes));
+        root
+    }
+
+    /// Write `{name}.torrent`. With a single start path the torrent is named after it,
+    /// otherwise after the scan, and each start path is listed within it under a name of its
+    /// own, as given by `output::root_labels`.
+    pub async fn write(&self, state: &ScanState) -> std::io::Result<()> {
+        let args = &state.args;
+        let single_root = match state.roots.as_slice() {
+            [root] => Some(&root.path),
+            _ => None,
+        };
+        let name = single_root
+            .and_then(|root| root.file_name())
+            .map_or(args.name.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        let labels = output::root_labels(&state.roots);
+
+        let mut file_tree = BTreeMap::new();
+        let mut piece_layers = BTreeMap::new();
+        for (path, length, hashes) in std::mem::take(&mut *self.files.lock().unwrap()) {
+            let root = match state
+                .roots
+                .iter()
+                .position(|root| path.starts_with(&root.path))
+            {
+                Some(root) => root,
+                None => continue,
+            };
+            let mut components: Vec<String> = match single_root {
+                Some(_) => vec![],
+                None => vec![labels[root].clone()],
+            };
+            components.extend(
+                path.strip_prefix(&state.roots[root].path)
+                    .expect("found within the root")
+                    .iter()
+                    .map(|component| component.to_string_lossy().into_owned()),
+            );
+            // A single start path which is a file is the whole torrent, listed under its name
+            let file_name = components.pop().unwrap_or_else(|| name.clone());
+
+            let mut dir = &mut file_tree;
+            for component in components {
+                let node = dir
+                    .entry(component)
+                    .or_insert_with(|| Node::Directory(BTreeMap::new()));
+                dir = match node {
+                    Node::Directory(entries) => entries,
+                    Node::File { .. } => return Err(listed_twice(&path)),
+                };
+            }
+            if dir.contains_key(&file_name) {
+                return Err(listed_twice(&path));
+            }
+            if let (Some(root), false) = (hashes.root, hashes.layer.is_empty()) {
+                piece_layers.insert(
+                    ByteBuf::from(root.to_vec()),
+                    ByteBuf::from(hashes.layer.concat()),
+                );
+            }
+            dir.insert(
+                file_name,
+                Node::File {
+                    file: FileEntry {
+                        length,
+                        pieces_root: hashes.root.map(|root| ByteBuf::from(root.to_vec())),
+                    },
+                },
+            );
+        }
+
+        let metainfo = MetaInfo {
+            created_by: format!("dexy {}", env!("CARGO_PKG_VERSION")),
+            creation_date: state.header.timestamp,
+            info: Info {
+                file_tree,
+                meta_version: 2,
+                name,
+                piece_length: self.piece_length,
+            },
+            piece_layers,
+        };
+        let encoded = serde_bencode::to_bytes(&metainfo).map_err(std::io::Error::other)?;
+        output::write_atomic(args, "torrent", encoded).await
+    }
+}
+
+/// The error for a file whose place in the torrent is already taken, by a file or directory.
+fn listed_twice(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!(
+            "{} can't be listed, its place in the torrent is already taken",
+            path.to_string_lossy()
+        ),
+    )
+}
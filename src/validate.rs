@@ -0,0 +1,136 @@
+//! Checking that a previous scan is internally consistent, without touching the files it lists.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    hashing::HashAlgorithm, manifest::Manifest, ScannedFile, DIRECTORIES_KEY, EMPTY_KEY,
+    UNREADABLE_KEY,
+};
+
+/// Whether `hash` is a digest as `algorithm` writes them, lowercase hex of its length.
+fn is_well_formed(hash: &str, algorithm: HashAlgorithm) -> bool {
+    hash.len() == algorithm.hasher().finalize().len()
+        && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Problems with a single entry, listed under `key` in the manifest.
+fn check_file(key: &str, file: &ScannedFile, algorithm: HashAlgorithm, problems: &mut Vec<String>) {
+    let path = file.path.to_string_lossy();
+    let algorithm = file.algorithm.unwrap_or(algorithm);
+    match (key, &file.hash) {
+        (UNREADABLE_KEY, None) if file.error.is_some() => {}
+        (UNREADABLE_KEY, _) => problems.push(format!("unreadable entry with a hash: {}", path)),
+        (DIRECTORIES_KEY, None) if file.attributes.is_some() => {}
+        (DIRECTORIES_KEY, _) => problems.push(format!(
+            "directory entry with a hash or without attributes: {}",
+            path
+        )),
+        (_, None) => problems.push(format!("missing hash: {}", path)),
+        (_, Some(hash)) if !is_well_formed(hash, algorithm) => problems.push(format!(
+            "malformed {} hash {:?}: {}",
+            algorithm.name(),
+            hash,
+            path
+        )),
+        (EMPTY_KEY, Some(_)) => {}
+        (key, Some(hash)) if key != hash => {
+            problems.push(format!("listed under another hash {}: {}", key, path))
+        }
+        _ => {}
+    }
+
+    let mut chunk_hashes = file.chunk_hashes.iter().flatten();
+    if chunk_hashes.any(|hash| !is_well_formed(hash, algorithm)) {
+        problems.push(format!("malformed chunk hash: {}", path));
+    }
+    let mut cdc_chunks = file.cdc_chunks.iter().flatten();
+    if cdc_chunks.any(|chunk| !is_well_formed(&chunk.hash, algorithm)) {
+        problems.push(format!("malformed content defined chunk hash: {}", path));
+    }
+    if let Some(secondary) = &file.secondary {
+        if !is_well_formed(&secondary.hash, secondary.algorithm) {
+            problems.push(format!("malformed secondary hash: {}", path));
+        }
+    }
+    for (algorithm, hash) in &file.digests {
+        if !is_well_formed(hash, *algorithm) {
+            problems.push(format!("malformed {} digest: {}", algorithm.name(), path));
+        }
+    }
+}
+
+/// Every structural problem with a manifest, in the order they were found.
+fn problems(manifest: &Manifest) -> Vec<String> {
+    let mut problems = vec![];
+    let algorithm = manifest
+        .header
+        .as_ref()
+        .map(|header| header.algorithm)
+        .unwrap_or_default();
+
+    let start_directories: &[PathBuf] = match &manifest.header {
+        Some(header) if header.start_directories.is_empty() => {
+            problems.push("the header lists no start directories".into());
+            &[]
+        }
+        Some(header) => &header.start_directories,
+        None => &[],
+    };
+
+    let mut paths: HashMap<&Path, usize> = HashMap::new();
+    let mut keys: Vec<&String> = manifest.files.keys().collect();
+    keys.sort();
+    for key in keys {
+        for file in &manifest.files[key] {
+            check_file(key, file, algorithm, &mut problems);
+            *paths.entry(&file.path).or_default() += 1;
+
+            // Relative paths were deliberately made so with --emit-relative-to
+            let outside = file.path.is_absolute()
+                && !start_directories.is_empty()
+                && !start_directories
+                    .iter()
+                    .any(|dir| file.path.starts_with(dir));
+            if outside {
+                problems.push(format!(
+                    "outside the start directories: {}",
+                    file.path.to_string_lossy()
+                ));
+            }
+        }
+    }
+
+    let mut duplicated: Vec<(&Path, usize)> =
+        paths.into_iter().filter(|(_, count)| *count > 1).collect();
+    duplicated.sort();
+    for (path, count) in duplicated {
+        problems.push(format!(
+            "listed {} times: {}",
+            count,
+            path.to_string_lossy()
+        ));
+    }
+    problems
+}
+
+/// Check a manifest's structure, printing any problems found. Returns whether it had none.
+pub async fn validate_manifest(path: &Path) -> bool {
+    let manifest = match Manifest::load(path).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("unreadable: {} {}", path.to_string_lossy(), e);
+            return false;
+        }
+    };
+
+    let problems = problems(&manifest);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let files: usize = manifest.files.values().map(Vec::len).sum();
+    println!("{} problems found in {} entries", problems.len(), files);
+    problems.is_empty()
+}
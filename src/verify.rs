@@ -0,0 +1,256 @@
+//! Checking files against the hashes recorded by a previous scan.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    archive,
+    hashing::{self, HashAlgorithm, HashSettings, Hashed, SecondaryHash},
+    is_reserved_key,
+    manifest::Manifest,
+    path_bytes, ScannedFile,
+};
+
+/// The state of a file compared to when it was scanned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The file's contents still have the recorded hash
+    Matches,
+    /// The file's contents have changed
+    Changed,
+    /// Only one of the file's primary and secondary hashes still matches, which points to
+    /// corruption while hashing or a collision rather than an ordinary change
+    Inconsistent,
+    /// There is no longer a file at the recorded path
+    Missing,
+}
+
+impl ScannedFile {
+    /// Hash the file at the recorded path again, and compare it to the recorded hash. Files
+    /// without an algorithm of their own are hashed with `algorithm`, the one used by the scan
+    /// as a whole, keyed with `key` if the scan was. Entries without a hash, such as unreadable
+    /// files, are never considered to match.
+    pub fn verify(
+        &self,
+        algorithm: HashAlgorithm,
+        key: Option<Arc<[u8]>>,
+    ) -> std::io::Result<Verification> {
+        if self.link_target.is_some() {
+            return self.verify_link(algorithm, key);
+        }
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Verification::Missing),
+            Err(e) => return Err(e),
+        };
+        let algorithm = self.algorithm.unwrap_or(algorithm);
+        let settings = self.settings(key);
+        let hashed = match (self.sampled, self.tree) {
+            (Some(sample), _) => hashing::hash_sampled(file, algorithm, &settings, sample)?,
+            (None, Some(block_size)) => {
+                Some(hashing::hash_tree(file, algorithm, &settings, block_size)?)
+            }
+            (None, None) => hashing::hash_reader(file, algorithm, &settings)?,
+        }
+        .expect("every file is hashed without --text-only");
+        Ok(self.compare(hashed, &self.path))
+    }
+
+    /// Hash the contents of this archive member again, read from its archive, and compare it to
+    /// the recorded hash. `member` is its path within the archive.
+    pub fn verify_member(
+        &self,
+        member: &Path,
+        reader: &mut dyn Read,
+        algorithm: HashAlgorithm,
+        key: Option<Arc<[u8]>>,
+    ) -> std::io::Result<Verification> {
+        let algorithm = self.algorithm.unwrap_or(algorithm);
+        let hashed = hashing::hash_reader(reader, algorithm, &self.settings(key))?
+            .expect("every file is hashed without --text-only");
+        Ok(self.compare(hashed, member))
+    }
+
+    /// Hash the path a symlink scanned with `--symlink-hash` points to again, and compare it to
+    /// the recorded hash.
+    fn verify_link(
+        &self,
+        algorithm: HashAlgorithm,
+        key: Option<Arc<[u8]>>,
+    ) -> std::io::Result<Verification> {
+        let target = match std::fs::read_link(&self.path) {
+            Ok(target) => target,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Verification::Missing),
+            // No longer a link
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                return Ok(Verification::Changed)
+            }
+            Err(e) => return Err(e),
+        };
+        let target = match (self.link_target_resolved, self.path.parent()) {
+            (true, Some(parent)) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+        let algorithm = self.algorithm.unwrap_or(algorithm);
+        let mut hasher = self.settings(key).hasher(algorithm);
+        hasher.update(&path_bytes(&target));
+        if self.filename_hashed {
+            hasher.update_filename(&self.path);
+        }
+        let matches = self.hash.as_deref() == Some(&hasher.finalize());
+        Ok(match matches {
+            true => Verification::Matches,
+            false => Verification::Changed,
+        })
+    }
+
+    /// Settings to hash the contents again the way they were when scanned.
+    fn settings(&self, key: Option<Arc<[u8]>>) -> HashSettings {
+        HashSettings {
+            also: self
+                .secondary
+                .iter()
+                .map(|secondary| secondary.algorithm)
+                .collect(),
+            key,
+            // The file was already found to be text, so no need to look again
+            normalize_line_endings: self.line_endings_normalized,
+            text_sample: 0,
+            ..HashSettings::default()
+        }
+    }
+
+    /// Compare freshly hashed contents to the recorded hashes. `filename` is the name that was
+    /// hashed with the contents, for `--hash-filenames`.
+    fn compare(&self, mut hashed: Hashed, filename: &Path) -> Verification {
+        if self.filename_hashed {
+            hashed.update_filename(filename);
+        }
+
+        let found = SecondaryHash::find(
+            self.secondary.as_ref().map(|s| s.algorithm),
+            &hashed.take_also(),
+        );
+        let secondary_matches = match (&self.secondary, found) {
+            (Some(recorded), Some(found)) => Some(*recorded == found),
+            _ => None,
+        };
+        let matches = self.hash.as_deref() == Some(&hashed.hex_digest());
+        match (matches, secondary_matches) {
+            (true, None | Some(true)) => Verification::Matches,
+            (false, None | Some(false)) => Verification::Changed,
+            (true, Some(false)) | (false, Some(true)) => Verification::Inconsistent,
+        }
+    }
+}
+
+/// Check the members of one archive listed in a manifest, reading through the archive once.
+/// Members no longer in the archive are reported as missing.
+fn verify_archive(
+    archive: &Path,
+    mut members: HashMap<PathBuf, ScannedFile>,
+    algorithm: HashAlgorithm,
+    key: Option<Arc<[u8]>>,
+) -> Vec<(PathBuf, std::io::Result<Verification>)> {
+    let mut results = vec![];
+    let read = archive::for_each_member(archive, |member, reader| {
+        if let Some(file) = members.remove(&member) {
+            let result = file.verify_member(&member, reader, algorithm, key.clone());
+            results.push((file.path, result));
+        }
+        Ok(())
+    });
+    match read {
+        Ok(()) => results.extend(
+            members
+                .into_values()
+                .map(|file| (file.path, Ok(Verification::Missing))),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => results.extend(
+            members
+                .into_values()
+                .map(|file| (file.path, Ok(Verification::Missing))),
+        ),
+        Err(e) => results.extend(members.into_values().map(|file| {
+            let error = std::io::Error::new(e.kind(), e.to_string());
+            (file.path, Err(error))
+        })),
+    }
+    results
+}
+
+/// Check every file in a manifest, printing those which no longer match. Returns whether every
+/// file matched, which they can't if the manifest itself can't be read. `key` is needed to check
+/// scans run with `--hmac-key-file`.
+pub async fn verify_manifest(path: PathBuf, key: Option<Arc<[u8]>>) -> bool {
+    let manifest = match Manifest::load(&path).await {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("unreadable: {} {}", path.to_string_lossy(), e);
+            return false;
+        }
+    };
+    let keyed = manifest.header.as_ref().is_some_and(|header| header.keyed);
+    if keyed && key.is_none() {
+        println!(
+            "{} was hashed with a key, pass it with --hmac-key-file",
+            path.to_string_lossy()
+        );
+        return false;
+    }
+    // Only the key the scan was hashed with can reproduce its hashes
+    let key = key.filter(|_| keyed);
+    // Scans without a header predate the choice of algorithm
+    let algorithm = manifest
+        .header
+        .map(|header| header.algorithm)
+        .unwrap_or_default();
+    let files: Vec<ScannedFile> = manifest
+        .files
+        .into_iter()
+        .filter(|(key, _)| !is_reserved_key(key))
+        .flat_map(|(_, files)| files)
+        .collect();
+
+    let results = tokio::task::spawn_blocking(move || {
+        // Members of archives scanned with --scan-archives are checked a whole archive at a time
+        let mut archives: BTreeMap<PathBuf, HashMap<PathBuf, ScannedFile>> = BTreeMap::new();
+        let mut results = vec![];
+        for file in files {
+            match archive::split_member(&file.path) {
+                Some((archive, member)) => {
+                    archives.entry(archive).or_default().insert(member, file);
+                }
+                None => {
+                    let result = file.verify(algorithm, key.clone());
+                    results.push((file.path, result));
+                }
+            }
+        }
+        for (archive, members) in archives {
+            results.extend(verify_archive(&archive, members, algorithm, key.clone()));
+        }
+        results
+    })
+    .await
+    .unwrap();
+
+    let mut matched = 0;
+    for (path, result) in &results {
+        match result {
+            Ok(Verification::Matches) => matched += 1,
+            Ok(Verification::Changed) => println!("changed: {}", path.to_string_lossy()),
+            Ok(Verification::Inconsistent) => {
+                println!("inconsistent: {}", path.to_string_lossy())
+            }
+            Ok(Verification::Missing) => println!("missing: {}", path.to_string_lossy()),
+            Err(e) => println!("error: {} {}", path.to_string_lossy(), e),
+        }
+    }
+    println!("{} of {} files match", matched, results.len());
+    matched == results.len()
+}
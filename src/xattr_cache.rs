@@ -0,0 +1,104 @@
+//! Hashes kept with the files themselves in extended attributes, for `--xattr-cache`, so
+//! re-scanning a tree which has mostly not changed only reads the files which have. Unlike
+//! `--hash-cache` the hashes move with the files, whichever output the scan is written to.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use crate::hashing::HashAlgorithm;
+
+/// What is stored in each attribute, as JSON.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+    /// Both affect the hash, and depend on the file's size and the options given for the scan
+    sampled: Option<u64>,
+    tree: Option<u64>,
+}
+
+/// A hash read back from a file's attribute.
+pub struct Cached {
+    pub hash: String,
+    pub sampled: Option<u64>,
+    pub tree: Option<u64>,
+}
+
+/// Name of the attribute holding a file's hash with `algorithm`, so hashes with different
+/// algorithms are kept side by side.
+fn name(algorithm: HashAlgorithm) -> String {
+    format!("user.dexy.{}", algorithm.name())
+}
+
+#[derive(Default)]
+pub struct XattrCache {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl XattrCache {
+    /// The hash stored on the file at `path`, if the file hasn't changed since and it was hashed
+    /// the way this scan would hash it.
+    pub fn get(
+        &self,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        algorithm: HashAlgorithm,
+        sampled: Option<u64>,
+        tree: Option<u64>,
+    ) -> Option<Cached> {
+        let entry = xattr::get(path, name(algorithm))
+            .ok()
+            .flatten()
+            .and_then(|value| serde_json::from_slice::<Entry>(&value).ok())
+            .filter(|entry| {
+                entry.size == metadata.len()
+                    && metadata
+                        .modified()
+                        .is_ok_and(|modified| modified == entry.modified)
+                    && entry.sampled == sampled
+                    && entry.tree == tree
+            });
+        let counter = match entry {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        entry.map(|entry| Cached {
+            hash: entry.hash,
+            sampled: entry.sampled,
+            tree: entry.tree,
+        })
+    }
+
+    /// Store the hash of the file at `path`, as of the given metadata. Files on filesystems
+    /// without extended attributes, or which can't be written to, are just hashed again next
+    /// time.
+    pub fn insert(
+        &self,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        algorithm: HashAlgorithm,
+        cached: Cached,
+    ) {
+        let modified = match metadata.modified() {
+            Ok(modified) => modified,
+            Err(_) => return,
+        };
+        let entry = Entry {
+            size: metadata.len(),
+            modified,
+            hash: cached.hash,
+            sampled: cached.sampled,
+            tree: cached.tree,
+        };
+        if let Ok(value) = serde_json::to_vec(&entry) {
+            let _ = xattr::set(path, name(algorithm), &value);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn tar_members_are_hashed_with_archive_paths() {
+    for (name, gzip) in [("bundle.tar", false), ("bundle.tar.gz", true)] {
+        let root = tree(&[("loose", b"loose")]);
+        write_tar(
+            &root.path().join(name),
+            &[("docs/readme.md", b"readme"), ("data.csv", b"1,2,3")],
+            gzip,
+        );
+
+        let scan = scan_ok(&["--scan-archives"], &[root.path()]);
+        let archive = recorded(root.path(), name);
+        let hashes = scan.hashes();
+        let member = |path: &str| {
+            let path = format!("{}!{}", archive, path);
+            hashes
+                .iter()
+                .find(|(recorded, _)| *recorded == path)
+                .unwrap_or_else(|| panic!("{} wasn't recorded", path))
+                .1
+                .clone()
+        };
+        assert_eq!(member("docs/readme.md"), sha256(b"readme"));
+        assert_eq!(member("data.csv"), sha256(b"1,2,3"));
+        assert_eq!(scan.hash_of(root.path(), "loose"), sha256(b"loose"));
+        // The archive itself is still hashed as a file
+        assert_eq!(hashes.len(), 4);
+    }
+}
+
+#[test]
+fn archives_are_only_opened_when_asked() {
+    let root = tree(&[]);
+    write_tar(
+        &root.path().join("bundle.tar"),
+        &[("member", b"member")],
+        false,
+    );
+    let scan = scan_ok(&[], &[root.path()]);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "bundle.tar")]);
+}
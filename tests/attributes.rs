@@ -0,0 +1,69 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use filetime::FileTime;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn no_atime_leaves_access_times_alone() {
+    let root = tree(&[("file", b"contents")]);
+    let path = root.path().join("file");
+    // Old enough that relatime would update it on the next read
+    let now = SystemTime::now();
+    let accessed = FileTime::from_system_time(now - Duration::from_secs(2 * 86400));
+    let modified = FileTime::from_system_time(now - Duration::from_secs(3 * 86400));
+    filetime::set_file_times(&path, accessed, modified).unwrap();
+
+    let scan = scan_ok(&["--no-atime"], &[root.path()]);
+    assert_eq!(scan.hashes()[0].1, sha256(b"contents"));
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(FileTime::from_last_access_time(&metadata), accessed);
+}
+
+#[test]
+fn attributes_record_the_inode_and_device() {
+    use std::os::unix::fs::MetadataExt;
+    let root = tree(&[("file", b"contents")]);
+    let metadata = std::fs::metadata(root.path().join("file")).unwrap();
+
+    let scan = scan_ok(&["--load-file-attributes"], &[root.path()]);
+    let attributes = &scan.entries()[0]["attributes"];
+    assert_eq!(attributes["inode"], metadata.ino());
+    assert_eq!(attributes["device"], metadata.dev());
+}
+
+#[test]
+fn renamed_files_keep_their_inode_across_scans() {
+    let root = tree(&[("before", b"contents")]);
+    let first = scan_ok(&["--load-file-attributes"], &[root.path()]);
+    std::fs::rename(root.path().join("before"), root.path().join("after")).unwrap();
+    let second = scan_ok(&["--load-file-attributes"], &[root.path()]);
+
+    let (first, second) = (&first.entries()[0], &second.entries()[0]);
+    assert_ne!(first["path"], second["path"]);
+    assert_eq!(first["attributes"]["inode"], second["attributes"]["inode"]);
+}
+
+#[test]
+fn sparse_files_take_less_space_on_disk_than_their_size() {
+    let root = tree(&[("dense", &[1; 8192])]);
+    let sparse = std::fs::File::create(root.path().join("sparse")).unwrap();
+    sparse.set_len(16 * 1024 * 1024).unwrap();
+
+    let scan = scan_ok(&["--load-file-attributes"], &[root.path()]);
+    let attributes = |path: &str| {
+        scan.entries()
+            .into_iter()
+            .find(|entry| path_of(entry) == recorded(root.path(), path))
+            .unwrap()["attributes"]
+            .clone()
+    };
+    let sparse = attributes("sparse");
+    assert_eq!(sparse["size"], 16 * 1024 * 1024);
+    assert!(sparse["size_on_disk"].as_u64().unwrap() < 16 * 1024 * 1024);
+    let dense = attributes("dense");
+    assert_eq!(dense["size"], 8192);
+    assert!(dense["size_on_disk"].as_u64().unwrap() >= 8192);
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use common::*;
+use std::path::Path;
+
+/// Scan `root` with `--hash-cache`, writing to `out` so the cache is kept between scans.
+fn cached_scan(root: &Path, out: &Path) -> (Vec<(String, String)>, serde_json::Value) {
+    let events_dir = tempdir();
+    let events = events_dir.path().join("events.ndjson");
+    let output = dexy()
+        .arg("--hash-cache")
+        .arg("--events-file")
+        .arg(&events)
+        .arg("--out")
+        .arg(out)
+        .arg(root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let done = read_events(&events).pop().unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(out.join("dexy.json")).unwrap()).unwrap();
+    let hashes = entries(manifest["files"].as_object().unwrap())
+        .iter()
+        .map(|entry| (path_of(entry), entry["hash"].as_str().unwrap().to_string()))
+        .collect();
+    (hashes, done["summary"].clone())
+}
+
+#[test]
+fn a_second_scan_is_all_cache_hits() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b"), ("sub/c", b"c")]);
+    let out = tempdir();
+
+    let (first, summary) = cached_scan(root.path(), out.path());
+    assert_eq!(summary["cache_hits"], 0);
+    assert_eq!(summary["cache_misses"], 3);
+    assert!(out.path().join("dexy.cache.json").exists());
+
+    let (second, summary) = cached_scan(root.path(), out.path());
+    assert_eq!(summary["cache_hits"], 3);
+    assert_eq!(summary["cache_misses"], 0);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn changed_files_are_hashed_again() {
+    let root = tree(&[("same", b"same"), ("changed", b"before")]);
+    let out = tempdir();
+    cached_scan(root.path(), out.path());
+
+    write(root.path(), "changed", b"after!");
+    let (hashes, summary) = cached_scan(root.path(), out.path());
+    assert_eq!(summary["cache_hits"], 1);
+    assert_eq!(summary["cache_misses"], 1);
+    let changed = hashes.iter().find(|(path, _)| path.ends_with("changed"));
+    assert_eq!(changed.unwrap().1, sha256(b"after!"));
+}
+
+#[test]
+fn hashes_from_another_algorithm_are_not_reused() {
+    let root = tree(&[("a", b"a")]);
+    let out = tempdir();
+    cached_scan(root.path(), out.path());
+
+    let scan = dexy()
+        .args(["--hash-cache", "--algorithm", "blake3", "--out"])
+        .arg(out.path())
+        .arg(root.path())
+        .output()
+        .unwrap();
+    assert!(scan.status.success());
+    let scan = Scan { out, output: scan };
+    assert_ne!(scan.hash_of(root.path(), "a"), sha256(b"a"));
+}
+
+#[test]
+fn the_cache_is_not_scanned_as_a_file() {
+    let root = tree(&[("a", b"a")]);
+    let output = dexy()
+        .arg("--hash-cache")
+        .arg("--out")
+        .arg(root.path())
+        .arg(root.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(root.path().join("dexy.cache.json").exists());
+
+    let (hashes, summary) = cached_scan(root.path(), root.path());
+    assert_eq!(hashes.len(), 1);
+    assert_eq!(summary["cache_hits"], 1);
+}
+
+/// Whether files in `dir` can have user extended attributes, which some filesystems refuse.
+#[cfg(unix)]
+fn supports_xattrs(dir: &Path) -> bool {
+    let probe = write(dir, ".xattr-probe", b"");
+    let supported = xattr::set(&probe, "user.dexy.probe", b"1").is_ok();
+    std::fs::remove_file(probe).unwrap();
+    if !supported {
+        eprintln!(
+            "skipping, {} has no user extended attributes",
+            dir.display()
+        );
+    }
+    supported
+}
+
+/// The hash stored in the `--xattr-cache` attribute of the file at `path`.
+#[cfg(unix)]
+fn stored_hash(path: &Path) -> Option<String> {
+    let value = xattr::get(path, "user.dexy.sha256").unwrap()?;
+    let entry: serde_json::Value = serde_json::from_slice(&value).unwrap();
+    Some(entry["hash"].as_str().unwrap().to_string())
+}
+
+/// Change the hash stored on the file at `path`, leaving everything else as it was.
+#[cfg(unix)]
+fn replace_stored_hash(path: &Path, hash: &str) {
+    let value = xattr::get(path, "user.dexy.sha256").unwrap().unwrap();
+    let mut entry: serde_json::Value = serde_json::from_slice(&value).unwrap();
+    entry["hash"] = hash.into();
+    xattr::set(path, "user.dexy.sha256", entry.to_string().as_bytes()).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn hashes_are_kept_in_extended_attributes_and_reused() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b")]);
+    if !supports_xattrs(root.path()) {
+        return;
+    }
+
+    let (first, summary) = scan_summary(&["--xattr-cache"], &[root.path()]);
+    assert_eq!(summary["xattr_cache_hits"], 0);
+    assert_eq!(summary["xattr_cache_misses"], 2);
+    assert_eq!(stored_hash(&root.path().join("a")), Some(sha256(b"a")));
+    assert_eq!(stored_hash(&root.path().join("sub/b")), Some(sha256(b"b")));
+
+    // A stored hash is used as it is, without reading the file
+    replace_stored_hash(&root.path().join("a"), "stored");
+    let (second, summary) = scan_summary(&["--xattr-cache"], &[root.path()]);
+    assert_eq!(summary["xattr_cache_hits"], 2);
+    assert_eq!(summary["xattr_cache_misses"], 0);
+    assert_eq!(second.hash_of(root.path(), "a"), "stored");
+    assert_eq!(
+        second.hash_of(root.path(), "sub/b"),
+        first.hash_of(root.path(), "sub/b")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn extended_attributes_are_ignored_once_the_file_changes() {
+    use filetime::FileTime;
+    let root = tree(&[("resized", b"before"), ("touched", b"before")]);
+    if !supports_xattrs(root.path()) {
+        return;
+    }
+    scan_ok(&["--xattr-cache"], &[root.path()]);
+
+    // One changes size but keeps its modification time, the other the other way round
+    let resized = root.path().join("resized");
+    let modified = FileTime::from_last_modification_time(&std::fs::metadata(&resized).unwrap());
+    write(root.path(), "resized", b"longer after");
+    filetime::set_file_mtime(&resized, modified).unwrap();
+    let touched = root.path().join("touched");
+    write(root.path(), "touched", b"after!");
+    filetime::set_file_mtime(&touched, FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+
+    let (scan, summary) = scan_summary(&["--xattr-cache"], &[root.path()]);
+    assert_eq!(summary["xattr_cache_hits"], 0);
+    assert_eq!(summary["xattr_cache_misses"], 2);
+    assert_eq!(
+        scan.hash_of(root.path(), "resized"),
+        sha256(b"longer after")
+    );
+    assert_eq!(scan.hash_of(root.path(), "touched"), sha256(b"after!"));
+    // The new hashes replace the old
+    assert_eq!(stored_hash(&resized), Some(sha256(b"longer after")));
+    assert_eq!(stored_hash(&touched), Some(sha256(b"after!")));
+}
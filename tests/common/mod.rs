@@ -0,0 +1,212 @@
+//! Helpers shared by the integration tests, which run the dexy binary over trees built in
+//! temporary directories.
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+use tempfile::TempDir;
+
+/// A command running the dexy binary under test.
+pub fn dexy() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_dexy"))
+}
+
+/// A new temporary directory. The default names start with a dot, which dexy would skip as
+/// hidden.
+pub fn tempdir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("dexy-test")
+        .tempdir()
+        .unwrap()
+}
+
+/// Build a tree of files from `(relative path, contents)` pairs, creating directories as needed.
+pub fn tree(files: &[(&str, &[u8])]) -> TempDir {
+    let root = tempdir();
+    for (path, contents) in files {
+        write(root.path(), path, contents);
+    }
+    root
+}
+
+/// Write a file below `root`, creating its parent directories.
+pub fn write(root: &Path, path: &str, contents: &[u8]) -> PathBuf {
+    let path = root.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// The output of a scan, written to a directory of its own.
+pub struct Scan {
+    pub out: TempDir,
+    pub output: Output,
+}
+
+impl Scan {
+    /// Whether dexy exited successfully.
+    pub fn success(&self) -> bool {
+        self.output.status.success()
+    }
+
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.output.stderr).into_owned()
+    }
+
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.output.stdout).into_owned()
+    }
+
+    /// Path of a file in the output directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.out.path().join(name)
+    }
+
+    /// Parse a JSON file in the output directory.
+    pub fn json(&self, name: &str) -> Value {
+        let data = std::fs::read(self.path(name))
+            .unwrap_or_else(|e| panic!("{} wasn't written: {}\n{}", name, e, self.stderr()));
+        serde_json::from_slice(&data).unwrap()
+    }
+
+    /// The `files` map of `dexy.json`.
+    pub fn files(&self) -> serde_json::Map<String, Value> {
+        match self.json("dexy.json")["files"].take() {
+            Value::Object(files) => files,
+            other => panic!("files isn't a map: {}", other),
+        }
+    }
+
+    /// Every entry in `dexy.json`, sorted by path.
+    pub fn entries(&self) -> Vec<Value> {
+        entries(&self.files())
+    }
+
+    /// The hash recorded for every file, by path.
+    pub fn hashes(&self) -> Vec<(String, String)> {
+        self.entries()
+            .iter()
+            .filter_map(|entry| Some((path_of(entry), entry["hash"].as_str()?.to_string())))
+            .collect()
+    }
+
+    /// The hash recorded for the file at `path` below `root`.
+    pub fn hash_of(&self, root: &Path, path: &str) -> String {
+        let path = recorded(root, path);
+        self.hashes()
+            .into_iter()
+            .find(|(recorded, _)| *recorded == path)
+            .unwrap_or_else(|| panic!("{} wasn't recorded", path))
+            .1
+    }
+
+    /// Paths of every entry in `dexy.json`, sorted.
+    pub fn paths(&self) -> Vec<String> {
+        self.entries().iter().map(path_of).collect()
+    }
+}
+
+/// Every entry in a `files` map, sorted by path.
+pub fn entries(files: &serde_json::Map<String, Value>) -> Vec<Value> {
+    let mut entries: Vec<Value> = files
+        .values()
+        .flat_map(|group| group.as_array().unwrap().clone())
+        .collect();
+    entries.sort_by_key(path_of);
+    entries
+}
+
+/// The path of an entry.
+pub fn path_of(entry: &Value) -> String {
+    entry["path"].as_str().unwrap().to_string()
+}
+
+/// The canonical path of `path` below `root`, as dexy records it.
+pub fn recorded(root: &Path, path: &str) -> String {
+    root.canonicalize()
+        .unwrap()
+        .join(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Scan `roots` with `args`, writing the output to a new temporary directory.
+pub fn scan(args: &[&str], roots: &[&Path]) -> Scan {
+    let out = tempdir();
+    let output = dexy()
+        .args(args)
+        .arg("--out")
+        .arg(out.path())
+        .args(roots)
+        .output()
+        .unwrap();
+    Scan { out, output }
+}
+
+/// Scan `roots` with `args`, failing the test if dexy doesn't exit successfully.
+pub fn scan_ok(args: &[&str], roots: &[&Path]) -> Scan {
+    let scan = scan(args, roots);
+    assert!(scan.success(), "dexy failed: {}", scan.stderr());
+    scan
+}
+
+/// Lowercase hex SHA-256 of `data`, as dexy records it by default.
+pub fn sha256(data: &[u8]) -> String {
+    use sha2::Digest;
+    format!("{:x}", sha2::Sha256::digest(data))
+}
+
+/// A symlink below `root` to a path which doesn't exist, which can't be hashed however it's
+/// reached, even with permission to read everything.
+#[cfg(unix)]
+pub fn broken_link(root: &Path, path: &str) -> PathBuf {
+    let path = root.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::os::unix::fs::symlink(root.join("does-not-exist"), &path).unwrap();
+    path
+}
+
+/// Every event written with `--events-file`, in order.
+pub fn read_events(path: &Path) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+/// Scan `roots` with `args` as `scan_ok` does, also returning the summary from the `done` event.
+pub fn scan_summary(args: &[&str], roots: &[&Path]) -> (Scan, Value) {
+    let dir = tempdir();
+    let events = dir.path().join("events.ndjson");
+    let mut args = args.to_vec();
+    args.extend(["--events-file", events.to_str().unwrap()]);
+    let scan = scan_ok(&args, roots);
+    let done = read_events(&events).pop().unwrap();
+    assert_eq!(done["type"], "done");
+    (scan, done["summary"].clone())
+}
+
+/// Write a tar archive of `(member path, contents)` pairs to `path`, gzipped if `gzip`.
+pub fn write_tar(path: &Path, members: &[(&str, &[u8])], gzip: bool) {
+    let file = std::fs::File::create(path).unwrap();
+    let writer: Box<dyn std::io::Write> = match gzip {
+        true => Box::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        )),
+        false => Box::new(file),
+    };
+    let mut builder = tar::Builder::new(writer);
+    for (member, contents) in members {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, member, *contents).unwrap();
+    }
+    builder.into_inner().unwrap().flush().unwrap();
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::*;
+use std::path::Path;
+
+/// Run `dexy compare` on two trees, returning its exit code and the lines it printed.
+fn compare(left: &Path, right: &Path) -> (Option<i32>, Vec<String>, String) {
+    let output = dexy().arg("compare").arg(left).arg(right).output().unwrap();
+    (
+        output.status.code(),
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn identical_trees_match() {
+    let files: &[(&str, &[u8])] = &[("a", b"a"), ("sub/b", b"b"), ("sub/deeper/c", b"c")];
+    let (left, right) = (tree(files), tree(files));
+
+    let (code, lines, _) = compare(left.path(), right.path());
+    assert_eq!(code, Some(0));
+    assert_eq!(lines, vec!["3 of 3 files match"]);
+}
+
+#[test]
+fn the_single_difference_is_reported() {
+    let left = tree(&[("a", b"a"), ("sub/b", b"b"), ("sub/c", b"c")]);
+    let right = tree(&[("a", b"a"), ("sub/b", b"changed"), ("sub/c", b"c")]);
+
+    let (code, lines, _) = compare(left.path(), right.path());
+    assert_eq!(code, Some(1));
+    assert_eq!(lines, vec!["differs: sub/b", "2 of 3 files match"]);
+}
+
+#[test]
+fn files_on_one_side_are_reported() {
+    let left = tree(&[("both", b"both"), ("left", b"left")]);
+    let right = tree(&[("both", b"both"), ("sub/right", b"right")]);
+
+    let (code, lines, _) = compare(left.path(), right.path());
+    assert_eq!(code, Some(1));
+    assert_eq!(
+        lines,
+        vec![
+            format!("only in {}: left", left.path().to_string_lossy()),
+            format!("only in {}: sub/right", right.path().to_string_lossy()),
+            "1 of 3 files match".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn unreadable_roots_are_an_error() {
+    let root = tree(&[("a", b"a")]);
+    let missing = root.path().join("missing");
+    for (left, right) in [(root.path(), &*missing), (&*missing, root.path())] {
+        let (code, lines, stderr) = compare(left, right);
+        assert_eq!(code, Some(6));
+        assert!(lines.is_empty());
+        assert!(stderr.contains("Cannot read directory to compare"));
+        assert!(!stderr.contains("panicked"));
+    }
+}
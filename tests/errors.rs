@@ -0,0 +1,132 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+
+/// Paths in `{name}.errors.json`, sorted.
+fn error_paths(scan: &Scan) -> Vec<String> {
+    if !scan.path("dexy.errors.json").exists() {
+        return vec![];
+    }
+    let mut paths: Vec<String> = scan
+        .json("dexy.errors.json")
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["path"].as_str().unwrap().to_string())
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn only_errors_on_unmatched_paths_are_recorded() {
+    let root = tree(&[("ok", b"ok")]);
+    broken_link(root.path(), "noise/bad");
+    broken_link(root.path(), "real/bad");
+
+    let scan = scan_ok(&["--ignore-errors-matching", "/noise/"], &[root.path()]);
+    assert_eq!(error_paths(&scan), vec![recorded(root.path(), "real/bad")]);
+    assert!(!scan.stderr().contains("noise"));
+
+    let unfiltered = scan_ok(&[], &[root.path()]);
+    assert_eq!(error_paths(&unfiltered).len(), 2);
+}
+
+#[test]
+fn unreadable_files_are_recorded_with_their_error() {
+    let root = tree(&[("ok", b"ok")]);
+    broken_link(root.path(), "bad");
+
+    let scan = scan_ok(&["--record-unreadable"], &[root.path()]);
+    let files = scan.files();
+    let unreadable = files["unreadable"].as_array().unwrap();
+    assert_eq!(unreadable.len(), 1);
+    assert_eq!(unreadable[0]["path"], recorded(root.path(), "bad"));
+    assert!(unreadable[0]["hash"].is_null());
+    assert!(unreadable[0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("No such file"));
+    assert_eq!(
+        scan.hashes(),
+        vec![(recorded(root.path(), "ok"), sha256(b"ok"))]
+    );
+}
+
+#[test]
+fn unreadable_files_are_left_out_by_default() {
+    let root = tree(&[("ok", b"ok")]);
+    broken_link(root.path(), "bad");
+    let scan = scan_ok(&[], &[root.path()]);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "ok")]);
+}
+
+#[test]
+fn scans_are_abandoned_after_max_errors() {
+    let root = tree(&[("ok", b"ok")]);
+    for i in 0..20 {
+        broken_link(root.path(), &format!("bad{}", i));
+    }
+
+    let scan = scan(&["--max-errors", "3"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(3));
+    assert!(!scan.path("dexy.json").exists());
+}
+
+#[test]
+fn scans_under_max_errors_complete() {
+    let root = tree(&[("ok", b"ok")]);
+    for i in 0..5 {
+        broken_link(root.path(), &format!("bad{}", i));
+    }
+
+    let scan = scan_ok(&["--max-errors", "6"], &[root.path()]);
+    assert_eq!(error_paths(&scan).len(), 5);
+    assert_eq!(scan.hash_of(root.path(), "ok"), sha256(b"ok"));
+}
+
+/// A file the scan isn't permitted to read, or none if permissions aren't enforced, as when the
+/// tests are run as root.
+fn forbidden(root: &std::path::Path, path: &str) -> Option<std::path::PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = write(root, path, b"forbidden");
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+    std::fs::File::open(&path).is_err().then_some(path)
+}
+
+#[test]
+fn permission_errors_can_be_routine() {
+    let root = tree(&[("ok", b"ok")]);
+    let Some(_) = forbidden(root.path(), "forbidden") else {
+        eprintln!("skipped: permissions aren't enforced");
+        return;
+    };
+
+    let failing = scan(&["--max-errors", "1"], &[root.path()]);
+    assert_eq!(failing.output.status.code(), Some(3));
+
+    let (scan, summary) = scan_summary(
+        &["--ignore-permission-errors", "--max-errors", "1"],
+        &[root.path()],
+    );
+    assert!(scan.success());
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "ok")]);
+    assert!(error_paths(&scan).is_empty());
+    assert_eq!(summary["permission_denied"], 1);
+    assert_eq!(summary["errors"], 0);
+}
+
+#[test]
+fn other_errors_still_count_when_permission_errors_are_routine() {
+    let root = tree(&[("ok", b"ok")]);
+    forbidden(root.path(), "forbidden");
+    broken_link(root.path(), "bad");
+
+    let scan = scan(
+        &["--ignore-permission-errors", "--max-errors", "1"],
+        &[root.path()],
+    );
+    assert_eq!(scan.output.status.code(), Some(3));
+}
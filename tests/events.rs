@@ -0,0 +1,39 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn done_event_with_summary_is_last() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"bb"), ("sub/c", b"")]);
+    let dir = tempdir();
+    let events = dir.path().join("events.ndjson");
+    scan_ok(&["--events-file", events.to_str().unwrap()], &[root.path()]);
+
+    let events = read_events(&events);
+    let (done, rest) = events.split_last().unwrap();
+    assert_eq!(done["type"], "done");
+    assert!(rest.iter().all(|event| event["type"] != "done"));
+
+    let files = rest.iter().filter(|event| event["type"] == "file").count();
+    assert_eq!(files, 3);
+    let summary = &done["summary"];
+    assert_eq!(summary["files"], 3);
+    assert_eq!(summary["bytes"], 3);
+    assert_eq!(summary["empty_files"], 1);
+    assert_eq!(summary["errors"], 0);
+}
+
+#[test]
+fn file_events_carry_recorded_hashes() {
+    let root = tree(&[("a", b"hello")]);
+    let dir = tempdir();
+    let events = dir.path().join("events.ndjson");
+    scan_ok(&["--events-file", events.to_str().unwrap()], &[root.path()]);
+
+    let first = read_events(&events)
+        .into_iter()
+        .find(|event| event["type"] == "file")
+        .unwrap();
+    assert_eq!(first["path"], recorded(root.path(), "a"));
+    assert_eq!(first["hash"], sha256(b"hello"));
+}
@@ -0,0 +1,379 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn excluded_directory_names_are_pruned_at_any_depth() {
+    let root = tree(&[
+        ("index.js", b"index"),
+        ("node_modules/left-pad/index.js", b"pad"),
+        ("app/node_modules/react/index.js", b"react"),
+        ("app/src/main.js", b"main"),
+        ("app/node_modules_backup/kept.js", b"kept"),
+    ]);
+    let scan = scan_ok(&["--exclude-dir-name", "node_modules"], &[root.path()]);
+    assert_eq!(
+        scan.paths(),
+        vec![
+            recorded(root.path(), "app/node_modules_backup/kept.js"),
+            recorded(root.path(), "app/src/main.js"),
+            recorded(root.path(), "index.js"),
+        ]
+    );
+}
+
+#[test]
+fn several_directory_names_can_be_excluded() {
+    let root = tree(&[
+        ("target/debug/out", b"out"),
+        (".git/HEAD", b"head"),
+        ("src/lib.rs", b"lib"),
+    ]);
+    let scan = scan_ok(
+        &[
+            "--include-hidden",
+            "--exclude-dir-name",
+            "target",
+            "--exclude-dir-name",
+            ".git",
+        ],
+        &[root.path()],
+    );
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "src/lib.rs")]);
+}
+
+#[test]
+fn text_only_skips_binary_files() {
+    let root = tree(&[
+        ("notes.txt", b"plain text\n"),
+        ("image.bin", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+    ]);
+    let scan = scan_ok(&["--text-only"], &[root.path()]);
+    assert_eq!(
+        scan.hashes(),
+        vec![(recorded(root.path(), "notes.txt"), sha256(b"plain text\n"))]
+    );
+}
+
+#[test]
+fn text_sample_bytes_limits_how_far_binary_content_is_looked_for() {
+    let mut late_nul = vec![b'a'; 100];
+    late_nul.push(0);
+    let root = tree(&[("late", &late_nul)]);
+
+    let sniffed = scan_ok(&["--text-only"], &[root.path()]);
+    assert!(sniffed.entries().is_empty());
+
+    let short = scan_ok(
+        &["--text-only", "--text-sample-bytes", "50"],
+        &[root.path()],
+    );
+    assert_eq!(short.hash_of(root.path(), "late"), sha256(&late_nul));
+}
+
+/// Set a file's modification time to `offset` seconds from the unix timestamp `base`.
+fn set_modified(root: &std::path::Path, path: &str, base: i64, offset: i64) {
+    let time = filetime::FileTime::from_unix_time(base + offset, 0);
+    filetime::set_file_mtime(root.join(path), time).unwrap();
+}
+
+#[test]
+fn since_scan_only_hashes_files_modified_after_the_prior_scan() {
+    let root = tree(&[("old", b"old"), ("new", b"new")]);
+    let prior = scan_ok(&[], &[root.path()]);
+    let timestamp = prior.json("dexy.json")["timestamp"].as_i64().unwrap();
+    set_modified(root.path(), "old", timestamp, -60);
+    set_modified(root.path(), "new", timestamp, 60);
+
+    let since = prior.path("dexy.json");
+    let scan = scan_ok(&["--since-scan", since.to_str().unwrap()], &[root.path()]);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "new")]);
+}
+
+#[test]
+fn since_scan_uses_the_latest_modification_of_a_raw_scan() {
+    let root = tree(&[("old", b"old"), ("recent", b"recent"), ("new", b"new")]);
+    set_modified(root.path(), "old", 1_000_000_000, 0);
+    set_modified(root.path(), "recent", 1_000_000_000, 100);
+    set_modified(root.path(), "new", 1_000_000_000, 50);
+    let prior = scan_ok(&["--raw", "--load-file-attributes"], &[root.path()]);
+    set_modified(root.path(), "new", 1_000_000_000, 200);
+
+    let since = prior.path("dexy.json");
+    let scan = scan_ok(&["--since-scan", since.to_str().unwrap()], &[root.path()]);
+    // Files modified at the same time as the latest recorded may have changed since
+    assert_eq!(
+        scan.paths(),
+        vec![
+            recorded(root.path(), "new"),
+            recorded(root.path(), "recent")
+        ]
+    );
+}
+
+/// A tree with files last modified a year, a week and an hour ago, and one just now.
+fn aged_tree() -> tempfile::TempDir {
+    let root = tree(&[
+        ("year", b"y"),
+        ("week", b"w"),
+        ("hour", b"h"),
+        ("now", b"n"),
+    ]);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    set_modified(root.path(), "year", now, -365 * 86400);
+    set_modified(root.path(), "week", now, -7 * 86400);
+    set_modified(root.path(), "hour", now, -3600);
+    root
+}
+
+/// The names of the files recorded in `scan`, sorted.
+fn names(scan: &Scan) -> Vec<String> {
+    let mut names: Vec<String> = scan
+        .paths()
+        .iter()
+        .map(|path| path.rsplit('/').next().unwrap().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn files_older_than_a_duration_are_skipped() {
+    let root = aged_tree();
+    let scan = scan_ok(&["--exclude-older-than", "30d"], &[root.path()]);
+    assert_eq!(names(&scan), vec!["hour", "now", "week"]);
+
+    let scan = scan_ok(&["--exclude-older-than", "2d"], &[root.path()]);
+    assert_eq!(names(&scan), vec!["hour", "now"]);
+}
+
+#[test]
+fn files_newer_than_a_duration_are_skipped() {
+    let root = aged_tree();
+    let scan = scan_ok(&["--exclude-newer-than", "10m"], &[root.path()]);
+    assert_eq!(names(&scan), vec!["hour", "week", "year"]);
+}
+
+#[test]
+fn age_filters_combine() {
+    let root = aged_tree();
+    let scan = scan_ok(
+        &["--exclude-older-than", "30d", "--exclude-newer-than", "1d"],
+        &[root.path()],
+    );
+    assert_eq!(names(&scan), vec!["week"]);
+}
+
+#[test]
+fn age_filters_combine_with_since_scan() {
+    let root = aged_tree();
+    // A scan taken two days ago
+    let prior = scan_ok(&[], &[root.path()]);
+    let since = prior.path("dexy.json");
+    let mut manifest = prior.json("dexy.json");
+    let timestamp = manifest["timestamp"].as_u64().unwrap();
+    manifest["timestamp"] = (timestamp - 2 * 86400).into();
+    std::fs::write(&since, manifest.to_string()).unwrap();
+    let scan = scan_ok(
+        &[
+            "--since-scan",
+            since.to_str().unwrap(),
+            "--exclude-newer-than",
+            "10m",
+        ],
+        &[root.path()],
+    );
+    assert_eq!(names(&scan), vec!["hour"]);
+}
+
+#[test]
+fn durations_must_be_understood() {
+    let root = aged_tree();
+    let scan = scan(&["--exclude-older-than", "a while"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
+
+#[cfg(unix)]
+#[test]
+fn only_files_the_filter_command_accepts_are_hashed() {
+    let root = tree(&[
+        ("2024-01-01.log", b"new year"),
+        ("sub/2024-02-29.log", b"leap day"),
+        ("notes.log", b"notes"),
+        ("2024-03-01.txt", b"text"),
+    ]);
+    let scripts = tempdir();
+    let script = write(
+        scripts.path(),
+        "dated.sh",
+        b"case \"$(basename \"$1\")\" in\n  ????-??-??.log) exit 0 ;;\nesac\nexit 1\n",
+    );
+
+    let command = format!("sh {}", script.to_string_lossy());
+    let scan = scan_ok(&["--filter-command", &command], &[root.path()]);
+    assert_eq!(
+        scan.paths(),
+        vec![
+            recorded(root.path(), "2024-01-01.log"),
+            recorded(root.path(), "sub/2024-02-29.log"),
+        ]
+    );
+    assert_eq!(
+        scan.hash_of(root.path(), "sub/2024-02-29.log"),
+        sha256(b"leap day")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn filter_commands_can_look_at_contents() {
+    let root = tree(&[("todo", b"TODO: write tests"), ("done", b"all done")]);
+    let scan = scan_ok(&["--filter-command", "grep -q TODO"], &[root.path()]);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "todo")]);
+}
+
+#[test]
+fn filter_commands_which_cannot_run_are_errors() {
+    let root = tree(&[("file", b"contents")]);
+    let scan = scan_ok(
+        &["--filter-command", "dexy-no-such-command"],
+        &[root.path()],
+    );
+    assert!(scan.files().is_empty());
+    let errors = scan.json("dexy.errors.json");
+    assert_eq!(path_of(&errors[0]), recorded(root.path(), "file"));
+}
+
+/// Files with a few extensions, in differing case, and without one.
+fn mixed_extensions() -> tempfile::TempDir {
+    tree(&[
+        ("photo.jpg", b"jpg"),
+        ("sub/SHOUT.JPG", b"JPG"),
+        ("icon.png", b"png"),
+        ("scratch.tmp", b"tmp"),
+        ("README", b"readme"),
+        ("sub/Makefile", b"make"),
+    ])
+}
+
+#[test]
+fn included_extensions_are_the_only_ones_hashed() {
+    let root = mixed_extensions();
+    let scan = scan_ok(
+        &["--include-extension", "jpg", "--include-extension", "PNG"],
+        &[root.path()],
+    );
+    assert_eq!(names(&scan), vec!["SHOUT.JPG", "icon.png", "photo.jpg"]);
+}
+
+#[test]
+fn excluded_extensions_are_skipped() {
+    let root = mixed_extensions();
+    let scan = scan_ok(&["--exclude-extension", "tmp"], &[root.path()]);
+    assert_eq!(
+        names(&scan),
+        vec!["Makefile", "README", "SHOUT.JPG", "icon.png", "photo.jpg"]
+    );
+}
+
+#[test]
+fn exclusions_win_over_inclusions() {
+    let root = mixed_extensions();
+    let scan = scan_ok(
+        &[
+            "--include-extension",
+            "jpg",
+            "--include-extension",
+            "png",
+            "--exclude-extension",
+            "jpg",
+        ],
+        &[root.path()],
+    );
+    assert_eq!(names(&scan), vec!["icon.png"]);
+}
+
+#[test]
+fn files_without_an_extension_can_be_matched() {
+    let root = mixed_extensions();
+    let included = scan_ok(&["--include-extension", "--no-extension"], &[root.path()]);
+    assert_eq!(names(&included), vec!["Makefile", "README"]);
+
+    let excluded = scan_ok(&["--exclude-extension=--no-extension"], &[root.path()]);
+    assert_eq!(
+        names(&excluded),
+        vec!["SHOUT.JPG", "icon.png", "photo.jpg", "scratch.tmp"]
+    );
+}
+
+#[test]
+fn strict_hidden_skips_dotfiles_and_everything_in_hidden_directories() {
+    let root = tree(&[
+        ("visible", b"visible"),
+        (".dotfile", b"dotfile"),
+        (".git/config", b"config"),
+        ("sub/.cache/data/plain", b"plain"),
+        ("sub/kept", b"kept"),
+    ]);
+    let scan = scan_ok(&["--strict-hidden"], &[root.path()]);
+    assert_eq!(names(&scan), vec!["kept", "visible"]);
+
+    let everything = scan_ok(&["--include-hidden"], &[root.path()]);
+    assert_eq!(everything.entries().len(), 5);
+}
+
+#[test]
+fn strict_hidden_only_looks_below_the_start_directory() {
+    let parent = tree(&[
+        (".config/app/settings", b"settings"),
+        (".config/app/.lock", b""),
+    ]);
+    let start = parent.path().join(".config/app");
+
+    let strict = scan_ok(&["--strict-hidden"], &[&start]);
+    assert_eq!(strict.paths(), vec![recorded(&start, "settings")]);
+
+    // By default the hidden start directory hides everything in it
+    let loose = scan_ok(&[], &[&start]);
+    assert!(loose.entries().is_empty());
+}
+
+#[test]
+fn strict_hidden_cannot_include_hidden_files() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan(&["--strict-hidden", "--include-hidden"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
+
+#[test]
+fn scans_hashing_nothing_can_be_made_to_fail() {
+    let root = mixed_extensions();
+    let filtered = scan(
+        &["--fail-if-empty", "--include-extension", "mp4"],
+        &[root.path()],
+    );
+    assert_eq!(filtered.output.status.code(), Some(5));
+    assert!(
+        filtered.stderr().contains("No files were hashed"),
+        "{}",
+        filtered.stderr()
+    );
+
+    let empty = tempdir();
+    let nothing = scan(&["--fail-if-empty"], &[empty.path()]);
+    assert_eq!(nothing.output.status.code(), Some(5));
+}
+
+#[test]
+fn scans_hashing_something_are_not_affected_by_fail_if_empty() {
+    let root = mixed_extensions();
+    let scan = scan_ok(&["--fail-if-empty"], &[root.path()]);
+    assert_eq!(scan.entries().len(), 6);
+
+    // Filters which exclude everything still succeed without it
+    let filtered = scan_ok(&["--include-extension", "mp4"], &[root.path()]);
+    assert!(filtered.files().is_empty());
+}
@@ -0,0 +1,537 @@
+mod common;
+
+use common::*;
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn reads_are_limited_to_the_rate_across_all_files() {
+    let a = vec![1; 120_000];
+    let b = vec![2; 80_000];
+    let root = tree(&[("a", &a), ("b", &b)]);
+
+    let started = Instant::now();
+    let scan = scan_ok(&["--max-read-rate", "100000"], &[root.path()]);
+    let elapsed = started.elapsed();
+
+    // 200 kB at 100 kB/s, with room for scheduling either way
+    assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_secs(8), "{:?}", elapsed);
+    assert_eq!(scan.hash_of(root.path(), "a"), sha256(&a));
+    assert_eq!(scan.hash_of(root.path(), "b"), sha256(&b));
+}
+
+/// Scan `root` slowly, appending to `path` every `every` until the scan is done.
+fn scan_while_appending(
+    root: &std::path::Path,
+    path: &std::path::Path,
+    every: Duration,
+    times: usize,
+) -> Scan {
+    let out = tempdir();
+    let child = dexy()
+        .args([
+            "--max-read-rate",
+            "50000",
+            "--load-file-attributes",
+            "--out",
+        ])
+        .arg(out.path())
+        .arg(root)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    for _ in 0..times {
+        std::thread::sleep(every);
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"more").unwrap();
+    }
+    let output = child.wait_with_output().unwrap();
+    Scan { out, output }
+}
+
+#[test]
+fn a_file_written_once_while_hashed_is_hashed_again() {
+    let root = tree(&[("growing", &[1; 100_000])]);
+    let path = root.path().join("growing");
+    let scan = scan_while_appending(root.path(), &path, Duration::from_millis(500), 1);
+    assert!(scan.success());
+
+    let entry = &scan.entries()[0];
+    assert_eq!(entry["hash"], sha256(&std::fs::read(&path).unwrap()));
+    assert!(entry.get("raced").is_none());
+    assert_eq!(entry["attributes"]["size"], 100_004);
+}
+
+#[test]
+fn a_file_written_throughout_hashing_is_flagged() {
+    let root = tree(&[("growing", &[1; 100_000])]);
+    let path = root.path().join("growing");
+    // Keeps writing through both attempts at hashing it, of two seconds each
+    let scan = scan_while_appending(root.path(), &path, Duration::from_millis(300), 15);
+    assert!(scan.success());
+
+    let entry = &scan.entries()[0];
+    assert_eq!(entry["raced"], true);
+    // The attributes describe what was actually hashed
+    let size = entry["attributes"]["size"].as_u64().unwrap();
+    assert!(size >= 100_000);
+}
+
+#[cfg(unix)]
+#[test]
+fn followed_links_are_not_mistaken_for_races() {
+    let root = tree(&[("target", &[1; 1000])]);
+    std::os::unix::fs::symlink("target", root.path().join("link")).unwrap();
+    let scan = scan_ok(&[], &[root.path()]);
+    let entries = scan.entries();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|entry| entry.get("raced").is_none()));
+    assert!(entries
+        .iter()
+        .all(|entry| entry.get("size_mismatch").is_none()));
+}
+
+#[test]
+fn listed_algorithms_are_exactly_those_accepted() {
+    let output = dexy().arg("--list-algorithms").output().unwrap();
+    assert!(output.status.success());
+    let listed: Vec<(String, String)> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let mut columns = line.split_whitespace();
+            let name = columns.next().unwrap().to_string();
+            (name, columns.next().unwrap().to_string())
+        })
+        .collect();
+    let names: Vec<&str> = listed.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["sha256", "sha512", "blake3", "xxh3", "md5", "sha1"]);
+
+    let known = [
+        (
+            "sha256",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        ("md5", "900150983cd24fb0d6963f7d28e17f72"),
+        ("sha1", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+    ];
+    let root = tree(&[("abc", b"abc")]);
+    for (name, kind) in &listed {
+        let scan = scan_ok(&["--algorithm", name], &[root.path()]);
+        let hash = scan.hash_of(root.path(), "abc");
+        if let Some((_, expected)) = known.iter().find(|(known, _)| known == name) {
+            assert_eq!(hash, *expected);
+        }
+        let cryptographic = !matches!(name.as_str(), "xxh3" | "md5" | "sha1");
+        let expected_kind = match cryptographic {
+            true => "cryptographic",
+            false => "non-cryptographic",
+        };
+        assert_eq!(kind, expected_kind, "{}", name);
+    }
+}
+
+#[test]
+fn unknown_algorithms_are_rejected() {
+    let root = tree(&[("abc", b"abc")]);
+    let scan = scan(&["--algorithm", "crc32"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
+
+#[test]
+fn repeated_algorithms_each_record_a_digest() {
+    let root = tree(&[("hello", b"hello world")]);
+    let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    let md5 = "5eb63bbbe01eeed093cb22bb8f5acdc3";
+    let sha1 = "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed";
+
+    let scan = scan_ok(
+        &["--algorithm", "sha256", "--algorithm", "md5"],
+        &[root.path()],
+    );
+    let entry = &scan.entries()[0];
+    assert_eq!(entry["hash"], sha256);
+    assert_eq!(
+        entry["digests"],
+        serde_json::json!({"sha256": sha256, "md5": md5})
+    );
+
+    // The first is the file's own hash, whatever order they're given in
+    let scan = scan_ok(
+        &[
+            "--algorithm",
+            "md5",
+            "--algorithm",
+            "sha1",
+            "--algorithm",
+            "sha256",
+        ],
+        &[root.path()],
+    );
+    let entry = &scan.entries()[0];
+    assert_eq!(entry["hash"], md5);
+    assert_eq!(
+        entry["digests"],
+        serde_json::json!({"md5": md5, "sha1": sha1, "sha256": sha256})
+    );
+
+    let single = scan_ok(&["--algorithm", "md5"], &[root.path()]);
+    assert!(single.entries()[0].get("digests").is_none());
+}
+
+#[test]
+fn line_endings_only_matter_without_normalization() {
+    let root = tree(&[
+        ("lf", b"one\ntwo\nthree\n"),
+        ("crlf", b"one\r\ntwo\r\nthree\r\n"),
+    ]);
+
+    let plain = scan_ok(&[], &[root.path()]);
+    assert_ne!(
+        plain.hash_of(root.path(), "lf"),
+        plain.hash_of(root.path(), "crlf")
+    );
+
+    let normalized = scan_ok(&["--normalize-line-endings"], &[root.path()]);
+    assert_eq!(
+        normalized.hash_of(root.path(), "crlf"),
+        sha256(b"one\ntwo\nthree\n")
+    );
+    assert_eq!(
+        normalized.hash_of(root.path(), "lf"),
+        normalized.hash_of(root.path(), "crlf")
+    );
+}
+
+#[test]
+fn line_endings_are_normalized_across_reads() {
+    // Puts a CRLF across the boundary between the reads of a 64KiB buffer
+    let mut crlf = vec![b'x'; 64 * 1024 - 1];
+    crlf.extend_from_slice(b"\r\nend\r\n");
+    let mut lf = vec![b'x'; 64 * 1024 - 1];
+    lf.extend_from_slice(b"\nend\n");
+    let root = tree(&[("crlf", &crlf), ("lf", &lf)]);
+
+    let scan = scan_ok(&["--normalize-line-endings"], &[root.path()]);
+    assert_eq!(scan.hash_of(root.path(), "crlf"), sha256(&lf));
+    assert_eq!(scan.hash_of(root.path(), "lf"), sha256(&lf));
+}
+
+#[test]
+fn binary_files_are_hashed_as_is() {
+    let binary = b"\0\x01\r\n\x02\r\n";
+    let root = tree(&[("binary", binary)]);
+    let scan = scan_ok(&["--normalize-line-endings"], &[root.path()]);
+    assert_eq!(scan.hash_of(root.path(), "binary"), sha256(binary));
+}
+
+/// The block hashes recorded for the only file in `scan`.
+fn chunk_hashes(scan: &Scan) -> Vec<String> {
+    scan.entries()[0]["chunk_hashes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|hash| hash.as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn only_the_changed_block_hash_differs() {
+    let mut contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let root = tree(&[("file", &contents)]);
+    let before = scan_ok(&["--chunk-hashes", "4096"], &[root.path()]);
+    let before_blocks = chunk_hashes(&before);
+    let expected: Vec<String> = contents.chunks(4096).map(sha256).collect();
+    assert_eq!(before_blocks, expected);
+    assert_eq!(before.hashes()[0].1, sha256(&contents));
+
+    contents[5000] ^= 0xff;
+    write(root.path(), "file", &contents);
+    let after = scan_ok(&["--chunk-hashes", "4096"], &[root.path()]);
+    let after_blocks = chunk_hashes(&after);
+    assert_eq!(after_blocks.len(), 3);
+    assert_eq!(after_blocks[0], before_blocks[0]);
+    assert_ne!(after_blocks[1], before_blocks[1]);
+    assert_eq!(after_blocks[2], before_blocks[2]);
+    assert_eq!(after.hashes()[0].1, sha256(&contents));
+}
+
+#[test]
+fn block_hashes_are_left_out_by_default() {
+    let root = tree(&[("file", b"contents")]);
+    let scan = scan_ok(&[], &[root.path()]);
+    assert!(scan.entries()[0].get("chunk_hashes").is_none());
+}
+
+/// The entry recorded for the file at `path` below `root`.
+fn entry(scan: &Scan, root: &std::path::Path, path: &str) -> serde_json::Value {
+    let path = recorded(root, path);
+    scan.entries()
+        .into_iter()
+        .find(|entry| path_of(entry) == path)
+        .unwrap_or_else(|| panic!("{} wasn't recorded", path))
+}
+
+#[test]
+fn extensions_can_be_hashed_with_their_own_algorithm() {
+    let root = tree(&[
+        ("video.MP4", b"video"),
+        ("notes.txt", b"notes"),
+        ("plain", b"plain"),
+    ]);
+    let scan = scan_ok(
+        &[
+            "--algorithm-for",
+            "mp4=xxh3",
+            "--algorithm-for",
+            "txt=blake3",
+        ],
+        &[root.path()],
+    );
+
+    let video = entry(&scan, root.path(), "video.MP4");
+    assert_eq!(video["algorithm"], "xxh3");
+    assert_eq!(
+        video["hash"],
+        format!("{:016x}", xxhash_rust::xxh3::xxh3_64(b"video"))
+    );
+    let notes = entry(&scan, root.path(), "notes.txt");
+    assert_eq!(notes["algorithm"], "blake3");
+    assert_eq!(notes["hash"], blake3::hash(b"notes").to_hex().as_str());
+    // Everything else uses --algorithm, which the envelope records
+    let plain = entry(&scan, root.path(), "plain");
+    assert!(plain.get("algorithm").is_none());
+    assert_eq!(plain["hash"], sha256(b"plain"));
+    assert_eq!(scan.json("dexy.json")["algorithm"], "sha256");
+}
+
+#[test]
+fn extension_mappings_must_name_an_algorithm() {
+    let root = tree(&[("file", b"contents")]);
+    for mapping in ["mp4", "mp4=", "mp4=crc32"] {
+        let scan = scan(&["--algorithm-for", mapping], &[root.path()]);
+        assert_eq!(scan.output.status.code(), Some(2), "{}", mapping);
+    }
+}
+
+#[test]
+fn tail_hashes_only_sample_the_ends_of_large_files() {
+    let first: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    let mut second = first.clone();
+    second[50_000] ^= 0xff;
+    let root = tree(&[("first", &first), ("second", &second), ("small", b"small")]);
+
+    let sampled = scan_ok(&["--tail-hash", "4096"], &[root.path()]);
+    let first_entry = entry(&sampled, root.path(), "first");
+    assert_eq!(first_entry["sampled"], 4096);
+    assert_eq!(
+        first_entry["hash"],
+        entry(&sampled, root.path(), "second")["hash"]
+    );
+    // Files smaller than both ends together are hashed in full
+    let small = entry(&sampled, root.path(), "small");
+    assert!(small.get("sampled").is_none());
+    assert_eq!(small["hash"], sha256(b"small"));
+
+    let full = scan_ok(&[], &[root.path()]);
+    assert_ne!(
+        full.hash_of(root.path(), "first"),
+        full.hash_of(root.path(), "second")
+    );
+}
+
+#[test]
+fn tail_hashes_include_the_size() {
+    let shorter: Vec<u8> = (0..50_000u32).map(|i| (i % 253) as u8).collect();
+    let mut longer = shorter[..25_000].to_vec();
+    longer.extend_from_slice(&[0; 1000]);
+    longer.extend_from_slice(&shorter[25_000..]);
+    let root = tree(&[("shorter", &shorter), ("longer", &longer)]);
+
+    let sampled = scan_ok(&["--tail-hash", "4096"], &[root.path()]);
+    assert_ne!(
+        sampled.hash_of(root.path(), "shorter"),
+        sampled.hash_of(root.path(), "longer")
+    );
+}
+
+#[test]
+fn files_smaller_than_both_ends_are_hashed_in_full() {
+    let contents = vec![7; 8191];
+    let root = tree(&[("file", &contents)]);
+    let scan = scan_ok(&["--tail-hash", "4096"], &[root.path()]);
+    let file = entry(&scan, root.path(), "file");
+    assert!(file.get("sampled").is_none());
+    assert_eq!(file["hash"], sha256(&contents));
+}
+
+#[test]
+fn the_size_actually_read_is_recorded_when_it_differs() {
+    let root = tree(&[("growing", &[1; 100_000])]);
+    let path = root.path().join("growing");
+    let scan = scan_while_appending(root.path(), &path, Duration::from_millis(300), 15);
+    assert!(scan.success());
+
+    let entry = &scan.entries()[0];
+    let mismatch = &entry["size_mismatch"];
+    let read = mismatch["read"].as_u64().unwrap();
+    assert!(read > mismatch["expected"].as_u64().unwrap(), "{}", entry);
+    assert_eq!(entry["attributes"]["size"], read);
+    // Only appended to, so the hash covers the start of the file as it is now
+    let contents = std::fs::read(&path).unwrap();
+    assert_eq!(entry["hash"], sha256(&contents[..read as usize]));
+}
+
+#[test]
+fn files_read_in_full_have_no_size_mismatch() {
+    let root = tree(&[("a", &[1; 100_000]), ("b", b"")]);
+    let scan = scan_ok(&["--load-file-attributes"], &[root.path()]);
+    for entry in scan.entries() {
+        assert!(entry.get("size_mismatch").is_none(), "{}", entry);
+    }
+}
+
+#[test]
+fn file_names_are_folded_into_the_hash_when_asked() {
+    let root = tree(&[("one", b"same"), ("two", b"same"), ("sub/one", b"same")]);
+
+    let plain = scan_ok(&[], &[root.path()]);
+    assert_eq!(plain.files().len(), 1);
+    assert!(plain.entries()[0].get("filename_hashed").is_none());
+
+    let named = scan_ok(&["--hash-filenames"], &[root.path()]);
+    let one = named.hash_of(root.path(), "one");
+    assert_ne!(one, named.hash_of(root.path(), "two"));
+    assert_ne!(one, sha256(b"same"));
+    // Only the name is folded in, not the directory it's in
+    assert_eq!(one, named.hash_of(root.path(), "sub/one"));
+    for entry in named.entries() {
+        assert_eq!(entry["filename_hashed"], true, "{}", entry);
+    }
+}
+
+/// Contents at either side of the 64K up to which files are always read, and across several
+/// of the pieces a mapped file is hashed in.
+fn mappable_tree() -> tempfile::TempDir {
+    let large: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let text: Vec<u8> = b"line\r\n".repeat(20_000);
+    tree(&[
+        ("small", b"small"),
+        ("exactly-64k", &[1; 64 * 1024]),
+        ("just-over", &[2; 64 * 1024 + 1]),
+        ("sub/large", &large),
+        ("sub/text", &text),
+        ("empty", b""),
+    ])
+}
+
+#[test]
+fn mapped_files_hash_the_same_as_read_ones() {
+    let root = mappable_tree();
+    for args in [
+        &[][..],
+        &["--chunk-hashes", "100000", "--load-file-attributes"],
+        &["--normalize-line-endings", "--text-only"],
+    ] {
+        let read = scan_ok(args, &[root.path()]);
+        let mapped = scan_ok(&[args, &["--io-backend", "mmap"]].concat(), &[root.path()]);
+        assert!(!read.entries().is_empty());
+        assert_eq!(mapped.entries(), read.entries(), "{:?}", args);
+    }
+    let mapped = scan_ok(&["--io-backend", "mmap"], &[root.path()]);
+    let large = std::fs::read(root.path().join("sub/large")).unwrap();
+    assert_eq!(mapped.hash_of(root.path(), "sub/large"), sha256(&large));
+}
+
+/// The hash of `contents` hashed as blocks of `block_size` with `--parallel-above`.
+fn tree_hash(contents: &[u8], block_size: usize) -> String {
+    let blocks: String = contents.chunks(block_size).map(sha256).collect();
+    let mut hashed = blocks.into_bytes();
+    hashed.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+    sha256(&hashed)
+}
+
+#[test]
+fn files_hashed_as_parallel_blocks_are_hashed_the_same_every_time() {
+    // More large files than workers, so several are hashed in blocks at once
+    let large: Vec<Vec<u8>> = (0..8u32)
+        .map(|n| (0..300_000u32).map(|i| ((i * 7 + n) % 251) as u8).collect())
+        .collect();
+    let mut files: Vec<(String, &[u8])> = large
+        .iter()
+        .enumerate()
+        .map(|(n, contents)| (format!("large-{}", n), &contents[..]))
+        .collect();
+    files.push(("copy".to_string(), &large[0]));
+    files.push(("small".to_string(), b"small"));
+    let files: Vec<(&str, &[u8])> = files
+        .iter()
+        .map(|(path, contents)| (path.as_str(), *contents))
+        .collect();
+    let root = tree(&files);
+
+    let args = [
+        "--parallel-above",
+        "64K",
+        "--parallel-block-size",
+        "16K",
+        "--thread-count",
+        "4",
+    ];
+    let first = scan_ok(&args, &[root.path()]);
+    for _ in 0..3 {
+        assert_eq!(scan_ok(&args, &[root.path()]).entries(), first.entries());
+    }
+    for (n, contents) in large.iter().enumerate() {
+        let entry = entry(&first, root.path(), &format!("large-{}", n));
+        assert_eq!(entry["tree"], 16384, "{}", entry);
+        assert_eq!(entry["hash"], tree_hash(contents, 16384), "{}", entry);
+    }
+    assert_eq!(
+        first.hash_of(root.path(), "copy"),
+        first.hash_of(root.path(), "large-0")
+    );
+    let small = entry(&first, root.path(), "small");
+    assert!(small.get("tree").is_none(), "{}", small);
+    assert_eq!(small["hash"], sha256(b"small"));
+
+    let output = dexy()
+        .arg("verify")
+        .arg(first.path("dexy.json"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "10 of 10 files match"
+    );
+}
+
+#[test]
+fn entropy_is_recorded_with_the_attributes() {
+    let ramp: Vec<u8> = (0..=255).collect();
+    let root = tree(&[("repeated", &[b'a'; 5000]), ("ramp", &ramp)]);
+    write_tar(
+        &root.path().join("archive.tar"),
+        &[("member", &ramp[..])],
+        false,
+    );
+    let scan = scan_ok(&["--entropy", "--scan-archives"], &[root.path()]);
+
+    assert_eq!(
+        entry(&scan, root.path(), "repeated")["attributes"]["entropy"],
+        0.0
+    );
+    assert_eq!(
+        entry(&scan, root.path(), "ramp")["attributes"]["entropy"],
+        8.0
+    );
+    // Members have no attributes to record it in
+    let member = entry(&scan, root.path(), "archive.tar!member");
+    assert_eq!(member["hash"], sha256(&ramp));
+    assert!(member["attributes"].is_null(), "{}", member);
+}
@@ -0,0 +1,714 @@
+mod common;
+
+use common::*;
+use std::collections::BTreeSet;
+
+#[test]
+fn shards_hold_the_hashes_with_their_prefix() {
+    let contents: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+    let files: Vec<(String, &[u8])> = contents
+        .iter()
+        .map(|c| (format!("file{}", c), c.as_bytes()))
+        .collect();
+    let files: Vec<(&str, &[u8])> = files.iter().map(|(p, c)| (p.as_str(), *c)).collect();
+    let root = tree(&files);
+
+    let scan = scan_ok(&["--shard-by-prefix", "1"], &[root.path()]);
+
+    let prefixes: BTreeSet<String> = contents
+        .iter()
+        .map(|c| sha256(c.as_bytes())[..1].to_string())
+        .collect();
+    let mut written = BTreeSet::new();
+    for entry in std::fs::read_dir(scan.out.path()).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        let prefix = name
+            .strip_prefix("dexy.")
+            .and_then(|name| name.strip_suffix(".json"))
+            .unwrap_or_else(|| panic!("unexpected output {}", name));
+        written.insert(prefix.to_string());
+    }
+    // Empty shards aren't written
+    assert_eq!(written, prefixes);
+
+    let mut hashes = 0;
+    for prefix in &written {
+        let shard = scan.json(&format!("dexy.{}.json", prefix));
+        for hash in shard["files"].as_object().unwrap().keys() {
+            assert!(hash.starts_with(prefix.as_str()));
+            hashes += 1;
+        }
+    }
+    assert_eq!(hashes, contents.len());
+}
+
+#[cfg(unix)]
+#[test]
+fn pretty_output_is_indented_json_for_every_file() {
+    let root = tree(&[("a", b"a")]);
+    broken_link(root.path(), "bad");
+    let scan = scan_ok(
+        &[
+            "--json-pretty",
+            "--load-file-attributes",
+            "--report-largest",
+            "1",
+        ],
+        &[root.path()],
+    );
+
+    for name in ["dexy.json", "dexy.errors.json", "dexy.largest.json"] {
+        let text = std::fs::read_to_string(scan.path(name)).unwrap();
+        assert!(text.contains("\n  "), "{} isn't indented:\n{}", name, text);
+        serde_json::from_str::<serde_json::Value>(&text).unwrap();
+    }
+    assert_eq!(scan.hash_of(root.path(), "a"), sha256(b"a"));
+}
+
+#[test]
+fn output_is_compact_by_default() {
+    let root = tree(&[("a", b"a"), ("b", b"b")]);
+    let scan = scan_ok(&[], &[root.path()]);
+    let text = std::fs::read_to_string(scan.path("dexy.json")).unwrap();
+    assert!(!text.trim_end().contains('\n'));
+}
+
+#[test]
+fn each_root_gets_its_own_output() {
+    let parent = tempdir();
+    write(parent.path(), "photos/a", b"a");
+    write(parent.path(), "music/b", b"b");
+    let (photos, music) = (parent.path().join("photos"), parent.path().join("music"));
+
+    let scan = scan_ok(&["--split-by-root"], &[&photos, &music]);
+    assert!(!scan.path("dexy.json").exists());
+    let paths = |name: &str| entries(scan.json(name)["files"].as_object().unwrap());
+    assert_eq!(paths("dexy.photos.json").len(), 1);
+    assert_eq!(
+        path_of(&paths("dexy.photos.json")[0]),
+        recorded(&photos, "a")
+    );
+    assert_eq!(path_of(&paths("dexy.music.json")[0]), recorded(&music, "b"));
+}
+
+#[test]
+fn roots_sharing_a_name_are_told_apart() {
+    let parent = tempdir();
+    write(parent.path(), "one/data/a", b"a");
+    write(parent.path(), "two/data/b", b"b");
+    let (one, two) = (
+        parent.path().join("one/data"),
+        parent.path().join("two/data"),
+    );
+
+    let scan = scan_ok(&["--split-by-root"], &[&one, &two]);
+    let first = entries(scan.json("dexy.data-1.json")["files"].as_object().unwrap());
+    let second = entries(scan.json("dexy.data-2.json")["files"].as_object().unwrap());
+    assert_eq!(
+        first.iter().map(path_of).collect::<Vec<_>>(),
+        vec![recorded(&one, "a")]
+    );
+    assert_eq!(
+        second.iter().map(path_of).collect::<Vec<_>>(),
+        vec![recorded(&two, "b")]
+    );
+}
+
+#[test]
+fn envelope_records_how_the_scan_was_run() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan_ok(&["--algorithm", "blake3"], &[root.path()]);
+    let manifest = scan.json("dexy.json");
+
+    assert_eq!(manifest["dexy_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(manifest["algorithm"], "blake3");
+    assert!(manifest["timestamp"].as_u64().unwrap() > 0);
+    assert!(!manifest["hostname"].as_str().unwrap().is_empty());
+    let root_path = root.path().canonicalize().unwrap();
+    assert_eq!(
+        manifest["start_directories"],
+        serde_json::json!([root_path.to_str().unwrap()])
+    );
+    let arguments: Vec<&str> = manifest["arguments"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|argument| argument.as_str().unwrap())
+        .collect();
+    assert!(arguments
+        .windows(2)
+        .any(|pair| pair == ["--algorithm", "blake3"]));
+    assert_eq!(scan.entries().len(), 1);
+}
+
+#[test]
+fn raw_output_is_the_bare_files_map() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan_ok(&["--raw"], &[root.path()]);
+    let manifest = scan.json("dexy.json");
+    let files = manifest.as_object().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(
+        path_of(&files[&sha256(b"a")][0]),
+        recorded(root.path(), "a")
+    );
+}
+
+#[test]
+fn a_failed_write_keeps_the_previous_output() {
+    let root = tree(&[("a", b"a")]);
+    let out = tempdir();
+    std::fs::write(out.path().join("dexy.json"), b"previous").unwrap();
+    // A directory in the way of the temporary file makes the write fail
+    std::fs::create_dir(out.path().join("dexy.json.tmp")).unwrap();
+
+    let output = dexy()
+        .arg("--out")
+        .arg(out.path())
+        .arg(root.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        std::fs::read(out.path().join("dexy.json")).unwrap(),
+        b"previous"
+    );
+}
+
+#[test]
+fn a_failed_rename_leaves_no_partial_output() {
+    let root = tree(&[("a", b"a")]);
+    let out = tempdir();
+    // A non-empty directory can't be replaced by the completed output
+    std::fs::create_dir_all(out.path().join("dexy.json/inside")).unwrap();
+
+    let output = dexy()
+        .arg("--out")
+        .arg(out.path())
+        .arg(root.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(out.path().join("dexy.json").is_dir());
+    assert!(!out.path().join("dexy.json.tmp").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn output_mode_sets_the_permissions_of_the_output() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tree(&[("a", b"a")]);
+    let scan = scan_ok(&["--output-mode", "640"], &[root.path()]);
+    let mode = std::fs::metadata(scan.path("dexy.json"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o640);
+}
+
+#[cfg(unix)]
+#[test]
+fn output_mode_must_be_octal() {
+    let root = tree(&[("a", b"a")]);
+    assert!(!scan(&["--output-mode", "rw-r"], &[root.path()]).success());
+}
+
+/// The entries in an ndjson output file.
+fn ndjson_entries(path: &std::path::Path) -> Vec<serde_json::Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn streamed_output_rotates_every_n_files() {
+    let root = tree(&[
+        ("1", b"1"),
+        ("2", b"2"),
+        ("3", b"3"),
+        ("4", b"4"),
+        ("5", b"5"),
+    ]);
+    let scan = scan_ok(
+        &["--format", "ndjson", "--rotate-every", "2"],
+        &[root.path()],
+    );
+
+    let counts: Vec<usize> = ["dexy.0001.ndjson", "dexy.0002.ndjson", "dexy.0003.ndjson"]
+        .iter()
+        .map(|name| ndjson_entries(&scan.path(name)).len())
+        .collect();
+    assert_eq!(counts, vec![2, 2, 1]);
+    assert!(!scan.path("dexy.0004.ndjson").exists());
+
+    let mut hashes: Vec<String> = (1..=3)
+        .flat_map(|i| ndjson_entries(&scan.path(&format!("dexy.{:04}.ndjson", i))))
+        .map(|entry| entry["hash"].as_str().unwrap().to_string())
+        .collect();
+    hashes.sort();
+    let mut expected: Vec<String> = ["1", "2", "3", "4", "5"]
+        .iter()
+        .map(|c| sha256(c.as_bytes()))
+        .collect();
+    expected.sort();
+    assert_eq!(hashes, expected);
+}
+
+#[test]
+fn rotation_needs_streamed_output() {
+    let root = tree(&[("1", b"1")]);
+    assert!(!scan(&["--rotate-every", "2"], &[root.path()]).success());
+}
+
+#[test]
+fn grouping_by_path_gives_one_entry_per_file() {
+    let root = tree(&[("a", b"same"), ("b", b"same"), ("c", b"other")]);
+    let scan = scan_ok(&["--group-by", "path"], &[root.path()]);
+
+    let files = scan.files();
+    assert_eq!(files.len(), 3);
+    for (path, contents) in [("a", b"same" as &[u8]), ("b", b"same"), ("c", b"other")] {
+        let entry = &files[&recorded(root.path(), path)];
+        assert_eq!(path_of(entry), recorded(root.path(), path));
+        assert_eq!(entry["hash"], sha256(contents));
+    }
+}
+
+#[test]
+fn scans_grouped_by_path_can_be_verified() {
+    let root = tree(&[("a", b"same"), ("b", b"same")]);
+    let scan = scan_ok(&["--group-by", "path"], &[root.path()]);
+
+    let verified = dexy()
+        .arg("verify")
+        .arg(scan.path("dexy.json"))
+        .output()
+        .unwrap();
+    assert!(verified.status.success());
+    assert!(String::from_utf8_lossy(&verified.stdout).contains("2 of 2 files match"));
+}
+
+/// Three files of 50KB, each in its own directory so they're streamed out one at a time.
+fn slow_tree() -> tempfile::TempDir {
+    tree(&[
+        ("one/file", &[1; 50_000]),
+        ("two/file", &[2; 50_000]),
+        ("three/file", &[3; 50_000]),
+    ])
+}
+
+#[test]
+fn line_flushing_lets_entries_be_read_during_the_scan() {
+    let root = slow_tree();
+    let out = tempdir();
+    // Hashing takes around three seconds at this rate
+    let mut child = dexy()
+        .args([
+            "--thread-count",
+            "1",
+            "--format",
+            "ndjson",
+            "--flush-policy",
+            "line",
+        ])
+        .args(["--max-read-rate", "50000", "--out"])
+        .arg(out.path())
+        .arg(root.path())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let output = out.path().join("dexy.ndjson");
+    let mut seen_early = false;
+    while child.try_wait().unwrap().is_none() {
+        let written = std::fs::read_to_string(&output).unwrap_or_default();
+        if written.ends_with('\n') && written.lines().count() < 3 {
+            serde_json::from_str::<serde_json::Value>(written.lines().next().unwrap()).unwrap();
+            seen_early = true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert!(child.wait().unwrap().success());
+    assert!(seen_early);
+    assert_eq!(ndjson_entries(&output).len(), 3);
+}
+
+#[test]
+fn buffered_output_is_complete_at_the_end() {
+    let root = slow_tree();
+    for policy in ["buffered", "interval:1"] {
+        let scan = scan_ok(
+            &["--format", "ndjson", "--flush-policy", policy],
+            &[root.path()],
+        );
+        let mut hashes: Vec<String> = ndjson_entries(&scan.path("dexy.ndjson"))
+            .iter()
+            .map(|entry| entry["hash"].as_str().unwrap().to_string())
+            .collect();
+        hashes.sort();
+        let mut expected = vec![
+            sha256(&[1; 50_000]),
+            sha256(&[2; 50_000]),
+            sha256(&[3; 50_000]),
+        ];
+        expected.sort();
+        assert_eq!(hashes, expected);
+    }
+}
+
+#[test]
+fn flush_policies_must_be_understood() {
+    let root = tree(&[("file", b"contents")]);
+    for policy in ["sometimes", "interval:", "interval:soon"] {
+        let scan = scan(
+            &["--format", "ndjson", "--flush-policy", policy],
+            &[root.path()],
+        );
+        assert_eq!(scan.output.status.code(), Some(2), "{}", policy);
+    }
+}
+
+#[test]
+fn canonical_output_is_byte_identical_across_runs() {
+    let contents: Vec<(String, String)> = (0..60)
+        .map(|i| {
+            (
+                format!("dir{}/file{}", i % 6, i),
+                format!("contents {}", i % 10),
+            )
+        })
+        .collect();
+    let files: Vec<(&str, &[u8])> = contents
+        .iter()
+        .map(|(path, contents)| (path.as_str(), contents.as_bytes()))
+        .collect();
+    let root = tree(&files);
+
+    let first = scan_ok(&["--canonical-json"], &[root.path()]);
+    let second = scan_ok(&["--canonical-json"], &[root.path()]);
+    let first = std::fs::read(first.path("dexy.json")).unwrap();
+    assert_eq!(first, std::fs::read(second.path("dexy.json")).unwrap());
+    assert!(!first.contains(&b'\n') && !first.contains(&b' '));
+}
+
+#[test]
+fn canonical_output_is_sorted() {
+    let root = tree(&[("b", b"same"), ("a", b"same"), ("c", b"other")]);
+    let scan = scan_ok(&["--canonical-json"], &[root.path()]);
+    let written = std::fs::read_to_string(scan.path("dexy.json")).unwrap();
+
+    // Parsing with `preserve_order` isn't available, so the order is checked in the text
+    let position = |needle: &str| written.find(needle).unwrap();
+    let (same, other) = (sha256(b"same"), sha256(b"other"));
+    assert_eq!(
+        position(&format!("\"{}\"", same)) < position(&format!("\"{}\"", other)),
+        same < other
+    );
+    assert!(position(&recorded(root.path(), "a")) < position(&recorded(root.path(), "b")));
+    assert!(position("\"attributes\"") < position("\"hash\""));
+    assert!(position("\"hash\"") < position("\"path\""));
+}
+
+/// A file as written with `--format msgpack`, keeping only what's compared.
+#[derive(serde::Deserialize)]
+struct BinaryFile {
+    hash: serde_bytes::ByteBuf,
+    path: String,
+}
+
+#[derive(serde::Deserialize)]
+struct BinaryManifest {
+    header: Option<serde::de::IgnoredAny>,
+    files: Vec<BinaryFile>,
+}
+
+#[test]
+fn binary_output_holds_the_same_hashes_as_raw_bytes() {
+    let root = tree(&[("a", b"same"), ("sub/b", b"same"), ("c", b"other")]);
+    let json = scan_ok(&[], &[root.path()]);
+
+    for raw in [false, true] {
+        let args: &[&str] = match raw {
+            true => &["--format", "msgpack", "--raw"],
+            false => &["--format", "msgpack"],
+        };
+        let binary = scan_ok(args, &[root.path()]);
+        let data = std::fs::read(binary.path("dexy.msgpack")).unwrap();
+        let decoded: BinaryManifest = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(decoded.header.is_none(), raw);
+
+        let mut hashes: Vec<(String, String)> = decoded
+            .files
+            .iter()
+            .map(|file| {
+                assert_eq!(file.hash.len(), 32);
+                let hex = file.hash.iter().map(|b| format!("{:02x}", b)).collect();
+                (file.path.clone(), hex)
+            })
+            .collect();
+        hashes.sort();
+        assert_eq!(hashes, json.hashes());
+    }
+}
+
+#[test]
+fn binary_output_can_be_verified() {
+    let root = tree(&[("a", b"a"), ("b", b"b")]);
+    let scan = scan_ok(&["--format", "msgpack"], &[root.path()]);
+    let verified = dexy()
+        .arg("verify")
+        .arg(scan.path("dexy.msgpack"))
+        .output()
+        .unwrap();
+    assert!(verified.status.success());
+    assert!(String::from_utf8_lossy(&verified.stdout).contains("2 of 2 files match"));
+}
+
+/// Paths listed in the per-directory manifest at `manifest`, sorted.
+fn mirrored_paths(manifest: &std::path::Path) -> Vec<String> {
+    let data = std::fs::read(manifest)
+        .unwrap_or_else(|e| panic!("{} wasn't written: {}", manifest.display(), e));
+    let manifest: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    entries(manifest["files"].as_object().unwrap())
+        .iter()
+        .map(path_of)
+        .collect()
+}
+
+#[test]
+fn each_directory_gets_a_manifest_of_its_own_files() {
+    let root = tree(&[
+        ("a", b"a"),
+        ("z", b"z"),
+        ("sub/b", b"b"),
+        ("sub/deeper/c", b"c"),
+    ]);
+    let scan = scan_ok(&["--mirror-manifests"], &[root.path()]);
+
+    let path = |path: &str| recorded(root.path(), path);
+    assert_eq!(
+        mirrored_paths(&root.path().join(".dexy.json")),
+        vec![path("a"), path("z")]
+    );
+    assert_eq!(
+        mirrored_paths(&root.path().join("sub/.dexy.json")),
+        vec![path("sub/b")]
+    );
+    assert_eq!(
+        mirrored_paths(&root.path().join("sub/deeper/.dexy.json")),
+        vec![path("sub/deeper/c")]
+    );
+    // The combined output is unaffected
+    assert_eq!(scan.paths().len(), 4);
+
+    // Manifests from an earlier scan aren't hashed
+    let again = scan_ok(&["--mirror-manifests"], &[root.path()]);
+    assert_eq!(again.paths(), scan.paths());
+}
+
+#[test]
+fn manifests_can_be_mirrored_into_another_tree() {
+    let parent = tempdir();
+    write(parent.path(), "source/a", b"a");
+    write(parent.path(), "source/sub/b", b"b");
+    let source = parent.path().join("source");
+    let mirror = tempdir();
+
+    scan_ok(
+        &[
+            "--mirror-manifests",
+            "--mirror-root",
+            mirror.path().to_str().unwrap(),
+        ],
+        &[&source],
+    );
+    assert!(!source.join(".dexy.json").exists());
+    assert!(!source.join("sub/.dexy.json").exists());
+    assert_eq!(
+        mirrored_paths(&mirror.path().join("source/.dexy.json")),
+        vec![recorded(&source, "a")]
+    );
+    assert_eq!(
+        mirrored_paths(&mirror.path().join("source/sub/.dexy.json")),
+        vec![recorded(&source, "sub/b")]
+    );
+}
+
+#[test]
+fn written_output_is_checked_against_the_scan() {
+    let root = tree(&[("a", b"shared"), ("sub/b", b"shared"), ("c", b"c")]);
+    let plain = scan_ok(&[], &[root.path()]);
+    for format in [&[][..], &["--raw"], &["--format", "msgpack"]] {
+        let args = [&["--verify-after-write"], format].concat();
+        let scan = scan_ok(&args, &[root.path()]);
+        assert!(!scan.stderr().contains("Output failed verification"));
+        if format.is_empty() {
+            assert_eq!(scan.hashes(), plain.hashes());
+        }
+    }
+}
+
+#[test]
+fn output_spread_over_several_files_cannot_be_checked() {
+    let root = tree(&[("a", b"a")]);
+    for args in [
+        &["--format", "ndjson"][..],
+        &["--group-by", "path"],
+        &["--split-by-root"],
+    ] {
+        let args = [&["--verify-after-write"], args].concat();
+        assert_eq!(scan(&args, &[root.path()]).output.status.code(), Some(2));
+    }
+}
+
+/// Paths and hashes of every file in the numbered parts written by a spilling scan, sorted,
+/// along with the number of parts.
+fn spilled(scan: &Scan) -> (Vec<(String, String)>, usize) {
+    let mut hashes = vec![];
+    let mut parts = 0;
+    while scan.path(&format!("dexy.{:04}.json", parts + 1)).exists() {
+        parts += 1;
+        let part = scan.json(&format!("dexy.{:04}.json", parts));
+        let files = part["files"].as_object().unwrap();
+        for entry in entries(files) {
+            hashes.push((path_of(&entry), entry["hash"].as_str().unwrap().to_string()));
+        }
+    }
+    hashes.sort();
+    (hashes, parts)
+}
+
+/// A tree of a few hundred small files over several directories, some sharing contents.
+fn many_files() -> tempfile::TempDir {
+    let contents: Vec<(String, String)> = (0..300)
+        .map(|i| (format!("dir{}/file{}", i % 10, i), format!("{}", i % 40)))
+        .collect();
+    let files: Vec<(&str, &[u8])> = contents
+        .iter()
+        .map(|(path, contents)| (path.as_str(), contents.as_bytes()))
+        .collect();
+    tree(&files)
+}
+
+#[test]
+fn hashes_over_the_memory_limit_are_written_out_in_parts() {
+    let root = many_files();
+    let whole = scan_ok(&[], &[root.path()]);
+    let scan = scan_ok(&["--max-memory", "2K"], &[root.path()]);
+    assert!(!scan.path("dexy.json").exists());
+
+    let (hashes, parts) = spilled(&scan);
+    assert!(parts > 1, "only {} part written", parts);
+    // Every file is written exactly once, across all the parts
+    assert_eq!(hashes, whole.hashes());
+}
+
+#[test]
+fn a_scan_within_the_memory_limit_is_written_in_one_part() {
+    let root = many_files();
+    let scan = scan_ok(&["--max-memory", "1G"], &[root.path()]);
+    let (hashes, parts) = spilled(&scan);
+    assert_eq!(parts, 1);
+    assert_eq!(hashes.len(), 300);
+}
+
+#[test]
+fn an_empty_scan_still_writes_a_part() {
+    let root = tempdir();
+    let scan = scan_ok(&["--max-memory", "2K"], &[root.path()]);
+    assert_eq!(spilled(&scan), (vec![], 1));
+}
+
+#[test]
+fn spilling_needs_a_single_json_output() {
+    let root = tree(&[("a", b"a")]);
+    for args in [
+        &["--max-memory", "2K", "--format", "ndjson"][..],
+        &["--spill-interval", "1", "--verify-after-write"],
+        &["--max-memory", "2 apples"],
+    ] {
+        assert_eq!(scan(args, &[root.path()]).output.status.code(), Some(2));
+    }
+}
+
+/// Decompress the gzipped NDJSON at `path`, parsing every line.
+fn gzipped_entries(path: &std::path::Path) -> Vec<serde_json::Value> {
+    use std::io::BufRead;
+    let file = std::fs::File::open(path).unwrap();
+    std::io::BufReader::new(flate2::read::GzDecoder::new(file))
+        .lines()
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect()
+}
+
+#[test]
+fn compressed_ndjson_holds_a_line_per_file() {
+    let root = many_files();
+    let plain = scan_ok(&["--format", "ndjson"], &[root.path()]);
+    let compressed = scan_ok(
+        &["--format", "ndjson", "--compress", "gzip"],
+        &[root.path()],
+    );
+    assert!(!compressed.path("dexy.ndjson").exists());
+
+    let path_and_hash = |entries: Vec<serde_json::Value>| {
+        let mut hashes: Vec<(String, String)> = entries
+            .iter()
+            .map(|entry| (path_of(entry), entry["hash"].as_str().unwrap().to_string()))
+            .collect();
+        hashes.sort();
+        hashes
+    };
+    let entries = gzipped_entries(&compressed.path("dexy.ndjson.gz"));
+    assert_eq!(entries.len(), 300);
+    assert_eq!(
+        path_and_hash(entries),
+        path_and_hash(ndjson_entries(&plain.path("dexy.ndjson")))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_compressed_output_is_finished_properly() {
+    let root = slow_tree();
+    let out = tempdir();
+    // Hashing takes around three seconds at this rate
+    let mut child = dexy()
+        .args([
+            "--thread-count",
+            "1",
+            "--format",
+            "ndjson",
+            "--compress",
+            "gzip",
+        ])
+        .args(["--max-read-rate", "50000", "--out"])
+        .arg(out.path())
+        .arg(root.path())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    let interrupted = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+
+    // The stream is complete, with whatever was hashed before the interrupt
+    let entries = gzipped_entries(&out.path().join("dexy.ndjson.gz"));
+    assert!(!entries.is_empty() && entries.len() < 3, "{:?}", entries);
+}
+
+#[test]
+fn only_ndjson_can_be_compressed() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan(&["--compress", "gzip"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::*;
+
+const NFC: &str = "caf\u{e9}";
+const NFD: &str = "cafe\u{301}";
+
+#[test]
+fn nfc_and_nfd_names_coalesce_under_normalization() {
+    let root = tree(&[(NFC, b"same"), (NFD, b"same")]);
+
+    let normalized = scan_ok(&["--normalize-paths", "nfc"], &[root.path()]);
+    assert_eq!(normalized.entries().len(), 1);
+
+    let plain = scan_ok(&[], &[root.path()]);
+    assert_eq!(plain.entries().len(), 2);
+}
+
+#[test]
+fn normalization_only_coalesces_identical_contents() {
+    let root = tree(&[(NFC, b"one"), (NFD, b"two")]);
+    let scan = scan_ok(&["--normalize-paths", "nfc"], &[root.path()]);
+    assert_eq!(scan.entries().len(), 2);
+}
+
+#[test]
+fn names_differing_in_case_coalesce_under_case_folding() {
+    let root = tree(&[("Readme.md", b"same"), ("README.md", b"same")]);
+    let scan = scan_ok(&["--normalize-paths", "case"], &[root.path()]);
+    assert_eq!(scan.entries().len(), 1);
+}
+
+#[test]
+fn paths_over_the_maximum_length_are_skipped() {
+    let long = "d".repeat(100);
+    let root = tree(&[
+        ("short", b"short"),
+        (&format!("{}/nested", long), b"nested"),
+        (&format!("{}.txt", long), b"long"),
+    ]);
+    let limit = recorded(root.path(), "short").len() + 20;
+
+    let scan = scan_ok(&["--max-path-length", &limit.to_string()], &[root.path()]);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "short")]);
+
+    let unlimited = scan_ok(&[], &[root.path()]);
+    assert_eq!(unlimited.paths().len(), 3);
+}
+
+#[cfg(windows)]
+#[test]
+fn paths_longer_than_max_path_are_scanned() {
+    let deep: Vec<String> = (0..12)
+        .map(|i| format!("{:02}{}", i, "x".repeat(28)))
+        .collect();
+    let path = format!("{}/file", deep.join("/"));
+    let root = tree(&[(&path, b"deep")]);
+    assert!(root.path().join(&path).as_os_str().len() > 260);
+
+    let scan = scan_ok(&[], &[root.path()]);
+    assert_eq!(scan.hashes().len(), 1);
+    assert_eq!(scan.hashes()[0].1, sha256(b"deep"));
+    assert!(!scan.path("dexy.errors.json").exists());
+}
+
+#[test]
+fn paths_are_recorded_relative_to_a_chosen_base() {
+    let base = tree(&[("web/index.html", b"index"), ("api/v1/server", b"server")]);
+    let scan = scan_ok(
+        &["--emit-relative-to", base.path().to_str().unwrap()],
+        &[&base.path().join("web"), &base.path().join("api")],
+    );
+    assert_eq!(
+        scan.hashes(),
+        vec![
+            ("api/v1/server".to_string(), sha256(b"server")),
+            ("web/index.html".to_string(), sha256(b"index")),
+        ]
+    );
+}
+
+#[test]
+fn start_directories_outside_the_base_are_rejected() {
+    let base = tree(&[("inside/a", b"a")]);
+    let outside = tree(&[("b", b"b")]);
+    let scan = scan(
+        &["--emit-relative-to", base.path().to_str().unwrap()],
+        &[&base.path().join("inside"), outside.path()],
+    );
+    assert_eq!(scan.output.status.code(), Some(2));
+    assert!(
+        scan.stderr().contains(&format!(
+            "start directory {} isn't inside --emit-relative-to",
+            recorded(outside.path(), "").trim_end_matches('/')
+        )),
+        "{}",
+        scan.stderr()
+    );
+    assert!(!scan.path("dexy.json").exists());
+}
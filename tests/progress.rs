@@ -0,0 +1,191 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn invalid_templates_are_rejected_before_scanning() {
+    let root = tree(&[("file", b"contents")]);
+    for option in ["--bar-template", "--spinner-template"] {
+        let scan = scan(&[option, "{wide_bar"], &[root.path()]);
+        assert_eq!(scan.output.status.code(), Some(2));
+        assert!(
+            scan.stderr().contains("unbalanced braces"),
+            "{}",
+            scan.stderr()
+        );
+        assert!(!scan.stderr().contains("panicked"));
+        assert!(!scan.path("dexy.json").exists());
+    }
+}
+
+#[test]
+fn valid_templates_and_presets_scan_as_usual() {
+    let root = tree(&[("file", b"contents")]);
+    for args in [
+        &["--bar-template", "{bar} {pos}/{len} {msg}"][..],
+        &["--spinner-template", "{spinner} {msg}"],
+        &["--progress-style", "throughput"],
+    ] {
+        let scan = scan_ok(args, &[root.path()]);
+        assert_eq!(scan.hashes()[0].1, sha256(b"contents"));
+    }
+}
+
+#[test]
+fn byte_progress_reaches_the_size_of_the_tree() {
+    let root = tree(&[
+        ("a", &[1; 1000]),
+        ("sub/b", &[2; 2345]),
+        ("sub/deeper/c", &[3; 17]),
+    ]);
+    let dir = tempdir();
+    let events = dir.path().join("events.ndjson");
+    scan_ok(
+        &[
+            "--progress-by",
+            "bytes",
+            "--events-file",
+            events.to_str().unwrap(),
+        ],
+        &[root.path()],
+    );
+
+    let progress: Vec<_> = read_events(&events)
+        .into_iter()
+        .filter(|event| event["type"] == "progress")
+        .collect();
+    let last = progress.last().unwrap();
+    assert_eq!(last["total"], 1000 + 2345 + 17);
+    assert_eq!(last["done"], last["total"]);
+    assert!(progress.iter().all(|event| event["total"] == last["total"]));
+}
+
+/// The JSON progress lines written to stderr with `--progress-style json-lines`.
+fn progress_lines(scan: &Scan) -> Vec<serde_json::Value> {
+    scan.stderr()
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn file_and_byte_counters_advance_at_a_nonzero_rate() {
+    let root = tree(&[
+        ("a", &[1; 40_000]),
+        ("b", &[2; 40_000]),
+        ("c", &[3; 40_000]),
+    ]);
+    let scan = scan_ok(
+        &["--progress-style", "json-lines", "--max-read-rate", "50000"],
+        &[root.path()],
+    );
+
+    let lines = progress_lines(&scan);
+    assert!(lines.len() >= 2, "{}", scan.stderr());
+    for pair in lines.windows(2) {
+        assert!(pair[1]["files"].as_u64() >= pair[0]["files"].as_u64());
+        assert!(pair[1]["bytes"].as_u64() >= pair[0]["bytes"].as_u64());
+    }
+    let last = lines.last().unwrap();
+    assert_eq!(last["files"], 3);
+    assert_eq!(last["bytes"], 120_000);
+    assert!(lines
+        .iter()
+        .any(|line| line["rate_mb_s"].as_f64().unwrap() > 0.0));
+}
+
+#[test]
+fn progress_log_gets_periodic_timestamped_lines() {
+    let root = tree(&[("a", &[1; 300_000])]);
+    let dir = tempdir();
+    let log = dir.path().join("progress.log");
+    // Slowed down so a periodic line is written before the final one
+    scan_ok(
+        &[
+            "--progress-log",
+            log.to_str().unwrap(),
+            "--max-read-rate",
+            "50000",
+        ],
+        &[root.path()],
+    );
+
+    let log = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert!(lines.len() >= 2, "{}", log);
+    for line in &lines {
+        let (timestamp, fields) = line.split_once(' ').unwrap();
+        assert!(humantime::parse_rfc3339(timestamp).is_ok(), "{}", line);
+        for field in ["files=", "bytes=", "rate=", "eta="] {
+            assert!(fields.contains(field), "{}", line);
+        }
+    }
+    let last = lines.last().unwrap();
+    assert!(last.contains(" files=1 bytes=300000 "), "{}", last);
+    assert!(last.ends_with(" eta=done"), "{}", last);
+}
+
+#[test]
+fn refresh_rates_must_be_positive_numbers() {
+    let root = tree(&[("file", b"contents")]);
+    for rate in ["0", "fast"] {
+        let scan = scan(&["--progress-refresh-rate", rate], &[root.path()]);
+        assert_eq!(scan.output.status.code(), Some(2), "{}", rate);
+        assert!(scan.stderr().contains("--progress-refresh-rate"));
+        assert!(!scan.path("dexy.json").exists());
+    }
+}
+
+#[test]
+fn any_positive_refresh_rate_scans_as_usual() {
+    let root = tree(&[("file", b"contents")]);
+    for rate in ["1", "20", "1000"] {
+        let scan = scan_ok(&["--progress-refresh-rate", rate], &[root.path()]);
+        assert_eq!(scan.hashes()[0].1, sha256(b"contents"));
+    }
+}
+
+#[test]
+fn json_lines_progress_stops_once_finished() {
+    let root = tree(&[("a", &[1; 40_000]), ("sub/b", &[2; 40_000])]);
+    let scan = scan_ok(
+        &["--progress-style", "json-lines", "--max-read-rate", "40000"],
+        &[root.path()],
+    );
+
+    // Nothing is drawn besides the lines of JSON
+    let stderr = scan.stderr();
+    assert!(
+        stderr.lines().all(|line| line.starts_with('{')),
+        "{}",
+        stderr
+    );
+    let lines = progress_lines(&scan);
+    for line in &lines {
+        for key in ["done", "total", "files", "bytes", "rate_mb_s", "finished"] {
+            assert!(line.get(key).is_some(), "{} missing from {}", key, line);
+        }
+    }
+    let finished: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line["finished"] == true)
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(finished, vec![lines.len() - 1], "{}", scan.stderr());
+    let last = lines.last().unwrap();
+    assert_eq!(last["done"], last["total"]);
+    assert_eq!(last["files"], 2);
+}
+
+#[test]
+fn eta_smoothing_must_be_between_zero_and_one() {
+    let root = tree(&[("file", b"contents")]);
+    for factor in ["0", "1.5", "steady"] {
+        let scan = scan(&["--eta-smoothing", factor], &[root.path()]);
+        assert_eq!(scan.output.status.code(), Some(2), "{}", factor);
+        assert!(scan.stderr().contains("isn't a number between 0 and 1"));
+    }
+    scan_ok(&["--eta-smoothing", "0.5"], &[root.path()]);
+}
@@ -0,0 +1,593 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn only_duplicates_keeps_whole_duplicate_groups() {
+    let root = tree(&[
+        ("a", b"shared"),
+        ("sub/b", b"shared"),
+        ("c", b"shared"),
+        ("single", b"single"),
+        ("pair1", b"pair"),
+        ("pair2", b"pair"),
+    ]);
+    let scan = scan_ok(&["--only-duplicates"], &[root.path()]);
+    let files = scan.files();
+
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[&sha256(b"shared")].as_array().unwrap().len(), 3);
+    assert_eq!(files[&sha256(b"pair")].as_array().unwrap().len(), 2);
+    assert!(!files.contains_key(&sha256(b"single")));
+}
+
+#[test]
+fn empty_files_are_counted_in_the_summary() {
+    let root = tree(&[("e1", b""), ("sub/e2", b""), ("e3", b""), ("x", b"x")]);
+
+    let (scan, summary) = scan_summary(&[], &[root.path()]);
+    assert_eq!(summary["empty_files"], 3);
+    assert_eq!(summary["files"], 4);
+    // Every empty file has the same hash, so they're grouped together
+    assert_eq!(scan.files()[&sha256(b"")].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn ignored_empty_files_are_still_counted() {
+    let root = tree(&[("e1", b""), ("e2", b""), ("x", b"x")]);
+    let (scan, summary) = scan_summary(&["--ignore-empty"], &[root.path()]);
+    assert_eq!(summary["empty_files"], 2);
+    assert_eq!(summary["files"], 1);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "x")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn identical_unlinked_files_are_reported_as_reclaimable() {
+    let contents = vec![7; 5000];
+    let root = tree(&[("a", &contents), ("b", &contents), ("unique", b"unique")]);
+    let scan = scan_ok(&["--hardlink-report"], &[root.path()]);
+    let report = scan.json("dexy.hardlink-report.json");
+
+    assert_eq!(report["reclaimable_bytes"], 5000);
+    let groups = report["groups"].as_array().unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0]["hash"], sha256(&contents));
+    assert_eq!(groups[0]["files"].as_array().unwrap().len(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn files_already_hardlinked_reclaim_nothing() {
+    let root = tree(&[("a", &[7; 5000])]);
+    std::fs::hard_link(root.path().join("a"), root.path().join("b")).unwrap();
+    let scan = scan_ok(&["--hardlink-report"], &[root.path()]);
+    let report = scan.json("dexy.hardlink-report.json");
+    assert_eq!(report["reclaimable_bytes"], 0);
+}
+
+#[test]
+fn files_in_the_reference_manifest_are_left_out() {
+    let archive = tree(&[("kept", b"shared"), ("other", b"other")]);
+    let reference = scan_ok(&[], &[archive.path()]);
+    let incoming = tree(&[("copy", b"shared"), ("new", b"new")]);
+
+    let manifest = reference.path("dexy.json");
+    let scan = scan_ok(
+        &["--dedup-against", manifest.to_str().unwrap()],
+        &[incoming.path()],
+    );
+    assert_eq!(scan.paths(), vec![recorded(incoming.path(), "new")]);
+
+    let excluded = scan.json("dexy.excluded.json");
+    let excluded = excluded.as_array().unwrap();
+    assert_eq!(excluded.len(), 1);
+    assert_eq!(path_of(&excluded[0]), recorded(incoming.path(), "copy"));
+    assert_eq!(excluded[0]["hash"], sha256(b"shared"));
+}
+
+#[test]
+fn reference_manifests_can_be_raw() {
+    let archive = tree(&[("kept", b"shared")]);
+    let reference = scan_ok(&["--raw"], &[archive.path()]);
+    let incoming = tree(&[("copy", b"shared"), ("new", b"new")]);
+
+    let manifest = reference.path("dexy.json");
+    let scan = scan_ok(
+        &["--dedup-against", manifest.to_str().unwrap()],
+        &[incoming.path()],
+    );
+    assert_eq!(scan.paths(), vec![recorded(incoming.path(), "new")]);
+}
+
+/// A tree with a file on its own and groups of two and five files sharing contents.
+fn duplicated_tree() -> tempfile::TempDir {
+    tree(&[
+        ("single", b"single"),
+        ("pair/1", b"pair"),
+        ("pair/2", b"pair"),
+        ("five/1", b"five"),
+        ("five/2", b"five"),
+        ("five/3", b"five"),
+        ("five/4", b"five"),
+        ("five/5", b"five"),
+    ])
+}
+
+/// The number of files under each hash in `scan`.
+fn group_sizes(scan: &Scan) -> std::collections::BTreeMap<String, usize> {
+    scan.files()
+        .iter()
+        .map(|(hash, files)| (hash.clone(), files.as_array().unwrap().len()))
+        .collect()
+}
+
+#[test]
+fn only_groups_with_enough_duplicates_are_output() {
+    let root = duplicated_tree();
+    let scan = scan_ok(
+        &["--only-duplicates", "--min-duplicate-count", "3"],
+        &[root.path()],
+    );
+    assert_eq!(
+        group_sizes(&scan),
+        [(sha256(b"five"), 5)].into_iter().collect()
+    );
+}
+
+#[test]
+fn every_duplicate_is_output_by_default() {
+    let root = duplicated_tree();
+    let scan = scan_ok(&["--only-duplicates"], &[root.path()]);
+    assert_eq!(
+        group_sizes(&scan),
+        [(sha256(b"five"), 5), (sha256(b"pair"), 2)]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn staged_duplicates_share_their_whole_contents() {
+    // The same size and the same first and last 64 KiB, but not the same in the middle
+    let first: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    let mut second = first.clone();
+    second[150_000] ^= 0xff;
+    let root = tree(&[
+        ("first", &first),
+        ("second", &second),
+        ("copy1", &first[..200_000]),
+        ("sub/copy2", &first[..200_000]),
+        ("pair1", b"pair"),
+        ("pair2", b"pair"),
+        ("unique", b"uniq"),
+    ]);
+
+    let staged = scan_ok(
+        &["--only-duplicates", "--staged-duplicates"],
+        &[root.path()],
+    );
+    assert_eq!(
+        group_sizes(&staged),
+        [(sha256(&first[..200_000]), 2), (sha256(b"pair"), 2)]
+            .into_iter()
+            .collect()
+    );
+    let hashed = scan_ok(&["--only-duplicates"], &[root.path()]);
+    assert_eq!(staged.entries(), hashed.entries());
+}
+
+#[test]
+fn staged_duplicates_cant_hash_the_whole_tree() {
+    let root = duplicated_tree();
+    for whole_tree in ["--fingerprint", "--directory-hashes"] {
+        let scan = scan(
+            &["--only-duplicates", "--staged-duplicates", whole_tree],
+            &[root.path()],
+        );
+        assert_eq!(scan.output.status.code(), Some(2), "{}", whole_tree);
+    }
+}
+
+#[test]
+fn min_duplicate_count_needs_only_duplicates() {
+    let root = duplicated_tree();
+    let scan = scan(&["--min-duplicate-count", "3"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
+
+/// Several empty files alongside a real pair of duplicates and a unique file.
+fn tree_with_empty_files() -> tempfile::TempDir {
+    tree(&[
+        ("e1", b""),
+        ("e2", b""),
+        ("sub/e3", b""),
+        ("sub/e4", b""),
+        ("pair1", b"pair"),
+        ("sub/pair2", b"pair"),
+        ("unique", b"unique"),
+    ])
+}
+
+#[test]
+fn empty_files_are_not_reported_as_duplicates() {
+    let root = tree_with_empty_files();
+    let scan = scan_ok(&["--only-duplicates"], &[root.path()]);
+    assert_eq!(
+        group_sizes(&scan),
+        [(sha256(b"pair"), 2)].into_iter().collect()
+    );
+}
+
+#[test]
+fn empty_files_can_be_listed_apart_from_duplicates() {
+    let root = tree_with_empty_files();
+    let scan = scan_ok(
+        &["--only-duplicates", "--dedup-zero-length-separately"],
+        &[root.path()],
+    );
+    assert_eq!(
+        group_sizes(&scan),
+        [("empty".to_string(), 4), (sha256(b"pair"), 2)]
+            .into_iter()
+            .collect()
+    );
+
+    let everything = scan_ok(&["--dedup-zero-length-separately"], &[root.path()]);
+    let groups = group_sizes(&everything);
+    assert_eq!(groups["empty"], 4);
+    assert!(!groups.contains_key(&sha256(b"")));
+    assert_eq!(groups[&sha256(b"unique")], 1);
+}
+
+/// Files of known sizes, with the largest two in a subdirectory.
+fn sized_tree() -> tempfile::TempDir {
+    tree(&[
+        ("tiny", &[1; 10]),
+        ("small", &[2; 500]),
+        ("sub/large", &[3; 5000]),
+        ("medium", &[4; 1000]),
+        ("sub/huge", &[5; 9000]),
+    ])
+}
+
+/// The `(path, size)` of each file in `{name}.largest.json`, in the order listed.
+fn largest(scan: &Scan) -> Vec<(String, u64)> {
+    scan.json("dexy.largest.json")
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| (path_of(file), file["size"].as_u64().unwrap()))
+        .collect()
+}
+
+#[test]
+fn the_largest_files_are_reported_largest_first() {
+    let root = sized_tree();
+    let scan = scan_ok(&["--report-largest", "3"], &[root.path()]);
+    assert_eq!(
+        largest(&scan),
+        vec![
+            (recorded(root.path(), "sub/huge"), 9000),
+            (recorded(root.path(), "sub/large"), 5000),
+            (recorded(root.path(), "medium"), 1000),
+        ]
+    );
+    let report = scan.json("dexy.largest.json");
+    assert_eq!(report[0]["hash"], sha256(&[5; 9000]));
+    // Sizes are needed for the report, so they're collected for the output too
+    assert_eq!(scan.entries()[0]["attributes"]["size"], 1000);
+}
+
+#[test]
+fn every_file_is_reported_when_there_are_fewer_than_asked_for() {
+    let root = sized_tree();
+    let scan = scan_ok(&["--report-largest", "100"], &[root.path()]);
+    let sizes: Vec<u64> = largest(&scan).into_iter().map(|(_, size)| size).collect();
+    assert_eq!(sizes, vec![9000, 5000, 1000, 500, 10]);
+}
+
+#[test]
+fn at_least_one_largest_file_must_be_asked_for() {
+    let root = sized_tree();
+    let scan = scan(&["--report-largest", "0"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+    assert!(!scan.path("dexy.largest.json").exists());
+}
+
+#[test]
+fn each_file_records_how_long_it_took_to_hash() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b")]);
+    let scan = scan_ok(&["--profile-files"], &[root.path()]);
+    for entry in scan.entries() {
+        assert!(entry["hash_duration_ms"].is_u64(), "{}", entry);
+    }
+
+    let unprofiled = scan_ok(&[], &[root.path()]);
+    assert!(unprofiled.entries()[0].get("hash_duration_ms").is_none());
+}
+
+#[test]
+fn deliberately_slow_files_top_the_slowest_report() {
+    let root = tree(&[
+        ("quick1", b"1"),
+        ("quick2", b"2"),
+        ("sub/slow", &[0; 150_000]),
+        ("quick3", b"3"),
+    ]);
+    // The slow file alone takes more than a second to read at this rate
+    let scan = scan_ok(
+        &[
+            "--profile-files",
+            "--report-slowest",
+            "2",
+            "--max-read-rate",
+            "100000",
+        ],
+        &[root.path()],
+    );
+    let report = scan.json("dexy.slowest.json");
+    let report = report.as_array().unwrap();
+    assert_eq!(report.len(), 2);
+    assert_eq!(path_of(&report[0]), recorded(root.path(), "sub/slow"));
+    assert_eq!(report[0]["hash"], sha256(&[0; 150_000]));
+    let slowest = report[0]["hash_duration_ms"].as_u64().unwrap();
+    assert!(slowest >= 500, "{}", slowest);
+    assert!(report[1]["hash_duration_ms"].as_u64().unwrap() < slowest);
+}
+
+#[test]
+fn the_slowest_report_needs_profiling() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan(&["--report-slowest", "2"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
+
+/// Scan `root` with `--update-existing --prune-unchanged` into `out`, which may hold a
+/// previous scan.
+fn pruned_scan(root: &std::path::Path, out: tempfile::TempDir) -> Scan {
+    let output = dexy()
+        .args(["--update-existing", "--prune-unchanged", "--out"])
+        .arg(out.path())
+        .arg(root)
+        .output()
+        .unwrap();
+    let scan = Scan { out, output };
+    assert!(scan.success(), "dexy failed: {}", scan.stderr());
+    scan
+}
+
+/// The paths listed in `dexy.removed.json`, sorted.
+fn removed(scan: &Scan) -> Vec<String> {
+    let mut removed: Vec<String> = scan
+        .json("dexy.removed.json")
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|path| path.as_str().unwrap().to_string())
+        .collect();
+    removed.sort();
+    removed
+}
+
+#[test]
+fn only_changed_and_new_files_are_kept_when_pruning() {
+    let root = tree(&[
+        ("same", b"same"),
+        ("modified", b"before"),
+        ("sub/gone", b"gone"),
+        ("sub/kept", b"kept"),
+    ]);
+    let first = pruned_scan(root.path(), tempdir());
+    // Everything is new to the first scan
+    let changed = first.json("dexy.changed.json");
+    assert_eq!(entries(changed["files"].as_object().unwrap()).len(), 4);
+    assert!(removed(&first).is_empty());
+
+    write(root.path(), "modified", b"after");
+    write(root.path(), "sub/new", b"new");
+    std::fs::remove_file(root.path().join("sub/gone")).unwrap();
+    let second = pruned_scan(root.path(), first.out);
+
+    let changed = second.json("dexy.changed.json");
+    let changed: Vec<(String, String)> = entries(changed["files"].as_object().unwrap())
+        .iter()
+        .map(|entry| (path_of(entry), entry["hash"].as_str().unwrap().to_string()))
+        .collect();
+    assert_eq!(
+        changed,
+        vec![
+            (recorded(root.path(), "modified"), sha256(b"after")),
+            (recorded(root.path(), "sub/new"), sha256(b"new")),
+        ]
+    );
+    assert_eq!(removed(&second), vec![recorded(root.path(), "sub/gone")]);
+    // The full manifest is still written alongside
+    assert_eq!(second.entries().len(), 4);
+}
+
+#[test]
+fn pruning_needs_update_existing() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan(&["--prune-unchanged"], &[root.path()]);
+    assert_eq!(scan.output.status.code(), Some(2));
+}
+
+/// Two copies of the same subtree, and a directory which differs from them by one file.
+fn mirrored_tree() -> tempfile::TempDir {
+    tree(&[
+        ("a/x", b"x"),
+        ("a/sub/y", b"y"),
+        ("a/sub/deeper/z", b"z"),
+        ("b/x", b"x"),
+        ("b/sub/y", b"y"),
+        ("b/sub/deeper/z", b"z"),
+        ("c/x", b"x"),
+        ("c/sub/y", b"changed"),
+        ("c/sub/deeper/z", b"z"),
+    ])
+}
+
+/// The hash of the directory at `path` below `root` in `scan`'s directory hashes, `""` being
+/// `root` itself.
+fn dir_hash(scan: &Scan, root: &std::path::Path, path: &str) -> String {
+    let hashes = scan.json("dexy.dir-hashes.json");
+    let path = recorded(root, path);
+    let path = path.trim_end_matches(std::path::MAIN_SEPARATOR);
+    hashes[path]
+        .as_str()
+        .unwrap_or_else(|| panic!("no hash for {} in {}", path, hashes))
+        .to_string()
+}
+
+#[test]
+fn identical_subtrees_share_a_directory_hash() {
+    let root = mirrored_tree();
+    let scan = scan_ok(&["--directory-hashes"], &[root.path()]);
+    for dir in ["", "sub", "sub/deeper"] {
+        let (a, b) = (format!("a/{}", dir), format!("b/{}", dir));
+        assert_eq!(
+            dir_hash(&scan, root.path(), &a),
+            dir_hash(&scan, root.path(), &b),
+            "{}",
+            dir
+        );
+    }
+    // Only the directories which hold the changed file differ
+    assert_eq!(
+        dir_hash(&scan, root.path(), "c/sub/deeper"),
+        dir_hash(&scan, root.path(), "a/sub/deeper")
+    );
+    for dir in ["c/sub", "c"] {
+        assert_ne!(
+            dir_hash(&scan, root.path(), dir),
+            dir_hash(&scan, root.path(), &dir.replacen('c', "a", 1)),
+            "{}",
+            dir
+        );
+    }
+}
+
+#[test]
+fn changing_a_file_changes_every_directory_above_it() {
+    let root = mirrored_tree();
+    let before = scan_ok(&["--directory-hashes"], &[root.path()]);
+    write(root.path(), "a/sub/deeper/z", b"changed");
+    let after = scan_ok(&["--directory-hashes"], &[root.path()]);
+
+    for dir in ["a/sub/deeper", "a/sub", "a", ""] {
+        assert_ne!(
+            dir_hash(&before, root.path(), dir),
+            dir_hash(&after, root.path(), dir),
+            "{}",
+            dir
+        );
+    }
+    for dir in ["b", "b/sub", "c", "c/sub/deeper"] {
+        assert_eq!(
+            dir_hash(&before, root.path(), dir),
+            dir_hash(&after, root.path(), dir),
+            "{}",
+            dir
+        );
+    }
+}
+
+/// Files of a small tree, to be written out in several places.
+const FINGERPRINTED: [(&str, &[u8]); 4] = [
+    ("a", b"a"),
+    ("sub/b", b"b"),
+    ("sub/deeper/c", b"c"),
+    ("copy", b"a"),
+];
+
+fn fingerprint(root: &std::path::Path) -> String {
+    let scan = scan_ok(&["--fingerprint"], &[root]);
+    let fingerprint = scan.json("dexy.json")["fingerprint"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(
+        scan.stdout()
+            .contains(&format!("fingerprint: {}", fingerprint)),
+        "{}",
+        scan.stdout()
+    );
+    fingerprint
+}
+
+#[test]
+fn the_same_tree_anywhere_has_the_same_fingerprint() {
+    let first = tree(&FINGERPRINTED);
+    let elsewhere = tempdir();
+    for (path, contents) in FINGERPRINTED {
+        write(elsewhere.path(), &format!("nested/copy/{}", path), contents);
+    }
+    assert_eq!(
+        fingerprint(first.path()),
+        fingerprint(&elsewhere.path().join("nested/copy"))
+    );
+}
+
+#[test]
+fn changing_any_file_changes_the_fingerprint() {
+    let original = fingerprint(tree(&FINGERPRINTED).path());
+    for changed in 0..FINGERPRINTED.len() {
+        let mut files = FINGERPRINTED;
+        files[changed].1 = b"changed";
+        assert_ne!(
+            fingerprint(tree(&files).path()),
+            original,
+            "{}",
+            files[changed].0
+        );
+    }
+    // Moving a file changes it too, even with the same contents
+    let mut files = FINGERPRINTED;
+    files[1].0 = "sub/moved";
+    assert_ne!(fingerprint(tree(&files).path()), original);
+}
+
+#[cfg(feature = "images")]
+#[test]
+fn scaled_copies_of_an_image_are_reported_as_similar() {
+    use image::{imageops, GrayImage, Luma};
+    let hills = GrayImage::from_fn(160, 120, |x, y| {
+        let shade = 0.5 + 0.4 * (x as f64 / 18.0).sin() * (y as f64 / 24.0 + 1.0).cos();
+        Luma([(shade * 255.0) as u8])
+    });
+    let stripes = GrayImage::from_fn(160, 120, |x, y| Luma([((x + 2 * y) / 8 % 2 * 200) as u8]));
+    let root = tempdir();
+    std::fs::create_dir(root.path().join("sub")).unwrap();
+    hills.save(root.path().join("hills.png")).unwrap();
+    imageops::resize(&hills, 320, 240, imageops::FilterType::Lanczos3)
+        .save(root.path().join("sub/larger.jpg"))
+        .unwrap();
+    stripes.save(root.path().join("stripes.png")).unwrap();
+
+    let scan = scan_ok(
+        &["--perceptual-hash", "--report-similar-images", "4"],
+        &[root.path()],
+    );
+    let groups = scan.json("dexy.similar-images.json");
+    let paths: Vec<Vec<&str>> = groups
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|group| {
+            group
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|image| image["path"].as_str().unwrap())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        paths,
+        vec![vec![
+            recorded(root.path(), "hills.png"),
+            recorded(root.path(), "sub/larger.jpg"),
+        ]]
+    );
+}
@@ -0,0 +1,135 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use serde_json::Value;
+use std::{
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn killed_then_resumed_scan_matches_an_uninterrupted_one() {
+    let contents: Vec<Vec<u8>> = (0..30u8).map(|i| vec![i; 10_000]).collect();
+    let paths: Vec<String> = (0..30).map(|i| format!("dir{:02}/file", i)).collect();
+    let files: Vec<(&str, &[u8])> = paths
+        .iter()
+        .zip(&contents)
+        .map(|(path, contents)| (path.as_str(), contents.as_slice()))
+        .collect();
+    let root = tree(&files);
+    let expected = scan_ok(&[], &[root.path()]).hashes();
+
+    // Slow enough that the first checkpoint is written long before the scan could finish
+    let out = tempdir();
+    let mut child = dexy()
+        .args(["--resume", "--max-read-rate", "10000", "--out"])
+        .arg(out.path())
+        .arg(root.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let checkpoint = out.path().join("dexy.checkpoint");
+    let started = Instant::now();
+    while !checkpoint.exists() {
+        assert!(started.elapsed() < Duration::from_secs(25), "no checkpoint");
+        assert!(child.try_wait().unwrap().is_none(), "finished early");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(!out.path().join("dexy.json").exists());
+    let saved: Value = serde_json::from_slice(&std::fs::read(&checkpoint).unwrap()).unwrap();
+    let completed = saved["completed"].as_array().unwrap().len();
+    assert!(completed > 0 && completed < 31, "{} completed", completed);
+
+    let resumed = dexy()
+        .arg("--resume")
+        .arg("--out")
+        .arg(out.path())
+        .arg(root.path())
+        .output()
+        .unwrap();
+    assert!(resumed.status.success());
+    let resumed = Scan {
+        out,
+        output: resumed,
+    };
+    assert_eq!(resumed.hashes(), expected);
+    assert!(!checkpoint.exists());
+}
+
+/// Send the signal `name`, e.g. `USR1`, to `child`.
+fn signal(child: &std::process::Child, name: &str) {
+    let sent = std::process::Command::new("kill")
+        .arg(format!("-{}", name))
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(sent.success());
+}
+
+/// Number of files hashed so far according to the events written to `events`.
+fn files_hashed(events: &std::path::Path) -> usize {
+    std::fs::read_to_string(events)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| line.contains("\"type\":\"file\""))
+        .count()
+}
+
+#[test]
+fn paused_scans_make_no_progress_until_resumed() {
+    let contents: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 10_000]).collect();
+    let paths: Vec<String> = (0..20).map(|i| format!("dir{:02}/file", i)).collect();
+    let files: Vec<(&str, &[u8])> = paths
+        .iter()
+        .zip(&contents)
+        .map(|(path, contents)| (path.as_str(), contents.as_slice()))
+        .collect();
+    let root = tree(&files);
+    let expected = scan_ok(&[], &[root.path()]).hashes();
+
+    // Around four seconds of hashing, one directory at a time
+    let out = tempdir();
+    let events = out.path().join("events.ndjson");
+    let mut child = dexy()
+        .args(["--thread-count", "1", "--max-read-rate", "50000"])
+        .arg("--events-file")
+        .arg(&events)
+        .arg("--out")
+        .arg(out.path())
+        .arg(root.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while files_hashed(&events) == 0 {
+        assert!(
+            started.elapsed() < Duration::from_secs(25),
+            "nothing hashed"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    signal(&child, "USR1");
+    // The directory being worked on when paused is still finished
+    std::thread::sleep(Duration::from_millis(500));
+    let paused_at = files_hashed(&events);
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(files_hashed(&events), paused_at);
+    assert!(paused_at < 20, "finished before pausing");
+    assert!(child.try_wait().unwrap().is_none());
+
+    signal(&child, "USR2");
+    assert!(child.wait().unwrap().success());
+    assert_eq!(files_hashed(&events), 20);
+    let resumed = Scan {
+        out,
+        output: child.wait_with_output().unwrap(),
+    };
+    assert_eq!(resumed.hashes(), expected);
+}
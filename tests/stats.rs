@@ -0,0 +1,115 @@
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+
+/// Run `dexy stats --json` on the manifest written by `scan`.
+fn stats(scan: &Scan) -> Value {
+    let output = dexy()
+        .args(["stats", "--json"])
+        .arg(scan.path("dexy.json"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn duplicates_and_sizes_are_totalled() {
+    let root = tree(&[
+        ("a.txt", b"same"),
+        ("b.txt", b"same"),
+        ("sub/c.md", b"same"),
+        ("d.txt", b"pair"),
+        ("e", b"pair"),
+        ("big.bin", &[1; 2000]),
+        ("empty", b""),
+    ]);
+    let scan = scan_ok(&["--load-file-attributes"], &[root.path()]);
+
+    let stats = stats(&scan);
+    assert_eq!(stats["files"], 7);
+    assert_eq!(stats["unique_hashes"], 4);
+    assert_eq!(stats["duplicate_groups"], 2);
+    assert_eq!(
+        stats["largest_duplicate_group"],
+        json!([sha256(b"same"), 3])
+    );
+    assert_eq!(
+        stats["extensions"],
+        json!({"txt": 3, "md": 1, "bin": 1, "": 2})
+    );
+    assert_eq!(stats["sizes"]["total_bytes"], 3 * 4 + 2 * 4 + 2000);
+    assert_eq!(stats["sizes"]["unique_bytes"], 4 + 4 + 2000);
+    assert_eq!(
+        stats["sizes"]["buckets"],
+        json!([
+            ["empty", 1],
+            ["< 1 KiB", 5],
+            ["< 1 MiB", 1],
+            ["< 1 GiB", 0],
+            [">= 1 GiB", 0]
+        ])
+    );
+}
+
+#[test]
+fn sizes_are_left_out_without_attributes() {
+    let root = tree(&[("a", b"same"), ("b", b"same")]);
+    let scan = scan_ok(&[], &[root.path()]);
+
+    let stats = stats(&scan);
+    assert_eq!(stats["files"], 2);
+    assert_eq!(stats["duplicate_groups"], 1);
+    assert!(stats["sizes"].is_null());
+}
+
+#[test]
+fn readable_stats_are_printed_by_default() {
+    let root = tree(&[("a", b"same"), ("b", b"same")]);
+    let scan = scan_ok(&[], &[root.path()]);
+    let output = dexy()
+        .arg("stats")
+        .arg(scan.path("dexy.json"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("files: 2"), "{}", stdout);
+    assert!(stdout.contains("duplicate groups: 1"), "{}", stdout);
+}
+
+#[test]
+fn bare_maps_are_read_as_version_zero() {
+    let root = tree(&[("a", b"same"), ("b", b"same")]);
+    let current = scan_ok(&[], &[root.path()]);
+    let bare = scan_ok(&["--raw"], &[root.path()]);
+
+    assert_eq!(current.json("dexy.json")["schema_version"], 1);
+    assert_eq!(stats(&current)["schema_version"], 1);
+    assert!(bare.json("dexy.json").get("schema_version").is_none());
+    assert_eq!(stats(&bare)["schema_version"], 0);
+    assert_eq!(stats(&bare)["files"], stats(&current)["files"]);
+}
+
+#[test]
+fn newer_schema_versions_are_refused() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan_ok(&[], &[root.path()]);
+    let mut manifest = scan.json("dexy.json");
+    manifest["schema_version"] = 99.into();
+    std::fs::write(scan.path("dexy.json"), manifest.to_string()).unwrap();
+
+    for command in ["stats", "verify", "validate"] {
+        let output = dexy()
+            .arg(command)
+            .arg(scan.path("dexy.json"))
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{}", command);
+        // `verify` and `validate` report it as unreadable, `stats` fails to load it
+        let printed = [output.stdout, output.stderr].concat();
+        let printed = String::from_utf8_lossy(&printed);
+        assert!(printed.contains("newer version of dexy"), "{}", printed);
+    }
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::*;
+
+/// Scan a file with an additional stream written by `add_stream`, returning the streams
+/// recorded for it.
+#[cfg(any(windows, target_os = "macos"))]
+fn recorded_streams(add_stream: impl Fn(&std::path::Path)) -> serde_json::Value {
+    let root = tree(&[("file", b"contents")]);
+    add_stream(&root.path().join("file"));
+    let scan = scan_ok(&["--include-streams"], &[root.path()]);
+    let entry = &scan.entries()[0];
+    // The stream doesn't change the hash of the contents
+    assert_eq!(entry["hash"], sha256(b"contents"));
+    entry["streams"].clone()
+}
+
+#[cfg(windows)]
+#[test]
+fn alternate_data_streams_are_hashed() {
+    let streams = recorded_streams(|file| {
+        let mut stream = file.as_os_str().to_owned();
+        stream.push(":extra");
+        std::fs::write(stream, b"hidden").unwrap();
+    });
+    assert_eq!(streams[":extra:$DATA"], sha256(b"hidden"));
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn resource_forks_are_hashed() {
+    let streams = recorded_streams(|file| {
+        std::fs::write(file.join("..namedfork/rsrc"), b"hidden").unwrap();
+    });
+    assert_eq!(streams["rsrc"], sha256(b"hidden"));
+}
+
+#[test]
+fn files_without_streams_record_none() {
+    let root = tree(&[("file", b"contents")]);
+    let scan = scan_ok(&["--include-streams"], &[root.path()]);
+    let entry = &scan.entries()[0];
+    assert_eq!(entry["hash"], sha256(b"contents"));
+    assert!(entry.get("streams").is_none());
+}
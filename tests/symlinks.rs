@@ -0,0 +1,213 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use std::os::unix::fs::symlink;
+
+#[test]
+fn links_are_identified_by_their_target() {
+    let root = tree(&[("one", b"same"), ("two", b"same")]);
+    symlink("one", root.path().join("first")).unwrap();
+    symlink("one", root.path().join("second")).unwrap();
+    symlink("two", root.path().join("third")).unwrap();
+
+    let scan = scan_ok(&["--symlink-hash"], &[root.path()]);
+    let first = scan.hash_of(root.path(), "first");
+    assert_eq!(first, scan.hash_of(root.path(), "second"));
+    assert_ne!(first, scan.hash_of(root.path(), "third"));
+    // The hash is of the target path rather than the contents it points to
+    assert_eq!(first, sha256(b"one"));
+    assert_ne!(first, scan.hash_of(root.path(), "one"));
+}
+
+#[test]
+fn relinking_changes_the_hash() {
+    let root = tree(&[("one", b"same"), ("two", b"same")]);
+    let link = root.path().join("link");
+    symlink("one", &link).unwrap();
+    let before = scan_ok(&["--symlink-hash"], &[root.path()]).hash_of(root.path(), "link");
+
+    std::fs::remove_file(&link).unwrap();
+    symlink("two", &link).unwrap();
+    let after = scan_ok(&["--symlink-hash"], &[root.path()]).hash_of(root.path(), "link");
+    assert_ne!(before, after);
+}
+
+#[test]
+fn paths_keep_a_symlinked_root_without_canonicalization() {
+    let parent = tempdir();
+    write(parent.path(), "real/a", b"a");
+    let link = parent.path().join("link");
+    symlink("real", &link).unwrap();
+
+    let given = scan_ok(&["--canonical-paths", "false"], &[&link]);
+    assert_eq!(
+        given.paths(),
+        vec![link.join("a").to_string_lossy().into_owned()]
+    );
+
+    let canonical = scan_ok(&[], &[&link]);
+    assert_eq!(canonical.paths(), vec![recorded(parent.path(), "real/a")]);
+}
+
+#[test]
+fn relative_roots_stay_relative_without_canonicalization() {
+    let parent = tempdir();
+    write(parent.path(), "dir/a", b"a");
+    let out = tempdir();
+    let output = dexy()
+        .args(["--canonical-paths", "false", "--out"])
+        .arg(out.path())
+        .arg("dir")
+        .current_dir(parent.path())
+        .output()
+        .unwrap();
+    let scan = Scan { out, output };
+    assert!(scan.success(), "{}", scan.stderr());
+    assert_eq!(scan.paths(), vec!["dir/a"]);
+}
+
+#[test]
+fn links_leading_outside_the_start_directories_are_not_followed() {
+    let parent = tempdir();
+    write(parent.path(), "root/sub/a", b"a");
+    write(parent.path(), "outside/secret", b"secret");
+    let root = parent.path().join("root");
+    symlink("../outside", root.join("dir")).unwrap();
+    symlink("../outside/secret", root.join("file")).unwrap();
+    symlink("sub", root.join("inner")).unwrap();
+
+    let guarded = scan_ok(&[], &[&root]);
+    assert_eq!(
+        guarded.paths(),
+        vec![recorded(&root, "inner/a"), recorded(&root, "sub/a")]
+    );
+
+    let escaping = scan_ok(&["--allow-symlink-escape"], &[&root]);
+    assert_eq!(escaping.hash_of(&root, "dir/secret"), sha256(b"secret"));
+    assert_eq!(escaping.hash_of(&root, "file"), sha256(b"secret"));
+}
+
+#[test]
+fn links_into_another_start_directory_are_followed() {
+    let parent = tempdir();
+    write(parent.path(), "root/a", b"a");
+    write(parent.path(), "other/b", b"b");
+    let root = parent.path().join("root");
+    symlink("../other", root.join("other")).unwrap();
+
+    let scan = scan_ok(&[], &[&root, &parent.path().join("other")]);
+    assert_eq!(scan.hash_of(&root, "other/b"), sha256(b"b"));
+    assert_eq!(scan.hash_of(parent.path(), "other/b"), sha256(b"b"));
+}
+
+/// The entry recorded for the file at `path` below `root`.
+fn entry(scan: &Scan, root: &std::path::Path, path: &str) -> serde_json::Value {
+    let path = recorded(root, path);
+    scan.entries()
+        .into_iter()
+        .find(|entry| path_of(entry) == path)
+        .unwrap_or_else(|| panic!("{} wasn't recorded", path))
+}
+
+#[test]
+fn relative_targets_are_resolved_against_the_link() {
+    let root = tree(&[("target", b"target")]);
+    std::fs::create_dir(root.path().join("sub")).unwrap();
+    symlink("../target", root.path().join("sub/link")).unwrap();
+
+    let raw = scan_ok(&["--symlink-hash"], &[root.path()]);
+    let link = entry(&raw, root.path(), "sub/link");
+    assert_eq!(link["link_target"], "../target");
+    assert!(link.get("link_target_resolved").is_none());
+
+    let resolved = scan_ok(
+        &["--symlink-hash", "--resolve-relative-symlinks"],
+        &[root.path()],
+    );
+    let link = entry(&resolved, root.path(), "sub/link");
+    let target = recorded(root.path(), "sub/../target");
+    assert_eq!(link["link_target"], target);
+    assert_eq!(link["link_target_resolved"], true);
+    assert_eq!(link["hash"], sha256(target.as_bytes()));
+    assert_eq!(
+        std::path::Path::new(&target).canonicalize().unwrap(),
+        root.path().join("target").canonicalize().unwrap()
+    );
+}
+
+#[test]
+fn absolute_targets_are_left_as_they_are() {
+    let root = tree(&[("target", b"target")]);
+    let target = root.path().join("target");
+    symlink(&target, root.path().join("link")).unwrap();
+
+    let scan = scan_ok(
+        &["--symlink-hash", "--resolve-relative-symlinks"],
+        &[root.path()],
+    );
+    let link = entry(&scan, root.path(), "link");
+    assert_eq!(link["link_target"], target.to_string_lossy().as_ref());
+    assert_eq!(link["hash"], sha256(target.to_string_lossy().as_bytes()));
+}
+
+/// A start directory linking to the first of a chain of three directories outside it, each
+/// linking to the next, with the first also linking back to itself.
+fn chained_links() -> (tempfile::TempDir, std::path::PathBuf) {
+    let parent = tempdir();
+    write(parent.path(), "root/top", b"top");
+    for (dir, next) in [("d1", Some("d2")), ("d2", Some("d3")), ("d3", None)] {
+        let dir = parent.path().join("chain").join(dir);
+        write(&dir, "file", dir.to_string_lossy().as_bytes());
+        if let Some(next) = next {
+            symlink(dir.with_file_name(next), dir.join("next")).unwrap();
+        }
+    }
+    let chain = parent.path().join("chain");
+    symlink(chain.join("d1"), chain.join("d1/back")).unwrap();
+    let root = parent.path().join("root");
+    symlink(chain.join("d1"), root.join("chain")).unwrap();
+    (parent, root)
+}
+
+#[test]
+fn symlinks_are_only_followed_to_the_maximum_depth() {
+    let (_parent, root) = chained_links();
+    let (scan, summary) = scan_summary(
+        &["--allow-symlink-escape", "--max-symlink-depth", "2"],
+        &[&root],
+    );
+    assert_eq!(
+        scan.paths(),
+        vec![
+            recorded(&root, "chain/file"),
+            recorded(&root, "chain/next/file"),
+            recorded(&root, "top"),
+        ]
+    );
+    assert_eq!(summary["symlink_depth_limited"], 1);
+    assert_eq!(summary["symlink_cycles"], 1);
+
+    let (scan, summary) = scan_summary(
+        &["--allow-symlink-escape", "--max-symlink-depth", "0"],
+        &[&root],
+    );
+    assert_eq!(scan.paths(), vec![recorded(&root, "top")]);
+    assert_eq!(summary["symlink_depth_limited"], 1);
+}
+
+#[test]
+fn symlinks_are_followed_to_any_depth_by_default() {
+    let (parent, root) = chained_links();
+    let (scan, summary) = scan_summary(&["--allow-symlink-escape"], &[&root]);
+    assert_eq!(scan.entries().len(), 4);
+    let last = parent.path().join("chain/d3");
+    assert_eq!(
+        scan.hash_of(&root, "chain/next/next/file"),
+        sha256(last.to_string_lossy().as_bytes())
+    );
+    assert_eq!(summary["symlink_depth_limited"], 0);
+    // The link back to the directory it's in is still skipped
+    assert_eq!(summary["symlink_cycles"], 1);
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use common::*;
+use serde_bencode::value::Value;
+use std::collections::BTreeMap;
+
+/// A bencoded dictionary's entry under `key`.
+fn get<'a>(dict: &'a Value, key: &str) -> &'a Value {
+    match dict {
+        Value::Dict(entries) => entries
+            .get(key.as_bytes())
+            .unwrap_or_else(|| panic!("no {} in {:?}", key, dict)),
+        other => panic!("{:?} isn't a dictionary", other),
+    }
+}
+
+/// The `(name, file tree)` in the info of the torrent written by `scan`.
+fn torrent(scan: &Scan) -> (String, Value) {
+    let data = std::fs::read(scan.path("dexy.torrent")).unwrap();
+    let metainfo: Value = serde_bencode::from_bytes(&data).unwrap();
+    let info = get(&metainfo, "info");
+    let name = match get(info, "name") {
+        Value::Bytes(name) => String::from_utf8(name.clone()).unwrap(),
+        other => panic!("{:?} isn't a name", other),
+    };
+    (name, get(info, "file tree").clone())
+}
+
+/// Every file in a torrent's file tree by its path within the torrent, with its length and
+/// pieces root as hex.
+fn listed_files(tree: &Value) -> BTreeMap<String, (i64, String)> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![(String::new(), tree)];
+    while let Some((prefix, node)) = pending.pop() {
+        let Value::Dict(entries) = node else {
+            panic!("{:?} isn't a directory", node)
+        };
+        for (name, child) in entries {
+            let name = String::from_utf8(name.clone()).unwrap();
+            if name.is_empty() {
+                let Value::Int(length) = get(child, "length") else {
+                    panic!("{:?} has no length", child)
+                };
+                let Value::Bytes(root) = get(child, "pieces root") else {
+                    panic!("{:?} has no pieces root", child)
+                };
+                let root = root.iter().map(|b| format!("{:02x}", b)).collect();
+                files.insert(prefix.clone(), (*length, root));
+            } else if prefix.is_empty() {
+                pending.push((name, child));
+            } else {
+                pending.push((format!("{}/{}", prefix, name), child));
+            }
+        }
+    }
+    files
+}
+
+#[test]
+fn a_single_start_directory_is_the_torrent() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"bb")]);
+    let scan = scan_ok(&["--torrent"], &[root.path()]);
+    let (name, tree) = torrent(&scan);
+    assert_eq!(
+        name,
+        root.path().file_name().unwrap().to_string_lossy().as_ref()
+    );
+    // Files of a single block are their own merkle root
+    assert_eq!(
+        listed_files(&tree),
+        [
+            ("a".to_string(), (1, sha256(b"a"))),
+            ("sub/b".to_string(), (2, sha256(b"bb"))),
+        ]
+        .into_iter()
+        .collect()
+    );
+    assert_eq!(
+        scan.entries()[0]["pieces_root"],
+        sha256(b"a"),
+        "{}",
+        scan.entries()[0]
+    );
+}
+
+#[test]
+fn a_single_file_is_the_whole_torrent() {
+    let root = tree(&[("only", b"only"), ("ignored", b"ignored")]);
+    let scan = scan_ok(&["--torrent"], &[&root.path().join("only")]);
+    let (name, tree) = torrent(&scan);
+    assert_eq!(name, "only");
+    assert_eq!(
+        listed_files(&tree),
+        [("only".to_string(), (4, sha256(b"only")))]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn several_start_paths_each_get_a_directory() {
+    let parent = tempdir();
+    write(parent.path(), "music/a", b"a");
+    write(parent.path(), "photos/b", b"b");
+    write(parent.path(), "notes", b"notes");
+    let scan = scan_ok(
+        &["--torrent"],
+        &[
+            &parent.path().join("music"),
+            &parent.path().join("photos"),
+            &parent.path().join("notes"),
+        ],
+    );
+    let (name, tree) = torrent(&scan);
+    assert_eq!(name, "dexy");
+    let files: Vec<String> = listed_files(&tree).into_keys().collect();
+    assert_eq!(files, vec!["music/a", "notes", "photos/b"]);
+}
+
+#[test]
+fn start_paths_sharing_a_name_are_told_apart() {
+    // A file in one is where the other has a directory
+    let parent = tempdir();
+    write(parent.path(), "a/data/x", b"file");
+    write(parent.path(), "b/data/x/y", b"nested");
+    let (a, b) = (parent.path().join("a/data"), parent.path().join("b/data"));
+
+    for roots in [[&a, &b], [&b, &a]] {
+        let scan = scan_ok(&["--torrent"], &[roots[0], roots[1]]);
+        let files = listed_files(&torrent(&scan).1);
+        let (first, second) = match roots[0] == &a {
+            true => ("data-1/x", "data-2/x/y"),
+            false => ("data-2/x", "data-1/x/y"),
+        };
+        assert_eq!(
+            files,
+            [
+                (first.to_string(), (4, sha256(b"file"))),
+                (second.to_string(), (6, sha256(b"nested"))),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+}
+
+#[test]
+fn labels_never_collide_with_another_start_path() {
+    let parent = tempdir();
+    write(parent.path(), "one/data/a", b"a");
+    write(parent.path(), "two/data/b", b"b");
+    write(parent.path(), "data-1/c", b"c");
+    let scan = scan_ok(
+        &["--torrent"],
+        &[
+            &parent.path().join("one/data"),
+            &parent.path().join("two/data"),
+            &parent.path().join("data-1"),
+        ],
+    );
+    let files: Vec<String> = listed_files(&torrent(&scan).1).into_keys().collect();
+    assert_eq!(files, vec!["data-1/c", "data-2/a", "data-3/b"]);
+}
@@ -0,0 +1,476 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn files_and_directories_can_be_mixed_as_start_paths() {
+    let files = tree(&[("one", b"1"), ("two", b"2"), ("ignored", b"x")]);
+    let dir = tree(&[("a", b"a"), ("sub/b", b"b")]);
+    let scan = scan_ok(
+        &[],
+        &[
+            &files.path().join("one"),
+            dir.path(),
+            &files.path().join("two"),
+        ],
+    );
+
+    let mut expected = vec![
+        recorded(files.path(), "one"),
+        recorded(files.path(), "two"),
+        recorded(dir.path(), "a"),
+        recorded(dir.path(), "sub/b"),
+    ];
+    expected.sort();
+    assert_eq!(scan.paths(), expected);
+    let hashes = scan.hashes();
+    let one = hashes.iter().find(|(path, _)| path.ends_with("one"));
+    assert_eq!(one.unwrap().1, sha256(b"1"));
+}
+
+/// A directory on a different filesystem to the temporary directories, if there is one.
+#[cfg(unix)]
+fn other_filesystem(than: &std::path::Path) -> Option<tempfile::TempDir> {
+    use std::os::unix::fs::MetadataExt;
+    let dir = tempfile::Builder::new()
+        .prefix("dexy-test")
+        .tempdir_in("/dev/shm")
+        .ok()?;
+    let device = |path: &std::path::Path| std::fs::metadata(path).unwrap().dev();
+    (device(dir.path()) != device(than)).then_some(dir)
+}
+
+#[cfg(unix)]
+#[test]
+fn other_filesystems_are_skipped_unless_followed() {
+    let root = tree(&[("local", b"local")]);
+    let Some(mount) = other_filesystem(root.path()) else {
+        eprintln!("skipped: no second filesystem at /dev/shm");
+        return;
+    };
+    write(mount.path(), "remote", b"remote");
+    std::os::unix::fs::symlink(mount.path(), root.path().join("mount")).unwrap();
+
+    let staying = scan_ok(
+        &["--allow-symlink-escape", "--follow-mounts", "false"],
+        &[root.path()],
+    );
+    assert_eq!(staying.paths(), vec![recorded(root.path(), "local")]);
+
+    let crossing = scan_ok(&["--allow-symlink-escape"], &[root.path()]);
+    assert_eq!(crossing.entries().len(), 2);
+}
+
+#[test]
+fn directories_are_recorded_under_their_own_key() {
+    let root = tree(&[("sub/a", b"a")]);
+    std::fs::create_dir(root.path().join("sub/empty")).unwrap();
+
+    let scan = scan_ok(&["--include-dir-entries"], &[root.path()]);
+    let files = scan.files();
+    let mut directories: Vec<String> = files["directories"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            assert!(entry["hash"].is_null());
+            assert_eq!(entry["attributes"]["file_type"], "Directory");
+            path_of(entry)
+        })
+        .collect();
+    directories.sort();
+    assert_eq!(
+        directories,
+        vec![
+            root.path()
+                .canonicalize()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            recorded(root.path(), "sub"),
+            recorded(root.path(), "sub/empty"),
+        ]
+    );
+    assert_eq!(scan.hash_of(root.path(), "sub/a"), sha256(b"a"));
+}
+
+#[test]
+fn directories_are_left_out_by_default() {
+    let root = tree(&[("sub/a", b"a")]);
+    let scan = scan_ok(&[], &[root.path()]);
+    assert!(!scan.files().contains_key("directories"));
+}
+
+#[test]
+fn nested_start_directories_are_scanned_once() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b"), ("sub/deeper/c", b"c")]);
+    let expected = vec![
+        recorded(root.path(), "a"),
+        recorded(root.path(), "sub/b"),
+        recorded(root.path(), "sub/deeper/c"),
+    ];
+
+    let child_last = scan_ok(&[], &[root.path(), &root.path().join("sub")]);
+    assert_eq!(child_last.paths(), expected);
+
+    let child_first = scan_ok(&[], &[&root.path().join("sub/deeper"), root.path()]);
+    assert_eq!(child_first.paths(), expected);
+}
+
+#[test]
+fn repeated_start_directories_are_scanned_once() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan_ok(&[], &[root.path(), root.path()]);
+    assert_eq!(scan.paths(), vec![recorded(root.path(), "a")]);
+}
+
+#[test]
+fn sibling_start_directories_sharing_a_prefix_are_both_scanned() {
+    let root = tree(&[("data/a", b"a"), ("data2/b", b"b")]);
+    let scan = scan_ok(
+        &[],
+        &[&root.path().join("data"), &root.path().join("data2")],
+    );
+    assert_eq!(
+        scan.paths(),
+        vec![
+            recorded(root.path(), "data/a"),
+            recorded(root.path(), "data2/b")
+        ]
+    );
+}
+
+#[test]
+fn prefetching_metadata_finds_the_same_files() {
+    let contents: Vec<(String, String)> = (0..300)
+        .map(|i| {
+            (
+                format!("dir{}/file{}", i % 7, i),
+                format!("contents {}", i % 50),
+            )
+        })
+        .collect();
+    let files: Vec<(&str, &[u8])> = contents
+        .iter()
+        .map(|(path, contents)| (path.as_str(), contents.as_bytes()))
+        .collect();
+    let root = tree(&files);
+    write(root.path(), ".hidden", b"hidden");
+
+    let sequential = scan_ok(&[], &[root.path()]);
+    for entries in ["1", "16", "1000"] {
+        let prefetched = scan_ok(&["--prefetch", entries], &[root.path()]);
+        assert_eq!(prefetched.hashes(), sequential.hashes());
+    }
+    assert_eq!(sequential.hashes().len(), 300);
+}
+
+/// The paths of files in the order they were hashed by a single thread, relative to `root`.
+fn hashing_order(traversal: &str, root: &std::path::Path) -> Vec<String> {
+    let dir = tempdir();
+    let events = dir.path().join("events.ndjson");
+    scan_ok(
+        &[
+            "--thread-count",
+            "1",
+            "--traversal",
+            traversal,
+            "--events-file",
+            events.to_str().unwrap(),
+        ],
+        &[root],
+    );
+    let prefix = recorded(root, "");
+    read_events(&events)
+        .iter()
+        .filter(|event| event["type"] == "file")
+        .map(|event| path_of(event).strip_prefix(&prefix).unwrap().to_string())
+        .collect()
+}
+
+/// Three directories, each holding a file and a nested subtree.
+fn nested_tree() -> tempfile::TempDir {
+    let mut files = vec![];
+    for top in ["a", "b", "c"] {
+        for path in ["f", "x/f", "x/y/f"] {
+            files.push(format!("{}/{}", top, path));
+        }
+    }
+    let files: Vec<(&str, &[u8])> = files.iter().map(|p| (p.as_str(), p.as_bytes())).collect();
+    tree(&files)
+}
+
+#[test]
+fn breadth_first_traversal_hashes_shallower_files_first() {
+    let root = nested_tree();
+    let order = hashing_order("bfs", root.path());
+    assert_eq!(order.len(), 9);
+    let depths: Vec<usize> = order.iter().map(|path| path.matches('/').count()).collect();
+    assert!(
+        depths.windows(2).all(|pair| pair[0] <= pair[1]),
+        "{:?}",
+        order
+    );
+}
+
+#[test]
+fn depth_first_traversal_finishes_each_subtree_first() {
+    let root = nested_tree();
+    let order = hashing_order("dfs", root.path());
+    assert_eq!(order.len(), 9);
+    // Each top-level directory's files are hashed together, parents before children
+    for subtree in order.chunks(3) {
+        let top = &subtree[0][..1];
+        let expected: Vec<String> = ["f", "x/f", "x/y/f"]
+            .iter()
+            .map(|path| format!("{}/{}", top, path))
+            .collect();
+        assert_eq!(subtree, expected, "{:?}", order);
+    }
+}
+
+/// Paths of the directories recorded by a scan with `--include-dir-entries` and `args`.
+fn directories(root: &std::path::Path, args: &[&str]) -> Vec<String> {
+    let args = [&["--include-dir-entries"], args].concat();
+    let scan = scan_ok(&args, &[root]);
+    let mut directories: Vec<String> = scan
+        .files()
+        .get("directories")
+        .and_then(|directories| directories.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(path_of)
+        .collect();
+    directories.sort();
+    directories
+}
+
+/// A tree with a directory holding a file, one holding only an empty directory, and one
+/// empty directory inside a directory with files.
+fn tree_with_empty_dirs() -> tempfile::TempDir {
+    let root = tree(&[("full/file", b"file")]);
+    for dir in ["full/empty", "hollow/nested", "alone"] {
+        std::fs::create_dir_all(root.path().join(dir)).unwrap();
+    }
+    root
+}
+
+#[test]
+fn directories_without_files_can_be_left_out() {
+    let root = tree_with_empty_dirs();
+    assert_eq!(
+        directories(root.path(), &["--exclude-empty-dirs"]),
+        vec![
+            root.path()
+                .canonicalize()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            recorded(root.path(), "full"),
+        ]
+    );
+}
+
+#[test]
+fn only_directories_without_files_can_be_output() {
+    let root = tree_with_empty_dirs();
+    let scan = scan_ok(
+        &["--include-dir-entries", "--only-empty-dirs"],
+        &[root.path()],
+    );
+    // Files are left out too, only the empty directories are of interest
+    assert!(!scan.files().contains_key(&sha256(b"file")));
+    assert_eq!(
+        directories(root.path(), &["--only-empty-dirs"]),
+        vec![
+            recorded(root.path(), "alone"),
+            recorded(root.path(), "full/empty"),
+            recorded(root.path(), "hollow"),
+            recorded(root.path(), "hollow/nested"),
+        ]
+    );
+}
+
+#[test]
+fn empty_directory_modes_need_directory_entries() {
+    let root = tree_with_empty_dirs();
+    for mode in ["--exclude-empty-dirs", "--only-empty-dirs"] {
+        assert_eq!(scan(&[mode], &[root.path()]).output.status.code(), Some(2));
+    }
+    let both = scan(
+        &[
+            "--include-dir-entries",
+            "--exclude-empty-dirs",
+            "--only-empty-dirs",
+        ],
+        &[root.path()],
+    );
+    assert_eq!(both.output.status.code(), Some(2));
+}
+
+/// The start directory, `a` or `b`, of each file in the order a single thread hashed them.
+fn roots_in_hashing_order(args: &[&str]) -> String {
+    let parent = tempdir();
+    for root in ["a", "b"] {
+        for dir in 1..=4 {
+            write(
+                parent.path(),
+                &format!("{}/d{}/file", root, dir),
+                root.as_bytes(),
+            );
+        }
+    }
+    let dir = tempdir();
+    let events = dir.path().join("events.ndjson");
+    let args = [
+        &[
+            "--thread-count",
+            "1",
+            "--events-file",
+            events.to_str().unwrap(),
+        ],
+        args,
+    ]
+    .concat();
+    scan_ok(&args, &[&parent.path().join("a"), &parent.path().join("b")]);
+    let prefix = recorded(parent.path(), "");
+    read_events(&events)
+        .iter()
+        .filter(|event| event["type"] == "file")
+        .map(|event| path_of(event).strip_prefix(&prefix).unwrap()[..1].to_string())
+        .collect()
+}
+
+#[test]
+fn parallel_roots_are_scanned_in_turn() {
+    assert_eq!(roots_in_hashing_order(&["--parallel-roots"]), "abababab");
+}
+
+#[test]
+fn roots_are_scanned_one_after_another_by_default() {
+    assert_eq!(roots_in_hashing_order(&[]), "aaaabbbb");
+}
+
+#[test]
+fn glob_patterns_are_expanded_into_start_directories() {
+    let root = tree(&[
+        ("data/2020/a", b"2020"),
+        ("data/2021/b", b"2021"),
+        ("data/2023/sub/c", b"2023"),
+        ("data/1999/d", b"1999"),
+        ("data/2022", b"not a directory"),
+        ("other/e", b"other"),
+    ]);
+    let pattern = root.path().join("data/202?/");
+    let scan = scan_ok(&["--expand-globs"], &[&pattern]);
+    assert_eq!(
+        scan.paths(),
+        vec![
+            recorded(root.path(), "data/2020/a"),
+            recorded(root.path(), "data/2021/b"),
+            recorded(root.path(), "data/2023/sub/c"),
+        ]
+    );
+    assert_eq!(
+        scan.json("dexy.json")["start_directories"]
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn patterns_are_taken_literally_unless_expanded() {
+    let root = tree(&[("data/2020/a", b"2020")]);
+    let scan = scan(&[], &[&root.path().join("data/202?")]);
+    assert!(!scan.success());
+    assert!(!scan.path("dexy.json").exists());
+}
+
+#[test]
+fn patterns_matching_nothing_are_rejected() {
+    let root = tree(&[("data/2020/a", b"2020")]);
+    let unmatched = scan(
+        &["--expand-globs"],
+        &[
+            &root.path().join("data/2020"),
+            &root.path().join("data/19*"),
+        ],
+    );
+    assert_eq!(unmatched.output.status.code(), Some(2));
+    assert!(
+        unmatched.stderr().contains("doesn't match anything"),
+        "{}",
+        unmatched.stderr()
+    );
+
+    let invalid = scan(&["--expand-globs"], &[&root.path().join("data/[")]);
+    assert_eq!(invalid.output.status.code(), Some(2));
+}
+
+/// Scan `root` with `args`, writing the output into `root` itself, and return the paths
+/// recorded relative to it, sorted.
+fn scan_into_itself(root: &std::path::Path, args: &[&str]) -> Vec<String> {
+    let output = dexy()
+        .args(args)
+        .arg("--out")
+        .arg(root)
+        .arg(root)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let name = args
+        .iter()
+        .position(|arg| *arg == "--name")
+        .map_or("dexy", |i| args[i + 1]);
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(root.join(format!("{}.json", name))).unwrap())
+            .unwrap();
+    let prefix = recorded(root, "");
+    entries(manifest["files"].as_object().unwrap())
+        .iter()
+        .map(|entry| path_of(entry).strip_prefix(&prefix).unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn output_written_inside_a_start_directory_is_not_hashed() {
+    let root = tree(&[
+        ("a", b"a"),
+        ("dexy-notes.txt", b"notes"),
+        ("sub/dexy.json", b"{}"),
+    ]);
+    let expected = vec!["a", "dexy-notes.txt", "sub/dexy.json"];
+    assert_eq!(
+        scan_into_itself(root.path(), &["--report-largest", "1"]),
+        expected
+    );
+    assert!(root.path().join("dexy.largest.json").exists());
+
+    // Neither the previous output nor the reports written alongside it are picked up
+    assert_eq!(
+        scan_into_itself(root.path(), &["--report-largest", "1"]),
+        expected
+    );
+}
+
+#[test]
+fn output_under_another_name_is_hashed() {
+    let root = tree(&[("a", b"a")]);
+    scan_into_itself(root.path(), &[]);
+    assert_eq!(
+        scan_into_itself(root.path(), &["--name", "other"]),
+        vec!["a", "dexy.json"]
+    );
+}
+
+#[test]
+fn own_output_can_be_hashed_when_asked() {
+    let root = tree(&[("a", b"a")]);
+    scan_into_itself(root.path(), &[]);
+    assert_eq!(
+        scan_into_itself(root.path(), &["--no-exclude-self"]),
+        vec!["a", "dexy.json"]
+    );
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use common::*;
+use serde_json::Value;
+use std::path::Path;
+
+/// Run `dexy validate` against `manifest`, returning its exit code and the lines it printed.
+fn validate(manifest: &Path) -> (Option<i32>, Vec<String>) {
+    let output = dexy().arg("validate").arg(manifest).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    (
+        output.status.code(),
+        stdout.lines().map(str::to_string).collect(),
+    )
+}
+
+/// A scan of a small tree, along with the tree.
+fn scanned() -> (tempfile::TempDir, Scan) {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b"), ("c", b"a")]);
+    let scan = scan_ok(&[], &[root.path()]);
+    (root, scan)
+}
+
+/// Replace `dexy.json` in `scan` with `edit` applied to it.
+fn tamper(scan: &Scan, edit: impl FnOnce(&mut Value)) -> std::path::PathBuf {
+    let mut manifest = scan.json("dexy.json");
+    edit(&mut manifest);
+    let path = scan.path("dexy.json");
+    std::fs::write(&path, manifest.to_string()).unwrap();
+    path
+}
+
+#[test]
+fn manifests_written_by_a_scan_are_valid() {
+    let (root, scan) = scanned();
+    let (code, lines) = validate(&scan.path("dexy.json"));
+    assert_eq!(code, Some(0));
+    assert_eq!(lines, vec!["0 problems found in 3 entries"]);
+
+    let raw = scan_ok(&["--raw"], &[root.path()]);
+    assert_eq!(validate(&raw.path("dexy.json")).0, Some(0));
+}
+
+#[test]
+fn malformed_hashes_are_flagged() {
+    let (root, scan) = scanned();
+    let path = tamper(&scan, |manifest| {
+        let group = manifest["files"][sha256(b"b")].as_array_mut().unwrap();
+        group[0]["hash"] = "not-a-hash".into();
+    });
+    let (code, lines) = validate(&path);
+    assert_eq!(code, Some(1));
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains("not-a-hash")
+                && line.ends_with(&recorded(root.path(), "sub/b"))),
+        "{:?}",
+        lines
+    );
+    assert_eq!(lines.last().unwrap(), "1 problems found in 3 entries");
+}
+
+#[test]
+fn hashes_of_the_wrong_length_for_the_algorithm_are_flagged() {
+    let (_root, scan) = scanned();
+    let path = tamper(&scan, |manifest| manifest["algorithm"] = "md5".into());
+    let (code, lines) = validate(&path);
+    assert_eq!(code, Some(1));
+    assert_eq!(lines.last().unwrap(), "3 problems found in 3 entries");
+}
+
+#[test]
+fn paths_listed_twice_are_flagged() {
+    let (root, scan) = scanned();
+    let path = tamper(&scan, |manifest| {
+        let group = manifest["files"][sha256(b"a")].as_array_mut().unwrap();
+        group[1]["path"] = group[0]["path"].clone();
+    });
+    let (code, lines) = validate(&path);
+    assert_eq!(code, Some(1));
+    let a = recorded(root.path(), "a");
+    let c = recorded(root.path(), "c");
+    let listed = lines
+        .iter()
+        .find(|line| line.starts_with("listed 2 times: "));
+    assert!(
+        matches!(listed, Some(line) if line.ends_with(&a) || line.ends_with(&c)),
+        "{:?}",
+        lines
+    );
+}
+
+#[test]
+fn inconsistent_headers_are_flagged() {
+    let (_root, scan) = scanned();
+    let path = tamper(&scan, |manifest| {
+        manifest["start_directories"] = serde_json::json!(["/somewhere/else"])
+    });
+    let (code, lines) = validate(&path);
+    assert_eq!(code, Some(1));
+    let outside = lines
+        .iter()
+        .filter(|line| line.starts_with("outside the start directories: "))
+        .count();
+    assert_eq!(outside, 3, "{:?}", lines);
+}
+
+#[test]
+fn unreadable_manifests_are_invalid() {
+    let dir = tempdir();
+    let truncated = write(dir.path(), "dexy.json", b"{\"files\": {");
+    let (code, lines) = validate(&truncated);
+    assert_eq!(code, Some(1));
+    assert!(lines[0].starts_with("unreadable: "), "{:?}", lines);
+
+    assert_eq!(validate(&dir.path().join("missing.json")).0, Some(1));
+}
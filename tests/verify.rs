@@ -0,0 +1,262 @@
+mod common;
+
+use common::*;
+use std::path::Path;
+
+/// Run `dexy verify` against `manifest`, returning its exit code and the lines it printed.
+fn verify(manifest: &Path, args: &[&str]) -> (Option<i32>, Vec<String>) {
+    let output = dexy()
+        .arg("verify")
+        .args(args)
+        .arg(manifest)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    (
+        output.status.code(),
+        stdout.lines().map(str::to_string).collect(),
+    )
+}
+
+#[test]
+fn unchanged_trees_verify() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b")]);
+    let scan = scan_ok(&[], &[root.path()]);
+
+    let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(0));
+    assert_eq!(lines, vec!["2 of 2 files match"]);
+}
+
+#[test]
+fn changed_and_missing_files_are_reported() {
+    let root = tree(&[
+        ("changed", b"before"),
+        ("missing", b"gone"),
+        ("same", b"same"),
+    ]);
+    let scan = scan_ok(&[], &[root.path()]);
+    write(root.path(), "changed", b"after");
+    std::fs::remove_file(root.path().join("missing")).unwrap();
+
+    let (code, mut lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(1));
+    assert_eq!(lines.pop().unwrap(), "1 of 3 files match");
+    lines.sort();
+    assert_eq!(
+        lines,
+        vec![
+            format!("changed: {}", recorded(root.path(), "changed")),
+            format!("missing: {}", recorded(root.path(), "missing")),
+        ]
+    );
+}
+
+#[test]
+fn raw_scans_verify() {
+    let root = tree(&[("a", b"a")]);
+    let scan = scan_ok(&["--raw", "--algorithm", "blake3"], &[root.path()]);
+    write(root.path(), "a", b"b");
+
+    let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(1));
+    assert_eq!(lines[0], format!("changed: {}", recorded(root.path(), "a")));
+}
+
+#[cfg(unix)]
+#[test]
+fn links_are_verified_by_their_target() {
+    use std::os::unix::fs::symlink;
+    let root = tree(&[("one", b"same"), ("two", b"same")]);
+    let link = root.path().join("link");
+    symlink("one", &link).unwrap();
+    let scan = scan_ok(&["--symlink-hash"], &[root.path()]);
+
+    let (code, _) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(0));
+
+    std::fs::remove_file(&link).unwrap();
+    symlink("two", &link).unwrap();
+    let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(1));
+    assert_eq!(
+        lines[0],
+        format!("changed: {}", recorded(root.path(), "link"))
+    );
+}
+
+#[test]
+fn archive_members_are_verified() {
+    let root = tree(&[]);
+    let archive = root.path().join("bundle.tar");
+    write_tar(&archive, &[("a", b"a"), ("b", b"b")], false);
+    let scan = scan_ok(&["--scan-archives"], &[root.path()]);
+
+    let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(0), "{:?}", lines);
+    assert_eq!(lines, vec!["3 of 3 files match"]);
+
+    write_tar(&archive, &[("a", b"changed"), ("b", b"b")], false);
+    let (code, mut lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(1));
+    assert_eq!(lines.pop().unwrap(), "1 of 3 files match");
+    lines.sort();
+    let archive = recorded(root.path(), "bundle.tar");
+    assert_eq!(
+        lines,
+        vec![
+            format!("changed: {}", archive),
+            format!("changed: {}!a", archive),
+        ]
+    );
+}
+
+#[test]
+fn normalized_line_endings_are_verified_as_scanned() {
+    let root = tree(&[("text", b"one\r\ntwo\r\n")]);
+    let scan = scan_ok(&["--normalize-line-endings"], &[root.path()]);
+
+    // Only the line endings changed, which the scan ignored
+    write(root.path(), "text", b"one\ntwo\n");
+    let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(0), "{:?}", lines);
+
+    write(root.path(), "text", b"one\ntwo\nthree\n");
+    let (code, _) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(1));
+}
+
+#[test]
+fn keyed_scans_need_their_key() {
+    let root = tree(&[("a", b"a")]);
+    let keys = tempdir();
+    let key = write(keys.path(), "key", b"secret");
+    let scan = scan_ok(&["--hmac-key-file", key.to_str().unwrap()], &[root.path()]);
+
+    let (code, _) = verify(
+        &scan.path("dexy.json"),
+        &["--hmac-key-file", key.to_str().unwrap()],
+    );
+    assert_eq!(code, Some(0));
+
+    let wrong = write(keys.path(), "wrong", b"guess");
+    let (code, _) = verify(
+        &scan.path("dexy.json"),
+        &["--hmac-key-file", wrong.to_str().unwrap()],
+    );
+    assert_eq!(code, Some(1));
+}
+
+/// Scan `root` with a secondary algorithm, then set the recorded `field` of `path`'s entry to a
+/// hash of something else, as a bitflip while hashing or a collision would leave it.
+fn scan_with_mismatch(root: &Path, path: &str, field: &str) -> Scan {
+    let scan = scan_ok(&["--secondary-algorithm", "blake3"], &[root]);
+    let mut manifest = scan.json("dexy.json");
+    let target = recorded(root, path);
+    for group in manifest["files"].as_object_mut().unwrap().values_mut() {
+        for entry in group.as_array_mut().unwrap() {
+            if path_of(entry) != target {
+                continue;
+            }
+            match field {
+                "secondary" => {
+                    entry["secondary"]["hash"] = blake3::hash(b"other").to_hex().as_str().into()
+                }
+                _ => entry[field] = sha256(b"other").into(),
+            }
+        }
+    }
+    std::fs::write(scan.path("dexy.json"), manifest.to_string()).unwrap();
+    scan
+}
+
+#[test]
+fn secondary_hashes_are_verified_too() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b")]);
+    let scan = scan_ok(&["--secondary-algorithm", "blake3"], &[root.path()]);
+    let entry = &scan.entries()[0];
+    assert_eq!(entry["secondary"]["algorithm"], "blake3");
+    assert_eq!(
+        entry["secondary"]["hash"],
+        blake3::hash(b"a").to_hex().as_str()
+    );
+    assert_eq!(
+        verify(&scan.path("dexy.json"), &[]),
+        (Some(0), vec!["2 of 2 files match".to_string()])
+    );
+
+    // Both hashes change along with the contents
+    write(root.path(), "a", b"changed");
+    let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+    assert_eq!(code, Some(1));
+    assert_eq!(lines[0], format!("changed: {}", recorded(root.path(), "a")));
+}
+
+#[test]
+fn files_where_only_one_hash_matches_are_inconsistent() {
+    let root = tree(&[("a", b"a"), ("sub/b", b"b")]);
+    for field in ["secondary", "hash"] {
+        let scan = scan_with_mismatch(root.path(), "sub/b", field);
+        let (code, lines) = verify(&scan.path("dexy.json"), &[]);
+        assert_eq!(code, Some(1), "{}", field);
+        assert_eq!(
+            lines,
+            vec![
+                format!("inconsistent: {}", recorded(root.path(), "sub/b")),
+                "1 of 2 files match".to_string(),
+            ]
+        );
+    }
+}
+
+#[test]
+fn keys_which_cannot_be_used_are_usage_errors() {
+    let root = tree(&[("a", b"a")]);
+    let keys = tempdir();
+    let empty = write(keys.path(), "empty", b"");
+    let missing = keys.path().join("missing");
+
+    for key in [&empty, &missing] {
+        let scan = scan(&["--hmac-key-file", key.to_str().unwrap()], &[root.path()]);
+        assert_eq!(scan.output.status.code(), Some(2));
+        assert!(
+            scan.stderr().contains("unable to read --hmac-key-file"),
+            "{}",
+            scan.stderr()
+        );
+        assert!(!scan.stderr().contains("panicked"));
+        assert!(!scan.path("dexy.json").exists());
+    }
+
+    let key = write(keys.path(), "key", b"secret");
+    let scan = scan_ok(&["--hmac-key-file", key.to_str().unwrap()], &[root.path()]);
+    for key in [&empty, &missing] {
+        let output = dexy()
+            .arg("verify")
+            .args(["--hmac-key-file", key.to_str().unwrap()])
+            .arg(scan.path("dexy.json"))
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("unable to read --hmac-key-file"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn unreadable_manifests_fail_verification() {
+    let dir = tempdir();
+    let missing = dir.path().join("dexy.json");
+    let (code, lines) = verify(&missing, &[]);
+    assert_eq!(code, Some(1));
+    assert!(lines[0].starts_with("unreadable: "), "{:?}", lines);
+
+    let corrupt = write(dir.path(), "corrupt.json", b"{\"files\": [");
+    let (code, lines) = verify(&corrupt, &[]);
+    assert_eq!(code, Some(1));
+    assert!(lines[0].starts_with("unreadable: "), "{:?}", lines);
+}